@@ -1,52 +1,68 @@
-
 extern crate glsl_to_spirv;
 
 use std::error::Error;
 use std::io::Read;
-use glsl_to_spirv::ShaderType;
+use std::path::Path;
+
+// The preprocessor is shared with the engine, which uses it to compile shaders at runtime.
+#[allow(dead_code)]
+#[path = "src/shader/preprocess.rs"]
+mod preprocess;
+
+use preprocess::Preprocessor;
+use preprocess::ShaderStage;
+
+fn shader_type(stage: ShaderStage) -> glsl_to_spirv::ShaderType {
+    match stage {
+        ShaderStage::Vertex => glsl_to_spirv::ShaderType::Vertex,
+        ShaderStage::Fragment => glsl_to_spirv::ShaderType::Fragment,
+        ShaderStage::Geometry => glsl_to_spirv::ShaderType::Geometry,
+        ShaderStage::TessellationControl => glsl_to_spirv::ShaderType::TessellationControl,
+        ShaderStage::TessellationEvaluation => glsl_to_spirv::ShaderType::TessellationEvaluation,
+        ShaderStage::Compute => glsl_to_spirv::ShaderType::Compute,
+    }
+}
 
 fn main() -> Result<(), Box<Error>> {
     // Tell the build script to only run again if we change our source shaders
     println!("cargo:rerun-if-changed=shaders/src");
+    println!("cargo:rerun-if-changed=shaders/include");
+    println!("cargo:rerun-if-changed=src/shader/preprocess.rs");
 
-    // Create destination path if necessary
-    std::fs::create_dir_all("shaders/bin")?;
+    // The compiled shaders are embedded in the engine with `include_bytes!(concat!(env!("OUT_DIR"), "/<name>.spv"))`.
+    let out_dir = std::env::var("OUT_DIR")?;
+
+    let preprocessor = Preprocessor::new().with_include_dir("shaders/include");
 
     for entry in std::fs::read_dir("shaders/src")? {
         let entry = entry?;
 
         if entry.file_type()?.is_file() {
             let in_path = entry.path();
+            let name = in_path.file_stem().and_then(|stem| stem.to_str()).ok_or("Shader file names must be valid unicode.")?;
 
-            let mut shader_type = ShaderType::Vertex;
-            // Support only vertex and fragment shaders currently
-            if let Some(name) = in_path.file_name() {
-                if let Some(str_name) = name.to_str() {
-                    if str_name.contains("_v") {
-                        shader_type = ShaderType::Vertex;
-                    } else if str_name.contains("_f") {
-                        shader_type = ShaderType::Fragment;
-                    }
-                }
-            }
-            let source = std::fs::read_to_string(&in_path)?;
-            let mut compiled_file = glsl_to_spirv::compile(&source, shader_type)?;
-            // Read the binary data from the compiled file
-            let mut compiled_bytes = Vec::new();
-            compiled_file.read_to_end(&mut compiled_bytes)?;
-            let path_str = in_path.file_name().unwrap().to_str().unwrap();
-            let use_str = path_str.replace(&(String::from(".") + in_path.extension().unwrap().to_str().unwrap()), "");
-
-            // Determine the output path based on the input name
-            let out_path = format!(
-                "shaders/bin/{}.spv",
-                use_str
-            );
-
-            std::fs::write(&out_path, &compiled_bytes)?;
+            // The first pass finds the stage and the variants of the shader.
+            let shader = preprocessor.process(&in_path, &[])?;
+            let stage = shader.stage.ok_or_else(|| format!(
+                "Could not determine the stage of '{}'. Add a `#pragma shader_stage(...)` directive or a stage suffix such as `_v` to its name.",
+                in_path.display()
+            ))?;
 
+            // Each combination of variant defines is compiled to its own file.
+            for defines in preprocess::variant_permutations(&shader.variants) {
+                let source = preprocessor.process(&in_path, &defines)?.source;
+                let mut compiled_file = glsl_to_spirv::compile(&source, shader_type(stage))
+                    .map_err(|e| format!("Failed to compile '{}' with {:?}: {}", in_path.display(), defines, e))?;
+                // Read the binary data from the compiled file
+                let mut compiled_bytes = Vec::new();
+                compiled_file.read_to_end(&mut compiled_bytes)?;
+
+                // Determine the output path based on the input name
+                let out_path = Path::new(&out_dir).join(format!("{}.spv", preprocess::variant_name(name, &defines)));
+                std::fs::write(&out_path, &compiled_bytes)?;
+            }
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
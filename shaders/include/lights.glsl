@@ -0,0 +1,20 @@
+// The lights uniform written by the engine's LightsController.
+
+const int MAX_LIGHTS = 20;
+
+const int POINT_LIGHT = 0;
+const int DIRECTIONAL_LIGHT = 1;
+
+struct LightData {
+    // The position of a point light, or the direction of a directional light.
+    vec3 pos;
+    int kind;
+    vec3 color;
+    // The index of the light's shadow map, or -1 if it casts no shadows.
+    int shadow_index;
+};
+
+struct LightsList {
+    int count;
+    LightData data[MAX_LIGHTS];
+};
@@ -0,0 +1,41 @@
+// The shadows uniform written by the engine's ShadowsController.
+
+const int MAX_POINT_SHADOWS = 4;
+const int MAX_CASCADES = 4;
+
+struct ShadowsList {
+    // Six matrices per point light, one for each face, ordered +X, -X, +Y, -Y, +Z, -Z.
+    mat4 point_matrices[MAX_POINT_SHADOWS * 6];
+    mat4 cascade_matrices[MAX_CASCADES];
+    // The distance from the camera at which each cascade ends.
+    vec4 cascade_splits;
+    int cascade_count;
+    int pcf_radius;
+    float depth_bias;
+    float normal_bias;
+    // The near and far planes of the projection of every face of the point light shadow maps.
+    float point_near;
+    float point_far;
+    // The distance from the light which is subtracted from fragments before they are compared with a point light shadow map.
+    float point_depth_bias;
+    float padding;
+};
+
+// The face of a point light's shadow map which a direction from the light falls on.
+// The layers of each light are ordered +X, -X, +Y, -Y, +Z, -Z, and ties go to the earlier axis. This matches `cube_face` in the engine.
+int cube_face(vec3 dir) {
+    vec3 a = abs(dir);
+    if (a.x >= a.y && a.x >= a.z) {
+        return dir.x > 0.0 ? 0 : 1;
+    }
+    if (a.y >= a.z) {
+        return dir.y > 0.0 ? 2 : 3;
+    }
+    return dir.z > 0.0 ? 4 : 5;
+}
+
+// The depth a point light shadow map stores for a distance along the axis of a face.
+// The projection of the faces is not linear in depth, so a bias in world units is applied to the distance rather than the depth.
+float point_shadow_depth(float distance, float near, float far) {
+    return far * (distance - near) / ((far - near) * distance);
+}
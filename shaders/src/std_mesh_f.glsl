@@ -1,22 +1,13 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
 
-const int MAX_LIGHTS = 20;
+#include "lights.glsl"
+#include "shadows.glsl"
+#include "brdf.glsl"
+
 // The specular exponent that will exist if the metallic value is exactly 0.
 const float MAX_SPECULAR = 128;
 
-const float PI = 3.14159265359;
-
-struct LightData {
-    vec3 pos;
-    vec3 color;
-};
-
-struct LightsList {
-    int count;
-    LightData data[MAX_LIGHTS];
-};
-
 const int MAX_MATERIALS = 20;
 const float DEFAULT_MATERIAL_BRIGHTNESS = 1.0;
 const float DEFAULT_MATERIAL_ROUGHNESS = 0.6;
@@ -42,11 +33,20 @@ layout(location = 0) in vec2 uv;
 layout(location = 1) in vec3 norm;
 layout(location = 2) in vec3 frag_pos;
 layout(location = 3) in vec3 view_pos;
+layout(location = 4) flat in int shadowed;
+layout(location = 5) in float view_depth;
 
 layout(set = 0, binding = 1) uniform u_LightList {
     LightsList lights;
 };
 
+layout(set = 0, binding = 2) uniform u_Shadows {
+    ShadowsList shadows;
+};
+layout(set = 0, binding = 3) uniform samplerShadow shadow_sampler;
+layout(set = 0, binding = 4) uniform texture2DArray point_shadow_maps;
+layout(set = 0, binding = 5) uniform texture2DArray cascade_shadow_maps;
+
 layout(set = 1, binding = 0) uniform u_Material {
     Material material;
 };
@@ -66,43 +66,50 @@ struct Frag {
     float roughness;
 };
 
-vec3 fresnelSchlick(float cosTheta, vec3 F0)
-{
-    return F0 + (1.0 - F0) * pow(1.0 - cosTheta, 5.0);
-}
-
-float DistributionGGX(vec3 N, vec3 H, float roughness)
-{
-    float a      = roughness*roughness;
-    float a2     = a*a;
-    float NdotH  = max(dot(N, H), 0.0);
-    float NdotH2 = NdotH*NdotH;
-
-    float num   = a2;
-    float denom = (NdotH2 * (a2 - 1.0) + 1.0);
-    denom = PI * denom * denom;
+// The fraction of the light which reaches a position in a layer of a shadow map, filtered over the taps within the PCF radius.
+// The position is compared with the depth specified rather than its own, so that callers can apply a bias. Positions outside of the shadow map are lit.
+float sample_shadow(texture2DArray maps, int layer, vec3 coords, float depth) {
+    vec2 shadow_uv = coords.xy * 0.5 + 0.5;
+    if (shadow_uv.x < 0.0 || shadow_uv.x > 1.0 || shadow_uv.y < 0.0 || shadow_uv.y > 1.0 || coords.z > 1.0) {
+        return 1.0;
+    }
 
-    return num / denom;
+    vec2 texel = 1.0 / vec2(textureSize(sampler2DArrayShadow(maps, shadow_sampler), 0).xy);
+    float lit = 0.0;
+    int taps = 0;
+    for (int x = -shadows.pcf_radius; x <= shadows.pcf_radius; ++x) {
+        for (int y = -shadows.pcf_radius; y <= shadows.pcf_radius; ++y) {
+            lit += texture(sampler2DArrayShadow(maps, shadow_sampler), vec4(shadow_uv + vec2(x, y) * texel, layer, depth));
+            ++taps;
+        }
+    }
+    return lit / float(taps);
 }
 
-float GeometrySchlickGGX(float NdotV, float roughness)
-{
-    float r = (roughness + 1.0);
-    float k = (r*r) / 8.0;
-
-    float num   = NdotV;
-    float denom = NdotV * (1.0 - k) + k;
-
-    return num / denom;
+// The normalized device coordinates of a position in a layer of a shadow map.
+vec3 shadow_coords(mat4 light_matrix, vec3 pos) {
+    vec4 clip = light_matrix * vec4(pos, 1.0);
+    return clip.xyz / clip.w;
 }
-float GeometrySmith(vec3 N, vec3 V, vec3 L, float roughness)
-{
-    float NdotV = max(dot(N, V), 0.0);
-    float NdotL = max(dot(N, L), 0.0);
-    float ggx2  = GeometrySchlickGGX(NdotV, roughness);
-    float ggx1  = GeometrySchlickGGX(NdotL, roughness);
-
-    return ggx1 * ggx2;
+
+float light_shadow(LightData light, vec3 pos) {
+    if (light.kind == DIRECTIONAL_LIGHT) {
+        for (int i = 0; i < shadows.cascade_count; ++i) {
+            if (view_depth < shadows.cascade_splits[i]) {
+                vec3 coords = shadow_coords(shadows.cascade_matrices[i], pos);
+                return sample_shadow(cascade_shadow_maps, i, coords, coords.z - shadows.depth_bias);
+            }
+        }
+        // Beyond the last cascade nothing is shadowed.
+        return 1.0;
+    }
+    vec3 dir = pos - light.pos;
+    int face = cube_face(dir);
+    int layer = light.shadow_index * 6 + face;
+    vec3 coords = shadow_coords(shadows.point_matrices[layer], pos);
+    // The distance along the axis of the face is the view space depth of the face's projection.
+    float distance = abs(dir[face / 2]) - shadows.point_depth_bias;
+    return sample_shadow(point_shadow_maps, layer, coords, point_shadow_depth(max(distance, shadows.point_near), shadows.point_near, shadows.point_far));
 }
 
 vec4 fwd_render_frag(Frag frag) {
@@ -118,11 +125,25 @@ vec4 fwd_render_frag(Frag frag) {
     for(int i = 0; i < lights.count; ++i)
     {
         // calculate per-light radiance
-        vec3 L = normalize(lights.data[i].pos - frag_pos);
+        // Directional lights store their direction in place of a position, and are not attenuated.
+        vec3 L;
+        vec3 radiance;
+        if (lights.data[i].kind == DIRECTIONAL_LIGHT) {
+            L = -normalize(lights.data[i].pos);
+            radiance = lights.data[i].color;
+        } else {
+            L = normalize(lights.data[i].pos - frag_pos);
+            float distance    = length(lights.data[i].pos  - frag_pos);
+            float attenuation = 1.0 / (distance * distance);
+            radiance          = lights.data[i].color * attenuation;
+        }
         vec3 H = normalize(V + L);
-        float distance    = length(lights.data[i].pos  - frag_pos);
-        float attenuation = 1.0 / (distance * distance);
-        vec3 radiance     = lights.data[i].color * attenuation;
+
+        // The shadow map is sampled at a position offset along the normal, more so at grazing angles, to avoid acne.
+        if (shadowed != 0 && lights.data[i].shadow_index >= 0) {
+            float offset = shadows.normal_bias * (1.0 - max(dot(N, L), 0.0));
+            radiance *= light_shadow(lights.data[i], frag_pos + N * offset);
+        }
 
         // cook-torrance brdf
         float NDF = DistributionGGX(N, H, frag.roughness);
@@ -2,6 +2,11 @@
 
    #extension GL_ARB_separate_shader_objects : enable
 
+   // Skinned meshes are transformed by their bones as well as their model matrix.
+   #pragma variant(SKINNED)
+   // Instanced meshes read their model matrix from a per-instance vertex buffer rather than the push constants.
+   #pragma variant(INSTANCED)
+
    const int BONES_PER_VERTEX = 4;
    const int MAX_BONES = 100;
 
@@ -20,11 +25,19 @@
    layout(location = 2) in vec2 uv_pos;
    layout(location = 3) in ivec4 bone_ids;
    layout(location = 4) in vec4 bone_weights;
+   #ifdef INSTANCED
+   layout(location = 5) in vec4 instance_model_0;
+   layout(location = 6) in vec4 instance_model_1;
+   layout(location = 7) in vec4 instance_model_2;
+   layout(location = 8) in vec4 instance_model_3;
+   #endif
 
    layout(push_constant) uniform Transform {
        mat4 model;
        mat4 view;
        mat4 projection;
+       // Non-zero if the mesh is shadowed by the lights which cast shadows.
+       int receive_shadows;
    };
    layout(set = 0, binding = 0) uniform u_BoneList {
        BoneList bone_list;
@@ -34,6 +47,9 @@
    layout(location = 1) out vec3 norm;
    layout(location = 2) out vec3 frag_pos;
    layout(location = 3) out vec3 view_pos;
+   layout(location = 4) flat out int shadowed;
+   // The distance in front of the camera, which the cascade of the directional light's shadow map is chosen by.
+   layout(location = 5) out float view_depth;
 
    void main() {
 
@@ -49,8 +65,16 @@
            }
        }
 
-       //mat4 local_transform = bone_transform;
-       mat4 local_transform = model;
+   #ifdef INSTANCED
+       mat4 model_transform = mat4(instance_model_0, instance_model_1, instance_model_2, instance_model_3);
+   #else
+       mat4 model_transform = model;
+   #endif
+   #ifdef SKINNED
+       mat4 local_transform = model_transform * bone_transform;
+   #else
+       mat4 local_transform = model_transform;
+   #endif
        mat4 camera_transform = projection * view;
        vec4 pos = camera_transform * local_transform * vec4(position, 1.0);
       gl_Position = pos;
@@ -60,5 +84,7 @@
       frag_pos = vec3(local_transform * vec4(position, 1.0));
       mat4 camera = inverse(view);
       view_pos = vec3(camera[3][0], camera[3][1], camera[3][2]);
+      view_depth = -(view * vec4(frag_pos, 1.0)).z;
+      shadowed = receive_shadows;
 
    }
\ No newline at end of file
@@ -0,0 +1,15 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Renders the depth of meshes from the view of a light into a layer of a shadow map.
+
+layout(location = 0) in vec3 position;
+
+layout(push_constant) uniform ShadowTransform {
+    mat4 model;
+    mat4 view_projection;
+};
+
+void main() {
+    gl_Position = view_projection * model * vec4(position, 1.0);
+}
@@ -24,7 +24,9 @@ impl<'a> UpdateCycle<'a> {
 
 pub struct AppInterface {
     pub instance: core::Instance,
-    pub window: window::Window,
+    /// The primary window of the application.
+    /// This is `None` for headless applications.
+    pub window: Option<window::Window>,
     pub graphics: render::Graphics,
     pub clear_color: Color,
     pub should_terminate: bool,
@@ -32,16 +34,52 @@ pub struct AppInterface {
 
 impl AppInterface {
 
-    pub fn new(application_name: &str) -> Self {
-        let instance: core::Instance = core::Instance::create(application_name);
-        let window: window::Window = window::Window::create_fullscreen(application_name).expect("Fatal Error: Failed to create primary window for Instance Engine.");
-        let graphics: render::Graphics = render::Graphics::create(&instance, &window);
+    /// Creates an application interface with a window and the default settings.
+    pub fn new(application_name: &str) -> Result<Self, Error> {
+        return Self::create(application_name, render::GraphicsSettings::default(), &core::AdapterPreference::default());
+    }
+
+    /// Creates an application interface whose window and swapchain are set up with the settings specified.
+    pub fn new_with_settings(application_name: &str, settings: render::GraphicsSettings) -> Result<Self, Error> {
+        return Self::create(application_name, settings, &core::AdapterPreference::default());
+    }
+
+    /// Creates an application interface which renders with the adapter which best matches the preference, e.g. to pick a software rasterizer explicitly.
+    pub fn new_with_adapter(application_name: &str, preference: &core::AdapterPreference) -> Result<Self, Error> {
+        return Self::create(application_name, render::GraphicsSettings::default(), preference);
+    }
 
-        return Self { instance, window, graphics, clear_color: Color::black(), should_terminate: false };
+    /// Creates an application interface with the settings specified, rendering with the adapter which best matches the preference.
+    pub fn create(application_name: &str, settings: render::GraphicsSettings, preference: &core::AdapterPreference) -> Result<Self, Error> {
+        let instance: core::Instance = Self::create_instance(application_name)?;
+        let window: window::Window = window::Window::create_with_settings(application_name, &settings)?;
+        let graphics: render::Graphics = render::Graphics::create_with_settings(&instance, &window, preference, settings)?;
+
+        return Ok(Self { instance, window: Some(window), graphics, clear_color: Color::black(), should_terminate: false });
+    }
+
+    /// Creates an application interface without a window.
+    /// Frames are rendered into offscreen images of the specified size, which can then be read back.
+    pub fn new_headless(application_name: &str, size: Vector2u, preference: &core::AdapterPreference) -> Result<Self, Error> {
+        let instance: core::Instance = Self::create_instance(application_name)?;
+        let graphics: render::Graphics = render::Graphics::create_headless(&instance, size, preference)?;
+
+        return Ok(Self { instance, window: None, graphics, clear_color: Color::black(), should_terminate: false });
+    }
+
+    /// Creates the instance, reporting a missing Vulkan loader as an error rather than letting the backend panic.
+    fn create_instance(application_name: &str) -> Result<core::Instance, Error> {
+        if !core::Instance::is_available() {
+            return Err(Error::NoSuitableAdapter(String::from("The Vulkan loader could not be found. Is a Vulkan driver installed?")));
+        }
+        return Ok(core::Instance::create(application_name));
     }
 
     pub fn poll_events(&mut self) -> Vec<window::Event> {
-        let events: Vec<window::Event> = self.window.collect_events();
+        let events: Vec<window::Event> = match self.window.as_mut() {
+            Some(window) => window.collect_events(),
+            None => Vec::new(),
+        };
         for event in events.iter() {
             if let window::winit::Event::WindowEvent { event, .. } = event {
                 match event {
@@ -58,20 +96,22 @@ impl AppInterface {
         return events;
     }
 
-    pub fn update(&mut self) {
-        if !self.graphics.render_surface.is_valid {
-            self.graphics.render_surface.rebuild(&self.window, &mut self.graphics.device);
-            log!(debug, 0, "Rebuilding swapchain.");
-        } else {
-            if self.graphics.render_surface.did_rebuild {
-                self.graphics.render_surface.did_rebuild = false;
-            }
-        }
+    /// Rebuilds the surface if it has been invalidated. Headless surfaces are revalidated without a window.
+    pub fn update(&mut self) -> Result<(), Error> {
+        return self.graphics.update(self.window.as_ref());
     }
 
     pub fn invalidate_surface(&mut self) {
         self.graphics.render_surface.invalidate();
     }
+
+    /// Changes the window and presentation settings. The swapchain is rebuilt with them on the next update.
+    pub fn apply_settings(&mut self, settings: render::GraphicsSettings) {
+        if let Some(window) = self.window.as_mut() {
+            window.apply_settings(&settings);
+        }
+        self.graphics.apply_settings(settings);
+    }
 }
 
 pub enum LoopInstruction {
@@ -111,7 +151,9 @@ impl App {
         if self.interface.should_terminate {
             return LoopInstruction::Exit;
         }
-        self.interface.update();
+        if let Err(e) = self.interface.update() {
+            log!(err, "Failed to rebuild the surface, retrying next frame: {}", e);
+        }
         return LoopInstruction::Continue;
     }
 }
\ No newline at end of file
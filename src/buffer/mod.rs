@@ -7,6 +7,9 @@ use std::ops::Deref;
 use gfx::Device as GfxDevice;
 use gfx::PhysicalDevice;
 
+pub mod upload;
+pub use self::upload::UploadBatch;
+
 pub trait BufferInterface : Send + Sync {
     fn raw_buffer(&self) -> &Buffer;
 }
@@ -16,11 +19,11 @@ pub trait BufferInterface : Send + Sync {
 pub type SharedBuffer = Arc<Buffer>;
 
 /// This structure represents a GPU buffer which contains memory of a specific type.
-/// This holds both the buffer object and the buffer memory.
+/// This holds both the buffer object and the allocation its memory is sub-allocated from.
 pub struct Buffer {
 
     pub buf: <Backend as gfx::Backend>::Buffer,
-    pub memory: <Backend as gfx::Backend>::Memory,
+    pub allocation: memory::Allocation,
     pub count: usize,
 
     device_token: core::DeviceToken,
@@ -29,57 +32,84 @@ pub struct Buffer {
 
 impl Buffer {
 
-    pub fn alloc_vertex<T: std::marker::Copy>(slice: &[T], device: &core::Device) -> Self {
+    pub fn alloc_vertex<T: pipeline::VertexFormat>(slice: &[T], device: &core::Device) -> Result<Self, Error> {
         return Self::alloc(slice, gfx::buffer::Usage::VERTEX, gfx::memory::Properties::CPU_VISIBLE, device);
     }
 
-    pub fn alloc_uniform<T: std::marker::Copy>(slice: &[T], device: &core::Device) -> Self {
+    pub fn alloc_uniform<T: std::marker::Copy>(slice: &[T], device: &core::Device) -> Result<Self, Error> {
         return Self::alloc(slice, gfx::buffer::Usage::UNIFORM, gfx::memory::Properties::CPU_VISIBLE, device);
     }
 
-    pub fn alloc_uniform_empty<T: std::marker::Copy>(count: usize, device: &core::Device) -> Self {
+    pub fn alloc_uniform_empty<T: std::marker::Copy>(count: usize, device: &core::Device) -> Result<Self, Error> {
         return Self::alloc_empty::<T>(count, gfx::buffer::Usage::UNIFORM, gfx::memory::Properties::CPU_VISIBLE, device);
     }
 
-    pub fn alloc<T: std::marker::Copy>(slice: &[T], usage: gfx::buffer::Usage, properties: gfx::memory::Properties, device: &core::Device) -> Self {
-        let mut buffer = Self::alloc_empty::<T>(slice.len(), usage, properties, device);
-        buffer.fill_buffer(slice, device);
-        return buffer;
+    /// Storage buffers can be written by compute shaders. The usage specified is added to the storage usage,
+    /// e.g. `Usage::VERTEX` for buffers which are also drawn from directly such as particles or skinned vertices.
+    pub fn alloc_storage<T: std::marker::Copy>(slice: &[T], usage: gfx::buffer::Usage, device: &core::Device) -> Result<Self, Error> {
+        return Self::alloc(slice, gfx::buffer::Usage::STORAGE | usage, gfx::memory::Properties::CPU_VISIBLE, device);
+    }
+
+    pub fn alloc_storage_empty<T: std::marker::Copy>(count: usize, usage: gfx::buffer::Usage, device: &core::Device) -> Result<Self, Error> {
+        return Self::alloc_empty::<T>(count, gfx::buffer::Usage::STORAGE | usage, gfx::memory::Properties::CPU_VISIBLE, device);
     }
 
-    pub fn alloc_empty<T: std::marker::Copy>(count: usize, usage: gfx::buffer::Usage, properties: gfx::memory::Properties, device: &core::Device) -> Self {
-        let memory_types = device.adapter.physical_device.memory_properties().memory_types;
+    pub fn alloc<T: std::marker::Copy>(slice: &[T], usage: gfx::buffer::Usage, properties: gfx::memory::Properties, device: &core::Device) -> Result<Self, Error> {
+        let mut buffer = Self::alloc_empty::<T>(slice.len(), usage, properties, device)?;
+        buffer.fill_buffer(slice, device)?;
+        return Ok(buffer);
+    }
 
+    /// Creates a buffer large enough to hold `count` elements and binds it to memory from the device allocator.
+    pub fn alloc_empty<T: std::marker::Copy>(count: usize, usage: gfx::buffer::Usage, properties: gfx::memory::Properties, device: &core::Device) -> Result<Self, Error> {
         let stride = std::mem::size_of::<T>() as u64;
-        let buffer_len = count as u64 * stride;
+        // Zero sized buffers are not valid, so empty buffers still get a single element.
+        let buffer_len = count.max(1) as u64 * stride;
         unsafe {
             let mut buffer = device.gpu
                 .create_buffer(buffer_len, usage)
-                .unwrap();
+                .map_err(|e| Error::Render(format!("Failed to create buffer: {}", e)))?;
 
-            let (upload_type, req) = device.upload_type_for(&buffer, properties);
+            let req = device.gpu.get_buffer_requirements(&buffer);
 
-            let buffer_memory = device.gpu.allocate_memory(upload_type, req.size).unwrap();
+            let mut allocator = device.allocator.lock().unwrap();
+            let allocation = match allocator.allocate(req, properties, memory::ResourceKind::Buffer) {
+                Ok(allocation) => allocation,
+                Err(e) => {
+                    device.gpu.destroy_buffer(buffer);
+                    return Err(e);
+                },
+            };
 
-            device.gpu
-                .bind_buffer_memory(&buffer_memory, 0, &mut buffer)
-                .unwrap();
+            if let Err(e) = device.gpu.bind_buffer_memory(allocator.memory(&allocation), allocation.offset, &mut buffer) {
+                allocator.free(&allocation);
+                device.gpu.destroy_buffer(buffer);
+                return Err(Error::Render(format!("Failed to bind buffer memory: {}", e)));
+            }
 
-            return Self { buf: buffer, memory: buffer_memory, count, device_token: device.create_token() };
+            return Ok(Self { buf: buffer, allocation, count, device_token: device.create_token() });
         }
-
     }
 
     /// It is the responsibility of the programmer to ensure the data is of the right size and format.
-    pub fn fill_buffer<T: Copy>(&mut self, data: &[T], device: &core::Device) {
-        let stride = std::mem::size_of::<T>() as u64;
-        let buffer_len = data.len() as u64 * stride;
-        unsafe {
-            let mut dest = device.gpu.acquire_mapping_writer::<T>(&self.memory, 0..buffer_len).unwrap();
-            dest.copy_from_slice(data);
-            device.gpu.release_mapping_writer(dest);
+    /// An error is returned if the buffer is not host visible or is too small for the data, in which case the buffer is left unchanged.
+    pub fn fill_buffer<T: Copy>(&mut self, data: &[T], device: &core::Device) -> Result<(), Error> {
+        if !self.allocation.write(0, data) {
+            return Err(Error::Render(String::from("Failed to fill buffer: the buffer is not host visible or is too small.")));
         }
+        device.allocator.lock().unwrap().flush(&self.allocation);
         self.count = data.len();
+        return Ok(());
+    }
+
+    /// Makes host writes through the mapped allocation visible to the device. This does nothing for coherent memory.
+    pub fn flush(&self) {
+        self.device_token.allocator.lock().unwrap().flush(&self.allocation);
+    }
+
+    /// Binds the buffer as a storage buffer rather than a uniform buffer.
+    pub fn as_storage(&self) -> StorageBinding {
+        return StorageBinding { buffer: self };
     }
 
 }
@@ -90,8 +120,8 @@ impl std::ops::Drop for Buffer {
         unsafe {
             use std::mem;
             self.device_token.gpu.destroy_buffer(mem::transmute_copy(&self.buf));
-            self.device_token.gpu.free_memory(mem::transmute_copy(&self.memory));
         }
+        self.device_token.allocator.lock().unwrap().free(&self.allocation);
     }
 
 }
@@ -116,22 +146,40 @@ impl pipeline::ShaderInput for Buffer {
 pub struct TextureBuffer {
 
     pub image: <Backend as gfx::Backend>::Image,
-    pub memory: <Backend as gfx::Backend>::Memory,
+    pub allocation: memory::Allocation,
     pub image_view: <Backend as gfx::Backend>::ImageView,
+    /// Views of each layer of an array image, which can be rendered to one at a time. This is empty for other images.
+    pub layer_views: Vec<<Backend as gfx::Backend>::ImageView>,
+
+    device_token: core::DeviceToken,
 
 }
 
 impl TextureBuffer {
 
-    pub fn empty(graphics: &mut render::Graphics) -> Self {
+    pub fn empty(graphics: &mut render::Graphics) -> Result<Self, Error> {
         return Self::create(&texture::Texture::new(), &mut graphics.device);
     }
 
-    pub fn new(size: Vector2u, format: gfx::format::Format, usage: gfx::image::Usage, aspects: gfx::format::Aspects, device: &core::Device) -> Self {
+    pub fn new(size: Vector2u, format: gfx::format::Format, usage: gfx::image::Usage, aspects: gfx::format::Aspects, device: &core::Device) -> Result<Self, Error> {
+        return Self::new_multisampled(size, format, usage, aspects, 1, device);
+    }
+
+    /// Creates an image with the number of samples per pixel specified. Multisampled images can only be rendered to and resolved, not sampled.
+    pub fn new_multisampled(size: Vector2u, format: gfx::format::Format, usage: gfx::image::Usage, aspects: gfx::format::Aspects, samples: u8, device: &core::Device) -> Result<Self, Error> {
+        return Self::create_image(size, format, usage, aspects, None, samples, device);
+    }
+
+    /// Creates an array image with the number of layers specified, which is viewed as a 2D array when it is sampled.
+    /// Each layer also has its own view in `layer_views`, so that the layers can be rendered to one at a time.
+    pub fn new_layered(size: Vector2u, format: gfx::format::Format, usage: gfx::image::Usage, aspects: gfx::format::Aspects, layers: u16, device: &core::Device) -> Result<Self, Error> {
+        return Self::create_image(size, format, usage, aspects, Some(layers), 1, device);
+    }
 
-        let memory_types = device.adapter.physical_device.memory_properties().memory_types;
+    fn create_image(size: Vector2u, format: gfx::format::Format, usage: gfx::image::Usage, aspects: gfx::format::Aspects, array_layers: Option<u16>, samples: u8, device: &core::Device) -> Result<Self, Error> {
 
-        let kind = gfx::image::Kind::D2(size.x, size.y, 1, 1);
+        let layers = array_layers.unwrap_or(1);
+        let kind = gfx::image::Kind::D2(size.x, size.y, layers, samples);
 
         unsafe {
             let mut image = device.gpu
@@ -142,52 +190,108 @@ impl TextureBuffer {
                     gfx::image::Tiling::Optimal,
                     usage,
                     gfx::image::ViewCapabilities::empty(),
-                ).expect("Failed to create unbound image");
+                ).map_err(|e| Error::Render(format!("Failed to create image: {}", e)))?;
 
             let image_req = device.gpu.get_image_requirements(&image);
 
-            let device_type = memory_types
-                .iter()
-                .enumerate()
-                .position(|(id, memory_type)| {
-                    image_req.type_mask & (1 << id) != 0
-                        && memory_type.properties.contains(gfx::memory::Properties::DEVICE_LOCAL)
-                }).unwrap()
-                .into();
-
-            let memory = device.gpu
-                .allocate_memory(device_type, image_req.size)
-                .expect("Failed to allocate image");
-
-            device.gpu
-                .bind_image_memory(&memory, 0, &mut image)
-                .expect("Failed to bind image");
+            let bound = {
+                let mut allocator = device.allocator.lock().unwrap();
+                match allocator.allocate(image_req, gfx::memory::Properties::DEVICE_LOCAL, memory::ResourceKind::Image) {
+                    Ok(allocation) => match device.gpu.bind_image_memory(allocator.memory(&allocation), allocation.offset, &mut image) {
+                        Ok(()) => Ok(allocation),
+                        Err(e) => {
+                            allocator.free(&allocation);
+                            Err(Error::Render(format!("Failed to bind image memory: {}", e)))
+                        },
+                    },
+                    Err(e) => Err(e),
+                }
+            };
+            let allocation = match bound {
+                Ok(allocation) => allocation,
+                Err(e) => {
+                    device.gpu.destroy_image(image);
+                    return Err(e);
+                },
+            };
 
-            let image_view = device.gpu
+            let view_kind = if array_layers.is_some() { gfx::image::ViewKind::D2Array } else { gfx::image::ViewKind::D2 };
+            let image_view = match device.gpu
                 .create_image_view(
                     &image,
-                    gfx::image::ViewKind::D2,
+                    view_kind,
                     format,
                     gfx::format::Swizzle::NO,
                     gfx::image::SubresourceRange {
                         aspects,
                         levels: 0..1,
-                        layers: 0..1,
+                        layers: 0..layers,
                     },
-                ).expect("Failed to create image view");
+                ) {
+                Ok(image_view) => image_view,
+                Err(e) => {
+                    device.gpu.destroy_image(image);
+                    device.allocator.lock().unwrap().free(&allocation);
+                    return Err(Error::Render(format!("Failed to create image view: {}", e)));
+                },
+            };
+
+            // From here on, dropping the texture buffer destroys everything which has been created.
+            let mut texture_buffer = Self { image, allocation, image_view, layer_views: Vec::new(), device_token: device.create_token() };
+            if array_layers.is_some() {
+                for layer in 0..layers {
+                    let layer_view = device.gpu
+                        .create_image_view(
+                            &texture_buffer.image,
+                            gfx::image::ViewKind::D2,
+                            format,
+                            gfx::format::Swizzle::NO,
+                            gfx::image::SubresourceRange {
+                                aspects,
+                                levels: 0..1,
+                                layers: layer..(layer + 1),
+                            },
+                        ).map_err(|e| Error::Render(format!("Failed to create image layer view: {}", e)))?;
+                    texture_buffer.layer_views.push(layer_view);
+                }
+            }
 
-            return Self { image, memory, image_view };
+            return Ok(texture_buffer);
         }
 
     }
 
-    pub fn create_depth(size: Vector2u, depth_format: gfx::format::Format, device: &core::Device) -> Self {
-        Self::new(size, depth_format, gfx::image::Usage::DEPTH_STENCIL_ATTACHMENT, gfx::format::Aspects::DEPTH | gfx::format::Aspects::STENCIL, device)
+    /// The view of a single layer of an array image, or of the whole image otherwise.
+    pub fn layer_view(&self, layer: u16) -> &<Backend as gfx::Backend>::ImageView {
+        return self.layer_views.get(layer as usize).unwrap_or(&self.image_view);
+    }
+
+    /// Creates an image which can be written by compute shaders and sampled afterwards.
+    /// It must be transitioned to the `General` layout with `CommandBuffer::image_barrier` before it is first used.
+    pub fn create_storage(size: Vector2u, format: gfx::format::Format, device: &core::Device) -> Result<Self, Error> {
+        Self::new(size, format, gfx::image::Usage::STORAGE | gfx::image::Usage::SAMPLED, gfx::format::Aspects::COLOR, device)
+    }
+
+    /// Binds the image as a storage image rather than a sampled image.
+    pub fn as_storage(&self) -> StorageImageBinding {
+        return StorageImageBinding { image: self };
+    }
+
+    pub fn create_depth(size: Vector2u, depth_format: gfx::format::Format, device: &core::Device) -> Result<Self, Error> {
+        Self::create_depth_multisampled(size, depth_format, 1, device)
+    }
+
+    pub fn create_depth_multisampled(size: Vector2u, depth_format: gfx::format::Format, samples: u8, device: &core::Device) -> Result<Self, Error> {
+        Self::new_multisampled(size, depth_format, gfx::image::Usage::DEPTH_STENCIL_ATTACHMENT, depth_format.surface_desc().aspects, samples, device)
+    }
+
+    /// Creates a multisampled color target, which is resolved into a single sampled image at the end of a render pass.
+    pub fn create_multisampled_color(size: Vector2u, format: gfx::format::Format, samples: u8, device: &core::Device) -> Result<Self, Error> {
+        Self::new_multisampled(size, format, gfx::image::Usage::COLOR_ATTACHMENT | gfx::image::Usage::TRANSIENT_ATTACHMENT, gfx::format::Aspects::COLOR, samples, device)
     }
 
-    pub fn create(texture: &texture::Texture, device: &mut core::Device) -> TextureBuffer {
+    pub fn create(texture: &texture::Texture, device: &mut core::Device) -> Result<TextureBuffer, Error> {
 
-        let texture_fence = device.gpu.create_fence(false);
         let (width, height) = (texture.dimensions.x, texture.dimensions.y);
 
         let mut texture_buffer = Self::new(
@@ -196,75 +300,89 @@ impl TextureBuffer {
             gfx::image::Usage::TRANSFER_DST | gfx::image::Usage::SAMPLED,
             gfx::format::Aspects::COLOR,
             &device
-        );
+        )?;
 
-        texture_buffer.upload_texture(texture, device);
+        texture_buffer.upload_texture(texture, device)?;
 
-        return texture_buffer;
+        return Ok(texture_buffer);
     }
 
-    pub fn upload_texture(&mut self, texture: &texture::Texture, device: &mut core::Device) {
-        let texture_fence = device.gpu.create_fence(false).unwrap();
+    /// Uploads the texture into this texture buffer and waits for the copy to complete.
+    /// Use an `UploadBatch` to upload many textures behind a single fence.
+    pub fn upload_texture(&mut self, texture: &texture::Texture, device: &mut core::Device) -> Result<(), Error> {
+        let mut batch = UploadBatch::new(device)?;
+        batch.upload_texture(texture, self, device)?;
+        return batch.submit(device);
+    }
+}
 
-        let (width, height) = (texture.dimensions.x, texture.dimensions.y);
+impl TextureBuffer {
+
+    /// Copies the contents of this texture buffer back to the CPU.
+    /// The image must currently be in the specified layout and is returned to that layout once the copy is complete.
+    pub fn download_texture(&self, layout: gfx::image::Layout, size: Vector2u, format: gfx::format::Format, device: &mut core::Device) -> Result<texture::Texture, Error> {
+        return Self::download_image(&self.image, layout, size, format, device);
+    }
+
+    /// Copies the contents of a 2D color image back to the CPU as RGBA texture data.
+    /// This performs the reverse of `upload_texture`: the image is copied into a CPU visible staging buffer which is then read row by row.
+    /// Images which use a BGRA format are swizzled so that the returned texture is always RGBA.
+    pub fn download_image(image: &<Backend as gfx::Backend>::Image, layout: gfx::image::Layout, size: Vector2u, format: gfx::format::Format, device: &mut core::Device) -> Result<texture::Texture, Error> {
+        let (width, height) = (size.x, size.y);
         let row_alignment_mask = device.adapter.physical_device.limits().min_buffer_copy_pitch_alignment as u32 - 1;
         let image_stride = 4usize;
         let row_pitch =
             (width * image_stride as u32 + row_alignment_mask) & !row_alignment_mask;
-        let upload_size = u64::from(height * row_pitch);
+        let download_size = u64::from(height * row_pitch);
 
-        let upload_buffer = Buffer::alloc_empty::<u8>(
-            upload_size as usize,
-            gfx::buffer::Usage::TRANSFER_SRC,
+        let download_buffer = Buffer::alloc_empty::<u8>(
+            download_size as usize,
+            gfx::buffer::Usage::TRANSFER_DST,
             gfx::memory::Properties::CPU_VISIBLE,
             &device
-        );
-        {
-            let mut data = unsafe { device.gpu
-                .acquire_mapping_writer::<u8>(&upload_buffer.memory, 0..upload_size)
-                .unwrap() };
-
-            for y in 0..height as usize {
-                let row = &(*texture.data)[y * (width as usize) * image_stride
-                    ..(y + 1) * (width as usize) * image_stride];
-                let dest_base = y * row_pitch as usize;
-                data[dest_base..dest_base + row.len()].copy_from_slice(row);
-            }
-
-            unsafe { device.gpu.release_mapping_writer(data) };
-        }
+        )?;
+
+        let texture_fence = device.gpu.create_fence(false)?;
+
+        let mut cmd_pool = match unsafe {
+            device.gpu.create_command_pool_typed(
+                &device.queue_group,
+                gfx::pool::CommandPoolCreateFlags::empty())
+        } {
+            Ok(cmd_pool) => cmd_pool,
+            Err(e) => {
+                unsafe { device.gpu.destroy_fence(texture_fence) };
+                return Err(e.into());
+            },
+        };
 
         let cmd_buffer = unsafe {
-            let mut cmd_pool =
-                device.gpu.create_command_pool_typed(
-                    &device.queue_group,
-                    gfx::pool::CommandPoolCreateFlags::empty())
-                    .expect("Failed to create stating command pool for buffer::TextureBuffer");
-
             let mut cmd_buffer = cmd_pool.acquire_command_buffer::<gfx::command::OneShot>();
 
+            let color_range = gfx::image::SubresourceRange {
+                aspects: gfx::format::Aspects::COLOR,
+                levels: 0..1,
+                layers: 0..1,
+            };
+
             let image_barrier = gfx::memory::Barrier::Image {
-                states: (gfx::image::Access::empty(), gfx::image::Layout::Undefined)
-                    ..(gfx::image::Access::TRANSFER_WRITE, gfx::image::Layout::TransferDstOptimal),
-                target: &self.image,
+                states: (gfx::image::Access::COLOR_ATTACHMENT_WRITE, layout)
+                    ..(gfx::image::Access::TRANSFER_READ, gfx::image::Layout::TransferSrcOptimal),
+                target: image,
                 families: None,
-                range: gfx::image::SubresourceRange {
-                    aspects: gfx::format::Aspects::COLOR,
-                    levels: 0..1,
-                    layers: 0..1,
-                },
+                range: color_range.clone(),
             };
 
             cmd_buffer.pipeline_barrier(
-                gfx::pso::PipelineStage::TOP_OF_PIPE..gfx::pso::PipelineStage::TRANSFER,
+                gfx::pso::PipelineStage::COLOR_ATTACHMENT_OUTPUT..gfx::pso::PipelineStage::TRANSFER,
                 gfx::memory::Dependencies::empty(),
                 &[image_barrier],
             );
 
-            cmd_buffer.copy_buffer_to_image(
-                &upload_buffer.buf,
-                &self.image,
-                gfx::image::Layout::TransferDstOptimal,
+            cmd_buffer.copy_image_to_buffer(
+                image,
+                gfx::image::Layout::TransferSrcOptimal,
+                &download_buffer.buf,
                 &[gfx::command::BufferImageCopy {
                     buffer_offset: 0,
                     buffer_width: row_pitch / (image_stride as u32),
@@ -284,19 +402,15 @@ impl TextureBuffer {
             );
 
             let image_barrier = gfx::memory::Barrier::Image {
-                states: (gfx::image::Access::TRANSFER_WRITE, gfx::image::Layout::TransferDstOptimal)
-                    ..(gfx::image::Access::SHADER_READ, gfx::image::Layout::ShaderReadOnlyOptimal),
-                target: &self.image,
+                states: (gfx::image::Access::TRANSFER_READ, gfx::image::Layout::TransferSrcOptimal)
+                    ..(gfx::image::Access::empty(), layout),
+                target: image,
                 families: None,
-                range: gfx::image::SubresourceRange {
-                    aspects: gfx::format::Aspects::COLOR,
-                    levels: 0..1,
-                    layers: 0..1,
-                },
+                range: color_range,
             };
 
             cmd_buffer.pipeline_barrier(
-                gfx::pso::PipelineStage::TRANSFER..gfx::pso::PipelineStage::FRAGMENT_SHADER,
+                gfx::pso::PipelineStage::TRANSFER..gfx::pso::PipelineStage::BOTTOM_OF_PIPE,
                 gfx::memory::Dependencies::empty(),
                 &[image_barrier],
             );
@@ -305,11 +419,55 @@ impl TextureBuffer {
             cmd_buffer
         };
 
-        unsafe { device.queue_group.queues[0].submit_nosemaphores(std::iter::once(&cmd_buffer), Some(&texture_fence)) };
+        let wait_result = unsafe {
+            device.queue_group.queues[0].submit_nosemaphores(std::iter::once(&cmd_buffer), Some(&texture_fence));
+            device.gpu.wait_for_fence(&texture_fence, !0)
+        };
 
         // Cleanup staging resources
-        unsafe { device.gpu.wait_for_fence(&texture_fence, !0) };
+        unsafe {
+            device.gpu.destroy_command_pool(cmd_pool.into_raw());
+            device.gpu.destroy_fence(texture_fence);
+        }
+        wait_result?;
+
+        let mut data: Vec<u8> = Vec::with_capacity((width * height) as usize * image_stride);
+        device.allocator.lock().unwrap().invalidate(&download_buffer.allocation);
+        {
+            let reader = download_buffer.allocation.read()
+                .ok_or_else(|| Error::Render(String::from("Failed to download texture - the download buffer is not host visible.")))?;
+
+            for y in 0..height as usize {
+                let src_base = y * row_pitch as usize;
+                data.extend_from_slice(&reader[src_base..src_base + (width as usize) * image_stride]);
+            }
+        }
+
+        if format.base_format().0 == gfx::format::SurfaceType::B8_G8_R8_A8 {
+            for pixel in data.chunks_mut(image_stride) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        return Ok(texture::Texture::from_bytes(&data, size));
     }
+
+}
+
+impl std::ops::Drop for TextureBuffer {
+
+    fn drop(&mut self) {
+        unsafe {
+            use std::mem;
+            for layer_view in self.layer_views.drain(..) {
+                self.device_token.gpu.destroy_image_view(layer_view);
+            }
+            self.device_token.gpu.destroy_image_view(mem::transmute_copy(&self.image_view));
+            self.device_token.gpu.destroy_image(mem::transmute_copy(&self.image));
+        }
+        self.device_token.allocator.lock().unwrap().free(&self.allocation);
+    }
+
 }
 
 impl pipeline::ShaderInput for TextureBuffer {
@@ -321,4 +479,40 @@ impl pipeline::ShaderInput for TextureBuffer {
         return gfx::pso::DescriptorType::SampledImage;
     }
 
-}
\ No newline at end of file
+}
+
+/// Binds a buffer as a storage buffer, which shaders can both read and write.
+pub struct StorageBinding<'a> {
+
+    pub buffer: &'a Buffer,
+
+}
+
+impl<'a> pipeline::ShaderInput for StorageBinding<'a> {
+
+    fn get_descriptor(&self) -> Option<gfx::pso::Descriptor<Backend>> {
+        return Some(gfx::pso::Descriptor::Buffer(&self.buffer.buf, None..None));
+    }
+    fn get_binding_type(&self) -> gfx::pso::DescriptorType {
+        return gfx::pso::DescriptorType::StorageBuffer;
+    }
+
+}
+
+/// Binds an image as a storage image, which shaders can both read and write. The image must be in the `General` layout.
+pub struct StorageImageBinding<'a> {
+
+    pub image: &'a TextureBuffer,
+
+}
+
+impl<'a> pipeline::ShaderInput for StorageImageBinding<'a> {
+
+    fn get_descriptor(&self) -> Option<gfx::pso::Descriptor<Backend>> {
+        return Some(gfx::pso::Descriptor::Image(&self.image.image_view, gfx::image::Layout::General));
+    }
+    fn get_binding_type(&self) -> gfx::pso::DescriptorType {
+        return gfx::pso::DescriptorType::StorageImage;
+    }
+
+}
@@ -0,0 +1,209 @@
+use crate::*;
+
+use gfx::Device as GfxDevice;
+use gfx::PhysicalDevice;
+
+/// Records copies from CPU visible staging buffers into device local buffers and images, which are all submitted behind a single fence.
+/// Static data (e.g. mesh geometry and textures) should be uploaded this way, since device local memory is much faster for the device to read.
+/// The destination resources must not be used until `submit` has returned.
+pub struct UploadBatch {
+
+    command_pool: gfx::CommandPool<Backend, gfx::General>,
+    command_buffer: command::CommandBuffer,
+    fence: <Backend as gfx::Backend>::Fence,
+
+    /// The staging buffers which are copied from. These are kept alive until the copies are complete.
+    staging: Vec<buffer::Buffer>,
+
+    device_token: core::DeviceToken,
+
+}
+
+impl UploadBatch {
+
+    pub fn new(device: &core::Device) -> Result<Self, Error> {
+        let mut command_pool = unsafe {
+            device.gpu.create_command_pool_typed(&device.queue_group, gfx::pool::CommandPoolCreateFlags::TRANSIENT)
+        }?;
+        let mut command_buffer = command::CommandBuffer::new(&mut command_pool);
+        command_buffer.begin();
+        let fence = device.gpu.create_fence(false)?;
+        return Ok(Self { command_pool, command_buffer, fence, staging: Vec::new(), device_token: device.create_token() });
+    }
+
+    /// Returns true if nothing has been recorded into the batch.
+    pub fn is_empty(&self) -> bool {
+        return self.staging.is_empty();
+    }
+
+    /// The total size of the staging buffers recorded into the batch, in bytes.
+    pub fn staging_size(&self) -> u64 {
+        return self.staging.iter().map(|buffer| buffer.allocation.size).sum();
+    }
+
+    /// Creates a device local buffer with the usage specified and records a copy of the data into it.
+    pub fn upload_buffer<T: Copy>(&mut self, data: &[T], usage: gfx::buffer::Usage, device: &core::Device) -> Result<buffer::Buffer, Error> {
+        let size = (data.len() * std::mem::size_of::<T>()) as u64;
+
+        let mut staging = buffer::Buffer::alloc_empty::<T>(data.len(), gfx::buffer::Usage::TRANSFER_SRC, gfx::memory::Properties::CPU_VISIBLE, device)?;
+        staging.fill_buffer(data, device)?;
+
+        let mut target = buffer::Buffer::alloc_empty::<T>(data.len(), usage | gfx::buffer::Usage::TRANSFER_DST, gfx::memory::Properties::DEVICE_LOCAL, device)?;
+        target.count = data.len();
+
+        if size > 0 {
+            unsafe {
+                self.command_buffer.cmd.copy_buffer(&staging.buf, &target.buf, &[gfx::command::BufferCopy { src: 0, dst: 0, size }]);
+            }
+        }
+
+        self.staging.push(staging);
+        return Ok(target);
+    }
+
+    /// Creates a device local vertex buffer and records a copy of the vertices into it.
+    pub fn upload_vertices<V: pipeline::VertexFormat>(&mut self, vertices: &[V], device: &core::Device) -> Result<buffer::Buffer, Error> {
+        return self.upload_buffer(vertices, gfx::buffer::Usage::VERTEX, device);
+    }
+
+    /// Records a copy of the texture into the image of a texture buffer, which is then transitioned so it can be sampled by fragment shaders.
+    /// The image must have been created with `TRANSFER_DST` usage and an RGBA format.
+    pub fn upload_texture(&mut self, texture: &texture::Texture, target: &buffer::TextureBuffer, device: &core::Device) -> Result<(), Error> {
+        let (width, height) = (texture.dimensions.x, texture.dimensions.y);
+        let expected_size = width as usize * height as usize * 4;
+        if texture.data.len() != expected_size {
+            return Err(Error::Texture(format!(
+                "Failed to upload texture - {} bytes of data were given for a {}x{} RGBA texture, which needs {}.", texture.data.len(), width, height, expected_size
+            )));
+        }
+        let row_alignment_mask = device.adapter.physical_device.limits().min_buffer_copy_pitch_alignment as u32 - 1;
+        let image_stride = 4usize;
+        let row_pitch =
+            (width * image_stride as u32 + row_alignment_mask) & !row_alignment_mask;
+        let upload_size = u64::from(height * row_pitch);
+
+        let staging = buffer::Buffer::alloc_empty::<u8>(
+            upload_size as usize,
+            gfx::buffer::Usage::TRANSFER_SRC,
+            gfx::memory::Properties::CPU_VISIBLE,
+            device
+        )?;
+
+        for y in 0..height as usize {
+            let row = &(*texture.data)[y * (width as usize) * image_stride
+                ..(y + 1) * (width as usize) * image_stride];
+            let dest_base = y as u64 * u64::from(row_pitch);
+            if !staging.allocation.write(dest_base, row) {
+                return Err(Error::Texture(String::from("Failed to upload texture - the data could not be written to the staging buffer.")));
+            }
+        }
+        device.allocator.lock().unwrap().flush(&staging.allocation);
+
+        let color_range = gfx::image::SubresourceRange {
+            aspects: gfx::format::Aspects::COLOR,
+            levels: 0..1,
+            layers: 0..1,
+        };
+
+        unsafe {
+            let cmd_buffer = &mut self.command_buffer.cmd;
+
+            let image_barrier = gfx::memory::Barrier::Image {
+                states: (gfx::image::Access::empty(), gfx::image::Layout::Undefined)
+                    ..(gfx::image::Access::TRANSFER_WRITE, gfx::image::Layout::TransferDstOptimal),
+                target: &target.image,
+                families: None,
+                range: color_range.clone(),
+            };
+
+            cmd_buffer.pipeline_barrier(
+                gfx::pso::PipelineStage::TOP_OF_PIPE..gfx::pso::PipelineStage::TRANSFER,
+                gfx::memory::Dependencies::empty(),
+                &[image_barrier],
+            );
+
+            cmd_buffer.copy_buffer_to_image(
+                &staging.buf,
+                &target.image,
+                gfx::image::Layout::TransferDstOptimal,
+                &[gfx::command::BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_width: row_pitch / (image_stride as u32),
+                    buffer_height: height as u32,
+                    image_layers: gfx::image::SubresourceLayers {
+                        aspects: gfx::format::Aspects::COLOR,
+                        level: 0,
+                        layers: 0..1,
+                    },
+                    image_offset: gfx::image::Offset { x: 0, y: 0, z: 0 },
+                    image_extent: gfx::image::Extent {
+                        width,
+                        height,
+                        depth: 1,
+                    },
+                }],
+            );
+
+            let image_barrier = gfx::memory::Barrier::Image {
+                states: (gfx::image::Access::TRANSFER_WRITE, gfx::image::Layout::TransferDstOptimal)
+                    ..(gfx::image::Access::SHADER_READ, gfx::image::Layout::ShaderReadOnlyOptimal),
+                target: &target.image,
+                families: None,
+                range: color_range,
+            };
+
+            cmd_buffer.pipeline_barrier(
+                gfx::pso::PipelineStage::TRANSFER..gfx::pso::PipelineStage::FRAGMENT_SHADER,
+                gfx::memory::Dependencies::empty(),
+                &[image_barrier],
+            );
+        }
+
+        self.staging.push(staging);
+        return Ok(());
+    }
+
+    /// Submits every recorded copy and waits for them to complete.
+    /// The staging buffers are freed once the copies are complete.
+    pub fn submit(mut self, device: &mut core::Device) -> Result<(), Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        unsafe {
+            // Make the copied buffer data visible to every stage which may read it.
+            let buffer_barrier = gfx::memory::Barrier::AllBuffers(
+                gfx::buffer::Access::TRANSFER_WRITE
+                    ..(gfx::buffer::Access::VERTEX_BUFFER_READ | gfx::buffer::Access::INDEX_BUFFER_READ | gfx::buffer::Access::CONSTANT_BUFFER_READ | gfx::buffer::Access::SHADER_READ)
+            );
+            self.command_buffer.cmd.pipeline_barrier(
+                gfx::pso::PipelineStage::TRANSFER..(gfx::pso::PipelineStage::VERTEX_INPUT | gfx::pso::PipelineStage::VERTEX_SHADER | gfx::pso::PipelineStage::FRAGMENT_SHADER),
+                gfx::memory::Dependencies::empty(),
+                &[buffer_barrier],
+            );
+            self.command_buffer.cmd.finish();
+
+            device.queue_group.queues[0].submit_nosemaphores(std::iter::once(&self.command_buffer.cmd), Some(&self.fence));
+            device.gpu.wait_for_fence(&self.fence, !0)?;
+        }
+
+        log!(debug, 2, "Uploaded {} staging buffers ({} bytes).", self.staging.len(), self.staging_size());
+        self.staging.clear();
+        return Ok(());
+    }
+
+}
+
+impl Drop for UploadBatch {
+
+    fn drop(&mut self) {
+        // A batch which was never submitted has nothing in flight, so its resources can be destroyed straight away.
+        unsafe {
+            use std::mem;
+            self.device_token.gpu.destroy_fence(mem::transmute_copy(&self.fence));
+            // Command buffers are freed along with their pool.
+            self.device_token.gpu.destroy_command_pool(mem::transmute_copy(&self.command_pool));
+        }
+    }
+
+}
@@ -1,153 +1,192 @@
 ﻿use crate::*;
 
 use gfx::Device as GfxDevice;
-use gfx::Swapchain;
 
 use std::iter;
+use std::ops::Range;
 
 pub type Fence = <Backend as gfx::Backend>::Fence;
 pub type Semaphore = <Backend as gfx::Backend>::Semaphore;
-pub type CommandPool = gfx::CommandPool<Backend, gfx::Graphics>;
+pub type CommandPool = gfx::CommandPool<Backend, gfx::General>;
+
+/// The number of frames which may be recorded or executing on the gpu at once, unless specified otherwise.
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
 
 pub struct Frame<'a> {
 
+    /// The index of the swapchain (or offscreen) image which is rendered to.
     pub frame_index: usize,
+    /// The index of the frame in flight whose resources are used to record and submit this frame.
+    pub frame_slot: usize,
     pub framebuffer: &'a render::Framebuffer,
-    pub command_pool: &'a mut CommandPool,
-
-    pub framebuffer_fence: &'a Fence,
-    pub acquire_semaphore: &'a Semaphore,
-    pub present_semaphore: &'a Semaphore,
+    pub slots: &'a mut FrameSlots,
 
 }
 
 impl<'a> Frame<'a> {
-    pub fn new(frame_index: usize, framebuffer: &'a render::Framebuffer, command_pool: &'a mut CommandPool, framebuffer_fence: &'a Fence, acquire_semaphore: &'a Semaphore, present_semaphore: &'a Semaphore) -> Self {
-        Self { frame_index, framebuffer, command_pool, framebuffer_fence, acquire_semaphore, present_semaphore }
+    pub fn new(frame_index: usize, frame_slot: usize, framebuffer: &'a render::Framebuffer, slots: &'a mut FrameSlots) -> Self {
+        Self { frame_index, frame_slot, framebuffer, slots }
     }
 
     pub fn begin_render<F>(&mut self, graphics: &mut render::Graphics, mut f: F) -> bool
         where F: FnMut(&mut render::Dispatch) {
 
-        let mut cmd_buffer = CommandBuffer::new(self.command_pool);
-        let mut dispatch: render::Dispatch = render::Dispatch::new(graphics, &mut cmd_buffer, self.framebuffer);
-        f(&mut dispatch);
-
-        cmd_buffer.finish();
-
-        let submission = gfx::Submission {
-            command_buffers: iter::once(&cmd_buffer.cmd),
-            wait_semaphores: iter::once((self.acquire_semaphore, gfx::pso::PipelineStage::BOTTOM_OF_PIPE)),
-            signal_semaphores: iter::once(self.present_semaphore),
-        };
-
-        unsafe { graphics.device.queue_group.queues[0].submit(submission, Some(self.framebuffer_fence)) };
-
-        let result = unsafe { graphics.render_surface.swapchain
-            .present(
-                &mut graphics.device.queue_group.queues[0],
-                self.frame_index as u32,
-                Some(self.present_semaphore),
-            ) };
-
-        if result.is_err() {
-            return false;
-        }
-        return true;
+        let framebuffer = self.framebuffer;
+        return self.slots.submit(self.frame_index, self.frame_slot, graphics, |graphics, command_buffer| {
+            let mut dispatch: render::Dispatch = render::Dispatch::new(graphics, command_buffer, framebuffer);
+            f(&mut dispatch);
+        });
 
     }
 }
 
-pub struct FrameAggregator {
+/// The resources used to record and submit frames, split into frame slots with one slot per frame in flight.
+/// Before a slot is reused its fence is waited on, after which its command pool is reset and its command buffer is recorded again.
+pub struct FrameSlots {
 
-    pub framebuffers: Vec<render::Framebuffer>,
     pub command_pools: Vec<CommandPool>,
-
-    pub framebuffer_fences: Vec<Fence>,
+    pub command_buffers: Vec<CommandBuffer>,
+    pub frame_fences: Vec<Fence>,
     pub acquire_semaphores: Vec<Semaphore>,
     pub present_semaphores: Vec<Semaphore>,
 
-    last_ref: usize,
+    /// The frame slot which last rendered to each image, if any.
+    image_slots: Vec<Option<usize>>,
+    next_slot: usize,
 
     device_token: core::DeviceToken,
 
 }
 
-impl FrameAggregator {
-
-    pub fn create(render_pass: &render::RenderPass, depth_format: Option<gfx::format::Format>, graphics: &mut render::Graphics) -> Self {
-        let framebuffers: Vec<render::Framebuffer> = graphics.render_surface.create_framebuffers(render_pass, depth_format, &graphics.device);
-        Self::new(framebuffers, &graphics.device)
-    }
-
-    pub fn new(framebuffers: Vec<render::Framebuffer>, device: &core::Device) -> Self {
-        let max_buffers = 16;
-        let count = framebuffers.len();
-        let mut command_pools: Vec<CommandPool> = Vec::with_capacity(count);
-        let mut framebuffer_fences: Vec<Fence> = Vec::with_capacity(count);
-        let mut acquire_semaphores: Vec<Semaphore> = Vec::with_capacity(count);
-        let mut present_semaphores: Vec<Semaphore> = Vec::with_capacity(count);
-        for i in 0..count {
+impl FrameSlots {
+
+    pub fn new(frames_in_flight: usize, device: &core::Device) -> Result<Self, Error> {
+        let count = frames_in_flight.max(1);
+        // The slots are filled in one at a time so that everything created before a failure is destroyed when they are dropped.
+        let mut slots = Self {
+            command_pools: Vec::with_capacity(count),
+            command_buffers: Vec::with_capacity(count),
+            frame_fences: Vec::with_capacity(count),
+            acquire_semaphores: Vec::with_capacity(count),
+            present_semaphores: Vec::with_capacity(count),
+            image_slots: Vec::new(),
+            next_slot: 0,
+            device_token: device.create_token(),
+        };
+        for _ in 0..count {
 
-            let command_pool = unsafe {  device.gpu.create_command_pool_typed(
+            let mut command_pool = unsafe {  device.gpu.create_command_pool_typed(
                 &device.queue_group,
                 gfx::pool::CommandPoolCreateFlags::empty()
-            ).unwrap() };
-            command_pools.push(command_pool);
-            framebuffer_fences.push(device.gpu.create_fence(true).unwrap());
-            acquire_semaphores.push(device.gpu.create_semaphore().unwrap());
-            present_semaphores.push(device.gpu.create_semaphore().unwrap());
+            )? };
+            slots.command_buffers.push(CommandBuffer::new(&mut command_pool));
+            slots.command_pools.push(command_pool);
+            // Fences start signalled so that the first wait on each slot returns immediately.
+            slots.frame_fences.push(device.gpu.create_fence(true)?);
+            slots.acquire_semaphores.push(device.gpu.create_semaphore()?);
+            slots.present_semaphores.push(device.gpu.create_semaphore()?);
 
         }
 
-        return Self { framebuffers, command_pools, framebuffer_fences, acquire_semaphores, present_semaphores, last_ref: 0, device_token: device.create_token() };
+        return Ok(slots);
     }
 
-    fn next_acq_pre_pair_index(&mut self) -> usize {
-        if self.last_ref >= self.acquire_semaphores.len() {
-            self.last_ref = 0
-        }
+    pub fn frames_in_flight(&self) -> usize {
+        return self.frame_fences.len();
+    }
+
+    fn next_slot_index(&mut self) -> usize {
+        let slot = self.next_slot;
+        self.next_slot = (self.next_slot + 1) % self.frames_in_flight();
+        return slot;
+    }
 
-        let ret = self.last_ref;
-        self.last_ref += 1;
-        ret
+    fn wait_for_slot(&self, slot: usize) {
+        unsafe { self.device_token.gpu.wait_for_fence(&self.frame_fences[slot], !0) }.log_expect("Failed to wait for frame fence.");
     }
 
-    fn acquire_next_indices(&mut self, graphics: &mut render::Graphics) -> Option<(usize, usize)> {
-        if !self.acquire_semaphores.is_empty() {
-            let si = self.next_acq_pre_pair_index();
-            if let Ok(fi) = unsafe { graphics.render_surface.swapchain.acquire_image(!0, gfx::FrameSync::Semaphore(&self.acquire_semaphores[si])) } {
-                return Some((fi as usize, si));
+    /// Waits until the next frame slot is free and acquires the next image of the surface.
+    /// Returns the index of the image and the index of the slot, or `None` if no image could be acquired.
+    pub fn acquire(&mut self, graphics: &mut render::Graphics) -> Option<(usize, usize)> {
+        let slot = self.next_slot_index();
+
+        // The resources of the slot may still be used by the frame submitted N frames ago.
+        self.wait_for_slot(slot);
+
+        let image = graphics.render_surface.acquire_image(&self.acquire_semaphores[slot])?;
+
+        // The image may still be rendered to by a frame which used a different slot (when there are more images than frames in flight).
+        if image >= self.image_slots.len() {
+            self.image_slots.resize(image + 1, None);
+        }
+        if let Some(previous_slot) = self.image_slots[image] {
+            if previous_slot != slot {
+                self.wait_for_slot(previous_slot);
             }
         }
-        None
-    }
+        self.image_slots[image] = Some(slot);
 
-    pub fn acquire_next(&mut self, graphics: &mut render::Graphics) -> Option<Frame> {
-        if let Some((fi, si)) = self.acquire_next_indices(graphics) {
-            return Some(self.next_frame(fi, si));
+        // Only reset the fence once an image has been acquired, otherwise it would never be signalled and the next wait would block forever.
+        unsafe {
+            self.device_token.gpu.reset_fence(&self.frame_fences[slot]).log_expect("Failed to reset frame fence.");
+            self.command_pools[slot].reset();
         }
-        None
+
+        return Some((image, slot));
     }
 
-    pub fn next_frame(&mut self, frame_index: usize, semaphore_index: usize) -> Frame {
-        Frame::new(frame_index, &self.framebuffers[frame_index], &mut self.command_pools[frame_index], &self.framebuffer_fences[frame_index], &self.acquire_semaphores[semaphore_index], &self.present_semaphores[semaphore_index])
+    /// Records the command buffer of the frame slot, submits it and presents the image.
+    pub fn submit<F>(&mut self, frame_index: usize, frame_slot: usize, graphics: &mut render::Graphics, f: F) -> bool
+        where F: FnOnce(&mut render::Graphics, &mut CommandBuffer) {
+
+        let command_buffer = &mut self.command_buffers[frame_slot];
+        command_buffer.begin();
+        f(graphics, command_buffer);
+        command_buffer.finish();
+
+        // Offscreen images are never acquired from or presented to a swapchain, so there is nothing to wait on or signal.
+        let is_offscreen = graphics.render_surface.is_offscreen();
+        let mut wait_semaphores: Vec<(&Semaphore, gfx::pso::PipelineStage)> = Vec::new();
+        let mut signal_semaphores: Vec<&Semaphore> = Vec::new();
+        if !is_offscreen {
+            // Writing to the color attachment must wait until the presentation engine has released the acquired image.
+            wait_semaphores.push((&self.acquire_semaphores[frame_slot], gfx::pso::PipelineStage::COLOR_ATTACHMENT_OUTPUT));
+            signal_semaphores.push(&self.present_semaphores[frame_slot]);
+        }
+
+        let submission = gfx::Submission {
+            command_buffers: iter::once(&command_buffer.cmd),
+            wait_semaphores,
+            signal_semaphores,
+        };
+
+        // The fence is signalled once the frame has finished executing, which allows the frame slot to be reused.
+        unsafe { graphics.device.queue_group.queues[0].submit(submission, Some(&self.frame_fences[frame_slot])) };
+
+        let result = graphics.render_surface.present(
+            &mut graphics.device.queue_group.queues[0],
+            frame_index,
+            &self.present_semaphores[frame_slot],
+        );
+
+        return result;
+
     }
 
 }
 
-impl Drop for FrameAggregator {
+impl Drop for FrameSlots {
 
     fn drop(&mut self) {
+        // Frames in flight may still be using the command buffers.
+        let _ = self.device_token.gpu.wait_idle();
         unsafe {
             use std::mem;
-            for framebuffer in self.framebuffers.iter() {
-                self.device_token.gpu.destroy_framebuffer(mem::transmute_copy(framebuffer));
-            }
+            // Command buffers are freed along with their pool.
             for command_pool in self.command_pools.iter() {
                 self.device_token.gpu.destroy_command_pool(mem::transmute_copy(command_pool));
             }
-            for fence in self.framebuffer_fences.iter() {
+            for fence in self.frame_fences.iter() {
                 self.device_token.gpu.destroy_fence(mem::transmute_copy(fence));
             }
             for acquire_semaphore in self.acquire_semaphores.iter() {
@@ -161,6 +200,77 @@ impl Drop for FrameAggregator {
 
 }
 
+/// Owns the framebuffers of a single render pass along with the frame slots used to render to them.
+/// For anything more than a single pass, use a `graph::RenderGraph`.
+pub struct FrameAggregator {
+
+    pub framebuffers: Vec<render::Framebuffer>,
+    pub slots: FrameSlots,
+    /// The depth buffer shared by the framebuffers, if the render pass has a depth attachment.
+    pub depth_buffer: Option<buffer::TextureBuffer>,
+    /// The color buffer which is resolved into the surface images, if the render pass is multisampled.
+    pub color_buffer: Option<buffer::TextureBuffer>,
+
+    device_token: core::DeviceToken,
+
+}
+
+impl FrameAggregator {
+
+    pub fn create(render_pass: &render::RenderPass, depth_format: Option<gfx::format::Format>, graphics: &mut render::Graphics) -> Result<Self, Error> {
+        return Self::create_with_frames_in_flight(render_pass, depth_format, DEFAULT_FRAMES_IN_FLIGHT, graphics);
+    }
+
+    pub fn create_with_frames_in_flight(render_pass: &render::RenderPass, depth_format: Option<gfx::format::Format>, frames_in_flight: usize, graphics: &mut render::Graphics) -> Result<Self, Error> {
+        let extent = graphics.render_surface.extent;
+        let size = Vector2u::new(extent.width, extent.height);
+        let samples = render_pass.samples;
+        let depth_buffer = match depth_format {
+            Some(format) => Some(buffer::TextureBuffer::create_depth_multisampled(size, format, samples, &graphics.device)?),
+            None => None,
+        };
+        let color_buffer = if samples > 1 {
+            Some(buffer::TextureBuffer::create_multisampled_color(size, graphics.device.color_format, samples, &graphics.device)?)
+        } else {
+            None
+        };
+        let framebuffers: Vec<render::Framebuffer> = graphics.render_surface.create_framebuffers(render_pass, depth_buffer.as_ref(), color_buffer.as_ref(), &graphics.device)?;
+        let mut aggregator = Self::new(framebuffers, frames_in_flight, &graphics.device)?;
+        aggregator.depth_buffer = depth_buffer;
+        aggregator.color_buffer = color_buffer;
+        return Ok(aggregator);
+    }
+
+    pub fn new(framebuffers: Vec<render::Framebuffer>, frames_in_flight: usize, device: &core::Device) -> Result<Self, Error> {
+        return Ok(Self { framebuffers, slots: FrameSlots::new(frames_in_flight, device)?, depth_buffer: None, color_buffer: None, device_token: device.create_token() });
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        return self.slots.frames_in_flight();
+    }
+
+    pub fn acquire_next(&mut self, graphics: &mut render::Graphics) -> Option<Frame> {
+        let (image, slot) = self.slots.acquire(graphics)?;
+        return Some(Frame::new(image, slot, &self.framebuffers[image], &mut self.slots));
+    }
+
+}
+
+impl Drop for FrameAggregator {
+
+    fn drop(&mut self) {
+        // Frames in flight may still be using the framebuffers.
+        let _ = self.device_token.gpu.wait_idle();
+        unsafe {
+            use std::mem;
+            for framebuffer in self.framebuffers.iter() {
+                self.device_token.gpu.destroy_framebuffer(mem::transmute_copy(framebuffer));
+            }
+        }
+    }
+
+}
+
 pub struct Encoder<'a> {
     pub pass: gfx::command::RenderPassInlineEncoder<'a, Backend>,
 }
@@ -175,13 +285,15 @@ impl<'a> Encoder<'a> {
 
 pub struct CommandBuffer {
 
-    pub cmd: gfx::command::CommandBuffer<Backend, gfx::Graphics>,
+    pub cmd: gfx::command::CommandBuffer<Backend, gfx::General>,
 
 }
 
 impl CommandBuffer {
 
-    pub fn new(command_pool: &mut gfx::CommandPool<Backend, gfx::Graphics>) -> Self {
+    /// Allocates a command buffer from the pool.
+    /// The command buffer can be recorded again once the pool has been reset.
+    pub fn new(command_pool: &mut gfx::CommandPool<Backend, gfx::General>) -> Self {
 
         let cmd = command_pool.acquire_command_buffer::<gfx::command::OneShot>();
 
@@ -189,27 +301,96 @@ impl CommandBuffer {
 
     }
 
+    pub fn begin(&mut self) {
+        unsafe { self.cmd.begin() };
+    }
+
     pub fn begin_draw(&mut self, framebuffer: &render::Framebuffer, render_pass: &render::RenderPass, render_surface: &render::Surface, clear_color: Color) -> Encoder {
+        let color = gfx::command::ClearValue::Color(gfx::command::ClearColor::Float(clear_color.to_raw_color()));
+        let depth = gfx::command::ClearValue::DepthStencil(gfx::command::ClearDepthStencil(1.0, 0));
+        if render_pass.samples > 1 {
+            // The resolve attachment is not cleared, but still needs a clear value.
+            return self.begin_pass(framebuffer, render_pass, &render_surface.viewport, &[color, depth, color]);
+        }
+        return self.begin_pass(framebuffer, render_pass, &render_surface.viewport, &[color, depth]);
+    }
+
+    /// Begins a render pass which covers the viewport.
+    /// There must be one clear value for each attachment of the render pass, in order, even if the attachment is not cleared.
+    pub fn begin_pass(&mut self, framebuffer: &render::Framebuffer, render_pass: &render::RenderPass, viewport: &gfx::pso::Viewport, clear_values: &[gfx::command::ClearValue]) -> Encoder {
         unsafe {
-            self.cmd.set_viewports(0, &[render_surface.viewport.clone()]);
+            self.cmd.set_viewports(0, &[viewport.clone()]);
 
-            self.cmd.set_scissors(0, &[render_surface.viewport.clone().rect]);
+            self.cmd.set_scissors(0, &[viewport.rect]);
 
             self.cmd.set_depth_bounds(0.0..1.0);
 
             let encoder = self.cmd.begin_render_pass_inline(
                 &render_pass.raw_render_pass,
-                &framebuffer,
-                render_surface.viewport.rect,
-                &[
-                    gfx::command::ClearValue::Color(gfx::command::ClearColor::Float(clear_color.to_raw_color())),
-                    gfx::command::ClearValue::DepthStencil(gfx::command::ClearDepthStencil(1.0, 0)),
-                ],
+                framebuffer,
+                viewport.rect,
+                clear_values,
             );
             return Encoder::new(encoder);
         }
     }
 
+    /// Dispatches the bound compute pipeline with the number of work groups specified in each dimension.
+    /// This must be recorded outside of a render pass.
+    pub fn dispatch(&mut self, groups: [u32; 3]) {
+        unsafe { self.cmd.dispatch(groups) };
+    }
+
+    /// Dispatches enough work groups of the size specified to cover the number of invocations in each dimension.
+    pub fn dispatch_invocations(&mut self, invocations: [u32; 3], group_size: [u32; 3]) {
+        let groups = [
+            (invocations[0] + group_size[0] - 1) / group_size[0],
+            (invocations[1] + group_size[1] - 1) / group_size[1],
+            (invocations[2] + group_size[2] - 1) / group_size[2],
+        ];
+        self.dispatch(groups);
+    }
+
+    /// Makes writes to the buffer by the source stages visible to the destination stages.
+    pub fn buffer_barrier(&mut self, buffer: &buffer::Buffer, stages: Range<gfx::pso::PipelineStage>, access: Range<gfx::buffer::Access>) {
+        let barrier = gfx::memory::Barrier::whole_buffer(&buffer.buf, access);
+        unsafe { self.cmd.pipeline_barrier(stages, gfx::memory::Dependencies::empty(), &[barrier]) };
+    }
+
+    /// Makes writes to the color image by the source stages visible to the destination stages, transitioning its layout.
+    /// Storage images must be transitioned to the `General` layout before they are written by a compute shader.
+    pub fn image_barrier(&mut self, image: &buffer::TextureBuffer, stages: Range<gfx::pso::PipelineStage>, access: Range<gfx::image::Access>, layouts: Range<gfx::image::Layout>) {
+        let barrier = gfx::memory::Barrier::Image {
+            states: (access.start, layouts.start)..(access.end, layouts.end),
+            target: &image.image,
+            families: None,
+            range: gfx::image::SubresourceRange {
+                aspects: gfx::format::Aspects::COLOR,
+                levels: 0..1,
+                layers: 0..1,
+            },
+        };
+        unsafe { self.cmd.pipeline_barrier(stages, gfx::memory::Dependencies::empty(), &[barrier]) };
+    }
+
+    /// Waits for a compute shader to finish writing a storage buffer before it is read as vertices or indices, e.g. after skinning or a particle update.
+    pub fn compute_to_vertex_barrier(&mut self, buffer: &buffer::Buffer) {
+        self.buffer_barrier(
+            buffer,
+            gfx::pso::PipelineStage::COMPUTE_SHADER..gfx::pso::PipelineStage::VERTEX_INPUT,
+            gfx::buffer::Access::SHADER_WRITE..(gfx::buffer::Access::VERTEX_BUFFER_READ | gfx::buffer::Access::INDEX_BUFFER_READ),
+        );
+    }
+
+    /// Waits for a compute shader to finish writing a storage buffer before it is used by a later dispatch or by the shaders of a draw.
+    pub fn compute_to_shader_barrier(&mut self, buffer: &buffer::Buffer) {
+        self.buffer_barrier(
+            buffer,
+            gfx::pso::PipelineStage::COMPUTE_SHADER..(gfx::pso::PipelineStage::COMPUTE_SHADER | gfx::pso::PipelineStage::VERTEX_SHADER | gfx::pso::PipelineStage::FRAGMENT_SHADER),
+            gfx::buffer::Access::SHADER_WRITE..(gfx::buffer::Access::SHADER_READ | gfx::buffer::Access::SHADER_WRITE),
+        );
+    }
+
     pub fn finish(&mut self) {
         unsafe { self.cmd.finish() };
     }
@@ -0,0 +1,388 @@
+use crate::*;
+
+use gfx::Device as GfxDevice;
+
+use ash::vk;
+use ash::version::DeviceV1_0;
+use ash::version::InstanceV1_0;
+
+use std::ffi::CStr;
+use std::mem;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Gets the directory application data such as caches is stored in, e.g. `~/.local/share/<application>` on Linux.
+pub fn data_dir(application_name: &str) -> Option<PathBuf> {
+    let base = if cfg!(target_os = "windows") {
+        std::env::var_os("LOCALAPPDATA").or_else(|| std::env::var_os("APPDATA")).map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Library").join("Application Support"))
+    } else {
+        std::env::var_os("XDG_DATA_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))
+    };
+    return base.map(|base| base.join(application_name));
+}
+
+/// Identifies the engine version, adapter and driver a pipeline cache was created by.
+/// Driver caches are only valid for the driver which created them, so a cache written by any other adapter, driver version or cache format must be discarded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PipelineCacheHeader {
+
+    pub format_version: u32,
+    pub engine_version: String,
+    pub vendor: u32,
+    pub device: u32,
+    pub adapter_name: String,
+    pub driver_version: u32,
+    /// The `pipelineCacheUUID` of the driver, which changes whenever the driver's cache format does.
+    pub pipeline_cache_uuid: [u8; 16],
+
+}
+
+impl PipelineCacheHeader {
+
+    const MAGIC: &'static [u8; 4] = b"IMPC";
+    /// Incremented whenever the layout of the file changes.
+    pub const FORMAT_VERSION: u32 = 2;
+
+    /// The header for caches created by the adapter specified with this version of the engine.
+    /// The driver version and cache UUID are not exposed by gfx-hal, so they are read from the Vulkan physical device with the same ids and name.
+    pub fn for_adapter(instance: &core::Instance, info: &gfx::AdapterInfo) -> Self {
+        let mut header = Self {
+            format_version: Self::FORMAT_VERSION,
+            engine_version: String::from(env!("CARGO_PKG_VERSION")),
+            vendor: info.vendor as u32,
+            device: info.device as u32,
+            adapter_name: info.name.clone(),
+            driver_version: 0,
+            pipeline_cache_uuid: [0; 16],
+        };
+        let raw = &instance.gfx_inst.raw.0;
+        for physical_device in raw.enumerate_physical_devices().unwrap_or_default() {
+            let properties = raw.get_physical_device_properties(physical_device);
+            let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }.to_string_lossy();
+            if properties.vendor_id == header.vendor && properties.device_id == header.device && name == header.adapter_name {
+                header.driver_version = properties.driver_version;
+                header.pipeline_cache_uuid = properties.pipeline_cache_uuid;
+                break;
+            }
+        }
+        return header;
+    }
+
+    pub fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(Self::MAGIC);
+        bytes.extend_from_slice(&self.format_version.to_le_bytes());
+        write_string(&self.engine_version, bytes);
+        bytes.extend_from_slice(&self.vendor.to_le_bytes());
+        bytes.extend_from_slice(&self.device.to_le_bytes());
+        write_string(&self.adapter_name, bytes);
+        bytes.extend_from_slice(&self.driver_version.to_le_bytes());
+        bytes.extend_from_slice(&self.pipeline_cache_uuid);
+    }
+
+    /// Reads a header from the start of the bytes, returning it along with the rest of the bytes.
+    pub fn read(bytes: &[u8]) -> Option<(Self, &[u8])> {
+        if bytes.len() < Self::MAGIC.len() || &bytes[..Self::MAGIC.len()] != Self::MAGIC {
+            return None;
+        }
+        let rest = &bytes[Self::MAGIC.len()..];
+        let (format_version, rest) = read_u32(rest)?;
+        if format_version != Self::FORMAT_VERSION {
+            return None;
+        }
+        let (engine_version, rest) = read_string(rest)?;
+        let (vendor, rest) = read_u32(rest)?;
+        let (device, rest) = read_u32(rest)?;
+        let (adapter_name, rest) = read_string(rest)?;
+        let (driver_version, rest) = read_u32(rest)?;
+        if rest.len() < 16 {
+            return None;
+        }
+        let mut pipeline_cache_uuid = [0u8; 16];
+        pipeline_cache_uuid.copy_from_slice(&rest[..16]);
+        let rest = &rest[16..];
+        return Some((Self { format_version, engine_version, vendor, device, adapter_name, driver_version, pipeline_cache_uuid }, rest));
+    }
+
+}
+
+/// A driver pipeline cache which every pipeline created through the device uses, so that shaders compiled by the driver are reused.
+/// The cache is loaded from a file when it is created and written back to it when it is dropped.
+pub struct PipelineCache {
+
+    pub cache: <Backend as gfx::Backend>::PipelineCache,
+    /// The file the cache is saved to. The cache is only kept in memory if this is `None`.
+    pub path: Option<PathBuf>,
+    pub header: PipelineCacheHeader,
+
+    device_token: core::DeviceToken,
+
+}
+
+impl PipelineCache {
+
+    /// The name of the cache file in the application's data directory.
+    pub const FILE_NAME: &'static str = "pipeline_cache.bin";
+
+    /// Creates the cache for the adapter of the device, loading it from the file specified if it exists and was written by the same engine version, adapter and driver.
+    pub fn create(path: Option<PathBuf>, instance: &core::Instance, device: &core::Device) -> Result<Self, Error> {
+        let header = PipelineCacheHeader::for_adapter(instance, &device.adapter.info);
+        let mut data: Vec<u8> = Vec::new();
+        if let Some(path) = path.as_ref() {
+            data = Self::load(path, &header);
+        }
+        let cache = Self::create_raw(&data, device)?;
+        return Ok(Self { cache, path, header, device_token: device.create_token() });
+    }
+
+    /// Reads the driver data from a cache file. Nothing is returned if the file does not exist, is corrupt or was written for another adapter, driver or engine version.
+    /// The data is only given to the driver once the whole header has been checked, including the driver version and cache UUID.
+    fn load(path: &Path, header: &PipelineCacheHeader) -> Vec<u8> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Vec::new(),
+        };
+        match PipelineCacheHeader::read(&bytes) {
+            Some((file_header, data)) => {
+                if file_header != *header {
+                    log!(debug, 1, "Discarding pipeline cache '{}', which was written for {:?}.", path.display(), file_header);
+                    return Vec::new();
+                }
+                log!(debug, 1, "Loaded {} bytes of pipeline cache data from '{}'.", data.len(), path.display());
+                return data.to_vec();
+            },
+            None => {
+                log!(debug, 1, "Discarding pipeline cache '{}', which is not a valid cache file.", path.display());
+                return Vec::new();
+            },
+        }
+    }
+
+    /// Writes the driver data of the cache to its file, if it has one.
+    pub fn save(&self) -> Result<(), Error> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let data = self.raw_data()?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut bytes = Vec::with_capacity(data.len() + 64);
+        self.header.write(&mut bytes);
+        bytes.extend_from_slice(&data);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // The cache is written to a temporary file first so that a crash while saving cannot leave a truncated cache behind.
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, &bytes)?;
+        std::fs::rename(&temp_path, path)?;
+        log!(debug, 1, "Saved {} bytes of pipeline cache data to '{}'.", data.len(), path.display());
+        return Ok(());
+    }
+
+    /// Creates the driver cache from data previously read back with `raw_data`.
+    /// gfx-hal 0.1 always creates empty caches, so the cache is created through Vulkan directly. The driver ignores data it cannot use.
+    fn create_raw(data: &[u8], device: &core::Device) -> Result<<Backend as gfx::Backend>::PipelineCache, Error> {
+        let info = vk::PipelineCacheCreateInfo {
+            s_type: vk::StructureType::PipelineCacheCreateInfo,
+            p_next: std::ptr::null(),
+            flags: vk::PipelineCacheCreateFlags::empty(),
+            initial_data_size: data.len(),
+            p_initial_data: if data.is_empty() { std::ptr::null() } else { data.as_ptr() as *const vk::c_void },
+        };
+        let cache = unsafe { vulkan_device(&device.gpu).create_pipeline_cache(&info, None) }.map_err(vulkan_error)?;
+        // The gfx-hal pipeline cache only holds the Vulkan handle.
+        return Ok(unsafe { mem::transmute::<vk::PipelineCache, <Backend as gfx::Backend>::PipelineCache>(cache) });
+    }
+
+    /// Reads the driver data of the cache.
+    /// gfx-hal 0.1 has no way to read the data of a pipeline cache, so it is read through Vulkan directly.
+    fn raw_data(&self) -> Result<Vec<u8>, Error> {
+        let device = vulkan_device(&self.device_token.gpu);
+        let cache = unsafe { mem::transmute_copy::<<Backend as gfx::Backend>::PipelineCache, vk::PipelineCache>(&self.cache) };
+        // The size of the data is queried first, then the data is read into a buffer of that size.
+        let mut size: usize = 0;
+        let result = unsafe { device.fp_v1_0().get_pipeline_cache_data(device.handle(), cache, &mut size, std::ptr::null_mut()) };
+        if result != vk::Result::Success {
+            return Err(vulkan_error(result));
+        }
+        let mut data = vec![0u8; size];
+        // The cache may grow between the two calls if pipelines are being created, in which case the data is incomplete and is not returned.
+        let result = unsafe { device.fp_v1_0().get_pipeline_cache_data(device.handle(), cache, &mut size, data.as_mut_ptr() as *mut vk::c_void) };
+        if result != vk::Result::Success {
+            return Err(vulkan_error(result));
+        }
+        data.truncate(size);
+        return Ok(data);
+    }
+
+}
+
+impl Drop for PipelineCache {
+
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            log!(err, "Failed to save the pipeline cache: {}", e);
+        }
+        unsafe {
+            self.device_token.gpu.destroy_pipeline_cache(mem::transmute_copy(&self.cache));
+        }
+    }
+
+}
+
+/// Gets the Vulkan device behind a gfx-hal device.
+/// `gfx_backend_vulkan::Device` only holds an `Arc<RawDevice>`, which it does not expose, so the device is reinterpreted as one.
+fn vulkan_device(device: &<Backend as gfx::Backend>::Device) -> &ash::Device<ash::version::V1_0> {
+    assert_eq!(mem::size_of::<<Backend as gfx::Backend>::Device>(), mem::size_of::<Arc<backend::RawDevice>>());
+    let raw = unsafe { &*(device as *const <Backend as gfx::Backend>::Device as *const Arc<backend::RawDevice>) };
+    return &raw.0;
+}
+
+fn vulkan_error(result: vk::Result) -> Error {
+    match result {
+        vk::Result::ErrorOutOfHostMemory => return gfx::device::OutOfMemory::OutOfHostMemory.into(),
+        vk::Result::ErrorOutOfDeviceMemory => return gfx::device::OutOfMemory::OutOfDeviceMemory.into(),
+        vk::Result::ErrorDeviceLost => return Error::DeviceLost,
+        result => return Error::Render(format!("Unexpected Vulkan result {:?}.", result)),
+    }
+}
+
+fn write_string(string: &str, bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&(string.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+fn read_u32(bytes: &[u8]) -> Option<(u32, &[u8])> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[..4]);
+    return Some((u32::from_le_bytes(value), &bytes[4..]));
+}
+
+fn read_string(bytes: &[u8]) -> Option<(String, &[u8])> {
+    let (len, rest) = read_u32(bytes)?;
+    let len = len as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let string = String::from_utf8(rest[..len].to_vec()).ok()?;
+    return Some((string, &rest[len..]));
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn header() -> PipelineCacheHeader {
+        return PipelineCacheHeader {
+            format_version: PipelineCacheHeader::FORMAT_VERSION,
+            engine_version: String::from("0.1.0"),
+            vendor: 0x10de,
+            device: 0x1c82,
+            adapter_name: String::from("GeForce GTX 1050 Ti"),
+            driver_version: 0x1a5c0000,
+            pipeline_cache_uuid: [7; 16],
+        };
+    }
+
+    fn file(header: &PipelineCacheHeader, data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        header.write(&mut bytes);
+        bytes.extend_from_slice(data);
+        return bytes;
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let bytes = file(&header(), &[1, 2, 3, 4, 5]);
+        let (read, data) = PipelineCacheHeader::read(&bytes).unwrap();
+        assert_eq!(read, header());
+        assert_eq!(data, &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn header_without_data_round_trips() {
+        let header = PipelineCacheHeader { engine_version: String::new(), adapter_name: String::from("llvmpipe (LLVM 7.0, 256 bits)"), ..header() };
+        let bytes = file(&header, &[]);
+        let (read, data) = PipelineCacheHeader::read(&bytes).unwrap();
+        assert_eq!(read, header);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn files_without_the_magic_are_rejected() {
+        let mut bytes = file(&header(), &[1, 2, 3]);
+        bytes[0] = b'X';
+        assert!(PipelineCacheHeader::read(&bytes).is_none());
+        assert!(PipelineCacheHeader::read(&[]).is_none());
+    }
+
+    #[test]
+    fn other_format_versions_are_rejected() {
+        let header = PipelineCacheHeader { format_version: PipelineCacheHeader::FORMAT_VERSION + 1, ..header() };
+        assert!(PipelineCacheHeader::read(&file(&header, &[1, 2, 3])).is_none());
+    }
+
+    #[test]
+    fn headers_of_other_drivers_differ() {
+        let updated = PipelineCacheHeader { driver_version: header().driver_version + 1, ..header() };
+        let (read, _) = PipelineCacheHeader::read(&file(&updated, &[])).unwrap();
+        assert_ne!(read, header());
+        let mut pipeline_cache_uuid = header().pipeline_cache_uuid;
+        pipeline_cache_uuid[15] = 0;
+        let other_format = PipelineCacheHeader { pipeline_cache_uuid, ..header() };
+        let (read, _) = PipelineCacheHeader::read(&file(&other_format, &[])).unwrap();
+        assert_ne!(read, header());
+    }
+
+    fn temp_file(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("imperium-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        return path;
+    }
+
+    #[test]
+    fn matching_caches_are_loaded() {
+        let path = temp_file("matching.bin", &file(&header(), &[1, 2, 3]));
+        assert_eq!(PipelineCache::load(&path, &header()), vec![1, 2, 3]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn caches_of_other_drivers_are_not_loaded() {
+        let updated = PipelineCacheHeader { driver_version: header().driver_version + 1, ..header() };
+        let path = temp_file("other-driver.bin", &file(&updated, &[1, 2, 3]));
+        assert!(PipelineCache::load(&path, &header()).is_empty());
+        std::fs::remove_file(&path).unwrap();
+        let path = temp_file("corrupt.bin", &[1, 2, 3]);
+        assert!(PipelineCache::load(&path, &header()).is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert!(PipelineCache::load(&std::env::temp_dir().join("imperium-missing.bin"), &header()).is_empty());
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        let bytes = file(&header(), &[]);
+        for len in 0..bytes.len() {
+            assert!(PipelineCacheHeader::read(&bytes[..len]).is_none(), "a header truncated to {} bytes was read", len);
+        }
+    }
+
+    #[test]
+    fn invalid_strings_are_rejected() {
+        let mut bytes = file(&header(), &[]);
+        // The first byte of the engine version, after the magic, format version and string length.
+        bytes[12] = 0xff;
+        assert!(PipelineCacheHeader::read(&bytes).is_none());
+    }
+
+}
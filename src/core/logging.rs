@@ -0,0 +1,568 @@
+use crate::*;
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use colored::Colorize as PrintColorize;
+
+/// Logs a formatted message through the engine logger.
+/// The subsystem of the record is the module the macro is invoked from.
+///
+/// ```ignore
+/// log!(msg, "Loaded {} models.", count);
+/// log!(debug, 2, "Created descriptor pool.");
+/// log!(err, "Failed to load texture: {}", e);
+/// ```
+#[macro_export]
+macro_rules! log {
+    (msg, $($arg:tt)*) => ({
+        $crate::core::logging::log_record($crate::core::logging::Level::Message, 0, module_path!(), || format!($($arg)*));
+    });
+    (debug, $verb:expr, $($arg:tt)*) => ({
+        $crate::core::logging::log_record($crate::core::logging::Level::Debug, $verb, module_path!(), || format!($($arg)*));
+    });
+    (temp, $($arg:tt)*) => ({
+        $crate::core::logging::log_record($crate::core::logging::Level::Temporary, 0, module_path!(), || format!($($arg)*));
+    });
+    (err, $($arg:tt)*) => ({
+        $crate::core::logging::log_record($crate::core::logging::Level::Error, 0, module_path!(), || format!($($arg)*));
+    });
+    (panic, $($arg:tt)*) => ({
+        $crate::core::logging::log_panic(module_path!(), format!($($arg)*))
+    })
+}
+
+/// The severity of a log record. Each level corresponds to a form of the `log!` macro.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Message,
+    Debug,
+    Temporary,
+    Error,
+    Panic,
+}
+
+impl Level {
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Level::Message => "message",
+            Level::Debug => "debug",
+            Level::Temporary => "temp",
+            Level::Error => "error",
+            Level::Panic => "panic",
+        };
+    }
+
+    fn index(&self) -> usize {
+        return *self as usize;
+    }
+
+}
+
+/// A single log entry as it is passed to each sink.
+#[derive(Clone, Debug)]
+pub struct Record {
+
+    pub level: Level,
+    /// The verbosity of debug records. This is always zero for other levels.
+    pub verbosity: u32,
+    /// The module path of the code which created the record (e.g. `imperium::spatial::pipe::mesh`).
+    pub subsystem: &'static str,
+    pub timestamp: SystemTime,
+    pub message: String,
+
+}
+
+impl Record {
+
+    /// The number of milliseconds between the unix epoch and the creation of the record.
+    pub fn timestamp_millis(&self) -> u64 {
+        let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        return since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64;
+    }
+
+    /// Formats the record as a single line of plain text.
+    pub fn to_line(&self) -> String {
+        let millis = self.timestamp_millis();
+        return format!("[{}.{:03}] {} {}: {}", millis / 1000, millis % 1000, self.level.name().to_uppercase(), self.subsystem, self.message);
+    }
+
+    /// Formats the record as a single json object without a trailing newline.
+    pub fn to_json(&self) -> String {
+        return format!(
+            "{{\"timestamp\":{},\"level\":\"{}\",\"verbosity\":{},\"subsystem\":\"{}\",\"message\":\"{}\"}}",
+            self.timestamp_millis(),
+            self.level.name(),
+            self.verbosity,
+            escape_json(self.subsystem),
+            escape_json(&self.message),
+        );
+    }
+
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+/// A destination for log records.
+/// Sinks are called from whichever thread created the record, one record at a time.
+pub trait Sink: Send {
+
+    fn write(&mut self, record: &Record);
+
+    fn flush(&mut self) {}
+
+}
+
+/// Writes colored records to stdout. This is the only sink installed by default.
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+
+    fn write(&mut self, record: &Record) {
+        let subsystem = format!("[{}]", record.subsystem);
+        match record.level {
+            Level::Message => println!("{} {} {}", "MESSAGE LOG:".bold().blue(), subsystem.dimmed(), record.message.blue()),
+            Level::Debug => println!("{} {} {}", "DEBUG LOG:".bold().green(), subsystem.dimmed(), record.message.green()),
+            Level::Temporary => println!("{} {} {}", "TEMPORARY LOG:".bold().yellow(), subsystem.dimmed(), record.message.yellow()),
+            Level::Error => println!("{} {} {}", "ERROR LOG:".bold().red(), subsystem.dimmed(), record.message.red()),
+            Level::Panic => println!("{} {} {}", "PANIC LOG:".bold().bright_red(), subsystem.dimmed(), record.message.bright_red()),
+        }
+    }
+
+}
+
+/// Writes plain text records to a file, rotating it once it grows past a size limit.
+/// On rotation `log.txt` becomes `log.txt.1`, `log.txt.1` becomes `log.txt.2` and so on, up to `max_files` old files.
+pub struct RotatingFileSink {
+
+    pub path: PathBuf,
+    pub max_bytes: u64,
+    pub max_files: usize,
+    file: fs::File,
+    written: u64,
+
+}
+
+impl RotatingFileSink {
+
+    pub fn create(path: &str, max_bytes: u64, max_files: usize) -> Result<Self, Error> {
+        let path = PathBuf::from(path);
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        return Ok(Self { path, max_bytes, max_files, file, written });
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        return PathBuf::from(name);
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                let from = self.rotated_path(i);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = fs::OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.written = 0;
+        return Ok(());
+    }
+
+}
+
+impl Sink for RotatingFileSink {
+
+    fn write(&mut self, record: &Record) {
+        let line = record.to_line() + "\n";
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate log file {}: {}", self.path.display(), e);
+            }
+        }
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.written += line.len() as u64;
+        }
+    }
+
+    fn flush(&mut self) {
+        let _ = self.file.flush();
+    }
+
+}
+
+/// Keeps the most recent records in memory, for example to display them in an in-game console.
+/// The sink is cheap to clone and every clone shares the same records, so a clone can be kept to read the records after the sink is added to the logger.
+#[derive(Clone)]
+pub struct RingBufferSink {
+
+    pub capacity: usize,
+    records: Arc<Mutex<VecDeque<Record>>>,
+
+}
+
+impl RingBufferSink {
+
+    pub fn new(capacity: usize) -> Self {
+        return Self { capacity, records: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))) };
+    }
+
+    /// Returns a copy of the stored records, oldest first.
+    pub fn records(&self) -> Vec<Record> {
+        if let Ok(records) = self.records.lock() {
+            return records.iter().cloned().collect();
+        }
+        return Vec::new();
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut records) = self.records.lock() {
+            records.clear();
+        }
+    }
+
+}
+
+impl Sink for RingBufferSink {
+
+    fn write(&mut self, record: &Record) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Ok(mut records) = self.records.lock() {
+            while records.len() >= self.capacity {
+                records.pop_front();
+            }
+            records.push_back(record.clone());
+        }
+    }
+
+}
+
+/// Writes each record as a json object on its own line.
+pub struct JsonLinesSink<W: Write + Send> {
+
+    pub writer: W,
+
+}
+
+impl<W: Write + Send> JsonLinesSink<W> {
+
+    pub fn new(writer: W) -> Self {
+        return Self { writer };
+    }
+
+}
+
+impl JsonLinesSink<fs::File> {
+
+    /// Creates a sink which appends to the file at the path specified.
+    pub fn create(path: &str) -> Result<Self, Error> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        return Ok(Self::new(file));
+    }
+
+}
+
+impl<W: Write + Send> Sink for JsonLinesSink<W> {
+
+    fn write(&mut self, record: &Record) {
+        let _ = writeln!(self.writer, "{}", record.to_json());
+    }
+
+    fn flush(&mut self) {
+        let _ = self.writer.flush();
+    }
+
+}
+
+/// Decides which records are passed to the sinks.
+/// Debug records pass if their verbosity is at most the verbosity of the most specific matching subsystem, or the default verbosity if none match.
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+
+    pub enabled_levels: [bool; 5],
+    pub verbosity: u32,
+    /// Pairs of module path prefixes and verbosities. A verbosity of `None` silences every record from the subsystem except panics.
+    pub subsystems: Vec<(String, Option<u32>)>,
+
+}
+
+impl LogFilter {
+
+    pub fn new() -> Self {
+        return Self { enabled_levels: [true; 5], verbosity: 0, subsystems: Vec::new() };
+    }
+
+    /// Parses a comma separated filter specification, e.g. `2,imperium::spatial=3,imperium::graph=off`.
+    /// An entry without a subsystem sets the default verbosity, and `off` silences a subsystem. Every level is enabled.
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        let mut filter = Self::new();
+        for entry in spec.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let invalid = || Error::InvalidLogFilter(entry.to_string());
+            let mut parts = entry.splitn(2, '=');
+            let first = parts.next().unwrap_or("").trim();
+            match parts.next() {
+                Some(verbosity) => {
+                    if first.is_empty() {
+                        return Err(invalid());
+                    }
+                    let verbosity = match verbosity.trim() {
+                        "off" => None,
+                        v => Some(v.parse::<u32>().map_err(|_| invalid())?),
+                    };
+                    filter.set_subsystem_verbosity(first, verbosity);
+                },
+                None => filter.verbosity = first.parse::<u32>().map_err(|_| invalid())?,
+            }
+        }
+        return Ok(filter);
+    }
+
+    pub fn set_level_enabled(&mut self, level: Level, enabled: bool) {
+        self.enabled_levels[level.index()] = enabled;
+    }
+
+    /// Sets the verbosity of all subsystems starting with the prefix specified, e.g. `imperium::spatial`.
+    pub fn set_subsystem_verbosity(&mut self, prefix: &str, verbosity: Option<u32>) {
+        self.subsystems.retain(|(p, _)| p != prefix);
+        self.subsystems.push((prefix.to_string(), verbosity));
+    }
+
+    pub fn allows(&self, level: Level, verbosity: u32, subsystem: &str) -> bool {
+        if level == Level::Panic {
+            return self.enabled_levels[level.index()];
+        }
+        if !self.enabled_levels[level.index()] {
+            return false;
+        }
+
+        let mut max_verbosity = Some(self.verbosity);
+        let mut matched_len = 0;
+        for (prefix, v) in self.subsystems.iter() {
+            let matches = subsystem == prefix || (subsystem.starts_with(prefix.as_str()) && subsystem[prefix.len()..].starts_with("::"));
+            if matches && prefix.len() >= matched_len {
+                max_verbosity = *v;
+                matched_len = prefix.len();
+            }
+        }
+
+        return match max_verbosity {
+            Some(max) => level != Level::Debug || verbosity <= max,
+            None => false,
+        };
+    }
+
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+/// The global logger. The filter and sinks are behind locks so that they can be changed at runtime and used from system threads.
+pub struct Logger {
+
+    pub filter: RwLock<LogFilter>,
+    pub sinks: Mutex<Vec<Box<dyn Sink + Send>>>,
+
+}
+
+lazy_static! {
+    static ref LOGGER: Logger = Logger { filter: RwLock::new(LogFilter::new()), sinks: Mutex::new(vec![Box::new(StdoutSink)]) };
+}
+
+/// Returns the global logger, creating it with a stdout sink on first use.
+pub fn logger() -> &'static Logger {
+    return &LOGGER;
+}
+
+pub fn set_filter(filter: LogFilter) {
+    if let Ok(mut f) = logger().filter.write() {
+        *f = filter;
+    }
+}
+
+/// Returns a copy of the current filter. Use `set_filter` to apply changes to it.
+pub fn filter() -> LogFilter {
+    return logger().filter.read().map(|f| f.clone()).unwrap_or_default();
+}
+
+pub fn set_level_enabled(level: Level, enabled: bool) {
+    if let Ok(mut f) = logger().filter.write() {
+        f.set_level_enabled(level, enabled);
+    }
+}
+
+/// Sets the maximum verbosity of debug records for subsystems without a specific verbosity.
+pub fn set_verbosity(verbosity: u32) {
+    if let Ok(mut f) = logger().filter.write() {
+        f.verbosity = verbosity;
+    }
+}
+
+pub fn set_subsystem_verbosity(prefix: &str, verbosity: Option<u32>) {
+    if let Ok(mut f) = logger().filter.write() {
+        f.set_subsystem_verbosity(prefix, verbosity);
+    }
+}
+
+pub fn add_sink(sink: Box<dyn Sink + Send>) {
+    if let Ok(mut sinks) = logger().sinks.lock() {
+        sinks.push(sink);
+    }
+}
+
+/// Removes every sink, including the default stdout sink.
+pub fn clear_sinks() {
+    if let Ok(mut sinks) = logger().sinks.lock() {
+        sinks.clear();
+    }
+}
+
+pub fn flush() {
+    if let Ok(mut sinks) = logger().sinks.lock() {
+        for sink in sinks.iter_mut() {
+            sink.flush();
+        }
+    }
+}
+
+pub fn enabled(level: Level, verbosity: u32, subsystem: &str) -> bool {
+    return logger().filter.read().map(|f| f.allows(level, verbosity, subsystem)).unwrap_or(true);
+}
+
+/// Passes a record to every sink if the filter allows it.
+/// The message is only formatted if the record passes the filter.
+pub fn log_record<F: FnOnce() -> String>(level: Level, verbosity: u32, subsystem: &'static str, message: F) {
+    if !enabled(level, verbosity, subsystem) {
+        return;
+    }
+    write_record(&Record { level, verbosity, subsystem, timestamp: SystemTime::now(), message: message() });
+}
+
+fn write_record(record: &Record) {
+    // A sink which panicked poisons the lock, but the remaining records should still be written.
+    let mut sinks = match logger().sinks.lock() {
+        Ok(sinks) => sinks,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    for sink in sinks.iter_mut() {
+        sink.write(record);
+    }
+}
+
+/// Logs the message as a panic record, flushes the sinks and then panics.
+pub fn log_panic(subsystem: &'static str, message: String) -> ! {
+    if enabled(Level::Panic, 0, subsystem) {
+        write_record(&Record { level: Level::Panic, verbosity: 0, subsystem, timestamp: SystemTime::now(), message: message.clone() });
+        flush();
+    }
+    panic!("Engine panic at 'log(panic)': {}", message);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parses_default_and_subsystem_verbosities() {
+        let filter = LogFilter::parse("2,imperium::spatial=3,imperium::graph=off").unwrap();
+        assert_eq!(filter.verbosity, 2);
+        assert_eq!(filter.subsystems, vec![(String::from("imperium::spatial"), Some(3)), (String::from("imperium::graph"), None)]);
+        assert!(filter.enabled_levels.iter().all(|enabled| *enabled));
+    }
+
+    #[test]
+    fn parse_ignores_whitespace_and_empty_entries() {
+        let filter = LogFilter::parse(" imperium::shader = 4 ,, 1 ").unwrap();
+        assert_eq!(filter.verbosity, 1);
+        assert_eq!(filter.subsystems, vec![(String::from("imperium::shader"), Some(4))]);
+        assert_eq!(LogFilter::parse("").unwrap().verbosity, 0);
+    }
+
+    #[test]
+    fn later_entries_replace_earlier_ones() {
+        let filter = LogFilter::parse("imperium::graph=1,imperium::graph=off").unwrap();
+        assert_eq!(filter.subsystems, vec![(String::from("imperium::graph"), None)]);
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        for spec in ["debug", "imperium::graph=", "imperium::graph=loud", "=2", "-1"].iter() {
+            match LogFilter::parse(spec) {
+                Err(Error::InvalidLogFilter(_)) => {},
+                _ => panic!("'{}' should not parse", spec),
+            }
+        }
+    }
+
+    #[test]
+    fn debug_records_are_limited_by_verbosity() {
+        let filter = LogFilter::parse("2").unwrap();
+        assert!(filter.allows(Level::Debug, 2, "imperium::render"));
+        assert!(!filter.allows(Level::Debug, 3, "imperium::render"));
+        // Other levels have no verbosity.
+        assert!(filter.allows(Level::Message, 0, "imperium::render"));
+        assert!(filter.allows(Level::Error, 0, "imperium::render"));
+    }
+
+    #[test]
+    fn most_specific_subsystem_wins() {
+        let filter = LogFilter::parse("0,imperium::spatial=1,imperium::spatial::pipe=3").unwrap();
+        assert!(filter.allows(Level::Debug, 3, "imperium::spatial::pipe::mesh"));
+        assert!(!filter.allows(Level::Debug, 2, "imperium::spatial::sys"));
+        assert!(filter.allows(Level::Debug, 1, "imperium::spatial"));
+        assert!(!filter.allows(Level::Debug, 1, "imperium::graph"));
+    }
+
+    #[test]
+    fn subsystems_match_whole_path_segments() {
+        let filter = LogFilter::parse("0,imperium::spatial=3").unwrap();
+        assert!(!filter.allows(Level::Debug, 3, "imperium::spatial_extra"));
+    }
+
+    #[test]
+    fn silenced_subsystems_still_panic() {
+        let filter = LogFilter::parse("imperium::graph=off").unwrap();
+        assert!(!filter.allows(Level::Message, 0, "imperium::graph"));
+        assert!(!filter.allows(Level::Error, 0, "imperium::graph::pass"));
+        assert!(filter.allows(Level::Panic, 0, "imperium::graph"));
+        assert!(filter.allows(Level::Message, 0, "imperium::render"));
+    }
+
+    #[test]
+    fn disabled_levels_are_filtered() {
+        let mut filter = LogFilter::new();
+        filter.set_level_enabled(Level::Temporary, false);
+        assert!(!filter.allows(Level::Temporary, 0, "imperium"));
+        assert!(filter.allows(Level::Message, 0, "imperium"));
+    }
+
+}
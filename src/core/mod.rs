@@ -1,8 +1,12 @@
 use super::*;
 
-extern crate colored;
+#[macro_use]
+pub mod logging;
+pub mod cache;
+pub use self::cache::*;
 
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::io::Read;
 use std::rc::Rc;
 
@@ -12,14 +16,15 @@ use gfx::Device as GfxDevice;
 use gfx::Instance as GfxInstance;
 use gfx::Swapchain;
 use gfx::DescriptorPool;
-
-use self::colored::{Color as PrintColor, Colorize as PrintColorize, ColoredString as PrintColoredString};
+use gfx::QueueFamily;
 
 /// The instance object for the engine which all other devices are created from.
 /// This structure encapsulates the backend instance object (e.g. vulkan instance).
 /// It is therefore only needed for device creation.
 pub struct Instance  {
     pub gfx_inst: Arc<backend::Instance>,
+    /// The name the instance was created with, which also names the directory the pipeline cache of windowed devices is saved in.
+    pub application_name: String,
 }
 
 impl Instance {
@@ -28,9 +33,157 @@ impl Instance {
     /// The name of the application is backend specific and may map to different things.
     pub fn create(application_name: & str) -> Self {
         let gfx_inst = Arc::new(backend::Instance::create(application_name, 1));
-        return Self { gfx_inst };
+        return Self { gfx_inst, application_name: String::from(application_name) };
+    }
+
+    /// Returns true if the Vulkan loader could be found. Creating an instance without it panics in the backend.
+    pub fn is_available() -> bool {
+        return backend::VK_ENTRY.is_ok();
+    }
+
+}
+
+/// Describes which adapter (physical device) should be used when creating a `Device`.
+/// Any preference other than `Any` is strict: adapters which do not match are never selected.
+#[derive(Clone, Debug)]
+pub enum AdapterPreference {
+
+    /// Use the highest scoring suitable adapter.
+    Any,
+    /// Use a discrete GPU.
+    Discrete,
+    /// Use a GPU integrated with the CPU.
+    Integrated,
+    /// Use a CPU/software rasterizer (e.g. lavapipe or SwiftShader).
+    Software,
+    /// Use an adapter whose name contains the specified string (case insensitive).
+    Name(String),
+
+}
+
+impl AdapterPreference {
+
+    /// Returns true if the adapter described by `info` satisfies this preference.
+    pub fn matches(&self, info: &gfx::AdapterInfo) -> bool {
+        match self {
+            AdapterPreference::Any => true,
+            AdapterPreference::Discrete => info.device_type == gfx::adapter::DeviceType::DiscreteGpu,
+            AdapterPreference::Integrated => info.device_type == gfx::adapter::DeviceType::IntegratedGpu,
+            AdapterPreference::Software => info.device_type == gfx::adapter::DeviceType::Cpu,
+            AdapterPreference::Name(name) => info.name.to_lowercase().contains(&name.to_lowercase()),
+        }
+    }
+
+}
+
+impl Default for AdapterPreference {
+    fn default() -> Self {
+        return AdapterPreference::Any;
+    }
+}
+
+/// The smallest maximum texture size an adapter must support to be used by the engine.
+pub const MIN_TEXTURE_SIZE: usize = 2048;
+
+/// Scores an adapter for use with the engine, where higher scores are better.
+/// `None` is returned if the adapter cannot be used, which is the case if:
+/// - It does not match the preference.
+/// - It has no queue family which supports graphics and compute (and presentation to the surface, if one is specified).
+/// - The surface reports no supported formats for it.
+/// - Its limits are below what the engine requires.
+pub fn score_adapter(adapter: &gfx::Adapter<Backend>, surface: Option<&<Backend as gfx::Backend>::Surface>, preference: &AdapterPreference) -> Option<u32> {
+    let score = score_adapter_info(&adapter.info, &adapter.physical_device.limits(), preference)?;
+
+    if !adapter.queue_families.iter().any(|family| is_suitable_queue_family(family, surface)) {
+        return None;
+    }
+
+    if let Some(surface) = surface {
+        let (_, formats, _, _) = surface.compatibility(&adapter.physical_device);
+        if let Some(formats) = formats {
+            if formats.is_empty() {
+                return None;
+            }
+        }
+    }
+
+    return Some(score);
+}
+
+/// Returns true if the engine can use the queue family, which is the case if it supports graphics and compute (and presentation to the surface, if one is specified).
+/// Compute work is recorded on the same queue as graphics work, so the family must support both.
+/// Adapters are only selected if they have such a family, and devices are only opened with such families.
+pub fn is_suitable_queue_family(family: &<Backend as gfx::Backend>::QueueFamily, surface: Option<&<Backend as gfx::Backend>::Surface>) -> bool {
+    return family.supports_graphics() && family.supports_compute() && surface.map_or(true, |surface| surface.supports_queue_family(family));
+}
+
+/// Scores an adapter from its description and limits alone, which is the part of `score_adapter` that does not query the adapter.
+/// `None` is returned if the adapter does not match the preference or its limits are below what the engine requires.
+pub fn score_adapter_info(info: &gfx::AdapterInfo, limits: &gfx::Limits, preference: &AdapterPreference) -> Option<u32> {
+    if !preference.matches(info) {
+        return None;
     }
+    if limits.max_texture_size < MIN_TEXTURE_SIZE {
+        return None;
+    }
+
+    let type_score: u32 = match info.device_type {
+        gfx::adapter::DeviceType::DiscreteGpu => 1000,
+        gfx::adapter::DeviceType::IntegratedGpu => 500,
+        gfx::adapter::DeviceType::VirtualGpu => 250,
+        gfx::adapter::DeviceType::Cpu => 100,
+        _ => 50,
+    };
 
+    // Prefer adapters with larger textures when the type is equal.
+    let limits_score: u32 = (limits.max_texture_size / MIN_TEXTURE_SIZE) as u32;
+
+    return Some(type_score + limits_score);
+}
+
+/// Selects the highest scoring adapter from the instance.
+fn select_adapter(instance: &core::Instance, surface: Option<&<Backend as gfx::Backend>::Surface>, preference: &AdapterPreference) -> Result<gfx::Adapter<Backend>, Error> {
+    let mut adapters: Vec<gfx::Adapter<Backend>> = instance.gfx_inst.enumerate_adapters();
+    if adapters.is_empty() {
+        return Err(Error::NoSuitableAdapter(String::from("No graphics adapters were found. Is a Vulkan driver installed?")));
+    }
+
+    let mut best: Option<(usize, u32)> = None;
+    for (i, adapter) in adapters.iter().enumerate() {
+        let score = score_adapter(adapter, surface, preference);
+        log!(debug, 1, "Adapter '{}' ({:?}) scored {:?}.", adapter.info.name, adapter.info.device_type, score);
+        if let Some(score) = score {
+            if best.map_or(true, |(_, best_score)| score > best_score) {
+                best = Some((i, score));
+            }
+        }
+    }
+
+    if let Some((index, _)) = best {
+        let adapter = adapters.remove(index);
+        log!(debug, 0, "Selected adapter '{}'.", adapter.info.name);
+        return Ok(adapter);
+    }
+    if let AdapterPreference::Any = preference {
+        return Err(Error::NoSuitableAdapter(String::from("No adapter supports the features required by the engine.")));
+    }
+    return Err(Error::NoSuitableAdapter(String::from("No adapter which supports the features required by the engine matches the adapter preference.")));
+}
+
+/// Chooses the color format of a surface from the formats it supports, preferring the one requested.
+/// If the surface has no list of formats any format can be used, so the preferred format or Rgba8Srgb is used. Otherwise an sRGB format is
+/// preferred over the first supported format.
+pub fn choose_color_format(formats: Option<Vec<gfx::format::Format>>, preferred_format: Option<gfx::format::Format>) -> Result<gfx::format::Format, Error> {
+    match formats {
+        Some(choices) => return choices
+            .iter()
+            .find(|format| Some(**format) == preferred_format)
+            .or(choices.iter().find(|format| format.base_format().1 == gfx::format::ChannelType::Srgb))
+            .or(choices.first())
+            .cloned()
+            .ok_or(Error::NoSurfaceFormat),
+        None => return Ok(preferred_format.unwrap_or(gfx::format::Format::Rgba8Srgb)),
+    }
 }
 
 /// The device structure which contains data about a device.
@@ -38,70 +191,120 @@ impl Instance {
 /// This structure must be passed to most graphics objects during initialisation.
 pub struct Device {
 
+    /// The format of the images presented to the surface. This changes when the surface is rebuilt with a different `GraphicsSettings::color_format`.
     pub color_format: gfx::format::Format,
     pub adapter: gfx::Adapter<Backend>,
     pub gpu: Arc<<Backend as gfx::Backend>::Device>,
-    pub queue_group: gfx::QueueGroup<Backend, gfx::Graphics>,
+    pub queue_group: gfx::QueueGroup<Backend, gfx::General>,
 
-    pub capabilites: gfx::SurfaceCapabilities,
+    /// The capabilities of the surface this device presents to.
+    /// This is `None` for headless devices, which render offscreen and never present.
+    pub capabilites: Option<gfx::SurfaceCapabilities>,
+
+    /// The allocator which device memory for buffers and images is sub-allocated from.
+    pub allocator: Arc<Mutex<memory::Allocator>>,
+
+    /// The cache every pipeline created through this device is created with.
+    /// Windowed devices save it in the data directory of the application so that it is reused on the next start, headless devices only keep it in memory.
+    pub pipeline_cache: Option<PipelineCache>,
 
 }
 
 impl Device {
 
     /// Creates a new device instance using the specified instance and window.
+    /// The adapter is chosen using `score_adapter` and the specified preference.
+    /// The preferred color format is used if the surface supports it, otherwise an sRGB format is chosen.
     /// This device can be used to create practically every graphics object.
-    pub fn create(instance: &core::Instance, window_surface: &window::WindowSurface) -> Device {
-        // Just select the first device
-        // TODO: Amend code to check if device is suitable.
-        let mut adapter: gfx::Adapter<Backend> = instance.gfx_inst.enumerate_adapters().remove(0);
+    pub fn create(instance: &core::Instance, window_surface: &window::WindowSurface, preference: &AdapterPreference, preferred_format: Option<gfx::format::Format>) -> Result<Device, Error> {
+        let adapter: gfx::Adapter<Backend> = select_adapter(instance, Some(&window_surface.surface), preference)?;
 
         let (gpu, queue_group) =
-            adapter.open_with::<_, gfx::Graphics>(1, |family| window_surface.surface.supports_queue_family(family))
-                .expect("Fatal Error: Failed to find valid device.");
+            adapter.open_with::<_, gfx::General>(1, |family| is_suitable_queue_family(family, Some(&window_surface.surface)))?;
 
         // We want to get the capabilities (`caps`) of the surface, which tells us what
         // parameters we can use for our swapchain later. We also get a list of supported
         // image formats for our surface.
         let (caps, formats, _, _) = window_surface.surface.compatibility(&adapter.physical_device);
 
-        let color_format = {
-            // We must pick a color format from the list of supported formats. If there
-            // is no list, we default to Rgba8Srgb.
-            match formats {
-                Some(choices) => choices
-                    .into_iter()
-                    .find(|format| format.base_format().1 == gfx::format::ChannelType::Srgb)
-                    .unwrap(),
-                None => gfx::format::Format::Rgba8Srgb,
-            }
-        };
+        let color_format = choose_color_format(formats, preferred_format)?;
 
-        return Device { color_format, adapter: adapter, gpu: Arc::new(gpu), queue_group, capabilites: caps };
+        let gpu = Arc::new(gpu);
+        let allocator = Arc::new(Mutex::new(memory::Allocator::new(gpu.clone(), adapter.physical_device.memory_properties())));
+        let mut device = Device { color_format, adapter: adapter, gpu, queue_group, capabilites: Some(caps), allocator, pipeline_cache: None };
+        let path = data_dir(&instance.application_name).map(|dir| dir.join(PipelineCache::FILE_NAME));
+        device.create_pipeline_cache(path, instance)?;
+        return Ok(device);
 
     }
 
-    pub fn load_shader(&self, path: &str) -> Result<<Backend as gfx::Backend>::ShaderModule, &'static str> {
+    /// Creates a new device which is not bound to any window surface.
+    /// A headless device renders into offscreen images, which makes it usable on machines without a display (e.g. CI servers using a software Vulkan driver).
+    pub fn create_headless(instance: &core::Instance, preference: &AdapterPreference) -> Result<Device, Error> {
+        let adapter: gfx::Adapter<Backend> = select_adapter(instance, None, preference)?;
 
-        if let Ok(mut f) = std::fs::File::open(path) {
-            let mut contents = String::new();
-            if let Ok(_) = f.read_to_string(&mut contents) {
-                return Ok(unsafe { self.gpu.create_shader_module(&contents.into_bytes()).unwrap() });
-            } else {
-                return Err("Failed to read shader file.");
-            }
-        } else {
-            return Err("Failed to open shader file. Does it exist?");
-        }
+        let (gpu, queue_group) =
+            adapter.open_with::<_, gfx::General>(1, |family| is_suitable_queue_family(family, None))?;
+
+        // There is no surface to negotiate a format with, so we use a format which maps directly to 8 bit RGBA texture data.
+        let color_format = gfx::format::Format::Rgba8Srgb;
 
+        let gpu = Arc::new(gpu);
+        let allocator = Arc::new(Mutex::new(memory::Allocator::new(gpu.clone(), adapter.physical_device.memory_properties())));
+        let mut device = Device { color_format, adapter: adapter, gpu, queue_group, capabilites: None, allocator, pipeline_cache: None };
+        // Headless devices are used by tests and tools, which must not replace the cache of the application, so their cache is only kept in memory.
+        device.create_pipeline_cache(None, instance)?;
+        return Ok(device);
     }
 
-    pub fn load_shader_raw(&self, bytes: &[u8]) -> Result<<Backend as gfx::Backend>::ShaderModule, &'static str> {
-        if let Ok(module) = unsafe { self.gpu.create_shader_module(bytes) } {
-            return Ok(module);
-        } else {
-            return Err("Failed to create shader module.");
+    /// Creates the pipeline cache of the device, loading it from and saving it to the path specified if there is one.
+    fn create_pipeline_cache(&mut self, path: Option<std::path::PathBuf>, instance: &core::Instance) -> Result<(), Error> {
+        self.pipeline_cache = Some(PipelineCache::create(path, instance, self)?);
+        return Ok(());
+    }
+
+    /// The raw pipeline cache which pipelines should be created with.
+    pub fn raw_pipeline_cache(&self) -> Option<&<Backend as gfx::Backend>::PipelineCache> {
+        return self.pipeline_cache.as_ref().map(|cache| &cache.cache);
+    }
+
+    /// Returns true if this device has no surface to present to.
+    pub fn is_headless(&self) -> bool {
+        return self.capabilites.is_none();
+    }
+
+    /// The layout the color target is left in at the end of a frame.
+    /// Swapchain images are transitioned for presentation whereas offscreen images are left ready to be copied from.
+    pub fn present_layout(&self) -> gfx::image::Layout {
+        if self.is_headless() {
+            return gfx::image::Layout::TransferSrcOptimal;
+        }
+        return gfx::image::Layout::Present;
+    }
+
+    /// Gets the largest number of samples per pixel up to the number requested which both color and depth attachments support.
+    /// Sample counts are powers of two, and a single sample is always supported.
+    pub fn clamp_samples(&self, samples: u8) -> u8 {
+        let limits = self.adapter.physical_device.limits();
+        let supported = limits.framebuffer_color_samples_count & limits.framebuffer_depth_samples_count;
+        let mut count: u8 = 1;
+        while count < 64 && count * 2 <= samples && supported & (count * 2) != 0 {
+            count *= 2;
         }
+        return count;
+    }
+
+    pub fn load_shader(&self, path: &str) -> Result<<Backend as gfx::Backend>::ShaderModule, Error> {
+
+        // SPIR-V is binary, so the file must not be read as text.
+        let bytes = std::fs::read(path)?;
+        return self.load_shader_raw(&bytes);
+
+    }
+
+    pub fn load_shader_raw(&self, bytes: &[u8]) -> Result<<Backend as gfx::Backend>::ShaderModule, Error> {
+        let module = unsafe { self.gpu.create_shader_module(bytes) }?;
+        return Ok(module);
     }
 
     pub fn upload_type_for(&self, unbound_buffer: &<Backend as gfx::Backend>::Buffer, properties: gfx::memory::Properties) -> (gfx::MemoryTypeId, gfx::memory::Requirements) {
@@ -120,10 +323,21 @@ impl Device {
         return (upload_type, req);
     }
 
+    /// Waits until the device has finished all submitted work, e.g. before destroying objects which frames in flight may still be using.
+    pub fn wait_idle(&self) -> Result<(), Error> {
+        self.gpu.wait_idle()?;
+        return Ok(());
+    }
+
     pub fn create_token(&self) -> DeviceToken {
         return DeviceToken::create(self);
     }
 
+    /// Returns statistics about the device memory allocated through this device.
+    pub fn memory_stats(&self) -> memory::MemoryStats {
+        return self.allocator.lock().unwrap().stats();
+    }
+
 }
 
 /// A structure which contains device data which can be used to destroy objects.
@@ -131,6 +345,7 @@ impl Device {
 pub struct DeviceToken {
 
     pub gpu: Arc<<Backend as gfx::Backend>::Device>,
+    pub allocator: Arc<Mutex<memory::Allocator>>,
 
 }
 
@@ -138,64 +353,11 @@ impl DeviceToken {
 
     /// Creates a device token from a device.
     pub fn create(device: &Device) -> Self {
-        return Self { gpu: device.gpu.clone() };
+        return Self { gpu: device.gpu.clone(), allocator: device.allocator.clone() };
     }
 
 }
 
-pub static mut PRINT_LOG: bool = true;
-pub static mut PRINT_DEBUG: bool = true;
-pub static mut PRINT_ERR: bool = true;
-pub static mut PRINT_PANIC: bool = true;
-
-pub static mut DEBUG_VERBOSITY: u32 = 0;
-
-#[macro_export]
-macro_rules! log {
-    (msg, $($arg:tt)*) => ({
-        $crate::core::log(format!($($arg)*));
-    });
-    (debug, $verb:expr, $($arg:tt)*) => ({
-        $crate::core::log_debug(format!($($arg)*), $verb);
-    });
-    (temp, $($arg:tt)*) => ({
-        $crate::core::log_temp(format!($($arg)*));
-    });
-    (err, $($arg:tt)*) => ({
-        $crate::core::log_err(format!($($arg)*));
-    });
-    (panic, $($arg:tt)*) => ({
-        $crate::core::log_panic(format!($($arg)*));
-    })
-}
-pub fn log(data: String) {
-    if unsafe { PRINT_LOG } {
-        println!("{} {}", "MESSAGE LOG:".bold().blue(), data.blue());
-    }
-
-}
-pub fn log_debug(data: String, verbosity: u32) {
-    if unsafe { PRINT_DEBUG } && unsafe { verbosity <= DEBUG_VERBOSITY } {
-        println!("{} {}", "DEBUG LOG:".bold().green(), data.green());
-    }
-}
-pub fn log_temp(data: String) {
-    if unsafe { PRINT_DEBUG } {
-        println!("{} {}", "TEMPORARY LOG:".bold().yellow(), data.yellow());
-    }
-}
-pub fn log_err(data: String) {
-    if unsafe { PRINT_ERR } {
-        println!("{} {}", "ERROR LOG:".bold().red(), data.red());
-    }
-}
-pub fn log_panic(data: String) {
-    if unsafe { PRINT_PANIC } {
-        println!("{} {}", "PANIC LOG:".bold().bright_red(), data.bright_red());
-    }
-    panic!("Engine panic at 'log(panic)': {}", data);
-}
-
 pub trait LogExpect {
     type Out;
     fn log_expect(self, err: &str) -> Self::Out;
@@ -206,10 +368,8 @@ impl<T, E> LogExpect for Result<T, E> {
     fn log_expect(self, err: &str) -> T {
         if let Ok(t) = self {
             return t;
-        } else {
-            log_panic(err.to_string());
         }
-        panic!("");
+        log!(panic, "{}", err);
     }
 }
 
@@ -218,9 +378,86 @@ impl<T> LogExpect for Option<T> {
     fn log_expect(self, err: &str) -> T {
         if let Some(t) = self {
             return t;
-        } else {
-            log_panic(err.to_string());
         }
-        panic!("");
+        log!(panic, "{}", err);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use gfx::adapter::DeviceType;
+
+    fn info(name: &str, device_type: DeviceType) -> gfx::AdapterInfo {
+        return gfx::AdapterInfo { name: name.to_string(), vendor: 0, device: 0, device_type };
+    }
+
+    fn limits(max_texture_size: usize) -> gfx::Limits {
+        return gfx::Limits { max_texture_size, ..gfx::Limits::default() };
+    }
+
+    #[test]
+    fn adapter_types_are_ranked() {
+        let discrete = score_adapter_info(&info("a", DeviceType::DiscreteGpu), &limits(4096), &AdapterPreference::Any).unwrap();
+        let integrated = score_adapter_info(&info("b", DeviceType::IntegratedGpu), &limits(4096), &AdapterPreference::Any).unwrap();
+        let software = score_adapter_info(&info("c", DeviceType::Cpu), &limits(4096), &AdapterPreference::Any).unwrap();
+        assert!(discrete > integrated);
+        assert!(integrated > software);
+    }
+
+    #[test]
+    fn larger_textures_break_ties() {
+        let small = score_adapter_info(&info("a", DeviceType::DiscreteGpu), &limits(MIN_TEXTURE_SIZE), &AdapterPreference::Any).unwrap();
+        let large = score_adapter_info(&info("a", DeviceType::DiscreteGpu), &limits(MIN_TEXTURE_SIZE * 8), &AdapterPreference::Any).unwrap();
+        assert!(large > small);
+    }
+
+    #[test]
+    fn insufficient_limits_are_rejected() {
+        assert_eq!(score_adapter_info(&info("a", DeviceType::DiscreteGpu), &limits(MIN_TEXTURE_SIZE - 1), &AdapterPreference::Any), None);
+    }
+
+    #[test]
+    fn preferences_are_strict() {
+        let software = info("llvmpipe (LLVM 15.0.6, 256 bits)", DeviceType::Cpu);
+        let discrete = info("NVIDIA GeForce RTX 3070", DeviceType::DiscreteGpu);
+        assert!(score_adapter_info(&software, &limits(4096), &AdapterPreference::Software).is_some());
+        assert_eq!(score_adapter_info(&discrete, &limits(4096), &AdapterPreference::Software), None);
+        assert_eq!(score_adapter_info(&software, &limits(4096), &AdapterPreference::Discrete), None);
+        assert_eq!(score_adapter_info(&discrete, &limits(4096), &AdapterPreference::Integrated), None);
+    }
+
+    #[test]
+    fn names_match_case_insensitively() {
+        let adapter = info("NVIDIA GeForce RTX 3070", DeviceType::DiscreteGpu);
+        assert!(score_adapter_info(&adapter, &limits(4096), &AdapterPreference::Name(String::from("geforce"))).is_some());
+        assert_eq!(score_adapter_info(&adapter, &limits(4096), &AdapterPreference::Name(String::from("radeon"))), None);
     }
+
+    #[test]
+    fn preferred_color_format_is_used_if_supported() {
+        use gfx::format::Format;
+        let formats = vec![Format::Bgra8Unorm, Format::Bgra8Srgb, Format::Rgba16Float];
+        assert_eq!(choose_color_format(Some(formats.clone()), Some(Format::Rgba16Float)).unwrap(), Format::Rgba16Float);
+        assert_eq!(choose_color_format(Some(formats.clone()), Some(Format::Rgba8Srgb)).unwrap(), Format::Bgra8Srgb);
+        assert_eq!(choose_color_format(Some(formats), None).unwrap(), Format::Bgra8Srgb);
+    }
+
+    #[test]
+    fn first_color_format_is_used_without_srgb() {
+        use gfx::format::Format;
+        assert_eq!(choose_color_format(Some(vec![Format::Bgra8Unorm, Format::Rgba8Unorm]), None).unwrap(), Format::Bgra8Unorm);
+        assert!(choose_color_format(Some(Vec::new()), None).is_err());
+    }
+
+    #[test]
+    fn any_color_format_can_be_used_without_a_list() {
+        use gfx::format::Format;
+        assert_eq!(choose_color_format(None, None).unwrap(), Format::Rgba8Srgb);
+        assert_eq!(choose_color_format(None, Some(Format::Bgra8Unorm)).unwrap(), Format::Bgra8Unorm);
+    }
+
 }
@@ -0,0 +1,197 @@
+use crate::*;
+
+use std::fmt;
+
+/// The error type used throughout the engine.
+/// Each variant corresponds to a subsystem and carries the underlying cause where one exists, so that callers can tell failures apart and recover.
+#[derive(Debug)]
+pub enum Error {
+
+    /// An io operation failed, for example because a file is missing or could not be read.
+    Io(std::io::Error),
+    /// An image could not be decoded or encoded.
+    Image(image::ImageError),
+    /// A window could not be created.
+    Window(String),
+    /// No graphics adapter could be used by the engine.
+    NoSuitableAdapter(String),
+    /// The surface of a window reports no color formats which its swapchain could use.
+    NoSurfaceFormat,
+    /// The logical device could not be opened on the selected adapter.
+    DeviceCreation(GfxError<gfx::error::DeviceCreationError>),
+    /// The device ran out of host or device memory.
+    OutOfMemory(GfxError<gfx::device::OutOfMemory>),
+    /// The device was lost, which invalidates every object created from it.
+    DeviceLost,
+    /// Device memory could not be allocated.
+    Allocation(GfxError<gfx::device::AllocationError>),
+    /// A swapchain could not be created for a surface.
+    Swapchain(GfxError<gfx::window::CreationError>),
+    /// A shader module could not be created.
+    Shader(GfxError<gfx::device::ShaderError>),
+    /// A shader could not be compiled from source. This contains the output of the compiler.
+    ShaderCompilation(String),
+    /// A shader could not be reflected, or its interface does not match the inputs supplied by the engine.
+    Reflection(String),
+    /// A pipeline could not be created.
+    Pipeline(GfxError<gfx::pso::CreationError>),
+    /// A descriptor set could not be allocated from a descriptor pool.
+    Descriptor(GfxError<gfx::pso::AllocationError>),
+    /// A render operation could not be performed.
+    Render(String),
+    /// Texture data was invalid.
+    Texture(String),
+    /// A model could not be loaded. This contains the message reported by assimp.
+    Model(String),
+    /// A material could not be created.
+    Material(String),
+    /// A script could not be invoked. This contains the id of the script.
+    Script(String),
+    /// An input state could not be found. This contains the id of the state.
+    Input(String),
+    /// A log filter specification could not be parsed. This contains the entry which is invalid.
+    InvalidLogFilter(String),
+
+}
+
+impl fmt::Display for Error {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "Io error: {}", e),
+            Error::Image(e) => write!(f, "Image error: {}", e),
+            Error::Window(msg) => write!(f, "Failed to create window: {}", msg),
+            Error::NoSuitableAdapter(msg) => write!(f, "No suitable adapter: {}", msg),
+            Error::NoSurfaceFormat => write!(f, "The surface does not support any color formats."),
+            Error::DeviceCreation(e) => write!(f, "Failed to create device: {}", e),
+            Error::OutOfMemory(e) => write!(f, "Out of memory: {}", e),
+            Error::DeviceLost => write!(f, "The device was lost."),
+            Error::Allocation(e) => write!(f, "Failed to allocate device memory: {}", e),
+            Error::Swapchain(e) => write!(f, "Failed to create swapchain: {}", e),
+            Error::Shader(e) => write!(f, "Failed to create shader module: {}", e),
+            Error::ShaderCompilation(msg) => write!(f, "Failed to compile shader: {}", msg),
+            Error::Reflection(msg) => write!(f, "Shader interface error: {}", msg),
+            Error::Pipeline(e) => write!(f, "Failed to create pipeline: {}", e),
+            Error::Descriptor(e) => write!(f, "Failed to allocate descriptor set: {}", e),
+            Error::Render(msg) => write!(f, "Render error: {}", msg),
+            Error::Texture(msg) => write!(f, "Texture error: {}", msg),
+            Error::Model(msg) => write!(f, "Failed to load model: {}", msg),
+            Error::Material(msg) => write!(f, "Failed to create material: {}", msg),
+            Error::Script(id) => write!(f, "No script found with the id '{}'.", id),
+            Error::Input(id) => write!(f, "No input state found with the id '{}'.", id),
+            Error::InvalidLogFilter(entry) => write!(f, "Invalid log filter entry '{}'.", entry),
+        }
+    }
+
+}
+
+impl std::error::Error for Error {
+
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Image(e) => Some(e),
+            Error::DeviceCreation(e) => Some(e),
+            Error::OutOfMemory(e) => Some(e),
+            Error::Allocation(e) => Some(e),
+            Error::Swapchain(e) => Some(e),
+            Error::Shader(e) => Some(e),
+            Error::Pipeline(e) => Some(e),
+            Error::Descriptor(e) => Some(e),
+            _ => None,
+        }
+    }
+
+}
+
+/// Wraps an error reported by gfx-hal.
+/// The gfx-hal error types only implement `failure::Fail`, so this lets them be returned as the `source` of an `Error`.
+#[derive(Debug)]
+pub struct GfxError<E>(pub E);
+
+impl<E: fmt::Display> fmt::Display for GfxError<E> {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return self.0.fmt(f);
+    }
+
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for GfxError<E> {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        return Error::Io(e);
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        // Missing or unreadable files are reported as io errors so they can be told apart from invalid image data.
+        match e {
+            image::ImageError::IoError(io) => Error::Io(io),
+            e => Error::Image(e),
+        }
+    }
+}
+
+impl From<gfx::error::DeviceCreationError> for Error {
+    fn from(e: gfx::error::DeviceCreationError) -> Self {
+        return Error::DeviceCreation(GfxError(e));
+    }
+}
+
+impl From<gfx::device::OutOfMemory> for Error {
+    fn from(e: gfx::device::OutOfMemory) -> Self {
+        return Error::OutOfMemory(GfxError(e));
+    }
+}
+
+impl From<gfx::device::OomOrDeviceLost> for Error {
+    fn from(e: gfx::device::OomOrDeviceLost) -> Self {
+        match e {
+            gfx::device::OomOrDeviceLost::OutOfMemory(oom) => Error::OutOfMemory(GfxError(oom)),
+            gfx::device::OomOrDeviceLost::DeviceLost(_) => Error::DeviceLost,
+        }
+    }
+}
+
+impl From<gfx::error::HostExecutionError> for Error {
+    fn from(e: gfx::error::HostExecutionError) -> Self {
+        match e {
+            gfx::error::HostExecutionError::OutOfHostMemory => Error::OutOfMemory(GfxError(gfx::device::OutOfMemory::OutOfHostMemory)),
+            gfx::error::HostExecutionError::OutOfDeviceMemory => Error::OutOfMemory(GfxError(gfx::device::OutOfMemory::OutOfDeviceMemory)),
+            gfx::error::HostExecutionError::DeviceLost => Error::DeviceLost,
+        }
+    }
+}
+
+impl From<gfx::device::AllocationError> for Error {
+    fn from(e: gfx::device::AllocationError) -> Self {
+        return Error::Allocation(GfxError(e));
+    }
+}
+
+impl From<gfx::window::CreationError> for Error {
+    fn from(e: gfx::window::CreationError) -> Self {
+        return Error::Swapchain(GfxError(e));
+    }
+}
+
+impl From<gfx::device::ShaderError> for Error {
+    fn from(e: gfx::device::ShaderError) -> Self {
+        return Error::Shader(GfxError(e));
+    }
+}
+
+impl From<gfx::pso::CreationError> for Error {
+    fn from(e: gfx::pso::CreationError) -> Self {
+        return Error::Pipeline(GfxError(e));
+    }
+}
+
+impl From<gfx::pso::AllocationError> for Error {
+    fn from(e: gfx::pso::AllocationError) -> Self {
+        return Error::Descriptor(GfxError(e));
+    }
+}
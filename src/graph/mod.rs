@@ -0,0 +1,821 @@
+use crate::*;
+
+use gfx::Device as GfxDevice;
+
+use std::mem;
+
+/// The name of the attachment which refers to the images of the render surface.
+/// This attachment always exists and has the color format and size of the surface.
+pub const BACKBUFFER: &str = "backbuffer";
+
+/// The name of the attachment which passes render to in place of the backbuffer when the graph is multisampled.
+/// It is resolved into the backbuffer at the end of the last pass which renders to it.
+pub const MULTISAMPLED_BACKBUFFER: &str = "backbuffer_multisampled";
+
+/// The size of an attachment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttachmentSize {
+
+    /// A size which is a multiple of the size of the render surface, and which follows it when the surface is rebuilt.
+    Relative(f32, f32),
+    /// A fixed size in pixels.
+    Absolute(Vector2u),
+
+}
+
+impl AttachmentSize {
+
+    /// The size of the render surface.
+    pub fn swapchain() -> Self {
+        return AttachmentSize::Relative(1.0, 1.0);
+    }
+
+    pub fn resolve(&self, surface_extent: gfx::image::Extent) -> Vector2u {
+        return match self {
+            AttachmentSize::Relative(x, y) => Vector2u::new(
+                ((surface_extent.width as f32 * x) as u32).max(1),
+                ((surface_extent.height as f32 * y) as u32).max(1),
+            ),
+            AttachmentSize::Absolute(size) => *size,
+        };
+    }
+
+}
+
+/// How long the contents of an attachment must be kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttachmentLifetime {
+
+    /// The contents are only needed while the graph is executing and are discarded after the last pass which uses them.
+    Transient,
+    /// The contents are kept between frames, so the first pass to use the attachment in a frame sees the contents from the previous frame unless it clears it.
+    Persistent,
+
+}
+
+/// Describes an image which passes of a graph can render to and read from.
+#[derive(Clone, Debug)]
+pub struct AttachmentDesc {
+
+    pub name: String,
+    pub format: gfx::format::Format,
+    pub size: AttachmentSize,
+    pub lifetime: AttachmentLifetime,
+    /// The number of samples per pixel. Multisampled attachments cannot be sampled, they must be resolved into another attachment first.
+    pub samples: u8,
+    /// The number of layers of an array attachment, or `None` for a single image. Each pass renders to one layer of an array, and shaders sample all of its layers as a 2D array.
+    pub layers: Option<u16>,
+
+}
+
+impl AttachmentDesc {
+
+    /// Creates a transient attachment.
+    pub fn new(name: &str, format: gfx::format::Format, size: AttachmentSize) -> Self {
+        return Self { name: name.to_string(), format, size, lifetime: AttachmentLifetime::Transient, samples: 1, layers: None };
+    }
+
+    pub fn persistent(mut self) -> Self {
+        self.lifetime = AttachmentLifetime::Persistent;
+        return self;
+    }
+
+    pub fn with_samples(mut self, samples: u8) -> Self {
+        self.samples = samples;
+        return self;
+    }
+
+    pub fn with_layers(mut self, layers: u16) -> Self {
+        self.layers = Some(layers);
+        return self;
+    }
+
+    pub fn is_layered(&self) -> bool {
+        return self.layers.is_some();
+    }
+
+    pub fn layer_count(&self) -> u16 {
+        return self.layers.unwrap_or(1);
+    }
+
+    pub fn is_depth(&self) -> bool {
+        return self.format.surface_desc().aspects.contains(gfx::format::Aspects::DEPTH);
+    }
+
+}
+
+/// Describes a pass of a graph by the attachments it writes to and reads from.
+/// Passes are executed in the order they are added to the graph.
+#[derive(Clone, Debug)]
+pub struct PassDesc {
+
+    pub name: String,
+    /// The attachments which are rendered to, in the order of the fragment shader outputs.
+    pub colors: Vec<String>,
+    pub depth: Option<String>,
+    /// The attachments the multisampled color attachments are resolved into at the end of the pass, in the order of the color attachments.
+    pub resolves: Vec<String>,
+    /// The attachments which are sampled by the shaders of the pass. These must be written by an earlier pass, unless they are persistent.
+    pub inputs: Vec<String>,
+    /// If set, the color attachments are cleared to this color at the start of the pass. Otherwise their contents are loaded.
+    pub clear_color: Option<Color>,
+    /// If set, the depth attachment is cleared to this depth at the start of the pass. Otherwise its contents are loaded.
+    pub clear_depth: Option<f32>,
+    /// The layer of layered attachments which the pass renders to. Passes which render to different layers of an attachment do not depend on each other.
+    pub layer: u16,
+
+}
+
+impl PassDesc {
+
+    pub fn new(name: &str) -> Self {
+        return Self { name: name.to_string(), colors: Vec::new(), depth: None, resolves: Vec::new(), inputs: Vec::new(), clear_color: None, clear_depth: None, layer: 0 };
+    }
+
+    pub fn with_color(mut self, attachment: &str) -> Self {
+        self.colors.push(attachment.to_string());
+        return self;
+    }
+
+    pub fn with_depth(mut self, attachment: &str) -> Self {
+        self.depth = Some(attachment.to_string());
+        return self;
+    }
+
+    pub fn with_resolve(mut self, attachment: &str) -> Self {
+        self.resolves.push(attachment.to_string());
+        return self;
+    }
+
+    pub fn with_input(mut self, attachment: &str) -> Self {
+        self.inputs.push(attachment.to_string());
+        return self;
+    }
+
+    pub fn with_clear_color(mut self, color: Color) -> Self {
+        self.clear_color = Some(color);
+        return self;
+    }
+
+    pub fn with_clear_depth(mut self, depth: f32) -> Self {
+        self.clear_depth = Some(depth);
+        return self;
+    }
+
+    pub fn with_layer(mut self, layer: u16) -> Self {
+        self.layer = layer;
+        return self;
+    }
+
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AttachmentUse {
+    Color,
+    Depth,
+    Resolve,
+    Sampled,
+}
+
+impl AttachmentUse {
+
+    fn layout(&self) -> gfx::image::Layout {
+        return match self {
+            AttachmentUse::Color | AttachmentUse::Resolve => gfx::image::Layout::ColorAttachmentOptimal,
+            AttachmentUse::Depth => gfx::image::Layout::DepthStencilAttachmentOptimal,
+            AttachmentUse::Sampled => gfx::image::Layout::ShaderReadOnlyOptimal,
+        };
+    }
+
+}
+
+/// A pass of a built graph, with the render pass and framebuffers created for it.
+pub struct GraphPass {
+
+    pub desc: PassDesc,
+    pub render_pass: render::RenderPass,
+    /// The indices of the graph attachments used by the render pass, in attachment order.
+    pub attachments: Vec<usize>,
+    pub clear_values: Vec<gfx::command::ClearValue>,
+    /// One framebuffer per surface image if the pass renders to the backbuffer, otherwise a single framebuffer.
+    pub framebuffers: Vec<render::Framebuffer>,
+    pub viewport: gfx::pso::Viewport,
+
+}
+
+impl GraphPass {
+
+    pub fn uses_backbuffer(&self) -> bool {
+        return self.attachments.contains(&0);
+    }
+
+    pub fn framebuffer(&self, image_index: usize) -> &render::Framebuffer {
+        if self.uses_backbuffer() {
+            return &self.framebuffers[image_index];
+        }
+        return &self.framebuffers[0];
+    }
+
+}
+
+/// Collects the attachments and passes of a graph.
+/// If the graph is multisampled, passes which render to the backbuffer render to a multisampled image instead, along with multisampled depth attachments.
+///
+/// ```ignore
+/// let graph = graph::RenderGraphBuilder::new()
+///     .with_attachment(graph::AttachmentDesc::new("depth", render::RenderPass::STD_DEPTH_FORMAT, graph::AttachmentSize::swapchain()))
+///     .with_pass(graph::PassDesc::new("main").with_color(graph::BACKBUFFER).with_depth("depth").with_clear_color(Color::black()).with_clear_depth(1.0))
+///     .build(&mut graphics)?;
+/// ```
+#[derive(Clone)]
+pub struct RenderGraphBuilder {
+
+    pub attachments: Vec<AttachmentDesc>,
+    pub passes: Vec<PassDesc>,
+    pub frames_in_flight: usize,
+    /// The number of samples per pixel the backbuffer is rendered with, which is clamped to what the device supports.
+    pub samples: u8,
+
+}
+
+impl RenderGraphBuilder {
+
+    pub fn new() -> Self {
+        return Self { attachments: Vec::new(), passes: Vec::new(), frames_in_flight: command::DEFAULT_FRAMES_IN_FLIGHT, samples: 1 };
+    }
+
+    pub fn with_attachment(mut self, attachment: AttachmentDesc) -> Self {
+        self.attachments.push(attachment);
+        return self;
+    }
+
+    pub fn with_pass(mut self, pass: PassDesc) -> Self {
+        self.passes.push(pass);
+        return self;
+    }
+
+    pub fn with_frames_in_flight(mut self, frames_in_flight: usize) -> Self {
+        self.frames_in_flight = frames_in_flight;
+        return self;
+    }
+
+    pub fn with_samples(mut self, samples: u8) -> Self {
+        self.samples = samples;
+        return self;
+    }
+
+    pub fn build(self, graphics: &mut render::Graphics) -> Result<RenderGraph, Error> {
+        return RenderGraph::create(self, graphics);
+    }
+
+}
+
+/// A set of passes which are recorded into a single command buffer each frame.
+/// The graph creates the render passes, the images of its attachments and the framebuffers from the declarations of its passes.
+/// Layout transitions between passes are part of the render passes, and each render pass has external subpass dependencies
+/// which order it against the attachment writes and shader reads of the passes around it.
+pub struct RenderGraph {
+
+    /// The attachments of the graph. The first attachment is always the backbuffer.
+    pub attachments: Vec<AttachmentDesc>,
+    pub passes: Vec<GraphPass>,
+    pub slots: command::FrameSlots,
+    /// The number of samples per pixel the backbuffer is rendered with.
+    pub samples: u8,
+
+    /// The description the graph was built from, which it is rebuilt from when the number of samples changes.
+    builder: RenderGraphBuilder,
+
+    /// The images of each attachment, or `None` for the backbuffer.
+    images: Vec<Option<buffer::TextureBuffer>>,
+    /// The passes which use each attachment and how, in execution order.
+    attachment_uses: Vec<Vec<(usize, AttachmentUse)>>,
+
+    device_token: core::DeviceToken,
+
+}
+
+impl RenderGraph {
+
+    fn create(builder: RenderGraphBuilder, graphics: &mut render::Graphics) -> Result<Self, Error> {
+
+        let original = builder.clone();
+        let samples = graphics.device.clamp_samples(builder.samples);
+        let mut builder = builder;
+        if samples > 1 {
+            Self::multisample_backbuffer(&mut builder, samples, graphics.device.color_format)?;
+        }
+
+        let mut attachments: Vec<AttachmentDesc> = Vec::with_capacity(builder.attachments.len() + 1);
+        attachments.push(AttachmentDesc::new(BACKBUFFER, graphics.device.color_format, AttachmentSize::swapchain()));
+        for attachment in builder.attachments.into_iter() {
+            if attachments.iter().any(|a| a.name == attachment.name) {
+                return Err(Error::Render(String::from("Failed to build render graph - attachment names must be unique.")));
+            }
+            attachments.push(attachment);
+        }
+
+        let find = |name: &str| -> Result<usize, Error> {
+            return attachments.iter().position(|a| a.name == name).ok_or_else(|| Error::Render(String::from("Failed to build render graph - a pass uses an attachment which does not exist.")));
+        };
+
+        // The uses of each attachment by each pass, in execution order.
+        let mut pass_uses: Vec<Vec<(usize, AttachmentUse)>> = Vec::with_capacity(builder.passes.len());
+        let mut pass_samples: Vec<u8> = Vec::with_capacity(builder.passes.len());
+        for pass in builder.passes.iter() {
+            let mut uses: Vec<(usize, AttachmentUse)> = Vec::new();
+            for color in pass.colors.iter() {
+                let index = find(color)?;
+                if attachments[index].is_depth() {
+                    return Err(Error::Render(String::from("Failed to build render graph - a depth attachment is used as a color attachment.")));
+                }
+                uses.push((index, AttachmentUse::Color));
+            }
+            if let Some(depth) = pass.depth.as_ref() {
+                let index = find(depth)?;
+                if !attachments[index].is_depth() {
+                    return Err(Error::Render(String::from("Failed to build render graph - a color attachment is used as a depth attachment.")));
+                }
+                uses.push((index, AttachmentUse::Depth));
+            }
+            let target_samples = uses.first().map_or(1, |(index, _)| attachments[*index].samples);
+            if uses.iter().any(|(index, _)| attachments[*index].samples != target_samples) {
+                return Err(Error::Render(String::from("Failed to build render graph - the attachments of a pass must all have the same number of samples.")));
+            }
+            if !pass.resolves.is_empty() && (pass.resolves.len() != pass.colors.len() || target_samples == 1) {
+                return Err(Error::Render(String::from("Failed to build render graph - a pass must resolve each of its color attachments, and they must be multisampled.")));
+            }
+            for resolve in pass.resolves.iter() {
+                let index = find(resolve)?;
+                if attachments[index].is_depth() || attachments[index].samples != 1 {
+                    return Err(Error::Render(String::from("Failed to build render graph - attachments can only be resolved into single sampled color attachments.")));
+                }
+                if uses.iter().any(|(i, _)| *i == index) {
+                    return Err(Error::Render(String::from("Failed to build render graph - a pass cannot resolve into an attachment it renders to.")));
+                }
+                uses.push((index, AttachmentUse::Resolve));
+            }
+            for input in pass.inputs.iter() {
+                let index = find(input)?;
+                if index == 0 {
+                    return Err(Error::Render(String::from("Failed to build render graph - the backbuffer cannot be sampled.")));
+                }
+                if uses.iter().any(|(i, _)| *i == index) {
+                    return Err(Error::Render(String::from("Failed to build render graph - a pass cannot sample an attachment it renders to.")));
+                }
+                if attachments[index].samples != 1 {
+                    return Err(Error::Render(String::from("Failed to build render graph - multisampled attachments cannot be sampled.")));
+                }
+                uses.push((index, AttachmentUse::Sampled));
+            }
+            if uses.iter().all(|(_, u)| *u == AttachmentUse::Sampled) {
+                return Err(Error::Render(String::from("Failed to build render graph - every pass must render to at least one attachment.")));
+            }
+            if uses.iter().any(|(index, u)| *u != AttachmentUse::Sampled && attachments[*index].is_layered() && pass.layer >= attachments[*index].layer_count()) {
+                return Err(Error::Render(String::from("Failed to build render graph - a pass renders to a layer which its attachment does not have.")));
+            }
+            if uses.iter().any(|(index, _)| attachments[*index].is_layered() && attachments[*index].samples != 1) {
+                return Err(Error::Render(String::from("Failed to build render graph - layered attachments cannot be multisampled.")));
+            }
+            pass_uses.push(uses);
+            pass_samples.push(target_samples);
+        }
+
+        // Gather the uses of each attachment across the whole graph.
+        let mut attachment_uses: Vec<Vec<(usize, AttachmentUse)>> = vec![Vec::new(); attachments.len()];
+        for (pass_index, uses) in pass_uses.iter().enumerate() {
+            for (attachment, attachment_use) in uses.iter() {
+                attachment_uses[*attachment].push((pass_index, *attachment_use));
+            }
+        }
+
+        if attachment_uses[0].is_empty() {
+            return Err(Error::Render(String::from("Failed to build render graph - no pass renders to the backbuffer.")));
+        }
+        for (attachment, uses) in attachments.iter().zip(attachment_uses.iter()) {
+            if let Some((_, AttachmentUse::Sampled)) = uses.first() {
+                if attachment.lifetime == AttachmentLifetime::Transient {
+                    return Err(Error::Render(String::from("Failed to build render graph - a transient attachment is sampled before it is rendered to.")));
+                }
+            }
+        }
+
+        let pass_layers: Vec<u16> = builder.passes.iter().map(|pass| pass.layer).collect();
+        let mut passes: Vec<GraphPass> = Vec::with_capacity(builder.passes.len());
+        for (pass_index, desc) in builder.passes.into_iter().enumerate() {
+            // Each pass only depends on the passes which use the same layer of layered attachments, or which sample all of their layers.
+            let layer_uses: Vec<Vec<(usize, AttachmentUse)>> = attachment_uses.iter().zip(attachments.iter())
+                .map(|(uses, attachment)| uses.iter().cloned().filter(|(p, u)| !attachment.is_layered() || *u == AttachmentUse::Sampled || pass_layers[*p] == desc.layer).collect())
+                .collect();
+            let (render_pass, pass_attachments, clear_values) = Self::create_render_pass(&desc, pass_index, &pass_uses[pass_index], pass_samples[pass_index], &attachments, &layer_uses, &graphics.device)?;
+            passes.push(GraphPass { desc, render_pass, attachments: pass_attachments, clear_values, framebuffers: Vec::new(), viewport: render::Surface::create_viewport(graphics.render_surface.extent) });
+        }
+
+        let mut graph = Self {
+            attachments,
+            passes,
+            slots: command::FrameSlots::new(builder.frames_in_flight, &graphics.device)?,
+            samples,
+            builder: original,
+            images: Vec::new(),
+            attachment_uses,
+            device_token: graphics.device.create_token(),
+        };
+        graph.create_resources(Vec::new(), graphics)?;
+        graph.create_framebuffers(graphics)?;
+
+        return Ok(graph);
+    }
+
+    /// Makes the passes which render to the backbuffer render to a multisampled attachment instead, which the last of them resolves into the backbuffer.
+    /// The depth attachments of those passes are multisampled as well.
+    fn multisample_backbuffer(builder: &mut RenderGraphBuilder, samples: u8, color_format: gfx::format::Format) -> Result<(), Error> {
+        let last = builder.passes.iter().rposition(|pass| pass.colors.iter().any(|color| color == BACKBUFFER));
+        for (pass_index, pass) in builder.passes.iter_mut().enumerate() {
+            if !pass.colors.iter().any(|color| color == BACKBUFFER) {
+                continue;
+            }
+            if pass.colors.len() != 1 || !pass.resolves.is_empty() {
+                return Err(Error::Render(String::from("Failed to build render graph - a multisampled pass which renders to the backbuffer cannot have other color attachments.")));
+            }
+            pass.colors[0] = MULTISAMPLED_BACKBUFFER.to_string();
+            if let Some(depth) = pass.depth.as_ref() {
+                if let Some(attachment) = builder.attachments.iter_mut().find(|a| a.name == *depth) {
+                    attachment.samples = samples;
+                }
+            }
+            if Some(pass_index) == last {
+                pass.resolves.push(BACKBUFFER.to_string());
+            }
+        }
+        builder.attachments.push(AttachmentDesc::new(MULTISAMPLED_BACKBUFFER, color_format, AttachmentSize::swapchain()).with_samples(samples));
+        return Ok(());
+    }
+
+    /// Works out the load and store operations and the layouts of each attachment of the pass from the passes before and after it.
+    fn create_render_pass(desc: &PassDesc, pass_index: usize, uses: &[(usize, AttachmentUse)], samples: u8, attachments: &[AttachmentDesc], attachment_uses: &[Vec<(usize, AttachmentUse)>], device: &core::Device) -> Result<(render::RenderPass, Vec<usize>, Vec<gfx::command::ClearValue>), Error> {
+
+        let mut raw_attachments: Vec<gfx::pass::Attachment> = Vec::new();
+        let mut pass_attachments: Vec<usize> = Vec::new();
+        let mut clear_values: Vec<gfx::command::ClearValue> = Vec::new();
+        let mut color_refs: Vec<gfx::pass::AttachmentRef> = Vec::new();
+        let mut depth_ref: Option<gfx::pass::AttachmentRef> = None;
+        let mut resolve_refs: Vec<gfx::pass::AttachmentRef> = Vec::new();
+        let mut samples_inputs = false;
+
+        for (attachment_index, attachment_use) in uses.iter() {
+            if *attachment_use == AttachmentUse::Sampled {
+                samples_inputs = true;
+                continue;
+            }
+
+            let attachment = &attachments[*attachment_index];
+            let all_uses = &attachment_uses[*attachment_index];
+            let position = all_uses.iter().position(|(p, _)| *p == pass_index).unwrap();
+            let previous = if position > 0 { Some(all_uses[position - 1].1) } else { None };
+            let next = all_uses.get(position + 1).map(|(_, u)| *u);
+            let is_backbuffer = *attachment_index == 0;
+            let is_persistent = attachment.lifetime == AttachmentLifetime::Persistent;
+
+            let clear = match attachment_use {
+                AttachmentUse::Depth => desc.clear_depth.is_some(),
+                _ => desc.clear_color.is_some(),
+            };
+
+            // Persistent attachments start each frame in the layout of their first use, which is where the last use of the previous frame leaves them.
+            let rest_layout = all_uses[0].1.layout();
+
+            // Resolved attachments are entirely overwritten.
+            let load = if *attachment_use == AttachmentUse::Resolve {
+                gfx::pass::AttachmentLoadOp::DontCare
+            } else if clear {
+                gfx::pass::AttachmentLoadOp::Clear
+            } else if previous.is_some() || is_persistent {
+                gfx::pass::AttachmentLoadOp::Load
+            } else {
+                gfx::pass::AttachmentLoadOp::DontCare
+            };
+            let store = if next.is_some() || is_persistent || is_backbuffer {
+                gfx::pass::AttachmentStoreOp::Store
+            } else {
+                gfx::pass::AttachmentStoreOp::DontCare
+            };
+
+            let initial_layout = match previous {
+                Some(previous) => previous.layout(),
+                None if is_persistent && !clear => rest_layout,
+                None => gfx::image::Layout::Undefined,
+            };
+            let final_layout = match next {
+                Some(next) => next.layout(),
+                None if is_backbuffer => device.present_layout(),
+                None if is_persistent => rest_layout,
+                None => attachment_use.layout(),
+            };
+
+            let index = raw_attachments.len();
+            raw_attachments.push(gfx::pass::Attachment {
+                format: Some(attachment.format),
+                samples: attachment.samples,
+                ops: gfx::pass::AttachmentOps::new(load, store),
+                stencil_ops: gfx::pass::AttachmentOps::DONT_CARE,
+                layouts: initial_layout..final_layout,
+            });
+            pass_attachments.push(*attachment_index);
+
+            match attachment_use {
+                AttachmentUse::Depth => {
+                    depth_ref = Some((index, gfx::image::Layout::DepthStencilAttachmentOptimal));
+                    clear_values.push(gfx::command::ClearValue::DepthStencil(gfx::command::ClearDepthStencil(desc.clear_depth.unwrap_or(1.0), 0)));
+                },
+                AttachmentUse::Resolve => {
+                    resolve_refs.push((index, gfx::image::Layout::ColorAttachmentOptimal));
+                    clear_values.push(gfx::command::ClearValue::Color(gfx::command::ClearColor::Float(Color::black().to_raw_color())));
+                },
+                _ => {
+                    color_refs.push((index, gfx::image::Layout::ColorAttachmentOptimal));
+                    clear_values.push(gfx::command::ClearValue::Color(gfx::command::ClearColor::Float(desc.clear_color.unwrap_or(Color::black()).to_raw_color())));
+                },
+            }
+        }
+
+        let subpass = gfx::pass::SubpassDesc {
+            colors: &color_refs,
+            depth_stencil: depth_ref.as_ref(),
+            inputs: &[],
+            resolves: &resolve_refs,
+            preserves: &[],
+        };
+
+        let attachment_stages = gfx::pso::PipelineStage::COLOR_ATTACHMENT_OUTPUT | gfx::pso::PipelineStage::EARLY_FRAGMENT_TESTS | gfx::pso::PipelineStage::LATE_FRAGMENT_TESTS;
+        let attachment_accesses = gfx::image::Access::COLOR_ATTACHMENT_READ | gfx::image::Access::COLOR_ATTACHMENT_WRITE
+            | gfx::image::Access::DEPTH_STENCIL_ATTACHMENT_READ | gfx::image::Access::DEPTH_STENCIL_ATTACHMENT_WRITE;
+        let attachment_writes = gfx::image::Access::COLOR_ATTACHMENT_WRITE | gfx::image::Access::DEPTH_STENCIL_ATTACHMENT_WRITE;
+
+        let mut dst_stages = attachment_stages;
+        let mut dst_accesses = attachment_accesses;
+        if samples_inputs {
+            dst_stages |= gfx::pso::PipelineStage::FRAGMENT_SHADER;
+            dst_accesses |= gfx::image::Access::SHADER_READ;
+        }
+
+        // Waits for earlier passes to finish writing to (or sampling) the attachments of this pass, and for the writes to be visible.
+        let incoming = gfx::pass::SubpassDependency {
+            passes: gfx::pass::SubpassRef::External..gfx::pass::SubpassRef::Pass(0),
+            stages: (attachment_stages | gfx::pso::PipelineStage::FRAGMENT_SHADER)..dst_stages,
+            accesses: attachment_writes..dst_accesses,
+        };
+
+        // Makes the writes of this pass visible to later passes which render to or sample its attachments, and to frame readback.
+        let outgoing = gfx::pass::SubpassDependency {
+            passes: gfx::pass::SubpassRef::Pass(0)..gfx::pass::SubpassRef::External,
+            stages: attachment_stages..(attachment_stages | gfx::pso::PipelineStage::FRAGMENT_SHADER | gfx::pso::PipelineStage::TRANSFER),
+            accesses: attachment_writes..(attachment_accesses | gfx::image::Access::SHADER_READ | gfx::image::Access::TRANSFER_READ),
+        };
+
+        let raw_render_pass = unsafe { device.gpu.create_render_pass(&raw_attachments, &[subpass], &[incoming, outgoing])? };
+
+        return Ok((render::RenderPass::from_raw_multisampled(raw_render_pass, samples), pass_attachments, clear_values));
+    }
+
+    /// Creates the images of every attachment other than the backbuffer.
+    /// The previous images of attachments with an absolute size are kept, as they do not depend on the surface.
+    fn create_resources(&mut self, previous: Vec<Option<buffer::TextureBuffer>>, graphics: &mut render::Graphics) -> Result<(), Error> {
+        let extent = graphics.render_surface.extent;
+        let mut previous = previous.into_iter();
+        let mut images: Vec<Option<buffer::TextureBuffer>> = Vec::with_capacity(self.attachments.len());
+        let mut persistent: Vec<(usize, gfx::image::Layout)> = Vec::new();
+        for (index, attachment) in self.attachments.iter().enumerate() {
+            let previous_image = previous.next().and_then(|image| image);
+            if index == 0 {
+                images.push(None);
+                continue;
+            }
+            if let (Some(image), AttachmentSize::Absolute(_)) = (previous_image, attachment.size) {
+                images.push(Some(image));
+                continue;
+            }
+            let uses = &self.attachment_uses[index];
+            let mut usage = if attachment.is_depth() { gfx::image::Usage::DEPTH_STENCIL_ATTACHMENT } else { gfx::image::Usage::COLOR_ATTACHMENT };
+            if uses.iter().any(|(_, u)| *u == AttachmentUse::Sampled) {
+                usage |= gfx::image::Usage::SAMPLED;
+            }
+            let aspects = attachment.format.surface_desc().aspects;
+            let size = attachment.size.resolve(extent);
+            if attachment.is_layered() {
+                images.push(Some(buffer::TextureBuffer::new_layered(size, attachment.format, usage, aspects, attachment.layer_count(), &graphics.device)?));
+            } else {
+                images.push(Some(buffer::TextureBuffer::new_multisampled(size, attachment.format, usage, aspects, attachment.samples, &graphics.device)?));
+            }
+            if attachment.lifetime == AttachmentLifetime::Persistent {
+                if let Some((_, first_use)) = uses.first() {
+                    persistent.push((index, first_use.layout()));
+                }
+            }
+        }
+        self.images = images;
+
+        // Persistent attachments are expected to be in their resting layout at the start of each frame, including the first.
+        if !persistent.is_empty() {
+            let transitions: Vec<(&buffer::TextureBuffer, gfx::image::SubresourceRange, gfx::image::Layout)> = persistent.iter()
+                .filter_map(|(index, layout)| {
+                    let attachment = &self.attachments[*index];
+                    let range = gfx::image::SubresourceRange { aspects: attachment.format.surface_desc().aspects, levels: 0..1, layers: 0..attachment.layer_count() };
+                    return self.images[*index].as_ref().map(|image| (image, range, *layout));
+                })
+                .collect();
+            Self::transition_images(&transitions, &mut graphics.device)?;
+        }
+        return Ok(());
+    }
+
+    fn transition_images(transitions: &[(&buffer::TextureBuffer, gfx::image::SubresourceRange, gfx::image::Layout)], device: &mut core::Device) -> Result<(), Error> {
+        unsafe {
+            let mut cmd_pool = device.gpu.create_command_pool_typed(&device.queue_group, gfx::pool::CommandPoolCreateFlags::empty())?;
+            let mut cmd_buffer = cmd_pool.acquire_command_buffer::<gfx::command::OneShot>();
+            cmd_buffer.begin();
+
+            let barriers: Vec<gfx::memory::Barrier<Backend>> = transitions.iter().map(|(image, range, layout)| gfx::memory::Barrier::Image {
+                states: (gfx::image::Access::empty(), gfx::image::Layout::Undefined)..(gfx::image::Access::empty(), *layout),
+                target: &image.image,
+                families: None,
+                range: range.clone(),
+            }).collect();
+            cmd_buffer.pipeline_barrier(gfx::pso::PipelineStage::TOP_OF_PIPE..gfx::pso::PipelineStage::BOTTOM_OF_PIPE, gfx::memory::Dependencies::empty(), barriers);
+            cmd_buffer.finish();
+
+            let fence = match device.gpu.create_fence(false) {
+                Ok(fence) => fence,
+                Err(e) => {
+                    device.gpu.destroy_command_pool(cmd_pool.into_raw());
+                    return Err(e.into());
+                },
+            };
+            device.queue_group.queues[0].submit_nosemaphores(std::iter::once(&cmd_buffer), Some(&fence));
+            let waited = device.gpu.wait_for_fence(&fence, !0);
+            device.gpu.destroy_fence(fence);
+            device.gpu.destroy_command_pool(cmd_pool.into_raw());
+            waited?;
+            return Ok(());
+        }
+    }
+
+    fn create_framebuffers(&mut self, graphics: &mut render::Graphics) -> Result<(), Error> {
+        graphics.render_surface.prepare_images(&graphics.device)?;
+        let surface_extent = graphics.render_surface.extent;
+        let attachments = &self.attachments;
+        let images = &self.images;
+        let gpu = &self.device_token.gpu;
+
+        for pass in self.passes.iter_mut() {
+            let sizes: Vec<Vector2u> = pass.attachments.iter().map(|index| attachments[*index].size.resolve(surface_extent)).collect();
+            let size = sizes[0];
+            if sizes.iter().any(|s| *s != size) {
+                return Err(Error::Render(String::from("Failed to build render graph - the attachments of a pass must all be the same size.")));
+            }
+            let extent = gfx::image::Extent { width: size.x, height: size.y, depth: 1 };
+
+            let framebuffer_count = if pass.uses_backbuffer() { graphics.render_surface.image_count() } else { 1 };
+            let mut framebuffers: Vec<render::Framebuffer> = Vec::with_capacity(framebuffer_count);
+            for image_index in 0..framebuffer_count {
+                let mut views: Vec<&<Backend as gfx::Backend>::ImageView> = Vec::with_capacity(pass.attachments.len());
+                for attachment in pass.attachments.iter() {
+                    let view = match images[*attachment].as_ref() {
+                        Some(image) => image.layer_view(pass.desc.layer),
+                        None => graphics.render_surface.image_view(image_index).ok_or_else(|| Error::Render(String::from("Failed to build render graph - the surface has no images.")))?,
+                    };
+                    views.push(view);
+                }
+                framebuffers.push(unsafe { gpu.create_framebuffer(&pass.render_pass.raw_render_pass, views, extent) }?);
+            }
+
+            pass.framebuffers = framebuffers;
+            pass.viewport = render::Surface::create_viewport(extent);
+        }
+        return Ok(());
+    }
+
+    fn destroy_framebuffers(&mut self) {
+        for pass in self.passes.iter_mut() {
+            for framebuffer in pass.framebuffers.drain(..) {
+                unsafe { self.device_token.gpu.destroy_framebuffer(framebuffer) };
+            }
+        }
+    }
+
+    /// Releases the attachment images. The device must not be using them.
+    fn destroy_images(&mut self) {
+        self.images.clear();
+    }
+
+    /// Recreates the attachment images and framebuffers after the surface has been rebuilt.
+    /// Images of attachments with an absolute size are kept, so descriptor sets which sample them stay valid.
+    /// If the color format of the surface has changed the whole graph is recreated, so pipelines created with its render passes must be recreated too.
+    pub fn rebuild(&mut self, graphics: &mut render::Graphics) -> Result<(), Error> {
+        if self.color_format() != graphics.device.color_format {
+            *self = Self::create(self.builder.clone(), graphics)?;
+            return Ok(());
+        }
+        let _ = self.device_token.gpu.wait_idle();
+        self.destroy_framebuffers();
+        let previous = mem::replace(&mut self.images, Vec::new());
+
+        self.create_resources(previous, graphics)?;
+        return self.create_framebuffers(graphics);
+    }
+
+    /// Rebuilds the graph to render the backbuffer with the number of samples specified.
+    /// Every render pass is recreated, so pipelines created with the render passes of the graph must be recreated too.
+    pub fn set_samples(&mut self, samples: u8, graphics: &mut render::Graphics) -> Result<(), Error> {
+        let mut builder = self.builder.clone();
+        builder.samples = samples;
+        *self = Self::create(builder, graphics)?;
+        return Ok(());
+    }
+
+    /// The number of samples the graph was last built with, before it was clamped to what the device supports.
+    pub fn requested_samples(&self) -> u8 {
+        return self.builder.samples;
+    }
+
+    /// The format of the backbuffer when the graph was built.
+    pub fn color_format(&self) -> gfx::format::Format {
+        return self.attachments[0].format;
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        return self.slots.frames_in_flight();
+    }
+
+    pub fn pass(&self, name: &str) -> Option<&GraphPass> {
+        return self.passes.iter().find(|pass| pass.desc.name == name);
+    }
+
+    /// Gets the render pass created for the pass with the specified name, which pipelines used by the pass must be created with.
+    pub fn render_pass(&self, name: &str) -> Option<&render::RenderPass> {
+        return self.pass(name).map(|pass| &pass.render_pass);
+    }
+
+    /// Gets the image of an attachment so that it can be bound as a shader input. The backbuffer has no image.
+    pub fn attachment_image(&self, name: &str) -> Option<&buffer::TextureBuffer> {
+        let index = self.attachments.iter().position(|a| a.name == name)?;
+        return self.images.get(index)?.as_ref();
+    }
+
+    /// Acquires the next surface image and records every pass into one command buffer, then submits and presents it.
+    /// The function is called once per pass, inside the render pass, with the name of the pass.
+    /// Returns false if nothing was presented, because the surface is waiting to be rebuilt or because no image could be acquired or presentation failed, in which case the surface is invalidated.
+    pub fn execute<F>(&mut self, graphics: &mut render::Graphics, mut f: F) -> bool
+        where F: FnMut(&str, &mut render::Graphics, &mut command::Encoder) {
+
+        // Nothing can be rendered until an invalidated surface has been rebuilt.
+        if !graphics.render_surface.is_valid {
+            return false;
+        }
+        if graphics.render_surface.did_rebuild {
+            if let Err(e) = self.rebuild(graphics) {
+                log!(err, "Failed to rebuild render graph: {}", e);
+                // The surface is rebuilt again, which retries rebuilding the graph.
+                graphics.render_surface.invalidate();
+                return false;
+            }
+        }
+
+        let (image_index, slot) = match self.slots.acquire(graphics) {
+            Some(indices) => indices,
+            None => {
+                // Invalidate swapchain for rebuilding.
+                graphics.render_surface.invalidate();
+                return false;
+            },
+        };
+
+        let passes = &self.passes;
+        let result = self.slots.submit(image_index, slot, graphics, |graphics, command_buffer| {
+            for pass in passes.iter() {
+                let mut encoder = command_buffer.begin_pass(pass.framebuffer(image_index), &pass.render_pass, &pass.viewport, &pass.clear_values);
+                f(&pass.desc.name, graphics, &mut encoder);
+            }
+        });
+
+        if !result {
+            graphics.render_surface.invalidate();
+        }
+        return result;
+    }
+
+}
+
+impl Drop for RenderGraph {
+
+    fn drop(&mut self) {
+        // Waiting for the device to finish using the graph lets a graph be replaced while its frames are still in flight.
+        let _ = self.device_token.gpu.wait_idle();
+        self.destroy_framebuffers();
+        self.destroy_images();
+        for pass in self.passes.iter() {
+            unsafe { self.device_token.gpu.destroy_render_pass(mem::transmute_copy(&pass.render_pass.raw_render_pass)) };
+        }
+    }
+
+}
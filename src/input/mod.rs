@@ -63,13 +63,13 @@ impl MovementStateHandler {
         self.states.push(state);
     }
 
-    pub fn is_triggered(&self, id: String) -> Result<bool, &'static str> {
+    pub fn is_triggered(&self, id: String) -> Result<bool, Error> {
         for state in self.states.iter() {
             if state.id == id {
                 return Ok(state.triggered());
             }
         }
-        return Err("Failed to find state with the specified id.");
+        return Err(Error::Input(id));
     }
 
     pub fn handle_events(&mut self, events: &Vec<window::Event>) {
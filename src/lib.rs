@@ -7,6 +7,7 @@
 //extern crate gfx_backend_metal as backend;
 //#[cfg(all(unix, not(target_os = "macos")))]
 extern crate gfx_backend_vulkan as backend;
+extern crate ash;
 
 pub use backend::Backend;
 pub extern crate gfx_hal as gfx;
@@ -16,17 +17,28 @@ pub extern crate cgmath;
 pub extern crate specs;
 pub extern crate specs_hierarchy;
 pub extern crate winit;
+#[cfg(feature = "runtime-shaders")]
+extern crate glsl_to_spirv;
+#[macro_use]
+extern crate lazy_static;
 
 pub mod types;
 pub use types::*;
 
+pub mod error;
+pub use error::Error;
+
 #[macro_use]
 pub mod core;
 pub mod render;
 pub mod command;
+pub mod graph;
+#[macro_use]
 pub mod pipeline;
+pub mod shader;
 pub mod window;
 pub mod buffer;
+pub mod memory;
 pub mod texture;
 pub mod input;
 
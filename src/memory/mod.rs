@@ -0,0 +1,561 @@
+use crate::*;
+
+use gfx::Device as GfxDevice;
+
+use std::ops::Range;
+use std::sync::Arc;
+
+pub type Memory = <Backend as gfx::Backend>::Memory;
+
+/// The default size of the memory blocks which allocations are sub-allocated from.
+pub const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The kind of resource memory is allocated for.
+/// Buffers and images are kept in separate blocks so that the buffer-image granularity of the device never has to be considered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    Buffer,
+    Image,
+}
+
+/// A range of device memory which a single resource is bound to.
+/// Allocations must be returned to the allocator they came from with `Allocator::free`.
+#[derive(Debug)]
+pub struct Allocation {
+
+    pub block: usize,
+    pub offset: u64,
+    pub size: u64,
+
+    /// A pointer to the start of the allocation if the memory is host visible, otherwise null.
+    mapped: *mut u8,
+    coherent: bool,
+
+}
+
+// The mapped pointer is only dereferenced through `write` and `read`, which require the same aliasing guarantees as the buffer the allocation is bound to.
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+impl Allocation {
+
+    pub fn is_mapped(&self) -> bool {
+        return !self.mapped.is_null();
+    }
+
+    /// Returns true if writes through the mapping do not need to be flushed.
+    pub fn is_coherent(&self) -> bool {
+        return self.coherent;
+    }
+
+    /// Copies data into the allocation at the byte offset specified.
+    /// Returns false if the allocation is not host visible or the data does not fit.
+    pub fn write<T: Copy>(&self, offset: u64, data: &[T]) -> bool {
+        let len = (data.len() * std::mem::size_of::<T>()) as u64;
+        if !self.is_mapped() || offset + len > self.size {
+            return false;
+        }
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr() as *const u8, self.mapped.offset(offset as isize), len as usize) };
+        return true;
+    }
+
+    /// Returns the mapped contents of the allocation, if it is host visible.
+    pub fn read(&self) -> Option<&[u8]> {
+        if !self.is_mapped() {
+            return None;
+        }
+        return Some(unsafe { std::slice::from_raw_parts(self.mapped, self.size as usize) });
+    }
+
+}
+
+/// Statistics about the device memory owned by an allocator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryStats {
+
+    /// The number of device memory allocations (blocks) made by the allocator.
+    pub block_count: usize,
+    /// The number of blocks which only contain a single large allocation.
+    pub dedicated_block_count: usize,
+    /// The total size of all blocks.
+    pub allocated_bytes: u64,
+    /// The total size of all live allocations, including alignment padding.
+    pub used_bytes: u64,
+    /// The number of live allocations.
+    pub allocation_count: usize,
+
+}
+
+struct MemoryBlock {
+
+    memory: Memory,
+    size: u64,
+    type_id: usize,
+    kind: ResourceKind,
+    dedicated: bool,
+    mapped: *mut u8,
+    coherent: bool,
+    free_list: FreeList,
+
+}
+
+/// The free ranges of a memory block, sorted by offset and never adjacent.
+struct FreeList {
+
+    ranges: Vec<Range<u64>>,
+    allocation_count: usize,
+
+}
+
+impl FreeList {
+
+    fn new(size: u64) -> Self {
+        return Self { ranges: vec![0..size], allocation_count: 0 };
+    }
+
+    /// Finds the first free range which can hold the size at the alignment specified and removes it from the free list.
+    /// Returns the offset and the size taken from the block, which includes any padding needed for alignment.
+    fn allocate(&mut self, size: u64, alignment: u64) -> Option<(u64, u64)> {
+        for i in 0..self.ranges.len() {
+            let range = self.ranges[i].clone();
+            let offset = align(range.start, alignment);
+            if offset + size > range.end {
+                continue;
+            }
+            self.ranges.remove(i);
+            // The padding before the aligned offset is kept with the allocation so that it is returned when the allocation is freed.
+            if offset + size < range.end {
+                self.ranges.insert(i, (offset + size)..range.end);
+            }
+            self.allocation_count += 1;
+            return Some((range.start, offset + size - range.start));
+        }
+        return None;
+    }
+
+    fn free(&mut self, range: Range<u64>) {
+        let index = self.ranges.iter().position(|r| r.start > range.start).unwrap_or(self.ranges.len());
+        self.ranges.insert(index, range);
+        // Coalesce with the next and previous ranges.
+        if index + 1 < self.ranges.len() && self.ranges[index].end == self.ranges[index + 1].start {
+            let next = self.ranges.remove(index + 1);
+            self.ranges[index].end = next.end;
+        }
+        if index > 0 && self.ranges[index - 1].end == self.ranges[index].start {
+            let current = self.ranges.remove(index);
+            self.ranges[index - 1].end = current.end;
+        }
+        self.allocation_count -= 1;
+    }
+
+    fn free_bytes(&self) -> u64 {
+        return self.ranges.iter().map(|r| r.end - r.start).sum();
+    }
+
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    if alignment <= 1 {
+        return value;
+    }
+    return (value + alignment - 1) / alignment * alignment;
+}
+
+/// Sub-allocates device memory for long lived resources from large blocks using a free-list.
+/// Host visible blocks are mapped once when they are created and stay mapped until they are freed.
+pub struct Allocator {
+
+    /// The size of new blocks. Allocations larger than half of this get a dedicated block.
+    pub block_size: u64,
+
+    blocks: Vec<Option<MemoryBlock>>,
+    memory_types: Vec<gfx::adapter::MemoryType>,
+    /// The start and size of each allocation, keyed by block and offset, so the padding can be returned on free.
+    ranges: std::collections::HashMap<(usize, u64), Range<u64>>,
+
+    gpu: Arc<<Backend as gfx::Backend>::Device>,
+
+}
+
+// The mapped pointers of the blocks are only handed out through allocations, so the allocator can be shared behind a mutex.
+unsafe impl Send for Allocator {}
+
+impl Allocator {
+
+    pub fn new(gpu: Arc<<Backend as gfx::Backend>::Device>, memory_properties: gfx::MemoryProperties) -> Self {
+        return Self { block_size: DEFAULT_BLOCK_SIZE, blocks: Vec::new(), memory_types: memory_properties.memory_types, ranges: std::collections::HashMap::new(), gpu };
+    }
+
+    /// Finds a memory type which is allowed by the requirements and has the properties specified.
+    /// Host visible memory is preferably also coherent, so that writes never need to be flushed.
+    fn find_memory_type(&self, type_mask: u64, properties: gfx::memory::Properties) -> Option<usize> {
+        let allowed = |id: usize, props: gfx::memory::Properties| -> bool {
+            return type_mask & (1_u64 << id) != 0 && self.memory_types[id].properties.contains(props);
+        };
+        if properties.contains(gfx::memory::Properties::CPU_VISIBLE) {
+            let coherent = properties | gfx::memory::Properties::COHERENT;
+            if let Some(id) = (0..self.memory_types.len()).find(|id| allowed(*id, coherent)) {
+                return Some(id);
+            }
+        }
+        return (0..self.memory_types.len()).find(|id| allowed(*id, properties));
+    }
+
+    fn create_block(&mut self, type_id: usize, kind: ResourceKind, size: u64, dedicated: bool) -> Result<usize, Error> {
+        let memory = unsafe { self.gpu.allocate_memory(gfx::adapter::MemoryTypeId(type_id), size) }?;
+        let properties = self.memory_types[type_id].properties;
+        let mut mapped: *mut u8 = std::ptr::null_mut();
+        if properties.contains(gfx::memory::Properties::CPU_VISIBLE) {
+            mapped = unsafe { self.gpu.map_memory(&memory, 0..size) }.map_err(|_| Error::Render(String::from("Failed to map host visible memory block.")))?;
+        }
+        let block = MemoryBlock {
+            memory,
+            size,
+            type_id,
+            kind,
+            dedicated,
+            mapped,
+            coherent: properties.contains(gfx::memory::Properties::COHERENT),
+            free_list: FreeList::new(size),
+        };
+        log!(debug, 2, "Allocated memory block of {} bytes (type {}).", size, type_id);
+
+        // Reuse the index of a freed block if possible so that block indices stay small.
+        if let Some(index) = self.blocks.iter().position(|b| b.is_none()) {
+            self.blocks[index] = Some(block);
+            return Ok(index);
+        }
+        self.blocks.push(Some(block));
+        return Ok(self.blocks.len() - 1);
+    }
+
+    pub fn allocate(&mut self, requirements: gfx::memory::Requirements, properties: gfx::memory::Properties, kind: ResourceKind) -> Result<Allocation, Error> {
+        let type_id = self.find_memory_type(requirements.type_mask, properties).ok_or_else(|| Error::Render(String::from("No memory type matches the requirements of the resource.")))?;
+        let size = requirements.size.max(1);
+        let alignment = requirements.alignment.max(1);
+
+        let mut found: Option<(usize, u64, u64)> = None;
+        if size <= self.block_size / 2 {
+            for (index, block) in self.blocks.iter_mut().enumerate() {
+                if let Some(block) = block.as_mut() {
+                    if block.dedicated || block.type_id != type_id || block.kind != kind {
+                        continue;
+                    }
+                    if let Some((start, taken)) = block.free_list.allocate(size, alignment) {
+                        found = Some((index, start, taken));
+                        break;
+                    }
+                }
+            }
+        }
+
+        let (index, start, taken) = match found {
+            Some(found) => found,
+            None => {
+                let dedicated = size > self.block_size / 2;
+                let block_size = if dedicated { size } else { self.block_size };
+                let index = self.create_block(type_id, kind, block_size, dedicated)?;
+                let (start, taken) = self.blocks[index].as_mut().unwrap().free_list.allocate(size, alignment).ok_or_else(|| Error::Render(String::from("Failed to allocate from a new memory block.")))?;
+                (index, start, taken)
+            },
+        };
+
+        let block = self.blocks[index].as_ref().unwrap();
+        let offset = align(start, alignment);
+        self.ranges.insert((index, offset), start..(start + taken));
+        let mapped = if block.mapped.is_null() { std::ptr::null_mut() } else { unsafe { block.mapped.offset(offset as isize) } };
+        return Ok(Allocation { block: index, offset, size, mapped, coherent: block.coherent });
+    }
+
+    pub fn free(&mut self, allocation: &Allocation) {
+        let range = match self.ranges.remove(&(allocation.block, allocation.offset)) {
+            Some(range) => range,
+            None => {
+                log!(err, "Attempted to free memory which was not allocated by this allocator.");
+                return;
+            },
+        };
+        let mut release = false;
+        if let Some(block) = self.blocks[allocation.block].as_mut() {
+            block.free_list.free(range);
+            // Dedicated blocks are released immediately. Shared blocks are kept to be reused.
+            release = block.dedicated && block.free_list.allocation_count == 0;
+        }
+        if release {
+            if let Some(block) = self.blocks[allocation.block].take() {
+                self.release_block(block);
+            }
+        }
+    }
+
+    fn release_block(&self, block: MemoryBlock) {
+        unsafe {
+            if !block.mapped.is_null() {
+                self.gpu.unmap_memory(&block.memory);
+            }
+            self.gpu.free_memory(block.memory);
+        }
+    }
+
+    /// Frees every shared block which has no allocations left in it.
+    pub fn trim(&mut self) {
+        for i in 0..self.blocks.len() {
+            let empty = self.blocks[i].as_ref().map(|b| b.free_list.allocation_count == 0).unwrap_or(false);
+            if empty {
+                if let Some(block) = self.blocks[i].take() {
+                    self.release_block(block);
+                }
+            }
+        }
+    }
+
+    /// Gets the device memory object that an allocation is part of, for binding resources to it.
+    pub fn memory(&self, allocation: &Allocation) -> &Memory {
+        return &self.blocks[allocation.block].as_ref().log_expect("The memory block of the allocation has been freed.").memory;
+    }
+
+    /// Makes host writes to a non-coherent allocation visible to the device. This does nothing for coherent memory.
+    pub fn flush(&self, allocation: &Allocation) {
+        if allocation.coherent {
+            return;
+        }
+        if let Some(block) = self.blocks[allocation.block].as_ref() {
+            // The whole block is flushed, as ranges would otherwise have to be aligned to the non-coherent atom size.
+            let _ = unsafe { self.gpu.flush_mapped_memory_ranges(std::iter::once((&block.memory, ..))) };
+        }
+    }
+
+    /// Makes device writes to a non-coherent allocation visible to the host. This does nothing for coherent memory.
+    pub fn invalidate(&self, allocation: &Allocation) {
+        if allocation.coherent {
+            return;
+        }
+        if let Some(block) = self.blocks[allocation.block].as_ref() {
+            let _ = unsafe { self.gpu.invalidate_mapped_memory_ranges(std::iter::once((&block.memory, ..))) };
+        }
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats::default();
+        for block in self.blocks.iter().filter_map(|b| b.as_ref()) {
+            stats.block_count += 1;
+            if block.dedicated {
+                stats.dedicated_block_count += 1;
+            }
+            stats.allocated_bytes += block.size;
+            stats.used_bytes += block.size - block.free_list.free_bytes();
+            stats.allocation_count += block.free_list.allocation_count;
+        }
+        return stats;
+    }
+
+}
+
+impl Drop for Allocator {
+
+    fn drop(&mut self) {
+        if !self.ranges.is_empty() {
+            log!(err, "{} allocations were still alive when the memory allocator was dropped.", self.ranges.len());
+        }
+        for block in self.blocks.drain(..).filter_map(|b| b).collect::<Vec<_>>() {
+            self.release_block(block);
+        }
+    }
+
+}
+
+/// A host visible buffer for data which is rewritten every frame (e.g. lights, bones or per-object constants), bound using dynamic offsets.
+/// The buffer is split into one segment per frame, so data written for one frame never overwrites data which an earlier frame in flight may still be reading.
+/// `begin_frame` must be called once per frame, before anything is written for that frame.
+/// This is the allocator for all data which only lives for a frame; anything written to it is only valid until its segment is reused.
+pub struct UniformRing {
+
+    pub buffer: buffer::Buffer,
+    /// The size of each segment, in bytes.
+    pub segment_size: u64,
+    pub segment_count: usize,
+    /// The alignment of the offsets returned by `push`, which satisfies the dynamic offset alignment of the device.
+    pub alignment: u64,
+
+    segment: usize,
+    offset: u64,
+
+}
+
+impl UniformRing {
+
+    /// The default size of a segment, which is enough for a few hundred small uniform structures per frame.
+    pub const DEFAULT_SEGMENT_SIZE: u64 = 256 * 1024;
+
+    /// Creates a ring which can be written to by frames up to `frames_in_flight` ahead of the device.
+    pub fn new(segment_size: u64, frames_in_flight: usize, device: &core::Device) -> Result<Self, Error> {
+        return Self::with_usage(segment_size, frames_in_flight, gfx::buffer::Usage::UNIFORM | gfx::buffer::Usage::STORAGE, device);
+    }
+
+    /// Creates a ring whose buffer can be used as specified, e.g. as a per-instance vertex buffer.
+    pub fn with_usage(segment_size: u64, frames_in_flight: usize, usage: gfx::buffer::Usage, device: &core::Device) -> Result<Self, Error> {
+        use gfx::PhysicalDevice;
+        let limits = device.adapter.physical_device.limits();
+        let alignment = limits.min_uniform_buffer_offset_alignment.max(limits.min_storage_buffer_offset_alignment).max(16);
+        let segment_size = align(segment_size, alignment);
+
+        // Data for a frame is written before the frame waits for its slot, so one more segment than there are frames in flight is needed
+        // to guarantee that the segment being written was last read by a frame which has already completed.
+        let segment_count = frames_in_flight + 1;
+        let buffer = buffer::Buffer::alloc_empty::<u8>(
+            (segment_size * segment_count as u64) as usize,
+            usage,
+            gfx::memory::Properties::CPU_VISIBLE,
+            device
+        )?;
+        return Ok(Self { buffer, segment_size, segment_count, alignment, segment: 0, offset: 0 });
+    }
+
+    /// Moves to the next segment, discarding everything written to it during an earlier frame.
+    pub fn begin_frame(&mut self) {
+        self.segment = (self.segment + 1) % self.segment_count;
+        self.offset = 0;
+    }
+
+    /// Writes the data into the segment of the current frame and returns the dynamic offset to bind it with.
+    /// Returns `None` if the segment is full.
+    pub fn push<T: Copy>(&mut self, data: &T) -> Option<u32> {
+        return self.push_slice(std::slice::from_ref(data));
+    }
+
+    /// Writes the data into the segment of the current frame and returns the dynamic offset to bind it with.
+    pub fn push_slice<T: Copy>(&mut self, data: &[T]) -> Option<u32> {
+        let size = (data.len() * std::mem::size_of::<T>()) as u64;
+        if self.offset + size > self.segment_size {
+            log!(err, "Uniform ring segment is full ({} of {} bytes used).", self.offset, self.segment_size);
+            return None;
+        }
+        let offset = self.segment as u64 * self.segment_size + self.offset;
+        if !self.buffer.allocation.write(offset, data) {
+            return None;
+        }
+        if !self.buffer.allocation.is_coherent() {
+            self.buffer.flush();
+        }
+        self.offset = align(self.offset + size, self.alignment);
+        return Some(offset as u32);
+    }
+
+    /// The number of bytes written during the current frame.
+    pub fn used(&self) -> u64 {
+        return self.offset;
+    }
+
+    /// Gets a shader input which binds `T` sized ranges of the ring as a dynamic uniform buffer.
+    pub fn uniform_binding<T>(&self) -> DynamicBinding {
+        return DynamicBinding { buffer: &self.buffer, range: std::mem::size_of::<T>() as u64, binding_type: gfx::pso::DescriptorType::UniformBufferDynamic };
+    }
+
+    /// Gets a shader input which binds `T` sized ranges of the ring as a dynamic storage buffer.
+    pub fn storage_binding<T>(&self) -> DynamicBinding {
+        return DynamicBinding { buffer: &self.buffer, range: std::mem::size_of::<T>() as u64, binding_type: gfx::pso::DescriptorType::StorageBufferDynamic };
+    }
+
+}
+
+/// A range of a buffer which is bound with a dynamic offset, which is specified each time the descriptor set is bound.
+pub struct DynamicBinding<'a> {
+
+    pub buffer: &'a buffer::Buffer,
+    pub range: u64,
+    pub binding_type: gfx::pso::DescriptorType,
+
+}
+
+impl<'a> pipeline::ShaderInput for DynamicBinding<'a> {
+
+    fn get_descriptor(&self) -> Option<gfx::pso::Descriptor<Backend>> {
+        return Some(gfx::pso::Descriptor::Buffer(&self.buffer.buf, Some(0)..Some(self.range)));
+    }
+    fn get_binding_type(&self) -> gfx::pso::DescriptorType {
+        return self.binding_type;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn allocations_split_the_first_range_which_fits() {
+        let mut list = FreeList::new(1024);
+        assert_eq!(list.allocate(256, 1), Some((0, 256)));
+        assert_eq!(list.allocate(256, 1), Some((256, 256)));
+        assert_eq!(list.ranges, vec![512..1024]);
+        assert_eq!(list.allocation_count, 2);
+        assert_eq!(list.free_bytes(), 512);
+    }
+
+    #[test]
+    fn an_exact_fit_removes_the_range() {
+        let mut list = FreeList::new(256);
+        assert_eq!(list.allocate(256, 1), Some((0, 256)));
+        assert!(list.ranges.is_empty());
+        assert_eq!(list.allocate(1, 1), None);
+    }
+
+    #[test]
+    fn alignment_padding_is_taken_with_the_allocation() {
+        let mut list = FreeList::new(1024);
+        list.allocate(10, 1).unwrap();
+        // The allocation starts at 10 but is aligned to 64, so 54 bytes of padding are taken with it.
+        let (start, taken) = list.allocate(100, 64).unwrap();
+        assert_eq!((start, taken), (10, 154));
+        assert_eq!(align(start, 64), 64);
+        assert_eq!(list.ranges, vec![164..1024]);
+    }
+
+    #[test]
+    fn ranges_which_are_too_small_once_aligned_are_skipped() {
+        let mut list = FreeList::new(1024);
+        list.allocate(8, 1).unwrap();
+        let hole = list.allocate(16, 1).unwrap();
+        list.allocate(100, 1).unwrap();
+        list.free(hole.0..(hole.0 + hole.1));
+        // The 16 byte hole at 8 only has 8 bytes left once it is aligned to 16, so the range after the last allocation is used.
+        assert_eq!(list.allocate(16, 16), Some((124, 20)));
+        assert_eq!(list.allocate(16, 1), Some((8, 16)));
+    }
+
+    #[test]
+    fn freed_ranges_coalesce_with_both_neighbours() {
+        let mut list = FreeList::new(300);
+        let a = list.allocate(100, 1).unwrap();
+        let b = list.allocate(100, 1).unwrap();
+        let c = list.allocate(100, 1).unwrap();
+        list.free(a.0..(a.0 + a.1));
+        list.free(c.0..(c.0 + c.1));
+        assert_eq!(list.ranges, vec![0..100, 200..300]);
+        list.free(b.0..(b.0 + b.1));
+        assert_eq!(list.ranges, vec![0..300]);
+        assert_eq!(list.allocation_count, 0);
+    }
+
+    #[test]
+    fn freed_ranges_stay_sorted() {
+        let mut list = FreeList::new(400);
+        let allocations: Vec<(u64, u64)> = (0..4).map(|_| list.allocate(100, 1).unwrap()).collect();
+        list.free(allocations[2].0..(allocations[2].0 + allocations[2].1));
+        list.free(allocations[0].0..(allocations[0].0 + allocations[0].1));
+        assert_eq!(list.ranges, vec![0..100, 200..300]);
+        list.free(allocations[3].0..(allocations[3].0 + allocations[3].1));
+        assert_eq!(list.ranges, vec![0..100, 200..400]);
+    }
+
+    #[test]
+    fn align_rounds_up_to_the_alignment() {
+        assert_eq!(align(0, 256), 0);
+        assert_eq!(align(1, 256), 256);
+        assert_eq!(align(256, 256), 256);
+        assert_eq!(align(257, 256), 512);
+        assert_eq!(align(13, 0), 13);
+    }
+
+}
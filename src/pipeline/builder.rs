@@ -0,0 +1,331 @@
+use crate::*;
+use pipeline::*;
+
+use gfx::Device as GfxDevice;
+
+use std::ops::Range;
+
+pub type ShaderModule = <Backend as gfx::Backend>::ShaderModule;
+
+/// Presets for how the fragments written by a pipeline are blended with the color target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlendPreset {
+
+    /// Fragments replace the color target.
+    Opaque,
+    /// Fragments are blended using their alpha.
+    Alpha,
+    /// Fragments are added to the color target.
+    Additive,
+
+}
+
+impl BlendPreset {
+
+    pub fn to_raw(&self) -> gfx::pso::BlendState {
+        match self {
+            BlendPreset::Opaque => gfx::pso::BlendState::Off,
+            BlendPreset::Alpha => gfx::pso::BlendState::ALPHA,
+            BlendPreset::Additive => gfx::pso::BlendState::ADD,
+        }
+    }
+
+}
+
+/// Presets for the depth test of a pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthPreset {
+
+    /// No depth testing. This must be used if the render pass has no depth attachment.
+    Off,
+    /// Fragments are tested against the depth buffer but do not write to it (e.g. for transparent geometry).
+    ReadOnly,
+    /// Fragments closer than the depth buffer pass and write their depth.
+    ReadWrite,
+
+}
+
+impl DepthPreset {
+
+    pub fn to_raw(&self) -> gfx::pso::DepthStencilDesc {
+        let depth = match self {
+            DepthPreset::Off => gfx::pso::DepthTest::Off,
+            DepthPreset::ReadOnly => gfx::pso::DepthTest::On { fun: gfx::pso::Comparison::Less, write: false },
+            DepthPreset::ReadWrite => gfx::pso::DepthTest::On { fun: gfx::pso::Comparison::Less, write: true },
+        };
+        return gfx::pso::DepthStencilDesc { depth, depth_bounds: false, stencil: gfx::pso::StencilTest::default() };
+    }
+
+}
+
+/// Presets for which faces are culled by the rasterizer. Front faces are counter clockwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CullPreset {
+
+    None,
+    Back,
+    Front,
+
+}
+
+impl CullPreset {
+
+    pub fn to_raw(&self) -> gfx::pso::Face {
+        match self {
+            CullPreset::None => gfx::pso::Face::NONE,
+            CullPreset::Back => gfx::pso::Face::BACK,
+            CullPreset::Front => gfx::pso::Face::FRONT,
+        }
+    }
+
+}
+
+/// Builds a graphics pipeline along with its layout.
+/// By default a pipeline renders filled triangle lists with back face culling, opaque blending and a read/write depth test.
+/// At least a vertex shader must be specified. The shader modules are not destroyed by the builder.
+pub struct PipelineBuilder<'a> {
+
+    pub vertex_shader: Option<&'a ShaderModule>,
+    pub fragment_shader: Option<&'a ShaderModule>,
+    pub vertex_layouts: Vec<VertexLayout>,
+    pub set_layouts: Vec<&'a DescriptorSetLayout>,
+    pub push_constants: Vec<(gfx::pso::ShaderStageFlags, Range<u32>)>,
+    pub primitive: gfx::Primitive,
+    pub polygon_mode: gfx::pso::PolygonMode,
+    pub blend: BlendPreset,
+    pub depth: DepthPreset,
+    pub cull: CullPreset,
+    /// The number of color attachments of the subpass, which are each blended with the blend preset. Depth-only pipelines have none.
+    pub color_targets: usize,
+    /// The index of the subpass of the render pass the pipeline is used in.
+    pub subpass: usize,
+    /// The reflected interface of the shaders, which the layouts are validated against.
+    pub reflection: Option<&'a shader::ShaderReflection>,
+
+}
+
+impl<'a> PipelineBuilder<'a> {
+
+    pub fn new() -> Self {
+        return Self {
+            vertex_shader: None,
+            fragment_shader: None,
+            vertex_layouts: Vec::new(),
+            set_layouts: Vec::new(),
+            push_constants: Vec::new(),
+            primitive: gfx::Primitive::TriangleList,
+            polygon_mode: gfx::pso::PolygonMode::Fill,
+            blend: BlendPreset::Opaque,
+            depth: DepthPreset::ReadWrite,
+            cull: CullPreset::Back,
+            color_targets: 1,
+            subpass: 0,
+            reflection: None,
+        };
+    }
+
+    pub fn with_vertex_shader(mut self, module: &'a ShaderModule) -> Self {
+        self.vertex_shader = Some(module);
+        return self;
+    }
+
+    pub fn with_fragment_shader(mut self, module: &'a ShaderModule) -> Self {
+        self.fragment_shader = Some(module);
+        return self;
+    }
+
+    /// Adds a vertex buffer binding. Bindings are numbered in the order they are added.
+    pub fn with_vertex_layout(mut self, layout: VertexLayout) -> Self {
+        self.vertex_layouts.push(layout);
+        return self;
+    }
+
+    /// Adds a vertex buffer binding which contains vertices of the type specified.
+    pub fn with_vertex<V: VertexFormat>(self) -> Self {
+        return self.with_vertex_layout(V::vertex_layout());
+    }
+
+    /// Adds a descriptor set layout. Sets are numbered in the order they are added.
+    pub fn with_descriptor_set_layout(mut self, layout: &'a DescriptorSetLayout) -> Self {
+        self.set_layouts.push(layout);
+        return self;
+    }
+
+    /// Adds a push constant range of the specified size in bytes, placed after any previously added ranges.
+    /// Ranges are measured in 4 byte words, so sizes which are not a multiple of 4 are rounded up.
+    pub fn with_push_constants(mut self, stages: gfx::pso::ShaderStageFlags, size: u32) -> Self {
+        let start = self.push_constants.last().map_or(0, |(_, range)| range.end);
+        self.push_constants.push((stages, start..(start + (size + 3) / 4)));
+        return self;
+    }
+
+    pub fn with_primitive(mut self, primitive: gfx::Primitive) -> Self {
+        self.primitive = primitive;
+        return self;
+    }
+
+    pub fn with_polygon_mode(mut self, polygon_mode: gfx::pso::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        return self;
+    }
+
+    pub fn with_blend(mut self, blend: BlendPreset) -> Self {
+        self.blend = blend;
+        return self;
+    }
+
+    pub fn with_depth(mut self, depth: DepthPreset) -> Self {
+        self.depth = depth;
+        return self;
+    }
+
+    pub fn with_cull(mut self, cull: CullPreset) -> Self {
+        self.cull = cull;
+        return self;
+    }
+
+    pub fn with_color_targets(mut self, color_targets: usize) -> Self {
+        self.color_targets = color_targets;
+        return self;
+    }
+
+    pub fn with_subpass(mut self, subpass: usize) -> Self {
+        self.subpass = subpass;
+        return self;
+    }
+
+    /// Validates the descriptor set layouts and vertex layouts against the reflected shader interface when the pipeline is built.
+    /// If no push constants have been added, the reflected push constant range is used.
+    pub fn with_reflection(mut self, reflection: &'a shader::ShaderReflection) -> Self {
+        self.reflection = Some(reflection);
+        return self;
+    }
+
+    /// Creates the pipeline layout and the pipeline for use in the render pass specified.
+    pub fn build(self, render_pass: &render::RenderPass, device: &core::Device) -> Result<PipelineController, Error> {
+        let vertex_shader = self.vertex_shader.ok_or_else(|| Error::Render(String::from("A pipeline needs a vertex shader.")))?;
+
+        let mut push_constants = self.push_constants.clone();
+        if let Some(reflection) = self.reflection {
+            reflection.validate_set_layouts(&self.set_layouts)?;
+            reflection.validate_vertex_layouts(&self.vertex_layouts)?;
+            if push_constants.is_empty() {
+                push_constants = reflection.push_constant_ranges();
+            }
+            let declared = push_constants.iter().map(|(_, range)| range.end * 4).max().unwrap_or(0);
+            if declared < reflection.push_constant_size {
+                return Err(Error::Reflection(format!("The shaders use {} bytes of push constants but the pipeline only declares {}.", reflection.push_constant_size, declared)));
+            }
+        }
+
+        let layout = PipelineLayout::create(&self.set_layouts, &push_constants, device)?;
+
+        let shader_entries = gfx::pso::GraphicsShaderSet {
+            vertex: gfx::pso::EntryPoint::<Backend> {
+                entry: "main",
+                module: vertex_shader,
+                specialization: Default::default(),
+            },
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: self.fragment_shader.map(|module| gfx::pso::EntryPoint::<Backend> {
+                entry: "main",
+                module,
+                specialization: Default::default(),
+            }),
+        };
+
+        let rasterizer = gfx::pso::Rasterizer {
+            polygon_mode: self.polygon_mode,
+            cull_face: self.cull.to_raw(),
+            front_face: gfx::pso::FrontFace::CounterClockwise,
+            depth_clamping: false,
+            depth_bias: None,
+            conservative: false,
+        };
+
+        let subpass = gfx::pass::Subpass {
+            index: self.subpass,
+            main_pass: &render_pass.raw_render_pass,
+        };
+
+        let mut pipeline_desc = gfx::pso::GraphicsPipelineDesc::new(
+            shader_entries,
+            self.primitive,
+            rasterizer,
+            &layout.layout,
+            subpass,
+        );
+
+        for _ in 0..self.color_targets {
+            pipeline_desc
+                .blender
+                .targets
+                .push(gfx::pso::ColorBlendDesc(gfx::pso::ColorMask::ALL, self.blend.to_raw()));
+        }
+
+        for (binding, vertex_layout) in self.vertex_layouts.iter().enumerate() {
+            pipeline_desc.vertex_buffers.push(gfx::pso::VertexBufferDesc {
+                binding: binding as u32,
+                stride: vertex_layout.stride,
+                rate: if vertex_layout.per_instance { 1 } else { 0 },
+            });
+            for attribute in vertex_layout.attributes.iter() {
+                pipeline_desc.attributes.push(gfx::pso::AttributeDesc {
+                    location: attribute.location,
+                    binding: binding as u32,
+                    element: gfx::pso::Element {
+                        format: attribute.format,
+                        offset: attribute.offset,
+                    },
+                });
+            }
+        }
+
+        pipeline_desc.depth_stencil = self.depth.to_raw();
+
+        // The rasterizer must produce as many samples as the attachments of the render pass have.
+        if render_pass.samples > 1 {
+            pipeline_desc.multisampling = Some(gfx::pso::Multisampling {
+                rasterization_samples: render_pass.samples,
+                sample_shading: None,
+                sample_mask: !0,
+                alpha_coverage: false,
+                alpha_to_one: false,
+            });
+        }
+
+        let pipeline = match Pipeline::create(pipeline_desc, device) {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                unsafe { device.gpu.destroy_pipeline_layout(layout.layout) };
+                return Err(e);
+            },
+        };
+        return Ok(PipelineController::new(pipeline, layout));
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use gfx::pso::ShaderStageFlags;
+
+    #[test]
+    fn push_constant_ranges_are_rounded_up_to_whole_words() {
+        let builder = PipelineBuilder::new()
+            .with_push_constants(ShaderStageFlags::VERTEX, 64)
+            .with_push_constants(ShaderStageFlags::FRAGMENT, 6)
+            .with_push_constants(ShaderStageFlags::FRAGMENT, 1);
+        assert_eq!(builder.push_constants, vec![
+            (ShaderStageFlags::VERTEX, 0..16),
+            (ShaderStageFlags::FRAGMENT, 16..18),
+            (ShaderStageFlags::FRAGMENT, 18..19),
+        ]);
+    }
+
+}
@@ -0,0 +1,115 @@
+use crate::*;
+use pipeline::*;
+
+use gfx::Device as GfxDevice;
+
+use std::ops::Range;
+
+/// A compute pipeline along with its layout, which are destroyed when it is dropped. The device must not be using the pipeline by then.
+/// Compute work is recorded on the same queue as graphics work, which is opened with both capabilities.
+pub struct ComputePipeline {
+
+    pub pipeline: <Backend as gfx::Backend>::ComputePipeline,
+    pub layout: PipelineLayout,
+    device_token: core::DeviceToken,
+
+}
+
+impl ComputePipeline {
+
+    /// Creates a compute pipeline which runs the `main` entry point of the shader.
+    /// If a reflection of the shader is given, the descriptor set layouts are validated against it and its push constant range is used when none are specified.
+    pub fn create(shader: &ShaderModule, set_layouts: &[&DescriptorSetLayout], push_constants: &[(gfx::pso::ShaderStageFlags, Range<u32>)],
+                  reflection: Option<&shader::ShaderReflection>, device: &core::Device) -> Result<ComputePipeline, Error> {
+        let mut push_constants = push_constants.to_vec();
+        if let Some(reflection) = reflection {
+            reflection.validate_set_layouts(set_layouts)?;
+            if push_constants.is_empty() {
+                push_constants = reflection.push_constant_ranges();
+            }
+        }
+
+        let layout = PipelineLayout::create(set_layouts, &push_constants, device)?;
+        let desc = gfx::pso::ComputePipelineDesc::new(
+            gfx::pso::EntryPoint::<Backend> {
+                entry: "main",
+                module: shader,
+                specialization: Default::default(),
+            },
+            &layout.layout,
+        );
+
+        let pipeline = match unsafe { device.gpu.create_compute_pipeline(&desc, device.raw_pipeline_cache()) } {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                unsafe { device.gpu.destroy_pipeline_layout(layout.layout) };
+                return Err(e.into());
+            },
+        };
+        return Ok(ComputePipeline { pipeline, layout, device_token: device.create_token() });
+    }
+
+    pub fn bind(&self, command_buffer: &mut command::CommandBuffer) {
+        unsafe { command_buffer.cmd.bind_compute_pipeline(&self.pipeline) };
+    }
+
+    /// Binds the descriptor sets, with an offset for each dynamic buffer binding in binding order.
+    pub fn bind_descriptor_sets(&self, input_sets: &[&DescriptorSet], dynamic_offsets: &[u32], command_buffer: &mut command::CommandBuffer) {
+        let sets = input_sets.iter().map(|set| &set.desc_set);
+        unsafe { command_buffer.cmd.bind_compute_descriptor_sets(&self.layout.layout, 0, sets, dynamic_offsets) };
+    }
+
+    /// Pushes constants to the start of the push constant range of the shader.
+    pub fn push_constants<T: Copy>(&self, constants: &T, command_buffer: &mut command::CommandBuffer) {
+        let words = constant_words(constants);
+        unsafe { command_buffer.cmd.push_compute_constants(&self.layout.layout, 0, &words) };
+    }
+
+}
+
+impl Drop for ComputePipeline {
+
+    fn drop(&mut self) {
+        use std::mem;
+        unsafe {
+            self.device_token.gpu.destroy_compute_pipeline(mem::transmute_copy(&self.pipeline));
+            self.device_token.gpu.destroy_pipeline_layout(mem::transmute_copy(&self.layout.layout));
+        }
+    }
+
+}
+
+/// Copies constants into the 4 byte words push constants are written in.
+/// Constants whose size is not a multiple of 4 are padded with zeros, and the copy means they do not need to be aligned to 4 bytes.
+fn constant_words<T: Copy>(constants: &T) -> Vec<u32> {
+    let size = std::mem::size_of::<T>();
+    let mut words = vec![0u32; size / 4];
+    if words.len() * 4 < size {
+        words.push(0);
+    }
+    unsafe { std::ptr::copy_nonoverlapping(constants as *const T as *const u8, words.as_mut_ptr() as *mut u8, size) };
+    return words;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn bytes(words: &[u32]) -> Vec<u8> {
+        return unsafe { std::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 4) }.to_vec();
+    }
+
+    #[test]
+    fn constants_are_copied_into_words() {
+        let words = constant_words(&[1u32, 2, 3]);
+        assert_eq!(words, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn constants_are_padded_to_whole_words() {
+        assert_eq!(bytes(&constant_words(&[1u8, 2, 3, 4, 5, 6])), [1, 2, 3, 4, 5, 6, 0, 0]);
+        assert_eq!(bytes(&constant_words(&7u8)), [7, 0, 0, 0]);
+    }
+
+}
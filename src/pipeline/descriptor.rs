@@ -0,0 +1,291 @@
+use crate::*;
+use pipeline::*;
+
+use gfx::Device as GfxDevice;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// The bookkeeping of the sets of a single layout: how many sets the pools can still hand out and which released sets can be recycled.
+/// This is kept apart from the device objects so that the pool growth and the recycling delay can be tested without a device.
+struct SetBookkeeping<S> {
+
+    /// The number of pools which have been created.
+    pool_count: usize,
+    /// The number of sets which can still be allocated from the last pool.
+    remaining: usize,
+    /// Sets which have been released and are no longer used by any frame in flight.
+    free: Vec<S>,
+    /// Sets which have been released, along with the frame they were released in.
+    pending: Vec<(u64, S)>,
+
+}
+
+impl<S> SetBookkeeping<S> {
+
+    fn new() -> Self {
+        return Self { pool_count: 0, remaining: 0, free: Vec::new(), pending: Vec::new() };
+    }
+
+    /// Each pool holds twice as many sets as the one before it, up to `DescriptorAllocator::MAX_POOL_SIZE`.
+    fn next_pool_size(&self) -> usize {
+        let size = DescriptorAllocator::INITIAL_POOL_SIZE << self.pool_count.min(16);
+        return size.min(DescriptorAllocator::MAX_POOL_SIZE);
+    }
+
+    /// Returns the size of the pool which must be created before another set can be allocated, if the last pool is full.
+    fn pool_needed(&self) -> Option<usize> {
+        if self.remaining == 0 {
+            return Some(self.next_pool_size());
+        }
+        return None;
+    }
+
+    fn pool_created(&mut self, size: usize) {
+        self.pool_count += 1;
+        self.remaining = size;
+    }
+
+    fn set_allocated(&mut self) {
+        self.remaining -= 1;
+    }
+
+    fn release(&mut self, frame: u64, set: S) {
+        self.pending.push((frame, set));
+    }
+
+    /// Makes the sets which were released before the frame specified available to be allocated again.
+    fn recycle(&mut self, recycle_before: u64) {
+        let mut i = 0;
+        while i < self.pending.len() {
+            if self.pending[i].0 < recycle_before {
+                let (_, set) = self.pending.swap_remove(i);
+                self.free.push(set);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.free.clear();
+        self.pending.clear();
+    }
+
+}
+
+/// A set released during a frame may be used by up to `frames_in_flight` frames, including that one, so it is recycled once they have all been waited for.
+fn recycle_before(frame: u64, frames_in_flight: usize) -> u64 {
+    return frame.saturating_sub(frames_in_flight as u64);
+}
+
+/// The pools and recycled sets of a single descriptor set layout.
+struct LayoutPools {
+
+    layout: Arc<DescriptorSetLayout>,
+    pools: Vec<DescriptorPool>,
+    sets: SetBookkeeping<DescriptorSet>,
+
+}
+
+impl LayoutPools {
+
+    fn new(layout: Arc<DescriptorSetLayout>) -> Self {
+        return Self { layout, pools: Vec::new(), sets: SetBookkeeping::new() };
+    }
+
+}
+
+struct AllocatorPools {
+
+    layouts: Vec<LayoutPools>,
+    frame: u64,
+    frames_in_flight: usize,
+
+    device_token: core::DeviceToken,
+
+}
+
+impl AllocatorPools {
+
+    fn layout_pools(&mut self, layout: &Arc<DescriptorSetLayout>) -> &mut LayoutPools {
+        let index = match self.layouts.iter().position(|pools| Arc::ptr_eq(&pools.layout, layout)) {
+            Some(index) => index,
+            None => {
+                self.layouts.push(LayoutPools::new(layout.clone()));
+                self.layouts.len() - 1
+            },
+        };
+        return &mut self.layouts[index];
+    }
+
+}
+
+impl Drop for AllocatorPools {
+
+    fn drop(&mut self) {
+        // Sets are freed along with their pool.
+        for layout_pools in self.layouts.iter_mut() {
+            layout_pools.sets.clear();
+            for pool in layout_pools.pools.drain(..) {
+                unsafe { self.device_token.gpu.destroy_descriptor_pool(pool.pool) };
+            }
+        }
+    }
+
+}
+
+/// Allocates descriptor sets from a growing list of pools for each layout, so that objects such as materials do not need a pool each.
+/// Sets are returned to the allocator when their `AllocatedDescriptorSet` is dropped, and are handed out again once no frame in flight can still be using them.
+/// `begin_frame` must be called once per frame for released sets to be recycled.
+/// The allocator is a handle which can be cloned, and its pools are destroyed once every clone and every set allocated from it have been dropped.
+#[derive(Clone)]
+pub struct DescriptorAllocator {
+
+    pools: Arc<Mutex<AllocatorPools>>,
+
+}
+
+impl DescriptorAllocator {
+
+    /// The number of sets in the first pool created for a layout.
+    pub const INITIAL_POOL_SIZE: usize = 16;
+    /// The maximum number of sets in a single pool.
+    pub const MAX_POOL_SIZE: usize = 1024;
+
+    /// Creates an allocator for sets which are used by frames up to `frames_in_flight` ahead of the device.
+    pub fn new(frames_in_flight: usize, device: &core::Device) -> Self {
+        let pools = AllocatorPools { layouts: Vec::new(), frame: 0, frames_in_flight, device_token: device.create_token() };
+        return Self { pools: Arc::new(Mutex::new(pools)) };
+    }
+
+    /// Allocates a set with the layout specified. The contents of a recycled set are left as they were, so every binding should be written before it is used.
+    pub fn allocate(&self, layout: &Arc<DescriptorSetLayout>, device: &core::Device) -> Result<AllocatedDescriptorSet, Error> {
+        let mut pools = self.pools.lock().unwrap();
+        let layout_pools = pools.layout_pools(layout);
+
+        if let Some(set) = layout_pools.sets.free.pop() {
+            return Ok(AllocatedDescriptorSet { set: Some(set), layout: layout.clone(), allocator: self.clone() });
+        }
+
+        if let Some(size) = layout_pools.sets.pool_needed() {
+            log!(debug, 4, "Creating a descriptor pool for {} sets.", size);
+            layout_pools.pools.push(DescriptorPool::new(size, &[(layout.as_ref(), size)], device)?);
+            layout_pools.sets.pool_created(size);
+        }
+
+        let pool = layout_pools.pools.last_mut().unwrap();
+        let set = DescriptorSet::new(layout.as_ref(), pool, device)?;
+        layout_pools.sets.set_allocated();
+        return Ok(AllocatedDescriptorSet { set: Some(set), layout: layout.clone(), allocator: self.clone() });
+    }
+
+    /// Moves to the next frame, recycling sets released by frames which have now completed.
+    pub fn begin_frame(&self) {
+        let mut pools = self.pools.lock().unwrap();
+        pools.frame += 1;
+        let recycle_before = recycle_before(pools.frame, pools.frames_in_flight);
+        for layout_pools in pools.layouts.iter_mut() {
+            layout_pools.sets.recycle(recycle_before);
+        }
+    }
+
+    /// The number of pools which have been created for the layout.
+    pub fn pool_count(&self, layout: &Arc<DescriptorSetLayout>) -> usize {
+        let pools = self.pools.lock().unwrap();
+        return pools.layouts.iter().find(|pools| Arc::ptr_eq(&pools.layout, layout)).map_or(0, |pools| pools.pools.len());
+    }
+
+    fn release(&self, layout: &Arc<DescriptorSetLayout>, set: DescriptorSet) {
+        let mut pools = self.pools.lock().unwrap();
+        let frame = pools.frame;
+        pools.layout_pools(layout).sets.release(frame, set);
+    }
+
+}
+
+/// A descriptor set which is returned to its `DescriptorAllocator` when dropped.
+pub struct AllocatedDescriptorSet {
+
+    set: Option<DescriptorSet>,
+    pub layout: Arc<DescriptorSetLayout>,
+    allocator: DescriptorAllocator,
+
+}
+
+impl std::ops::Deref for AllocatedDescriptorSet {
+
+    type Target = DescriptorSet;
+
+    fn deref(&self) -> &DescriptorSet {
+        return self.set.as_ref().unwrap();
+    }
+
+}
+
+impl std::ops::DerefMut for AllocatedDescriptorSet {
+
+    fn deref_mut(&mut self) -> &mut DescriptorSet {
+        return self.set.as_mut().unwrap();
+    }
+
+}
+
+impl Drop for AllocatedDescriptorSet {
+
+    fn drop(&mut self) {
+        if let Some(set) = self.set.take() {
+            self.allocator.release(&self.layout, set);
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn pools_double_in_size_up_to_the_maximum() {
+        let mut sets: SetBookkeeping<u32> = SetBookkeeping::new();
+        let mut sizes = Vec::new();
+        for _ in 0..9 {
+            let size = sets.pool_needed().unwrap();
+            sizes.push(size);
+            sets.pool_created(size);
+            for _ in 0..size {
+                assert!(sets.pool_needed().is_none());
+                sets.set_allocated();
+            }
+        }
+        assert_eq!(sizes, vec![16, 32, 64, 128, 256, 512, 1024, 1024, 1024]);
+    }
+
+    #[test]
+    fn released_sets_wait_for_the_frames_in_flight() {
+        let frames_in_flight = 2;
+        let mut sets: SetBookkeeping<u32> = SetBookkeeping::new();
+        sets.release(0, 7);
+        for frame in 1..=frames_in_flight as u64 {
+            sets.recycle(recycle_before(frame, frames_in_flight));
+            assert!(sets.free.is_empty(), "a set was recycled in frame {}", frame);
+        }
+        sets.recycle(recycle_before(frames_in_flight as u64 + 1, frames_in_flight));
+        assert_eq!(sets.free, vec![7]);
+        assert!(sets.pending.is_empty());
+    }
+
+    #[test]
+    fn only_sets_released_in_completed_frames_are_recycled() {
+        let mut sets: SetBookkeeping<u32> = SetBookkeeping::new();
+        sets.release(3, 1);
+        sets.release(5, 2);
+        sets.release(4, 3);
+        sets.recycle(recycle_before(7, 2));
+        sets.free.sort();
+        assert_eq!(sets.free, vec![1, 3]);
+        assert_eq!(sets.pending, vec![(5, 2)]);
+    }
+
+}
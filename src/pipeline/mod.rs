@@ -4,6 +4,18 @@ use gfx::Device as GfxDevice;
 use gfx::DescriptorPool as GfxDescriptorPool;
 use std::ops::Range;
 
+#[macro_use]
+pub mod vertex;
+pub use self::vertex::*;
+pub mod builder;
+pub use self::builder::*;
+pub mod compute;
+pub use self::compute::*;
+pub mod descriptor;
+pub use self::descriptor::*;
+
+pub use shader::ShaderStage;
+
 pub struct PipelineLayout {
 
     pub layout: <Backend as gfx::Backend>::PipelineLayout,
@@ -12,13 +24,13 @@ pub struct PipelineLayout {
 
 impl PipelineLayout {
 
-    pub fn create(input_layout: &[&DescriptorSetLayout], push_constant_ranges: &[(gfx::pso::ShaderStageFlags, Range<u32>)], device: &core::Device) -> PipelineLayout {
+    pub fn create(input_layout: &[&DescriptorSetLayout], push_constant_ranges: &[(gfx::pso::ShaderStageFlags, Range<u32>)], device: &core::Device) -> Result<PipelineLayout, Error> {
         let mut layouts: Vec<&<Backend as gfx::Backend>::DescriptorSetLayout> = Vec::with_capacity(input_layout.len());
         for il in input_layout {
             layouts.push(&il.set_layout);
         }
-        let layout = unsafe { device.gpu.create_pipeline_layout(layouts, push_constant_ranges).unwrap() };
-        return PipelineLayout { layout };
+        let layout = unsafe { device.gpu.create_pipeline_layout(layouts, push_constant_ranges)? };
+        return Ok(PipelineLayout { layout });
     }
 
     pub fn bind_descriptor_sets(&self, input_sets: &[&DescriptorSet], encoder: &mut command::Encoder) {
@@ -29,6 +41,15 @@ impl PipelineLayout {
         unsafe { encoder.pass.bind_graphics_descriptor_sets(&self.layout, 0, sets, &[]) };
     }
 
+    /// Binds the descriptor sets with an offset for each dynamic buffer binding, in binding order.
+    pub fn bind_descriptor_sets_dynamic(&self, input_sets: &[&DescriptorSet], dynamic_offsets: &[u32], encoder: &mut command::Encoder) {
+        let mut sets: Vec<&<Backend as gfx::Backend>::DescriptorSet> = Vec::with_capacity(input_sets.len());
+        for set in input_sets {
+            sets.push(&set.desc_set);
+        }
+        unsafe { encoder.pass.bind_graphics_descriptor_sets(&self.layout, 0, sets, dynamic_offsets) };
+    }
+
 }
 
 pub struct Pipeline {
@@ -39,20 +60,21 @@ pub struct Pipeline {
 
 impl Pipeline {
 
-    pub fn create(pipeline_desc: gfx::pso::GraphicsPipelineDesc<Backend>, device: &core::Device) -> Result<Pipeline, &'static str> {
+    pub fn create(pipeline_desc: gfx::pso::GraphicsPipelineDesc<Backend>, device: &core::Device) -> Result<Pipeline, Error> {
 
-        let pipeline = unsafe { device.gpu.create_graphics_pipeline(&pipeline_desc, None) };
-        if let Ok(graphics_pipeline) = pipeline {
-            return Ok(Pipeline { graphics_pipeline });
-        } else {
-            return Err("Failed to create graphics pipeline.");
-        }
+        let graphics_pipeline = unsafe { device.gpu.create_graphics_pipeline(&pipeline_desc, device.raw_pipeline_cache()) }?;
+        return Ok(Pipeline { graphics_pipeline });
     }
 
     pub fn bind(&self, command_buffer: &mut command::CommandBuffer) {
         unsafe { command_buffer.cmd.bind_graphics_pipeline(&self.graphics_pipeline) };
     }
 
+    /// Binds the pipeline inside a render pass.
+    pub fn bind_encoder(&self, encoder: &mut command::Encoder) {
+        unsafe { encoder.pass.bind_graphics_pipeline(&self.graphics_pipeline) };
+    }
+
 }
 
 pub struct PipelineController {
@@ -72,10 +94,26 @@ impl PipelineController {
         self.layout.bind_descriptor_sets(input_sets, encoder);
     }
 
+    pub fn bind_descriptor_sets_dynamic(&self, input_sets: &[&DescriptorSet], dynamic_offsets: &[u32], encoder: &mut command::Encoder) {
+        self.layout.bind_descriptor_sets_dynamic(input_sets, dynamic_offsets, encoder);
+    }
+
     pub fn bind(&self, command_buffer: &mut command::CommandBuffer) {
         self.pipeline.bind(command_buffer);
     }
 
+    pub fn bind_encoder(&self, encoder: &mut command::Encoder) {
+        self.pipeline.bind_encoder(encoder);
+    }
+
+    /// Destroys the pipeline and its layout. The device must not be using the pipeline.
+    pub fn destroy(self, device: &core::Device) {
+        unsafe {
+            device.gpu.destroy_graphics_pipeline(self.pipeline.graphics_pipeline);
+            device.gpu.destroy_pipeline_layout(self.layout.layout);
+        }
+    }
+
 }
 
 
@@ -86,17 +124,25 @@ pub struct VertexInput<'a> {
 
 }
 
-pub enum ShaderStage {
+impl ShaderStage {
 
-    Vertex,
-    Fragment,
+    pub fn to_flags(&self) -> gfx::pso::ShaderStageFlags {
+        match self {
+            ShaderStage::Vertex => gfx::pso::ShaderStageFlags::VERTEX,
+            ShaderStage::Fragment => gfx::pso::ShaderStageFlags::FRAGMENT,
+            ShaderStage::Geometry => gfx::pso::ShaderStageFlags::GEOMETRY,
+            ShaderStage::TessellationControl => gfx::pso::ShaderStageFlags::HULL,
+            ShaderStage::TessellationEvaluation => gfx::pso::ShaderStageFlags::DOMAIN,
+            ShaderStage::Compute => gfx::pso::ShaderStageFlags::COMPUTE,
+        }
+    }
 
 }
 
 pub struct DescriptorSetLayout {
 
     pub set_layout: <Backend as gfx::Backend>::DescriptorSetLayout,
-    pub bindings: Vec<gfx::pso::DescriptorType>,
+    pub bindings: Vec<gfx::pso::DescriptorSetLayoutBinding>,
 
 }
 
@@ -104,19 +150,14 @@ impl DescriptorSetLayout {
 
     /// There must be the same number of shader inputs specified as in the actual descriptor set in the shader.
     /// If the data is not yet initialized, this can easily be specified - all that matters is the correct layout data is supplied.
-    pub fn create(inputs: &[(&ShaderInput, ShaderStage)], device: &core::Device) -> DescriptorSetLayout {
+    pub fn create(inputs: &[(&ShaderInput, ShaderStage)], device: &core::Device) -> Result<DescriptorSetLayout, Error> {
         let mut binding_data: Vec<_> = Vec::with_capacity(inputs.len());
-        let mut bindings: Vec<gfx::pso::DescriptorType> = Vec::with_capacity(inputs.len());
 
         let mut i = 0;
 
         for input in inputs.iter() {
             let binding_type = input.0.get_binding_type();
-            bindings.push(binding_type);
-            let stage_flags = match input.1 {
-                ShaderStage::Vertex => gfx::pso::ShaderStageFlags::VERTEX,
-                ShaderStage::Fragment => gfx::pso::ShaderStageFlags::FRAGMENT,
-            };
+            let stage_flags = input.1.to_flags();
             binding_data.push(gfx::pso::DescriptorSetLayoutBinding {
                 binding: i,
                 ty: binding_type,
@@ -127,12 +168,16 @@ impl DescriptorSetLayout {
             i += 1;
         }
 
-        // TODO: what is a descriptor set, what is the layout?
+        return Self::create_raw(binding_data, device);
+    }
+
+    /// Creates a layout from raw bindings, which may be sparse. This is used for layouts reflected from shaders.
+    pub fn create_raw(bindings: Vec<gfx::pso::DescriptorSetLayoutBinding>, device: &core::Device) -> Result<DescriptorSetLayout, Error> {
         let set_layout = unsafe { device.gpu.create_descriptor_set_layout(
-            &binding_data,
+            &bindings,
             &[],
-        ).unwrap() };
-        return DescriptorSetLayout { set_layout, bindings };
+        )? };
+        return Ok(DescriptorSetLayout { set_layout, bindings });
     }
 
 }
@@ -145,22 +190,22 @@ pub struct DescriptorPool {
 
 impl DescriptorPool {
 
-    pub fn new(max_sets: usize, input_layouts: &[(&DescriptorSetLayout, usize)], device: &core::Device) -> DescriptorPool {
+    pub fn new(max_sets: usize, input_layouts: &[(&DescriptorSetLayout, usize)], device: &core::Device) -> Result<DescriptorPool, Error> {
         let mut desc_ranges: Vec<_> = Vec::new();
         for input_layout in input_layouts {
             for binding in input_layout.0.bindings.iter() {
                 desc_ranges.push(gfx::pso::DescriptorRangeDesc {
-                    ty: *binding,
-                    count: input_layout.1,
+                    ty: binding.ty,
+                    count: binding.count * input_layout.1,
                 });
             }
         }
 
-        let mut pool = unsafe { device.gpu.create_descriptor_pool(
+        let pool = unsafe { device.gpu.create_descriptor_pool(
             max_sets,
             &desc_ranges,
-        ).unwrap() };
-        return DescriptorPool { pool };
+        )? };
+        return Ok(DescriptorPool { pool });
     }
 
 }
@@ -178,18 +223,15 @@ impl DescriptorSet {
 
     /// Creates a new descriptor set object.
     /// The layout of the set needs to be provided in order to create the actual descriptor set.
-    pub fn new(input_layout: &DescriptorSetLayout, descriptor_pool: &mut DescriptorPool, device: &core::Device) -> Result<DescriptorSet, &'static str> {
+    pub fn new(input_layout: &DescriptorSetLayout, descriptor_pool: &mut DescriptorPool, device: &core::Device) -> Result<DescriptorSet, Error> {
 
-        if let Ok(desc_set) = unsafe { descriptor_pool.pool.allocate_set(&input_layout.set_layout) } {
-            return Ok(DescriptorSet { desc_set });
-        } else {
-             return Err("Failed to create descriptor set. Perhaps the descriptor pool was not initialized properly?");
-        }
+        let desc_set = unsafe { descriptor_pool.pool.allocate_set(&input_layout.set_layout) }?;
+        return Ok(DescriptorSet { desc_set });
 
     }
 
     /// The inputs specified should have their corresponding bindings as the second argument of the tuple. This means that not all the inputs need to be initialized.
-    pub fn with_inputs(inputs: &[(&ShaderInput, u32)], input_layout: &DescriptorSetLayout, descriptor_pool: &mut DescriptorPool, device: &core::Device) -> Result<DescriptorSet, &'static str> {
+    pub fn with_inputs(inputs: &[(&ShaderInput, u32)], input_layout: &DescriptorSetLayout, descriptor_pool: &mut DescriptorPool, device: &core::Device) -> Result<DescriptorSet, Error> {
 
         let mut descriptor_set: DescriptorSet = Self::new(input_layout, descriptor_pool, device)?;
         {
@@ -326,6 +368,30 @@ impl ShaderInputDescriptor {
 
     }
 
+    pub fn dynamic_uniform_buffer_descriptor() -> ShaderInputDescriptor {
+
+        return ShaderInputDescriptor {
+            binding_type: gfx::pso::DescriptorType::UniformBufferDynamic,
+        }
+
+    }
+
+    pub fn storage_buffer_descriptor() -> ShaderInputDescriptor {
+
+        return ShaderInputDescriptor {
+            binding_type: gfx::pso::DescriptorType::StorageBuffer,
+        }
+
+    }
+
+    pub fn storage_image_descriptor() -> ShaderInputDescriptor {
+
+        return ShaderInputDescriptor {
+            binding_type: gfx::pso::DescriptorType::StorageImage,
+        }
+
+    }
+
 }
 
 impl ShaderInput for ShaderInputDescriptor {
@@ -347,9 +413,18 @@ pub struct TextureSampler {
 
 impl TextureSampler {
 
-    pub fn new(device: &core::Device) -> TextureSampler {
-        let sampler = unsafe { device.gpu.create_sampler(gfx::image::SamplerInfo::new(gfx::image::Filter::Linear, gfx::image::WrapMode::Tile)).unwrap() };
-        return TextureSampler { sampler };
+    pub fn new(device: &core::Device) -> Result<TextureSampler, Error> {
+        let sampler = unsafe { device.gpu.create_sampler(gfx::image::SamplerInfo::new(gfx::image::Filter::Linear, gfx::image::WrapMode::Tile))? };
+        return Ok(TextureSampler { sampler });
+    }
+
+    /// Creates a sampler which compares sampled depths with a reference depth, as used to sample shadow maps.
+    /// Linear filtering blends the results of the comparisons of neighbouring texels.
+    pub fn comparison(device: &core::Device) -> Result<TextureSampler, Error> {
+        let mut info = gfx::image::SamplerInfo::new(gfx::image::Filter::Linear, gfx::image::WrapMode::Clamp);
+        info.comparison = Some(gfx::pso::Comparison::LessEqual);
+        let sampler = unsafe { device.gpu.create_sampler(info)? };
+        return Ok(TextureSampler { sampler });
     }
 
 }
@@ -0,0 +1,192 @@
+use crate::*;
+
+/// A single vertex attribute, read by the shader from the specified location.
+#[derive(Clone, Copy, Debug)]
+pub struct VertexAttribute {
+
+    pub location: u32,
+    pub format: gfx::format::Format,
+    /// The offset of the attribute from the start of the vertex, in bytes.
+    pub offset: u32,
+
+}
+
+/// Describes how vertices are laid out in a vertex buffer.
+#[derive(Clone, Debug)]
+pub struct VertexLayout {
+
+    /// The size of a single vertex, in bytes.
+    pub stride: u32,
+    pub attributes: Vec<VertexAttribute>,
+    /// True if the buffer advances once per instance rather than once per vertex.
+    pub per_instance: bool,
+
+}
+
+impl VertexLayout {
+
+    pub fn new(stride: u32) -> Self {
+        return Self { stride, attributes: Vec::new(), per_instance: false };
+    }
+
+    pub fn with_attribute(mut self, location: u32, format: gfx::format::Format, offset: u32) -> Self {
+        self.attributes.push(VertexAttribute { location, format, offset });
+        return self;
+    }
+
+    pub fn per_instance(mut self) -> Self {
+        self.per_instance = true;
+        return self;
+    }
+
+}
+
+/// A type which can be read by a shader as a single vertex attribute.
+pub trait AttributeFormat {
+
+    const FORMAT: gfx::format::Format;
+
+}
+
+macro_rules! attribute_format {
+    ($ty:ty, $format:ident) => {
+        impl AttributeFormat for $ty {
+            const FORMAT: gfx::format::Format = gfx::format::Format::$format;
+        }
+    };
+}
+
+attribute_format!(f32, R32Float);
+attribute_format!(Vector2f, Rg32Float);
+attribute_format!(Vector3f, Rgb32Float);
+attribute_format!(Vector4f, Rgba32Float);
+attribute_format!(i32, R32Int);
+attribute_format!(Vector2i, Rg32Int);
+attribute_format!(Vector3i, Rgb32Int);
+attribute_format!(Vector4i, Rgba32Int);
+attribute_format!(u32, R32Uint);
+attribute_format!(Vector2u, Rg32Uint);
+attribute_format!(Vector3u, Rgb32Uint);
+attribute_format!(Vector4u, Rgba32Uint);
+
+/// Gets the format of a vertex attribute from a reference to it. This is used by `vertex_format!`.
+pub fn attribute_format_of<T: AttributeFormat>(_attribute: &T) -> gfx::format::Format {
+    return T::FORMAT;
+}
+
+/// A `#[repr(C)]` vertex type whose attributes can be read by a vertex shader.
+/// This should be implemented with `vertex_format!`, which computes the offsets and formats of the attributes from the struct itself.
+pub trait VertexFormat: Copy {
+
+    /// The attributes of the vertex, with the shader locations they are read from.
+    fn attributes() -> Vec<VertexAttribute>;
+
+    /// The layout of a vertex buffer which contains this type of vertex.
+    fn vertex_layout() -> VertexLayout {
+        let mut layout = VertexLayout::new(std::mem::size_of::<Self>() as u32);
+        layout.attributes = Self::attributes();
+        return layout;
+    }
+
+}
+
+/// Implements `VertexFormat` for a `#[repr(C)]` vertex struct by mapping shader locations to its fields.
+/// The offset and format of each attribute are taken from the field, so they cannot drift from the struct definition.
+/// Every field must be mapped, in the order the fields are declared. The first call to `attributes` panics if the fields are not laid out as they would be by `#[repr(C)]`.
+///
+/// ```ignore
+/// vertex_format!(UvVertex2f { 0 => pos, 1 => uv });
+/// ```
+#[macro_export]
+macro_rules! vertex_format {
+    ($vertex:ty { $($location:expr => $field:ident),* $(,)* }) => {
+        impl $crate::pipeline::VertexFormat for $vertex {
+            fn attributes() -> Vec<$crate::pipeline::VertexAttribute> {
+                // Vertices are plain data, so a zeroed vertex is valid and can be used to find the field offsets.
+                let vertex: $vertex = unsafe { std::mem::zeroed() };
+                let base = &vertex as *const $vertex as usize;
+                // The offset, size and alignment of each field.
+                let fields: Vec<(usize, usize, usize)> = vec![$(
+                    (&vertex.$field as *const _ as usize - base, std::mem::size_of_val(&vertex.$field), std::mem::align_of_val(&vertex.$field))
+                ),*];
+                $crate::pipeline::assert_c_layout(stringify!($vertex), &fields, std::mem::size_of::<$vertex>(), std::mem::align_of::<$vertex>());
+                return vec![$(
+                    $crate::pipeline::VertexAttribute {
+                        location: $location,
+                        format: $crate::pipeline::attribute_format_of(&vertex.$field),
+                        offset: (&vertex.$field as *const _ as usize - base) as u32,
+                    }
+                ),*];
+            }
+        }
+    };
+}
+
+/// Panics unless the fields, given as `(offset, size, alignment)` in declaration order, make up a struct laid out as `#[repr(C)]` would lay it out. This is used by `vertex_format!`.
+pub fn assert_c_layout(name: &str, fields: &[(usize, usize, usize)], size: usize, align: usize) {
+    let mut end = 0;
+    for (index, (offset, field_size, field_align)) in fields.iter().enumerate() {
+        let expected = (end + field_align - 1) / field_align * field_align;
+        assert_eq!(*offset, expected, "Field {} of vertex type {} is not at its `#[repr(C)]` offset. Vertex types must be `#[repr(C)]` and map every field in declaration order.", index, name);
+        end = offset + field_size;
+    }
+    assert_eq!(size, (end + align - 1) / align * align, "Vertex type {} has fields which are not mapped to attributes.", name);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use gfx::format::Format;
+
+    fn layout<V: VertexFormat>() -> (u32, Vec<(u32, Format, u32)>) {
+        let layout = V::vertex_layout();
+        return (layout.stride, layout.attributes.iter().map(|a| (a.location, a.format, a.offset)).collect());
+    }
+
+    #[test]
+    fn model_vertex_layout() {
+        assert_eq!(layout::<spatial::model::ModelVertex>(), (64, vec![
+            (0, Format::Rgb32Float, 0),
+            (1, Format::Rgb32Float, 12),
+            (2, Format::Rg32Float, 24),
+            (3, Format::Rgba32Int, 32),
+            (4, Format::Rgba32Float, 48),
+        ]));
+    }
+
+    #[test]
+    fn uv_vertex_layouts() {
+        assert_eq!(layout::<texture::UvVertex3f>(), (20, vec![(0, Format::Rgb32Float, 0), (1, Format::Rg32Float, 12)]));
+        assert_eq!(layout::<texture::UvVertex2f>(), (16, vec![(0, Format::Rg32Float, 0), (1, Format::Rg32Float, 8)]));
+    }
+
+    #[test]
+    fn instance_data_layout() {
+        assert_eq!(layout::<spatial::pipe::mesh::InstanceData>(), (64, vec![
+            (5, Format::Rgba32Float, 0),
+            (6, Format::Rgba32Float, 16),
+            (7, Format::Rgba32Float, 32),
+            (8, Format::Rgba32Float, 48),
+        ]));
+    }
+
+    #[test]
+    fn c_layouts_are_accepted() {
+        assert_c_layout("Padded", &[(0, 4, 4), (8, 8, 8), (16, 2, 2)], 24, 8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn reordered_fields_are_rejected() {
+        assert_c_layout("Reordered", &[(8, 4, 4), (0, 8, 8)], 16, 8);
+    }
+
+    #[test]
+    #[should_panic]
+    fn unmapped_fields_are_rejected() {
+        assert_c_layout("Unmapped", &[(0, 4, 4)], 8, 4);
+    }
+
+}
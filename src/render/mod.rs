@@ -10,36 +10,114 @@ use gfx::Device as GfxDevice;
 use gfx::Swapchain;
 use gfx::DescriptorPool;
 
+pub mod settings;
+pub use self::settings::*;
 
 
 pub type Framebuffer = <Backend as gfx::Backend>::Framebuffer;
 
+/// The images that a `Surface` renders into.
+pub enum SurfaceTarget {
+
+    /// Frames are rendered to the images of a window swapchain and presented to the screen.
+    Swapchain {
+        window_surface: window::WindowSurface,
+        /// This is `None` if rebuilding the swapchain failed, which destroys the previous swapchain, until it is rebuilt successfully.
+        swapchain: Option<<Backend as gfx::Backend>::Swapchain>,
+        backbuffer: Option<gfx::Backbuffer<Backend>>,
+        /// The swapchain images, which are taken from the backbuffer once framebuffers are first created.
+        images: Vec<<Backend as gfx::Backend>::Image>,
+        image_views: Vec<<Backend as gfx::Backend>::ImageView>,
+    },
+
+    /// Frames are rendered to offscreen color images which are never presented.
+    /// The images are cycled through in order, in the same way as swapchain images.
+    Offscreen {
+        images: Vec<buffer::TextureBuffer>,
+        next_image: usize,
+    },
+
+}
+
 pub struct Surface {
 
     pub is_valid: bool,
     pub did_rebuild: bool,
 
-    pub window_surface: window::WindowSurface,
+    pub target: SurfaceTarget,
 
-    pub swapchain: <Backend as gfx::Backend>::Swapchain,
-    pub backbuffer: Option<gfx::Backbuffer<Backend>>,
+    /// The index of the image which was last submitted for presentation.
+    pub last_image: Option<usize>,
 
     pub viewport: gfx::pso::Viewport,
     pub extent: gfx::image::Extent,
 
+    /// The settings the swapchain is built with.
+    pub settings: GraphicsSettings,
+
     device_token: core::DeviceToken,
 
 }
 
 impl Surface {
 
-    pub fn create(mut window_surface: window::WindowSurface, device: &mut core::Device) -> Surface {
+    /// The number of color images created for an offscreen surface.
+    pub const OFFSCREEN_IMAGE_COUNT: usize = 2;
 
-        let swap_config = gfx::SwapchainConfig::from_caps(&device.capabilites, device.color_format, gfx::window::Extent2D { width: window_surface.size.x as u32, height: window_surface.size.y as u32 });
+    /// Swapchain images can be copied from so that frames can be read back to the CPU.
+    fn swapchain_image_usage() -> gfx::image::Usage {
+        return gfx::image::Usage::COLOR_ATTACHMENT | gfx::image::Usage::TRANSFER_SRC;
+    }
+
+    pub fn create(mut window_surface: window::WindowSurface, settings: GraphicsSettings, device: &mut core::Device) -> Result<Surface, Error> {
+
+        if device.is_headless() {
+            return Err(Error::Render(String::from("Cannot create a swapchain surface using a headless device.")));
+        }
+        let (caps, _, present_modes, _) = window_surface.surface.compatibility(&device.adapter.physical_device);
+        let swap_config = settings.swapchain_config(&caps, &present_modes, device.color_format, window_surface.size)
+            .with_image_usage(Self::swapchain_image_usage());
 
         let extent = swap_config.extent.to_extent();
 
-        let viewport = gfx::pso::Viewport {
+        let viewport = Self::create_viewport(extent);
+
+        let (swapchain, backbuffer) = unsafe { device.gpu.create_swapchain(&mut window_surface.surface, swap_config, None) }?;
+
+        let target = SurfaceTarget::Swapchain { window_surface, swapchain: Some(swapchain), backbuffer: Some(backbuffer), images: Vec::new(), image_views: Vec::new() };
+
+        return Ok(Surface { is_valid: true, did_rebuild: false, target, last_image: None, viewport, extent, settings, device_token: device.create_token() });
+
+    }
+
+    /// Creates a surface which renders into offscreen color images of the specified size instead of a swapchain.
+    pub fn create_offscreen(size: Vector2u, device: &mut core::Device) -> Result<Surface, Error> {
+
+        let extent = gfx::image::Extent { width: size.x, height: size.y, depth: 1 };
+
+        let viewport = Self::create_viewport(extent);
+
+        let mut images: Vec<buffer::TextureBuffer> = Vec::with_capacity(Self::OFFSCREEN_IMAGE_COUNT);
+        for _ in 0..Self::OFFSCREEN_IMAGE_COUNT {
+            images.push(buffer::TextureBuffer::new(
+                size,
+                device.color_format,
+                gfx::image::Usage::COLOR_ATTACHMENT | gfx::image::Usage::TRANSFER_SRC,
+                gfx::format::Aspects::COLOR,
+                device,
+            )?);
+        }
+
+        let target = SurfaceTarget::Offscreen { images, next_image: 0 };
+
+        let settings = GraphicsSettings::new().with_size(size);
+
+        return Ok(Surface { is_valid: true, did_rebuild: false, target, last_image: None, viewport, extent, settings, device_token: device.create_token() });
+
+    }
+
+    pub fn create_viewport(extent: gfx::image::Extent) -> gfx::pso::Viewport {
+        return gfx::pso::Viewport {
             rect: gfx::pso::Rect {
                 x: 0 as i16,
                 y: 0 as i16,
@@ -48,66 +126,148 @@ impl Surface {
             },
             depth: (0.0 as f32)..(1.0 as f32),
         };
-
-        let (swapchain, backbuffer) = unsafe { device.gpu.create_swapchain(&mut window_surface.surface, swap_config, None).unwrap() };
-
-        return Surface { is_valid: true, did_rebuild: false, window_surface, swapchain, backbuffer: Some(backbuffer), viewport, extent, device_token: device.create_token() }
-
     }
 
-    pub fn create_framebuffers(&mut self, render_pass: &RenderPass, depth: Option<gfx::format::Format>, device: &core::Device) -> Vec<Framebuffer> {
-
-        let mut depth_view: Option<buffer::TextureBuffer> = None;
-
-        if let Some(depth_format) = depth {
-            depth_view = Some(buffer::TextureBuffer::create_depth(Vector2u::new(self.extent.width, self.extent.height), depth_format, &device));
+    /// Returns true if this surface renders into offscreen images rather than a swapchain.
+    pub fn is_offscreen(&self) -> bool {
+        if let SurfaceTarget::Offscreen { .. } = self.target {
+            return true;
         }
+        return false;
+    }
 
-        unsafe {
-            if let Some(backbuffer) = self.backbuffer.take() {
-                let (frame_views, framebuffers) = match backbuffer {
-                    gfx::Backbuffer::Images(images) => {
+    /// Takes the images out of the swapchain backbuffer and creates a view of each, if this has not been done since the swapchain was last built.
+    /// Offscreen images already have views, so this does nothing for offscreen surfaces.
+    /// If a view cannot be created, the images are kept in the backbuffer so that this can be retried.
+    pub fn prepare_images(&mut self, device: &core::Device) -> Result<(), Error> {
+        if let SurfaceTarget::Swapchain { backbuffer, images, image_views, .. } = &mut self.target {
+            if let Some(backbuffer) = backbuffer.take() {
+                match backbuffer {
+                    gfx::Backbuffer::Images(backbuffer_images) => {
                         let color_range = gfx::image::SubresourceRange {
                             aspects: gfx::format::Aspects::COLOR,
                             levels: 0..1,
                             layers: 0..1,
                         };
 
-                        let image_views = images
-                            .iter()
-                            .map(|image| {
-                                device.gpu
-                                    .create_image_view(
-                                        image,
-                                        gfx::image::ViewKind::D2,
-                                        device.color_format,
-                                        gfx::format::Swizzle::NO,
-                                        color_range.clone(),
-                                    ).unwrap()
-                            }).collect::<Vec<_>>();
-
-                        let fbos: Vec<Framebuffer> = image_views
-                            .iter()
-                            .map(|image_view| {
-                                let attachments: Vec<&<Backend as gfx::Backend>::ImageView>;
-                                if let Some(depth) = depth_view.as_ref() {
-                                    attachments = vec![image_view, &depth.image_view];
-                                } else {
-                                    attachments = vec![image_view];
-                                }
-                                device.gpu
-                                    .create_framebuffer(&render_pass.raw_render_pass, attachments, self.extent)
-                                    .unwrap()
-                            }).collect();
-
-                        (image_views, fbos)
+                        let mut views: Vec<<Backend as gfx::Backend>::ImageView> = Vec::with_capacity(backbuffer_images.len());
+                        for image in backbuffer_images.iter() {
+                            let view = unsafe {
+                                device.gpu.create_image_view(
+                                    image,
+                                    gfx::image::ViewKind::D2,
+                                    device.color_format,
+                                    gfx::format::Swizzle::NO,
+                                    color_range.clone(),
+                                )
+                            };
+                            match view {
+                                Ok(view) => views.push(view),
+                                Err(e) => {
+                                    for view in views.drain(..) {
+                                        unsafe { device.gpu.destroy_image_view(view) };
+                                    }
+                                    *backbuffer = Some(gfx::Backbuffer::Images(backbuffer_images));
+                                    return Err(Error::Render(format!("Failed to create swapchain image view: {}", e)));
+                                },
+                            }
+                        }
+                        *image_views = views;
+                        *images = backbuffer_images;
+                    },
+                    gfx::Backbuffer::Framebuffer(_) => {
+                        return Err(Error::Render(String::from("Backends which provide a framebuffer instead of swapchain images are not supported.")));
+                    },
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// The number of images which are rendered to in turn. This is only valid once `prepare_images` has been called.
+    pub fn image_count(&self) -> usize {
+        match &self.target {
+            SurfaceTarget::Swapchain { images, .. } => images.len(),
+            SurfaceTarget::Offscreen { images, .. } => images.len(),
+        }
+    }
+
+    /// Gets the view of the image at the specified index, if it exists.
+    pub fn image_view(&self, index: usize) -> Option<&<Backend as gfx::Backend>::ImageView> {
+        match &self.target {
+            SurfaceTarget::Swapchain { image_views, .. } => image_views.get(index),
+            SurfaceTarget::Offscreen { images, .. } => images.get(index).map(|image| &image.image_view),
+        }
+    }
+
+    /// Creates a framebuffer for each image of the surface.
+    /// The depth buffer, if any, is shared between all framebuffers and must outlive them.
+    /// If a multisampled color buffer is given, it is rendered to in place of the surface image and resolved into it, which is then the last attachment.
+    pub fn create_framebuffers(&mut self, render_pass: &RenderPass, depth: Option<&buffer::TextureBuffer>, multisampled_color: Option<&buffer::TextureBuffer>, device: &core::Device) -> Result<Vec<Framebuffer>, Error> {
+
+        self.prepare_images(device)?;
+
+        let extent = self.extent;
+
+        let mut framebuffers: Vec<Framebuffer> = Vec::with_capacity(self.image_count());
+        for image_view in (0..self.image_count()).filter_map(|i| self.image_view(i)) {
+            let mut attachments: Vec<&<Backend as gfx::Backend>::ImageView> = Vec::with_capacity(3);
+            attachments.push(multisampled_color.map_or(image_view, |color| &color.image_view));
+            if let Some(depth) = depth {
+                attachments.push(&depth.image_view);
+            }
+            if multisampled_color.is_some() {
+                attachments.push(image_view);
+            }
+            match unsafe { device.gpu.create_framebuffer(&render_pass.raw_render_pass, attachments, extent) } {
+                Ok(framebuffer) => framebuffers.push(framebuffer),
+                Err(e) => {
+                    for framebuffer in framebuffers.drain(..) {
+                        unsafe { device.gpu.destroy_framebuffer(framebuffer) };
                     }
-                    gfx::Backbuffer::Framebuffer(fbo) => (Vec::new(), vec![fbo]),
-                };
-                return framebuffers;
+                    return Err(e.into());
+                },
             }
         }
-        return Vec::new();
+        return Ok(framebuffers);
+    }
+
+    /// Acquires the index of the next image to render to.
+    /// For swapchain surfaces, the semaphore is signalled once the image is ready to be rendered to.
+    /// Offscreen images are always ready, so the semaphore is not used.
+    pub fn acquire_image(&mut self, semaphore: &command::Semaphore) -> Option<usize> {
+        match &mut self.target {
+            SurfaceTarget::Swapchain { swapchain, .. } => {
+                let swapchain = swapchain.as_mut()?;
+                if let Ok(index) = unsafe { swapchain.acquire_image(!0, gfx::FrameSync::Semaphore(semaphore)) } {
+                    return Some(index as usize);
+                }
+                return None;
+            },
+            SurfaceTarget::Offscreen { images, next_image } => {
+                if images.is_empty() {
+                    return None;
+                }
+                let index = *next_image;
+                *next_image = (index + 1) % images.len();
+                return Some(index);
+            },
+        }
+    }
+
+    /// Presents the image at the specified index once the semaphore has been signalled.
+    /// This does nothing for offscreen surfaces.
+    pub fn present(&mut self, queue: &mut gfx::CommandQueue<Backend, gfx::General>, index: usize, semaphore: &command::Semaphore) -> bool {
+        self.last_image = Some(index);
+        if let SurfaceTarget::Swapchain { swapchain, .. } = &mut self.target {
+            let swapchain = match swapchain.as_mut() {
+                Some(swapchain) => swapchain,
+                None => return false,
+            };
+            let result = unsafe { swapchain.present(queue, index as u32, Some(semaphore)) };
+            return result.is_ok();
+        }
+        return true;
     }
 
     /// Makes the swapchain invalid so that we must rebuild it next frame.
@@ -115,41 +275,88 @@ impl Surface {
         self.is_valid = false;
     }
 
+    /// Changes the settings of the surface. The swapchain is rebuilt with them next frame.
+    pub fn apply_settings(&mut self, settings: GraphicsSettings) {
+        self.settings = settings;
+        self.invalidate();
+    }
+
     /// Rebuilds the swapchain data for this surface object.
-    pub fn rebuild(&mut self, window: &window::Window, device: &mut core::Device) {
-        self.destroy_swapchain();
-        self.window_surface.size = window.get_size();
-        let (caps, _, _, _) = self.window_surface.surface.compatibility(&device.adapter.physical_device);
-        let swap_config = gfx::SwapchainConfig::from_caps(&caps, device.color_format, gfx::window::Extent2D { width: self.window_surface.size.x as u32, height: self.window_surface.size.y as u32 });
-        let extent = swap_config.extent.to_extent();
+    /// Offscreen surfaces keep their images, so they are simply revalidated.
+    /// Use `rebuild_offscreen` to revalidate an offscreen surface without a window.
+    /// If the swapchain cannot be rebuilt the surface stays invalid, so that rebuilding is retried.
+    pub fn rebuild(&mut self, window: &window::Window, device: &mut core::Device) -> Result<(), Error> {
+        self.destroy_swapchain()?;
+        if let SurfaceTarget::Swapchain { window_surface, swapchain, backbuffer, images, image_views } = &mut self.target {
+            for image_view in image_views.drain(..) {
+                unsafe { device.gpu.destroy_image_view(image_view) };
+            }
+            // The images belong to the previous swapchain.
+            images.clear();
+            *backbuffer = None;
+            window_surface.size = window.get_size();
+            let (caps, formats, present_modes, _) = window_surface.surface.compatibility(&device.adapter.physical_device);
+            // Render graphs are recreated when they are next rebuilt if this changes the color format.
+            match core::choose_color_format(formats, self.settings.color_format) {
+                Ok(format) => device.color_format = format,
+                Err(e) => log!(err, "Failed to choose a color format, {:?} is still used: {}", device.color_format, e),
+            }
+            let swap_config = self.settings.swapchain_config(&caps, &present_modes, device.color_format, window_surface.size)
+                .with_image_usage(Self::swapchain_image_usage());
+            let extent = swap_config.extent.to_extent();
 
-        let viewport = gfx::pso::Viewport {
-            rect: gfx::pso::Rect {
-                x: 0 as i16,
-                y: 0 as i16,
-                w: extent.width as i16,
-                h: extent.height as i16,
-            },
-            depth: (0.0 as f32)..(1.0 as f32),
-        };
+            let viewport = Self::create_viewport(extent);
 
-        // We can use `transmute_copy` because we will not use it again.
-        let (swapchain, backbuffer) =  unsafe { device.gpu.create_swapchain(&mut self.window_surface.surface, swap_config, Some(mem::transmute_copy(&self.swapchain))).unwrap() };
+            // The previous swapchain is destroyed by the backend even if the new one cannot be created.
+            let (new_swapchain, new_backbuffer) = unsafe { device.gpu.create_swapchain(&mut window_surface.surface, swap_config, swapchain.take()) }?;
 
-        // Store the new stuff.
-       // swapchain_stuff = Some((swapchain, extent, frame_views, framebuffers));
-        self.swapchain = swapchain;
-        self.backbuffer = Some(backbuffer);
-        self.viewport = viewport;
-        self.extent = extent;
+            // Store the new stuff.
+            *swapchain = Some(new_swapchain);
+            *backbuffer = Some(new_backbuffer);
+            self.viewport = viewport;
+            self.extent = extent;
+        }
+
+        self.last_image = None;
 
         // Revalidate.
         self.is_valid = true;
         self.did_rebuild = true;
+        return Ok(());
+    }
+
+    /// Revalidates an offscreen surface after it has been invalidated, for example by `apply_settings`.
+    /// Offscreen images keep their size, so only the render graphs drawing into them are rebuilt.
+    pub fn rebuild_offscreen(&mut self) {
+        self.last_image = None;
+        self.is_valid = true;
+        self.did_rebuild = true;
+    }
+
+    /// Gets the image at the specified index, if it exists.
+    pub fn image(&self, index: usize) -> Option<&<Backend as gfx::Backend>::Image> {
+        match &self.target {
+            SurfaceTarget::Swapchain { images, .. } => images.get(index),
+            SurfaceTarget::Offscreen { images, .. } => images.get(index).map(|image| &image.image),
+        }
+    }
+
+    /// Copies the image which was last presented back to the CPU.
+    /// This waits for the device to be idle, so it should not be used every frame.
+    pub fn read_last_image(&self, device: &mut core::Device) -> Result<texture::Texture, Error> {
+        let image = self.last_image.and_then(|index| self.image(index))
+            .ok_or_else(|| Error::Render(String::from("Failed to read the last image - no frame has been rendered yet.")))?;
+        self.device_token.gpu.wait_idle()?;
+        let size = Vector2u::new(self.extent.width, self.extent.height);
+        let layout = device.present_layout();
+        let format = device.color_format;
+        return buffer::TextureBuffer::download_image(image, layout, size, format, device);
     }
 
-    pub fn destroy_swapchain(&mut self) {
-        self.device_token.gpu.wait_idle().expect("Failed to wait idle device!");
+    /// Waits for the device to finish using the swapchain so that it can be replaced.
+    pub fn destroy_swapchain(&mut self) -> Result<(), Error> {
+        self.device_token.gpu.wait_idle()?;
+        return Ok(());
     }
 
     pub fn get_size(&self) -> Vector2f {
@@ -161,7 +368,23 @@ impl Surface {
 impl Drop for Surface {
 
     fn drop(&mut self) {
-        unsafe { self.device_token.gpu.destroy_swapchain(mem::transmute_copy(&self.swapchain) ) };
+        match &self.target {
+            SurfaceTarget::Swapchain { swapchain, image_views, .. } => {
+                let _ = self.device_token.gpu.wait_idle();
+                unsafe {
+                    for image_view in image_views.iter() {
+                        self.device_token.gpu.destroy_image_view(mem::transmute_copy(image_view));
+                    }
+                    if let Some(swapchain) = swapchain {
+                        self.device_token.gpu.destroy_swapchain(mem::transmute_copy(swapchain));
+                    }
+                }
+            },
+            SurfaceTarget::Offscreen { .. } => {
+                // The offscreen images free themselves once the device is no longer using them.
+                let _ = self.device_token.gpu.wait_idle();
+            },
+        }
     }
 
 }
@@ -169,6 +392,8 @@ impl Drop for Surface {
 pub struct RenderPass {
 
     pub raw_render_pass: <Backend as gfx::Backend>::RenderPass,
+    /// The number of samples per pixel of the attachments which are rendered to. Pipelines used in the pass are created with the same number.
+    pub samples: u8,
 
 }
 
@@ -176,31 +401,55 @@ impl RenderPass {
 
     pub const STD_DEPTH_FORMAT: gfx::format::Format = gfx::format::Format::D32FloatS8Uint;
 
-    pub fn create_basic(device: &core::Device) -> Self {
+    pub fn create_basic(device: &core::Device) -> Result<Self, Error> {
+        return Self::create_multisampled(1, device);
+    }
+
+    /// Creates a render pass with a color and depth attachment which have the number of samples specified, clamped to what the device supports.
+    /// If there is more than one sample, the color attachment is resolved into a third attachment, which is the surface image.
+    /// Framebuffers for the pass can be created with `Surface::create_framebuffers`.
+    pub fn create_multisampled(samples: u8, device: &core::Device) -> Result<Self, Error> {
+
+        let samples = device.clamp_samples(samples);
+        let multisampled = samples > 1;
 
         let raw_render_pass = {
 
             let color_attachment = gfx::pass::Attachment {
                 format: Some(device.color_format),
-                samples: 1,
-                ops: gfx::pass::AttachmentOps::new(gfx::pass::AttachmentLoadOp::Clear, gfx::pass::AttachmentStoreOp::Store),
+                samples,
+                ops: gfx::pass::AttachmentOps::new(
+                    gfx::pass::AttachmentLoadOp::Clear,
+                    // Multisampled color is only needed until it has been resolved.
+                    if multisampled { gfx::pass::AttachmentStoreOp::DontCare } else { gfx::pass::AttachmentStoreOp::Store },
+                ),
                 stencil_ops: gfx::pass::AttachmentOps::DONT_CARE,
-                layouts: gfx::image::Layout::Undefined..gfx::image::Layout::Present,
+                layouts: gfx::image::Layout::Undefined..(if multisampled { gfx::image::Layout::ColorAttachmentOptimal } else { device.present_layout() }),
             };
 
             let depth_attachment = gfx::pass::Attachment {
                 format: Some(Self::STD_DEPTH_FORMAT),
-                samples: 1,
+                samples,
                 ops: gfx::pass::AttachmentOps::new(gfx::pass::AttachmentLoadOp::Clear, gfx::pass::AttachmentStoreOp::DontCare),
                 stencil_ops: gfx::pass::AttachmentOps::DONT_CARE,
                 layouts: gfx::image::Layout::Undefined..gfx::image::Layout::DepthStencilAttachmentOptimal,
             };
 
+            let resolve_attachment = gfx::pass::Attachment {
+                format: Some(device.color_format),
+                samples: 1,
+                ops: gfx::pass::AttachmentOps::new(gfx::pass::AttachmentLoadOp::DontCare, gfx::pass::AttachmentStoreOp::Store),
+                stencil_ops: gfx::pass::AttachmentOps::DONT_CARE,
+                layouts: gfx::image::Layout::Undefined..device.present_layout(),
+            };
+
+            let resolves: &[gfx::pass::AttachmentRef] = if multisampled { &[(2, gfx::image::Layout::ColorAttachmentOptimal)] } else { &[] };
+
             let subpass = gfx::pass::SubpassDesc {
                 colors: &[(0, gfx::image::Layout::ColorAttachmentOptimal)],
                 depth_stencil: Some(&(1, gfx::image::Layout::DepthStencilAttachmentOptimal)),
                 inputs: &[],
-                resolves: &[],
+                resolves,
                 preserves: &[],
             };
 
@@ -211,16 +460,24 @@ impl RenderPass {
                     ..(gfx::image::Access::COLOR_ATTACHMENT_READ | gfx::image::Access::COLOR_ATTACHMENT_WRITE),
             };
 
-            unsafe { device.gpu.create_render_pass(&[color_attachment, depth_attachment], &[subpass], &[dependency]).unwrap() }
+            if multisampled {
+                unsafe { device.gpu.create_render_pass(&[color_attachment, depth_attachment, resolve_attachment], &[subpass], &[dependency]) }?
+            } else {
+                unsafe { device.gpu.create_render_pass(&[color_attachment, depth_attachment], &[subpass], &[dependency]) }?
+            }
 
         };
 
-        return Self { raw_render_pass,  };
+        return Ok(Self { raw_render_pass, samples });
 
     }
 
     pub fn from_raw(raw: <Backend as gfx::Backend>::RenderPass) -> Self {
-        return Self { raw_render_pass: raw };
+        return Self::from_raw_multisampled(raw, 1);
+    }
+
+    pub fn from_raw_multisampled(raw: <Backend as gfx::Backend>::RenderPass, samples: u8) -> Self {
+        return Self { raw_render_pass: raw, samples };
     }
 
 }
@@ -239,14 +496,83 @@ pub struct Graphics {
 impl Graphics {
 
     /// Creates a new renderer object from the specified instance and window.
-    pub fn create(instance: &core::Instance, window: &window::Window) -> Self {
+    pub fn create(instance: &core::Instance, window: &window::Window) -> Result<Self, Error> {
+        return Self::create_with_adapter(instance, window, &core::AdapterPreference::default());
+    }
+
+    /// Creates a new renderer object using the adapter which best matches the preference.
+    pub fn create_with_adapter(instance: &core::Instance, window: &window::Window, preference: &core::AdapterPreference) -> Result<Self, Error> {
+        return Self::create_with_settings(instance, window, preference, GraphicsSettings::default());
+    }
+
+    /// Creates a new renderer object using the adapter which best matches the preference, which presents with the settings specified.
+    pub fn create_with_settings(instance: &core::Instance, window: &window::Window, preference: &core::AdapterPreference, settings: GraphicsSettings) -> Result<Self, Error> {
 
         let mut window_surface: window::WindowSurface = window::WindowSurface::create(instance, window);
-        let mut device: core::Device = core::Device::create(instance, &window_surface);
-        let render_surface: Surface = Surface::create(window_surface, &mut device);
+        let mut device: core::Device = core::Device::create(instance, &window_surface, preference, settings.color_format)?;
+        let render_surface: Surface = Surface::create(window_surface, settings, &mut device)?;
+
+        return Ok(Self { device, render_surface });
+
+    }
+
+    /// Creates a new headless renderer object which renders into offscreen images of the specified size.
+    /// No window is needed, so this can be used on machines without a display.
+    pub fn create_headless(instance: &core::Instance, size: Vector2u, preference: &core::AdapterPreference) -> Result<Self, Error> {
 
-        return Self { device, render_surface };
+        let mut device: core::Device = core::Device::create_headless(instance, preference)?;
+        let render_surface: Surface = Surface::create_offscreen(size, &mut device)?;
 
+        return Ok(Self { device, render_surface });
+
+    }
+
+    /// Reads the last rendered frame back to the CPU.
+    /// This can be used for screenshots, thumbnails or image comparisons.
+    pub fn capture_frame(&mut self) -> Result<texture::Texture, Error> {
+        return self.render_surface.read_last_image(&mut self.device);
+    }
+
+    /// Captures the last rendered frame and saves it as a png file at the specified path.
+    pub fn save_screenshot(&mut self, path: &str) -> Result<(), Error> {
+        return self.capture_frame()?.save_png(path);
+    }
+
+    /// Returns true if this renderer renders offscreen rather than to a window.
+    pub fn is_headless(&self) -> bool {
+        return self.render_surface.is_offscreen();
+    }
+
+    pub fn settings(&self) -> &GraphicsSettings {
+        return &self.render_surface.settings;
+    }
+
+    /// Changes the presentation settings, which rebuilds the swapchain next frame.
+    /// The window is not changed, `AppInterface::apply_settings` should be used to change both.
+    pub fn apply_settings(&mut self, settings: GraphicsSettings) {
+        self.render_surface.apply_settings(settings);
+    }
+
+    /// Rebuilds the surface if it has been invalidated, otherwise clears the rebuild flag of the previous frame.
+    /// This must be called once per frame: windowed surfaces are rebuilt for the window, headless surfaces pass `None`.
+    /// If the swapchain cannot be rebuilt the surface stays invalid and the error is returned, so rebuilding is retried next frame.
+    pub fn update(&mut self, window: Option<&window::Window>) -> Result<(), Error> {
+        if !self.render_surface.is_valid {
+            match window {
+                Some(window) => {
+                    log!(debug, 0, "Rebuilding swapchain.");
+                    self.render_surface.rebuild(window, &mut self.device)?;
+                },
+                None if self.render_surface.is_offscreen() => {
+                    self.render_surface.rebuild_offscreen();
+                    log!(debug, 0, "Revalidating offscreen surface.");
+                },
+                None => (),
+            }
+        } else if self.render_surface.did_rebuild {
+            self.render_surface.did_rebuild = false;
+        }
+        return Ok(());
     }
 }
 
@@ -271,7 +597,7 @@ impl RenderTransform {
         return RenderTransform { model, view, projection };
     }
 
-    pub fn alloc_buffer(self, device: &core::Device) -> buffer::Buffer {
+    pub fn alloc_buffer(self, device: &core::Device) -> Result<buffer::Buffer, Error> {
         return buffer::Buffer::alloc_uniform(&[self], device);
     }
 
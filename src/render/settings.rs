@@ -0,0 +1,285 @@
+use crate::*;
+
+/// How frames are queued for presentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresentMode {
+
+    /// Frames are presented in order at each vertical blank (vsync). This is supported by every device.
+    Fifo,
+    /// Frames are presented at each vertical blank, with newer frames replacing queued ones. This avoids tearing with less latency than `Fifo`.
+    Mailbox,
+    /// Frames are presented as soon as they are ready, which may tear.
+    Immediate,
+
+}
+
+impl PresentMode {
+
+    pub fn to_raw(&self) -> gfx::PresentMode {
+        match self {
+            PresentMode::Fifo => gfx::PresentMode::Fifo,
+            PresentMode::Mailbox => gfx::PresentMode::Mailbox,
+            PresentMode::Immediate => gfx::PresentMode::Immediate,
+        }
+    }
+
+}
+
+/// How the primary window is shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowMode {
+
+    /// A decorated window of the size in the settings. This is the default.
+    Windowed,
+    /// An undecorated window which is moved and resized to cover the monitor it is on, without making it fullscreen.
+    Borderless,
+    /// A fullscreen window on the primary monitor, which takes exclusive control of its display mode.
+    Exclusive,
+
+}
+
+/// Settings for the shadow maps of lights which cast shadows.
+/// The shadow maps are created with the render graph, so changing these rebuilds it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShadowSettings {
+
+    /// If false, no shadow maps are rendered and nothing is shadowed.
+    pub enabled: bool,
+    /// The size in pixels of each of the six faces of a point light's shadow map.
+    pub point_resolution: u32,
+    /// The size in pixels of each cascade of the directional light's shadow map.
+    pub directional_resolution: u32,
+    /// The number of point lights which can cast shadows at once, at most `MAX_POINT_SHADOWS`.
+    pub point_shadow_count: u32,
+    /// The number of cascades the view is split into for the directional light, at most `MAX_CASCADES`.
+    pub cascade_count: u32,
+    /// The distance from the camera up to which the directional light casts shadows.
+    pub directional_distance: f32,
+    /// The distance up to which point lights cast shadows.
+    pub point_range: f32,
+    /// The radius in texels of the percentage closer filter. A radius of 0 takes a single filtered sample.
+    pub pcf_radius: u32,
+    /// The depth which is subtracted from fragments before they are compared with the shadow map, to avoid self-shadowing.
+    pub depth_bias: f32,
+    /// The distance fragments are moved along their normal before they are compared with the shadow map, which increases at grazing angles.
+    pub normal_bias: f32,
+    /// The distance from the light which is subtracted from fragments before they are compared with a point light's shadow map.
+    /// This is in world units, as the depth of the perspective projection of point shadow maps is not linear and `depth_bias` would shadow distant fragments less.
+    pub point_depth_bias: f32,
+
+}
+
+impl ShadowSettings {
+
+    pub const MAX_POINT_SHADOWS: u32 = 4;
+    pub const MAX_CASCADES: u32 = 4;
+
+    pub fn new() -> Self {
+        return Self {
+            enabled: true,
+            point_resolution: 512,
+            directional_resolution: 2048,
+            point_shadow_count: 1,
+            cascade_count: 3,
+            directional_distance: 1000.0,
+            point_range: 1000.0,
+            pcf_radius: 1,
+            depth_bias: 0.0005,
+            normal_bias: 0.05,
+            point_depth_bias: 0.5,
+        };
+    }
+
+    /// Settings which render no shadow maps.
+    pub fn disabled() -> Self {
+        return Self { enabled: false, ..Self::new() };
+    }
+
+    pub fn with_point_shadows(mut self, count: u32, resolution: u32) -> Self {
+        self.point_shadow_count = count;
+        self.point_resolution = resolution;
+        return self;
+    }
+
+    pub fn with_cascades(mut self, count: u32, resolution: u32) -> Self {
+        self.cascade_count = count;
+        self.directional_resolution = resolution;
+        return self;
+    }
+
+    pub fn with_directional_distance(mut self, distance: f32) -> Self {
+        self.directional_distance = distance;
+        return self;
+    }
+
+    pub fn with_point_range(mut self, range: f32) -> Self {
+        self.point_range = range;
+        return self;
+    }
+
+    pub fn with_pcf_radius(mut self, pcf_radius: u32) -> Self {
+        self.pcf_radius = pcf_radius;
+        return self;
+    }
+
+    pub fn with_bias(mut self, depth_bias: f32, normal_bias: f32) -> Self {
+        self.depth_bias = depth_bias;
+        self.normal_bias = normal_bias;
+        return self;
+    }
+
+    pub fn with_point_bias(mut self, point_depth_bias: f32) -> Self {
+        self.point_depth_bias = point_depth_bias;
+        return self;
+    }
+
+    /// The near plane of the projection of each face of a point light's shadow map.
+    pub fn point_near(&self) -> f32 {
+        return self.point_range * 0.001;
+    }
+
+    /// The number of point lights which cast shadows, which is 0 if shadows are disabled.
+    pub fn point_shadows(&self) -> u32 {
+        return if self.enabled { self.point_shadow_count.min(Self::MAX_POINT_SHADOWS) } else { 0 };
+    }
+
+    /// The number of cascades of the directional light, which is 0 if shadows are disabled.
+    pub fn cascades(&self) -> u32 {
+        return if self.enabled { self.cascade_count.min(Self::MAX_CASCADES) } else { 0 };
+    }
+
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+/// Settings for presentation and the primary window, which are applied when the graphics are created and can be changed with `AppInterface::apply_settings`.
+/// Settings the device or surface do not support fall back to supported values, e.g. `Fifo` presentation.
+#[derive(Clone, Debug)]
+pub struct GraphicsSettings {
+
+    pub present_mode: PresentMode,
+    /// The number of swapchain images. The smallest number the surface supports is used if this is `None`.
+    pub image_count: Option<u32>,
+    /// The color format to use if the surface supports it.
+    /// Changing this recreates the render graph when the swapchain is rebuilt, along with the pipelines which render in it.
+    pub color_format: Option<gfx::format::Format>,
+    /// The number of samples per pixel used by multisampled render targets.
+    pub msaa_samples: u8,
+    pub window_mode: WindowMode,
+    /// The size of the window in windowed mode. The window system chooses the size if this is `None`.
+    pub size: Option<Vector2u>,
+    pub shadows: ShadowSettings,
+
+}
+
+impl GraphicsSettings {
+
+    pub fn new() -> Self {
+        return Self {
+            present_mode: PresentMode::Fifo,
+            image_count: None,
+            color_format: None,
+            msaa_samples: 1,
+            window_mode: WindowMode::Windowed,
+            size: None,
+            shadows: ShadowSettings::new(),
+        };
+    }
+
+    pub fn with_present_mode(mut self, present_mode: PresentMode) -> Self {
+        self.present_mode = present_mode;
+        return self;
+    }
+
+    /// Uses `Fifo` presentation if vsync is enabled, or `Immediate` presentation otherwise.
+    pub fn with_vsync(self, vsync: bool) -> Self {
+        return self.with_present_mode(if vsync { PresentMode::Fifo } else { PresentMode::Immediate });
+    }
+
+    pub fn with_image_count(mut self, image_count: u32) -> Self {
+        self.image_count = Some(image_count);
+        return self;
+    }
+
+    pub fn with_color_format(mut self, color_format: gfx::format::Format) -> Self {
+        self.color_format = Some(color_format);
+        return self;
+    }
+
+    pub fn with_msaa_samples(mut self, msaa_samples: u8) -> Self {
+        self.msaa_samples = msaa_samples;
+        return self;
+    }
+
+    pub fn with_window_mode(mut self, window_mode: WindowMode) -> Self {
+        self.window_mode = window_mode;
+        return self;
+    }
+
+    pub fn with_size(mut self, size: Vector2u) -> Self {
+        self.size = Some(size);
+        return self;
+    }
+
+    pub fn with_shadows(mut self, shadows: ShadowSettings) -> Self {
+        self.shadows = shadows;
+        return self;
+    }
+
+    /// Returns true if vertical blanks are waited for.
+    pub fn is_vsync(&self) -> bool {
+        return self.present_mode != PresentMode::Immediate;
+    }
+
+    /// Creates the swapchain configuration for a surface with the specified capabilities and supported present modes.
+    pub fn swapchain_config(&self, caps: &gfx::SurfaceCapabilities, present_modes: &[gfx::PresentMode], format: gfx::format::Format, size: Vector2f) -> gfx::SwapchainConfig {
+        let mut present_mode = self.present_mode.to_raw();
+        if !present_modes.contains(&present_mode) {
+            log!(debug, 1, "Present mode {:?} is not supported by the surface, using Fifo.", self.present_mode);
+            present_mode = gfx::PresentMode::Fifo;
+        }
+
+        let mut config = gfx::SwapchainConfig::from_caps(caps, format, gfx::window::Extent2D { width: size.x as u32, height: size.y as u32 })
+            .with_mode(present_mode);
+        if let Some(image_count) = self.image_count {
+            config.image_count = image_count.max(caps.image_count.start).min(caps.image_count.end);
+        }
+        return config;
+    }
+
+}
+
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn shadow_counts_are_clamped_to_the_maximum() {
+        let settings = ShadowSettings::new().with_point_shadows(100, 256).with_cascades(100, 1024);
+        assert_eq!(settings.point_shadows(), ShadowSettings::MAX_POINT_SHADOWS);
+        assert_eq!(settings.cascades(), ShadowSettings::MAX_CASCADES);
+
+        let settings = ShadowSettings::new().with_point_shadows(2, 256).with_cascades(0, 1024);
+        assert_eq!(settings.point_shadows(), 2);
+        assert_eq!(settings.cascades(), 0);
+    }
+
+    #[test]
+    fn disabled_shadows_have_no_maps() {
+        let settings = ShadowSettings::disabled().with_point_shadows(2, 256).with_cascades(3, 1024);
+        assert_eq!(settings.point_shadows(), 0);
+        assert_eq!(settings.cascades(), 0);
+    }
+
+}
@@ -139,7 +139,7 @@ pub trait Aggregator {
     fn build_entity(mut entity_builder: EntityBuilder) -> EntityBuilder where Self : Sized;
 
     /// Register resources and systems.
-    fn load<'a, 'b : 'a>(&mut self, graphics: &mut render::Graphics, dispatcher_builder: scene::DispatcherBuilder<'a, 'b>, world: &mut scene::World) -> scene::DispatcherBuilder<'a, 'b>;
+    fn load<'a, 'b : 'a>(&mut self, graphics: &mut render::Graphics, dispatcher_builder: scene::DispatcherBuilder<'a, 'b>, world: &mut scene::World) -> Result<scene::DispatcherBuilder<'a, 'b>, Error>;
 
     /// Update resources.
     /// Systems are automatically run.
@@ -158,15 +158,15 @@ impl<'a, 'b : 'a, A: Aggregator> Scene<'a, 'b, A>
           <<A as scene::Aggregator>::Node as specs::Component>::Storage: std::default::Default {
 
     /// Creates a new scene with all the systems registered.
-    pub fn create(mut aggregator: A, graphics: &mut render::Graphics) -> Self  {
+    pub fn create(mut aggregator: A, graphics: &mut render::Graphics) -> Result<Self, Error> {
         let mut world: World = World::new();
         Self::register_resources(&mut world);
         let mut dispatcher_builder = Self::register_systems(DispatcherBuilder::new());
-        let mut dispatcher_builder = aggregator.load(graphics, dispatcher_builder, &mut world);
+        let mut dispatcher_builder = aggregator.load(graphics, dispatcher_builder, &mut world)?;
         let mut dispatcher: Dispatcher = dispatcher_builder.build();
         // Now we start call ths `on_start` method on the systems.
         dispatcher.setup(&mut world.res);
-        return Self { aggregator, world, dispatcher };
+        return Ok(Self { aggregator, world, dispatcher });
     }
 
     fn register_resources(world: &mut World)  {
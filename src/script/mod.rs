@@ -28,11 +28,11 @@ impl<A: scene::Aggregator> ScriptController<A> {
     }
 
     /// Returns the exit code of the invoked script.
-    pub fn invoke(&mut self, script_id: &str, scene: &mut scene::Scene<A>) -> Result<u32, &'static str> {
+    pub fn invoke(&mut self, script_id: &str, scene: &mut scene::Scene<A>) -> Result<u32, Error> {
         if let Some(script) = self.scripts.get_mut(script_id) {
             return Ok(script.run(scene));
         }
-        return Err("Failed to invoke the script - no script found with the specified id.");
+        return Err(Error::Script(script_id.to_string()));
     }
 
 }
\ No newline at end of file
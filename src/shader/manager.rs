@@ -0,0 +1,313 @@
+use crate::*;
+use shader::*;
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+/// Compiles GLSL source to SPIR-V. The source should already have been preprocessed.
+#[cfg(feature = "runtime-shaders")]
+pub fn compile_glsl(source: &str, stage: ShaderStage) -> Result<Vec<u8>, Error> {
+    use std::io::Read;
+
+    let shader_type = match stage {
+        ShaderStage::Vertex => glsl_to_spirv::ShaderType::Vertex,
+        ShaderStage::Fragment => glsl_to_spirv::ShaderType::Fragment,
+        ShaderStage::Geometry => glsl_to_spirv::ShaderType::Geometry,
+        ShaderStage::TessellationControl => glsl_to_spirv::ShaderType::TessellationControl,
+        ShaderStage::TessellationEvaluation => glsl_to_spirv::ShaderType::TessellationEvaluation,
+        ShaderStage::Compute => glsl_to_spirv::ShaderType::Compute,
+    };
+    let mut compiled = glsl_to_spirv::compile(source, shader_type).map_err(Error::ShaderCompilation)?;
+    let mut bytes = Vec::new();
+    compiled.read_to_end(&mut bytes)?;
+    return Ok(bytes);
+}
+
+/// GLSL can only be compiled at runtime with the `runtime-shaders` feature, so this always fails.
+#[cfg(not(feature = "runtime-shaders"))]
+pub fn compile_glsl(_source: &str, _stage: ShaderStage) -> Result<Vec<u8>, Error> {
+    return Err(Error::ShaderCompilation(String::from("GLSL can only be compiled at runtime when the engine is built with the `runtime-shaders` feature.")));
+}
+
+/// Identifies a shader loaded by a `ShaderManager`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ShaderHandle(usize);
+
+struct ShaderEntry {
+
+    /// The file the shader is identified by. For embedded shaders this is their source file, whether or not it exists.
+    key: PathBuf,
+    /// The file the shader is loaded from. This is None if the shader only exists in the binary or its source is not watched.
+    path: Option<PathBuf>,
+    /// The variant defines the shader is compiled with.
+    defines: Vec<String>,
+    /// The files included by the shader, which are watched along with it.
+    includes: Vec<PathBuf>,
+    stage: ShaderStage,
+    spirv: Vec<u8>,
+    /// The latest modification time of the file and its includes when it was last loaded.
+    modified: Option<SystemTime>,
+    version: u32,
+
+}
+
+impl ShaderEntry {
+
+    fn is_variant(&self, path: &Path, defines: &[String]) -> bool {
+        return self.key.as_path() == path && self.defines.as_slice() == defines;
+    }
+
+    fn latest_modified(&self) -> Option<SystemTime> {
+        return self.path.iter().chain(self.includes.iter()).filter_map(|path| modified_time(path)).max();
+    }
+
+}
+
+/// Loads shaders from disk at runtime and reloads them when their files change.
+/// SPIR-V files (`.spv`) are used as they are and any other file is preprocessed and compiled as GLSL, which needs the `runtime-shaders` feature.
+/// The manager only holds the compiled code, pipelines create their shader modules from it and should be rebuilt when `poll_changes` reports one of their shaders.
+pub struct ShaderManager {
+
+    entries: Vec<ShaderEntry>,
+    /// The directory shader sources are looked up in by `load_or_embedded`.
+    pub source_dir: PathBuf,
+    pub preprocessor: Preprocessor,
+    /// Whether files are checked for changes by `poll_changes`. This is only enabled by default in debug builds.
+    pub hot_reload: bool,
+    /// The minimum time between checks for changes.
+    pub poll_interval: Duration,
+    last_poll: Instant,
+
+}
+
+impl ShaderManager {
+
+    pub const DEFAULT_SOURCE_DIR: &'static str = "shaders/src";
+    pub const DEFAULT_INCLUDE_DIR: &'static str = "shaders/include";
+
+    pub fn new<P: AsRef<Path>>(source_dir: P) -> Self {
+        return Self {
+            entries: Vec::new(),
+            source_dir: source_dir.as_ref().to_path_buf(),
+            preprocessor: Preprocessor::new().with_include_dir(Self::DEFAULT_INCLUDE_DIR),
+            hot_reload: cfg!(debug_assertions),
+            poll_interval: Duration::from_millis(500),
+            last_poll: Instant::now(),
+        };
+    }
+
+    /// Loads a shader file. Loading the same file twice returns the same handle.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<ShaderHandle, Error> {
+        return self.load_variant(path, &[]);
+    }
+
+    /// Loads a GLSL shader file compiled with the variant defines specified.
+    pub fn load_variant<P: AsRef<Path>>(&mut self, path: P, defines: &[&str]) -> Result<ShaderHandle, Error> {
+        let path = path.as_ref();
+        let defines: Vec<String> = defines.iter().map(|define| String::from(*define)).collect();
+        if let Some(index) = self.entries.iter().position(|entry| entry.is_variant(path, &defines)) {
+            return Ok(ShaderHandle(index));
+        }
+        let (spirv, stage, includes) = Self::read_shader(&self.preprocessor, path, &defines)?;
+        let mut entry = ShaderEntry { key: path.to_path_buf(), path: Some(path.to_path_buf()), defines, includes, stage, spirv, modified: None, version: 0 };
+        entry.modified = entry.latest_modified();
+        self.entries.push(entry);
+        return Ok(ShaderHandle(self.entries.len() - 1));
+    }
+
+    /// Uses the SPIR-V compiled into the binary for the shader with the name specified, e.g. `std_mesh_f`.
+    /// If the GLSL source of the shader exists in the source directory and the `runtime-shaders` feature is enabled, it is watched so that edits to it are picked up without rebuilding the engine.
+    pub fn load_or_embedded(&mut self, name: &str, embedded: &[u8]) -> Result<ShaderHandle, Error> {
+        return self.load_variant_or_embedded(name, &[], embedded);
+    }
+
+    /// Like `load_or_embedded`, for the variant of the shader compiled with the defines specified.
+    /// The embedded code should be the matching variant, e.g. `std_mesh_v-skinned.spv` for `std_mesh_v` with `SKINNED`.
+    pub fn load_variant_or_embedded(&mut self, name: &str, defines: &[&str], embedded: &[u8]) -> Result<ShaderHandle, Error> {
+        let source = self.source_dir.join(format!("{}.glsl", name));
+        let defines: Vec<String> = defines.iter().map(|define| String::from(*define)).collect();
+        if let Some(index) = self.entries.iter().position(|entry| entry.is_variant(&source, &defines)) {
+            return Ok(ShaderHandle(index));
+        }
+
+        let mut stage = ShaderStage::from_path(Path::new(name));
+        let mut includes = Vec::new();
+        let mut path = None;
+        let key = source.clone();
+        if source.is_file() {
+            // The source is only preprocessed here, to find its stage and the includes to watch.
+            let shader = self.preprocessor.process(&source, &defines)?;
+            stage = shader.stage.or(stage);
+            // Without runtime compilation an edited source could never be reloaded, so it is not watched.
+            if cfg!(feature = "runtime-shaders") {
+                includes = shader.includes;
+                path = Some(source);
+            }
+        }
+        let stage = stage.ok_or_else(|| Error::ShaderCompilation(format!("Could not determine the stage of '{}'.", name)))?;
+
+        let mut entry = ShaderEntry { key, path, defines, includes, stage, spirv: embedded.to_vec(), modified: None, version: 0 };
+        entry.modified = entry.latest_modified();
+        self.entries.push(entry);
+        return Ok(ShaderHandle(self.entries.len() - 1));
+    }
+
+    pub fn spirv(&self, handle: ShaderHandle) -> &[u8] {
+        return &self.entries[handle.0].spirv;
+    }
+
+    pub fn stage(&self, handle: ShaderHandle) -> ShaderStage {
+        return self.entries[handle.0].stage;
+    }
+
+    /// The number of times the shader has been reloaded.
+    pub fn version(&self, handle: ShaderHandle) -> u32 {
+        return self.entries[handle.0].version;
+    }
+
+    pub fn create_module(&self, handle: ShaderHandle, device: &core::Device) -> Result<pipeline::ShaderModule, Error> {
+        return device.load_shader_raw(self.spirv(handle));
+    }
+
+    /// Reflects the combined interface of the shaders specified, e.g. the vertex and fragment shader of a pipeline.
+    pub fn reflect(&self, handles: &[ShaderHandle]) -> Result<ShaderReflection, Error> {
+        let mut reflection: Option<ShaderReflection> = None;
+        for handle in handles {
+            let stage_reflection = ShaderReflection::from_spirv(self.spirv(*handle))?;
+            reflection = Some(match reflection {
+                Some(reflection) => reflection.merge(&stage_reflection)?,
+                None => stage_reflection,
+            });
+        }
+        return reflection.ok_or_else(|| Error::Reflection(String::from("No shaders were specified.")));
+    }
+
+    /// Checks the files of the loaded shaders and their includes for changes, and reloads any shaders which were modified.
+    /// The handles of the reloaded shaders are returned. A shader which fails to compile keeps its previous code and is not reported.
+    pub fn poll_changes(&mut self) -> Vec<ShaderHandle> {
+        let mut changed = Vec::new();
+        if !self.hot_reload || self.last_poll.elapsed() < self.poll_interval {
+            return changed;
+        }
+        self.last_poll = Instant::now();
+
+        let preprocessor = &self.preprocessor;
+        for (index, entry) in self.entries.iter_mut().enumerate() {
+            let modified = entry.latest_modified();
+            if modified.is_none() || modified == entry.modified {
+                continue;
+            }
+            let path = match &entry.path {
+                Some(path) => path.clone(),
+                None => continue,
+            };
+            // The modification time is updated even if compilation fails, so a broken shader is only reported once.
+            entry.modified = modified;
+            match Self::read_shader(preprocessor, &path, &entry.defines) {
+                Ok((spirv, _, includes)) => {
+                    log!(msg, "Reloaded shader '{}'.", path.display());
+                    entry.spirv = spirv;
+                    entry.includes = includes;
+                    entry.version += 1;
+                    changed.push(ShaderHandle(index));
+                },
+                Err(e) => log!(err, "Failed to reload shader '{}': {}", path.display(), e),
+            }
+        }
+        return changed;
+    }
+
+    /// Reads a shader file, compiling it if it is GLSL. The stage and included files are returned with the code.
+    fn read_shader(preprocessor: &Preprocessor, path: &Path, defines: &[String]) -> Result<(Vec<u8>, ShaderStage, Vec<PathBuf>), Error> {
+        if path.extension().map_or(false, |extension| extension == "spv") {
+            let stage = ShaderStage::from_path(path).ok_or_else(|| Error::ShaderCompilation(format!("Could not determine the stage of '{}'.", path.display())))?;
+            return Ok((std::fs::read(path)?, stage, Vec::new()));
+        }
+        let shader = preprocessor.process(path, defines)?;
+        let stage = shader.stage.ok_or_else(|| Error::ShaderCompilation(format!("Could not determine the stage of '{}'.", path.display())))?;
+        return Ok((compile_glsl(&shader.source, stage)?, stage, shader.includes));
+    }
+
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    return std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Writes the files specified to a new directory for a test, replacing any left by a previous run.
+    fn write_files(test: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("imperium-shader-manager-{}", test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        return dir;
+    }
+
+    /// A manager which checks for changes every time it is polled.
+    fn manager(dir: &Path) -> ShaderManager {
+        let mut manager = ShaderManager::new(dir);
+        manager.hot_reload = true;
+        manager.poll_interval = Duration::from_millis(0);
+        return manager;
+    }
+
+    #[test]
+    fn spirv_files_are_loaded() {
+        let dir = write_files("load", &[("test_f.spv", &[1, 2, 3, 4])]);
+        let mut manager = manager(&dir);
+        let handle = manager.load(dir.join("test_f.spv")).unwrap();
+        assert_eq!(manager.spirv(handle), &[1, 2, 3, 4]);
+        assert_eq!(manager.stage(handle), ShaderStage::Fragment);
+        assert_eq!(manager.version(handle), 0);
+        assert!(manager.load(dir.join("missing_f.spv")).is_err());
+    }
+
+    #[test]
+    fn loading_a_file_twice_returns_the_same_handle() {
+        let dir = write_files("dedup", &[("first_v.spv", &[1, 0, 0, 0]), ("second_v.spv", &[2, 0, 0, 0])]);
+        let mut manager = manager(&dir);
+        let first = manager.load(dir.join("first_v.spv")).unwrap();
+        let second = manager.load(dir.join("second_v.spv")).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(manager.load(dir.join("first_v.spv")).unwrap(), first);
+
+        let embedded = manager.load_or_embedded("embedded_f", &[5, 0, 0, 0]).unwrap();
+        assert_eq!(manager.load_or_embedded("embedded_f", &[6, 0, 0, 0]).unwrap(), embedded);
+        assert_eq!(manager.spirv(embedded), &[5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rewritten_spirv_files_are_reloaded() {
+        let dir = write_files("reload", &[("test_c.spv", &[1, 0, 0, 0])]);
+        let path = dir.join("test_c.spv");
+        let mut manager = manager(&dir);
+        let handle = manager.load(&path).unwrap();
+        assert!(manager.poll_changes().is_empty());
+
+        // The file is rewritten until its modification time changes, since some file systems only store it to the second.
+        let previous = modified_time(&path);
+        for _ in 0..300 {
+            std::thread::sleep(Duration::from_millis(10));
+            std::fs::write(&path, &[2, 0, 0, 0]).unwrap();
+            if modified_time(&path) != previous {
+                break;
+            }
+        }
+
+        assert_eq!(manager.poll_changes(), vec![handle]);
+        assert_eq!(manager.spirv(handle), &[2, 0, 0, 0]);
+        assert_eq!(manager.version(handle), 1);
+        assert!(manager.poll_changes().is_empty());
+    }
+
+}
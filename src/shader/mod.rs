@@ -1,2 +1,10 @@
+use crate::*;
 
-pub fn compile_shader()
\ No newline at end of file
+pub mod reflect;
+pub use self::reflect::*;
+
+pub mod manager;
+pub use self::manager::*;
+
+pub mod preprocess;
+pub use self::preprocess::*;
@@ -0,0 +1,363 @@
+// This file is also included by `build.rs`, so it may only depend on the standard library.
+
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The stage of a pipeline a shader is run in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShaderStage {
+
+    Vertex,
+    Fragment,
+    Geometry,
+    TessellationControl,
+    TessellationEvaluation,
+    Compute,
+
+}
+
+impl ShaderStage {
+
+    /// Gets the stage from the name used in a `#pragma shader_stage(...)` directive.
+    pub fn from_name(name: &str) -> Option<ShaderStage> {
+        match name {
+            "vertex" => Some(ShaderStage::Vertex),
+            "fragment" => Some(ShaderStage::Fragment),
+            "geometry" => Some(ShaderStage::Geometry),
+            "tesscontrol" => Some(ShaderStage::TessellationControl),
+            "tesseval" => Some(ShaderStage::TessellationEvaluation),
+            "compute" => Some(ShaderStage::Compute),
+            _ => None,
+        }
+    }
+
+    /// Infers the stage of a shader from its file name.
+    /// The stage is taken from the extension (`.vert`, `.frag`, `.geom`, `.tesc`, `.tese` or `.comp`),
+    /// or otherwise from the suffix of the name (`_v`, `_f`, `_g`, `_tc`, `_te` or `_c`). Variant names after a `-` are ignored.
+    pub fn from_path(path: &Path) -> Option<ShaderStage> {
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        match extension {
+            "vert" => return Some(ShaderStage::Vertex),
+            "frag" => return Some(ShaderStage::Fragment),
+            "geom" => return Some(ShaderStage::Geometry),
+            "tesc" => return Some(ShaderStage::TessellationControl),
+            "tese" => return Some(ShaderStage::TessellationEvaluation),
+            "comp" => return Some(ShaderStage::Compute),
+            _ => {},
+        }
+
+        let stem = path.file_stem()?.to_str()?;
+        let name = stem.split('-').next().unwrap_or(stem);
+        let suffixes = [
+            ("_v", ShaderStage::Vertex),
+            ("_f", ShaderStage::Fragment),
+            ("_g", ShaderStage::Geometry),
+            ("_tc", ShaderStage::TessellationControl),
+            ("_te", ShaderStage::TessellationEvaluation),
+            ("_c", ShaderStage::Compute),
+        ];
+        return suffixes.iter().find(|(suffix, _)| name.ends_with(suffix)).map(|(_, stage)| *stage);
+    }
+
+}
+
+/// The output of the preprocessor, which can be passed to the GLSL compiler.
+#[derive(Clone, Debug)]
+pub struct PreprocessedShader {
+
+    pub source: String,
+    /// The stage from the `shader_stage` pragma, or from the file name if there is none.
+    pub stage: Option<ShaderStage>,
+    /// The defines declared with `variant` pragmas.
+    pub variants: Vec<String>,
+    /// Every file which was included, directly or indirectly.
+    pub includes: Vec<PathBuf>,
+
+}
+
+/// Expands includes and custom pragmas in GLSL source. On top of GLSL the following directives are understood:
+///
+/// ```glsl
+/// #include "lights.glsl"             // Resolved next to the including file, then in the include directories.
+/// #pragma shader_stage(fragment)     // The stage of the shader, which otherwise comes from the file name.
+/// #pragma variant(ALBEDO_TEXTURE)    // A define which is compiled both on and off, giving one variant per combination.
+/// ```
+///
+/// Each file is included at most once, so included files do not need include guards.
+#[derive(Clone, Debug)]
+pub struct Preprocessor {
+
+    /// The directories searched for included files which are not found next to the file including them.
+    pub include_dirs: Vec<PathBuf>,
+
+}
+
+impl Preprocessor {
+
+    pub fn new() -> Self {
+        return Self { include_dirs: Vec::new() };
+    }
+
+    pub fn with_include_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.include_dirs.push(dir.as_ref().to_path_buf());
+        return self;
+    }
+
+    /// Preprocesses the shader file with each of the defines specified set to 1.
+    pub fn process(&self, path: &Path, defines: &[String]) -> io::Result<PreprocessedShader> {
+        let mut shader = PreprocessedShader { source: String::new(), stage: None, variants: Vec::new(), includes: Vec::new() };
+        let has_version = self.expand(path, Some(defines), &mut shader)?;
+        if !defines.is_empty() && !has_version {
+            return Err(invalid_data(format!("'{}' has no #version directive.", path.display())));
+        }
+
+        if shader.stage.is_none() {
+            shader.stage = ShaderStage::from_path(path);
+        }
+        return Ok(shader);
+    }
+
+    /// Appends the expanded source of a file to the shader. The defines are only given for the root file, and are placed after its version directive.
+    /// Returns true if the file has a version directive.
+    fn expand(&self, path: &Path, defines: Option<&[String]>, shader: &mut PreprocessedShader) -> io::Result<bool> {
+        let source = std::fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let root = defines.is_some();
+        let mut has_version = false;
+
+        for (index, line) in source.lines().enumerate() {
+            let directive = line.trim();
+            if let Some(name) = directive_argument(directive, "#include") {
+                let name = name.trim_matches(|c| c == '"' || c == '<' || c == '>');
+                let include = self.resolve(name, dir)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Could not find '{}', included by '{}'.", name, path.display())))?;
+                if !shader.includes.contains(&include) {
+                    shader.includes.push(include.clone());
+                    shader.source.push_str("#line 1\n");
+                    self.expand(&include, None, shader)?;
+                }
+                // Line numbers in compiler errors continue from the include directive.
+                shader.source.push_str(&format!("#line {}\n", index + 2));
+            } else if let Some(pragma) = directive_argument(directive, "#pragma") {
+                if let Some(stage) = pragma_argument(pragma, "shader_stage") {
+                    let stage = ShaderStage::from_name(stage)
+                        .ok_or_else(|| invalid_data(format!("Unknown shader stage '{}' in '{}'.", stage, path.display())))?;
+                    if root {
+                        shader.stage = Some(stage);
+                    }
+                } else if let Some(variants) = pragma_argument(pragma, "variant") {
+                    for variant in variants.split(',').map(str::trim).filter(|variant| !variant.is_empty()) {
+                        if !shader.variants.iter().any(|v| v == variant) {
+                            shader.variants.push(String::from(variant));
+                        }
+                    }
+                } else {
+                    shader.source.push_str(line);
+                }
+                shader.source.push('\n');
+            } else {
+                shader.source.push_str(line);
+                shader.source.push('\n');
+                if directive_argument(directive, "#version").is_some() {
+                    has_version = true;
+                    // The version directive must come first, so the defines are placed after it.
+                    // The line numbers are those of the file itself, as includes may have been expanded before it.
+                    if let Some(defines) = defines.filter(|defines| !defines.is_empty()) {
+                        for define in defines {
+                            shader.source.push_str(&format!("#define {} 1\n", define));
+                        }
+                        shader.source.push_str(&format!("#line {}\n", index + 2));
+                    }
+                }
+            }
+        }
+        return Ok(has_version);
+    }
+
+    fn resolve(&self, name: &str, dir: &Path) -> Option<PathBuf> {
+        let local = dir.join(name);
+        if local.is_file() {
+            return Some(local);
+        }
+        return self.include_dirs.iter().map(|include_dir| include_dir.join(name)).find(|path| path.is_file());
+    }
+
+}
+
+/// Every combination of the variant defines, starting with none of them.
+pub fn variant_permutations(variants: &[String]) -> Vec<Vec<String>> {
+    let mut permutations = Vec::with_capacity(1 << variants.len());
+    for mask in 0..(1usize << variants.len()) {
+        permutations.push(variants.iter().enumerate().filter(|(i, _)| mask & (1 << i) != 0).map(|(_, variant)| variant.clone()).collect());
+    }
+    return permutations;
+}
+
+/// The name a variant of a shader is compiled to, e.g. `std_mesh_v-skinned` for `std_mesh_v` with `SKINNED` defined.
+pub fn variant_name(name: &str, defines: &[String]) -> String {
+    let mut variant = String::from(name);
+    for define in defines {
+        variant.push('-');
+        variant.push_str(&define.to_lowercase());
+    }
+    return variant;
+}
+
+/// Gets the rest of a directive if it starts with the keyword specified.
+fn directive_argument<'a>(directive: &'a str, keyword: &str) -> Option<&'a str> {
+    if !directive.starts_with(keyword) {
+        return None;
+    }
+    return Some(directive[keyword.len()..].trim());
+}
+
+/// Gets the argument of a pragma of the form `name(argument)`.
+fn pragma_argument<'a>(pragma: &'a str, name: &str) -> Option<&'a str> {
+    let argument = directive_argument(pragma, name)?;
+    if argument.starts_with('(') && argument.ends_with(')') {
+        return Some(argument[1..(argument.len() - 1)].trim());
+    }
+    return None;
+}
+
+fn invalid_data(message: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// Writes the files specified to a new directory for a test, replacing any left by a previous run.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("imperium-preprocess-{}", test));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (name, contents) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+        }
+        return dir;
+    }
+
+    fn defines(names: &[&str]) -> Vec<String> {
+        return names.iter().map(|name| String::from(*name)).collect();
+    }
+
+    #[test]
+    fn includes_are_expanded_once_with_line_directives() {
+        let dir = write_files("includes", &[
+            ("shader_f.glsl", "#version 450\n#include \"common.glsl\"\n#include \"common.glsl\"\nvoid main() {}\n"),
+            ("common.glsl", "float common;\n"),
+        ]);
+        let shader = Preprocessor::new().process(&dir.join("shader_f.glsl"), &[]).unwrap();
+        assert_eq!(shader.source, "#version 450\n#line 1\nfloat common;\n#line 3\n#line 4\nvoid main() {}\n");
+        assert_eq!(shader.includes, vec![dir.join("common.glsl")]);
+    }
+
+    #[test]
+    fn includes_are_resolved_in_the_include_dirs() {
+        let dir = write_files("include-dirs", &[
+            ("src/shader_v.glsl", "#version 450\n#include <lights.glsl>\n"),
+            ("include/lights.glsl", "#include \"brdf.glsl\"\nfloat lights;\n"),
+            ("include/brdf.glsl", "float brdf;\n"),
+        ]);
+        let shader = Preprocessor::new().with_include_dir(dir.join("include")).process(&dir.join("src/shader_v.glsl"), &[]).unwrap();
+        assert_eq!(shader.includes, vec![dir.join("include/lights.glsl"), dir.join("include/brdf.glsl")]);
+        assert!(shader.source.contains("float brdf;\n#line 2\nfloat lights;\n"));
+    }
+
+    #[test]
+    fn missing_include_is_an_error() {
+        let dir = write_files("missing-include", &[("shader_f.glsl", "#version 450\n#include \"missing.glsl\"\n")]);
+        let error = Preprocessor::new().process(&dir.join("shader_f.glsl"), &[]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn defines_follow_the_version_directive() {
+        let dir = write_files("defines", &[("shader_v.glsl", "#version 450\nvoid main() {}\n")]);
+        let shader = Preprocessor::new().process(&dir.join("shader_v.glsl"), &defines(&["SKINNED", "INSTANCED"])).unwrap();
+        assert_eq!(shader.source, "#version 450\n#define SKINNED 1\n#define INSTANCED 1\n#line 2\nvoid main() {}\n");
+    }
+
+    #[test]
+    fn defines_after_an_include_keep_the_line_numbers_of_the_file() {
+        let dir = write_files("defines-after-include", &[
+            ("shader_v.glsl", "#include \"header.glsl\"\n#version 450\nvoid main() {}\n"),
+            ("header.glsl", "// The first line.\n// The second line.\n"),
+        ]);
+        let shader = Preprocessor::new().process(&dir.join("shader_v.glsl"), &defines(&["SKINNED"])).unwrap();
+        assert_eq!(shader.source, "#line 1\n// The first line.\n// The second line.\n#line 2\n#version 450\n#define SKINNED 1\n#line 3\nvoid main() {}\n");
+    }
+
+    #[test]
+    fn defines_need_a_version_directive() {
+        let dir = write_files("no-version", &[("shader_v.glsl", "void main() {}\n")]);
+        let preprocessor = Preprocessor::new();
+        assert!(preprocessor.process(&dir.join("shader_v.glsl"), &[]).is_ok());
+        assert_eq!(preprocessor.process(&dir.join("shader_v.glsl"), &defines(&["SKINNED"])).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn pragmas_set_the_stage_and_variants() {
+        let dir = write_files("pragmas", &[
+            ("shader.glsl", "#version 450\n#pragma shader_stage(fragment)\n#pragma variant(ALBEDO, NORMAL)\n#include \"inc.glsl\"\n#pragma optimize(on)\n"),
+            ("inc.glsl", "#pragma shader_stage(vertex)\n#pragma variant(NORMAL)\n#pragma variant(SHADOWS)\n"),
+        ]);
+        let shader = Preprocessor::new().process(&dir.join("shader.glsl"), &[]).unwrap();
+        // The stage pragma of an included file is ignored.
+        assert_eq!(shader.stage, Some(ShaderStage::Fragment));
+        assert_eq!(shader.variants, defines(&["ALBEDO", "NORMAL", "SHADOWS"]));
+        // Custom pragmas are replaced with empty lines so line numbers are kept, other pragmas are left for the compiler.
+        assert!(shader.source.starts_with("#version 450\n\n\n"));
+        assert!(shader.source.ends_with("#pragma optimize(on)\n"));
+    }
+
+    #[test]
+    fn stage_falls_back_to_the_file_name() {
+        let dir = write_files("stage-from-name", &[("shader_te.glsl", "#version 450\n"), ("shader.comp", "#version 450\n"), ("shader.glsl", "#version 450\n")]);
+        let preprocessor = Preprocessor::new();
+        assert_eq!(preprocessor.process(&dir.join("shader_te.glsl"), &[]).unwrap().stage, Some(ShaderStage::TessellationEvaluation));
+        assert_eq!(preprocessor.process(&dir.join("shader.comp"), &[]).unwrap().stage, Some(ShaderStage::Compute));
+        assert_eq!(preprocessor.process(&dir.join("shader.glsl"), &[]).unwrap().stage, None);
+    }
+
+    #[test]
+    fn stage_from_path() {
+        assert_eq!(ShaderStage::from_path(Path::new("shaders/std_mesh_v-instanced.spv")), Some(ShaderStage::Vertex));
+        assert_eq!(ShaderStage::from_path(Path::new("blur_c.glsl")), Some(ShaderStage::Compute));
+        assert_eq!(ShaderStage::from_path(Path::new("terrain_tc.glsl")), Some(ShaderStage::TessellationControl));
+        assert_eq!(ShaderStage::from_path(Path::new("sky.frag")), Some(ShaderStage::Fragment));
+        assert_eq!(ShaderStage::from_path(Path::new("sky.glsl")), None);
+    }
+
+    #[test]
+    fn no_variants_have_a_single_permutation() {
+        assert_eq!(variant_permutations(&[]), vec![Vec::<String>::new()]);
+    }
+
+    #[test]
+    fn permutations_cover_every_combination() {
+        let permutations = variant_permutations(&defines(&["A", "B", "C"]));
+        assert_eq!(permutations, vec![
+            defines(&[]),
+            defines(&["A"]),
+            defines(&["B"]),
+            defines(&["A", "B"]),
+            defines(&["C"]),
+            defines(&["A", "C"]),
+            defines(&["B", "C"]),
+            defines(&["A", "B", "C"]),
+        ]);
+    }
+
+    #[test]
+    fn variant_names() {
+        assert_eq!(variant_name("std_mesh_v", &[]), "std_mesh_v");
+        assert_eq!(variant_name("std_mesh_v", &defines(&["SKINNED", "INSTANCED"])), "std_mesh_v-skinned-instanced");
+    }
+
+}
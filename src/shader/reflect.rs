@@ -0,0 +1,730 @@
+use crate::*;
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+// The subset of SPIR-V opcodes, decorations and enumerants which are needed to find the interface of a shader.
+const OP_NAME: u32 = 5;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_BOOL: u32 = 20;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+#[derive(Clone, Debug)]
+enum SpirvType {
+    Bool,
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+/// A descriptor binding used by a shader.
+#[derive(Clone, Debug)]
+pub struct ReflectedBinding {
+
+    pub set: u32,
+    pub binding: u32,
+    pub ty: gfx::pso::DescriptorType,
+    /// The number of descriptors in the binding, which is more than one for arrays.
+    pub count: usize,
+    pub stages: gfx::pso::ShaderStageFlags,
+    pub name: String,
+
+}
+
+/// A vertex attribute read by a vertex shader.
+#[derive(Clone, Debug)]
+pub struct ReflectedVertexInput {
+
+    pub location: u32,
+    pub format: gfx::format::Format,
+    pub name: String,
+
+}
+
+/// The interface of one or more shader stages, read from their SPIR-V.
+/// The reflections of each stage of a pipeline should be merged, so that the descriptor set layouts and push constant ranges cover every stage.
+#[derive(Clone, Debug)]
+pub struct ShaderReflection {
+
+    pub stages: gfx::pso::ShaderStageFlags,
+    pub bindings: Vec<ReflectedBinding>,
+    /// The size of the push constant block in bytes, or zero if there is none.
+    pub push_constant_size: u32,
+    pub push_constant_stages: gfx::pso::ShaderStageFlags,
+    /// The vertex attributes, sorted by location. This is only filled for vertex shaders.
+    pub vertex_inputs: Vec<ReflectedVertexInput>,
+
+}
+
+fn reflection_error<T>(msg: String) -> Result<T, Error> {
+    return Err(Error::Reflection(msg));
+}
+
+/// The number of operand words an instruction which is parsed needs at the least, so that truncated instructions are rejected before their operands are read.
+fn min_operands(opcode: u32) -> usize {
+    match opcode {
+        OP_TYPE_BOOL | OP_TYPE_SAMPLER | OP_TYPE_STRUCT => 1,
+        OP_NAME | OP_DECORATE | OP_TYPE_FLOAT | OP_TYPE_SAMPLED_IMAGE | OP_TYPE_RUNTIME_ARRAY => 2,
+        OP_ENTRY_POINT | OP_MEMBER_DECORATE | OP_TYPE_INT | OP_TYPE_VECTOR | OP_TYPE_MATRIX
+            | OP_TYPE_ARRAY | OP_TYPE_POINTER | OP_CONSTANT | OP_VARIABLE => 3,
+        OP_TYPE_IMAGE => 8,
+        _ => 0,
+    }
+}
+
+/// Returns true if a binding declared by a shader can be bound to a descriptor of the type specified.
+/// Dynamic buffers are declared in the same way as static buffers in the shader, so either may be used.
+pub fn descriptor_types_compatible(shader: gfx::pso::DescriptorType, input: gfx::pso::DescriptorType) -> bool {
+    use gfx::pso::DescriptorType::*;
+    match (shader, input) {
+        (UniformBuffer, UniformBufferDynamic) | (UniformBufferDynamic, UniformBuffer) => true,
+        (StorageBuffer, StorageBufferDynamic) | (StorageBufferDynamic, StorageBuffer) => true,
+        (a, b) => a == b,
+    }
+}
+
+/// The parsed module, which is only needed while the reflection is being built.
+struct Module {
+
+    stages: gfx::pso::ShaderStageFlags,
+    names: HashMap<u32, String>,
+    decorations: HashMap<(u32, u32), u32>,
+    flags: HashMap<u32, Vec<u32>>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>,
+    variables: Vec<(u32, u32, u32)>,
+
+}
+
+impl Module {
+
+    fn parse(bytes: &[u8]) -> Result<Module, Error> {
+        if bytes.len() % 4 != 0 || bytes.len() < 20 {
+            return reflection_error(format!("SPIR-V must be a whole number of words and at least a header long, but is {} bytes.", bytes.len()));
+        }
+        let mut words: Vec<u32> = bytes.chunks(4).map(|c| u32::from(c[0]) | u32::from(c[1]) << 8 | u32::from(c[2]) << 16 | u32::from(c[3]) << 24).collect();
+        if words[0] == SPIRV_MAGIC.swap_bytes() {
+            for word in words.iter_mut() {
+                *word = word.swap_bytes();
+            }
+        } else if words[0] != SPIRV_MAGIC {
+            return reflection_error(format!("Invalid SPIR-V magic number {:#x}.", words[0]));
+        }
+
+        let mut module = Module {
+            stages: gfx::pso::ShaderStageFlags::empty(),
+            names: HashMap::new(),
+            decorations: HashMap::new(),
+            flags: HashMap::new(),
+            member_decorations: HashMap::new(),
+            types: HashMap::new(),
+            constants: HashMap::new(),
+            variables: Vec::new(),
+        };
+
+        let mut i = 5;
+        while i < words.len() {
+            let opcode = words[i] & 0xffff;
+            let count = (words[i] >> 16) as usize;
+            if count == 0 || i + count > words.len() {
+                return reflection_error(format!("Malformed SPIR-V instruction at word {}.", i));
+            }
+            let ops = &words[i + 1..i + count];
+            if ops.len() < min_operands(opcode) {
+                return reflection_error(format!("SPIR-V instruction {} at word {} has {} operands, but needs at least {}.", opcode, i, ops.len(), min_operands(opcode)));
+            }
+            match opcode {
+                OP_ENTRY_POINT => {
+                    module.stages |= match ops[0] {
+                        0 => gfx::pso::ShaderStageFlags::VERTEX,
+                        1 => gfx::pso::ShaderStageFlags::HULL,
+                        2 => gfx::pso::ShaderStageFlags::DOMAIN,
+                        3 => gfx::pso::ShaderStageFlags::GEOMETRY,
+                        4 => gfx::pso::ShaderStageFlags::FRAGMENT,
+                        5 => gfx::pso::ShaderStageFlags::COMPUTE,
+                        _ => gfx::pso::ShaderStageFlags::empty(),
+                    };
+                },
+                OP_NAME => { module.names.insert(ops[0], decode_string(&ops[1..])); },
+                OP_DECORATE => {
+                    if ops.len() > 2 {
+                        module.decorations.insert((ops[0], ops[1]), ops[2]);
+                    } else {
+                        module.flags.entry(ops[0]).or_insert_with(Vec::new).push(ops[1]);
+                    }
+                },
+                OP_MEMBER_DECORATE => {
+                    if ops.len() > 3 {
+                        module.member_decorations.insert((ops[0], ops[1], ops[2]), ops[3]);
+                    }
+                },
+                OP_TYPE_BOOL => { module.types.insert(ops[0], SpirvType::Bool); },
+                OP_TYPE_INT => { module.types.insert(ops[0], SpirvType::Int { width: ops[1], signed: ops[2] != 0 }); },
+                OP_TYPE_FLOAT => { module.types.insert(ops[0], SpirvType::Float { width: ops[1] }); },
+                OP_TYPE_VECTOR => { module.types.insert(ops[0], SpirvType::Vector { component: ops[1], count: ops[2] }); },
+                OP_TYPE_MATRIX => { module.types.insert(ops[0], SpirvType::Matrix { column: ops[1], count: ops[2] }); },
+                OP_TYPE_IMAGE => { module.types.insert(ops[0], SpirvType::Image { dim: ops[2], sampled: ops[6] }); },
+                OP_TYPE_SAMPLER => { module.types.insert(ops[0], SpirvType::Sampler); },
+                OP_TYPE_SAMPLED_IMAGE => { module.types.insert(ops[0], SpirvType::SampledImage); },
+                OP_TYPE_ARRAY => { module.types.insert(ops[0], SpirvType::Array { element: ops[1], length: ops[2] }); },
+                OP_TYPE_RUNTIME_ARRAY => { module.types.insert(ops[0], SpirvType::RuntimeArray { element: ops[1] }); },
+                OP_TYPE_STRUCT => { module.types.insert(ops[0], SpirvType::Struct { members: ops[1..].to_vec() }); },
+                OP_TYPE_POINTER => { module.types.insert(ops[0], SpirvType::Pointer { pointee: ops[2] }); },
+                OP_CONSTANT => { module.constants.insert(ops[1], ops[2]); },
+                OP_VARIABLE => { module.variables.push((ops[0], ops[1], ops[2])); },
+                _ => {},
+            }
+            i += count;
+        }
+        return Ok(module);
+    }
+
+    fn ty(&self, id: u32) -> Result<&SpirvType, Error> {
+        return self.types.get(&id).ok_or_else(|| Error::Reflection(format!("SPIR-V references unknown type %{}.", id)));
+    }
+
+    fn has_flag(&self, id: u32, decoration: u32) -> bool {
+        return self.flags.get(&id).map_or(false, |flags| flags.contains(&decoration));
+    }
+
+    fn name(&self, id: u32) -> String {
+        return self.names.get(&id).cloned().unwrap_or_default();
+    }
+
+    /// The length of an array type. Lengths set by specialization constants are only known when the pipeline is created, so they cannot be reflected.
+    fn array_length(&self, array: u32, length: u32) -> Result<u32, Error> {
+        return self.constants.get(&length).cloned().ok_or_else(|| Error::Reflection(format!(
+            "The length of array type %{} is not a constant. Arrays sized by specialization constants cannot be reflected.", array
+        )));
+    }
+
+    /// The size of a type as laid out in a buffer block, using the offsets and strides decorated by the compiler.
+    fn size_of(&self, id: u32, matrix_stride: Option<u32>) -> Result<u32, Error> {
+        return Ok(match self.ty(id)? {
+            SpirvType::Bool => 4,
+            SpirvType::Int { width, .. } | SpirvType::Float { width } => width / 8,
+            SpirvType::Vector { component, count } => self.size_of(*component, None)? * count,
+            SpirvType::Matrix { column, count } => match matrix_stride {
+                Some(stride) => stride * count,
+                None => self.size_of(*column, None)? * count,
+            },
+            SpirvType::Array { element, length } => {
+                let length = self.array_length(id, *length)?;
+                match self.decorations.get(&(id, DECORATION_ARRAY_STRIDE)) {
+                    Some(stride) => stride * length,
+                    None => self.size_of(*element, matrix_stride)? * length,
+                }
+            },
+            SpirvType::RuntimeArray { .. } => 0,
+            SpirvType::Struct { members } => {
+                let mut size = 0;
+                for (index, member) in members.iter().enumerate() {
+                    let index = index as u32;
+                    let offset = self.member_decorations.get(&(id, index, DECORATION_OFFSET)).cloned().unwrap_or(size);
+                    let stride = self.member_decorations.get(&(id, index, DECORATION_MATRIX_STRIDE)).cloned();
+                    size = size.max(offset + self.size_of(*member, stride)?);
+                }
+                size
+            },
+            _ => 0,
+        });
+    }
+
+    fn descriptor_type(&self, storage: u32, pointee: u32) -> Result<(gfx::pso::DescriptorType, usize), Error> {
+        let (mut ty_id, mut count) = (pointee, 1usize);
+        match self.ty(ty_id)? {
+            SpirvType::Array { element, length } => {
+                count = self.array_length(ty_id, *length)? as usize;
+                ty_id = *element;
+            },
+            SpirvType::RuntimeArray { element } => {
+                // Unsized descriptor arrays are not supported by the engine, so they are treated as a single descriptor.
+                ty_id = *element;
+            },
+            _ => {},
+        }
+
+        let ty = match (storage, self.ty(ty_id)?) {
+            (STORAGE_UNIFORM, _) if self.has_flag(ty_id, DECORATION_BUFFER_BLOCK) => gfx::pso::DescriptorType::StorageBuffer,
+            (STORAGE_UNIFORM, _) => gfx::pso::DescriptorType::UniformBuffer,
+            (STORAGE_STORAGE_BUFFER, _) => gfx::pso::DescriptorType::StorageBuffer,
+            (_, SpirvType::Sampler) => gfx::pso::DescriptorType::Sampler,
+            (_, SpirvType::SampledImage) => gfx::pso::DescriptorType::CombinedImageSampler,
+            (_, SpirvType::Image { dim, sampled }) => match (*dim, *sampled) {
+                (DIM_SUBPASS_DATA, _) => gfx::pso::DescriptorType::InputAttachment,
+                (DIM_BUFFER, 2) => gfx::pso::DescriptorType::StorageTexelBuffer,
+                (DIM_BUFFER, _) => gfx::pso::DescriptorType::UniformTexelBuffer,
+                (_, 2) => gfx::pso::DescriptorType::StorageImage,
+                _ => gfx::pso::DescriptorType::SampledImage,
+            },
+            (_, ty) => return reflection_error(format!("Unsupported descriptor type {:?}.", ty)),
+        };
+        return Ok((ty, count));
+    }
+
+    fn vertex_format(&self, id: u32) -> Result<gfx::format::Format, Error> {
+        use gfx::format::Format;
+        let (component, count) = match self.ty(id)? {
+            SpirvType::Vector { component, count } => (*component, *count),
+            _ => (id, 1),
+        };
+        let format = match (self.ty(component)?, count) {
+            (SpirvType::Float { width: 32 }, 1) => Format::R32Float,
+            (SpirvType::Float { width: 32 }, 2) => Format::Rg32Float,
+            (SpirvType::Float { width: 32 }, 3) => Format::Rgb32Float,
+            (SpirvType::Float { width: 32 }, 4) => Format::Rgba32Float,
+            (SpirvType::Int { width: 32, signed: true }, 1) => Format::R32Int,
+            (SpirvType::Int { width: 32, signed: true }, 2) => Format::Rg32Int,
+            (SpirvType::Int { width: 32, signed: true }, 3) => Format::Rgb32Int,
+            (SpirvType::Int { width: 32, signed: true }, 4) => Format::Rgba32Int,
+            (SpirvType::Int { width: 32, signed: false }, 1) => Format::R32Uint,
+            (SpirvType::Int { width: 32, signed: false }, 2) => Format::Rg32Uint,
+            (SpirvType::Int { width: 32, signed: false }, 3) => Format::Rgb32Uint,
+            (SpirvType::Int { width: 32, signed: false }, 4) => Format::Rgba32Uint,
+            (ty, count) => return reflection_error(format!("Unsupported vertex input type {:?} x{}.", ty, count)),
+        };
+        return Ok(format);
+    }
+
+}
+
+/// Decodes a nul terminated SPIR-V literal string.
+fn decode_string(words: &[u32]) -> String {
+    let mut bytes: Vec<u8> = Vec::with_capacity(words.len() * 4);
+    'words: for word in words {
+        for i in 0..4 {
+            let byte = (word >> (i * 8)) as u8;
+            if byte == 0 {
+                break 'words;
+            }
+            bytes.push(byte);
+        }
+    }
+    return String::from_utf8_lossy(&bytes).into_owned();
+}
+
+impl ShaderReflection {
+
+    /// Reflects a compiled SPIR-V module.
+    pub fn from_spirv(bytes: &[u8]) -> Result<ShaderReflection, Error> {
+        let module = Module::parse(bytes)?;
+        let mut reflection = ShaderReflection {
+            stages: module.stages,
+            bindings: Vec::new(),
+            push_constant_size: 0,
+            push_constant_stages: gfx::pso::ShaderStageFlags::empty(),
+            vertex_inputs: Vec::new(),
+        };
+
+        for (result_type, id, storage) in module.variables.iter() {
+            let pointee = match module.ty(*result_type)? {
+                SpirvType::Pointer { pointee } => *pointee,
+                _ => return reflection_error(format!("Variable %{} does not have a pointer type.", id)),
+            };
+            match *storage {
+                STORAGE_UNIFORM_CONSTANT | STORAGE_UNIFORM | STORAGE_STORAGE_BUFFER => {
+                    let (ty, count) = module.descriptor_type(*storage, pointee)?;
+                    // Blocks are usually anonymous, in which case the name of the block type is more useful.
+                    let mut name = module.name(*id);
+                    if name.is_empty() {
+                        name = module.name(pointee);
+                    }
+                    reflection.bindings.push(ReflectedBinding {
+                        set: module.decorations.get(&(*id, DECORATION_DESCRIPTOR_SET)).cloned().unwrap_or(0),
+                        binding: module.decorations.get(&(*id, DECORATION_BINDING)).cloned().unwrap_or(0),
+                        ty,
+                        count,
+                        stages: module.stages,
+                        name,
+                    });
+                },
+                STORAGE_PUSH_CONSTANT => {
+                    reflection.push_constant_size = module.size_of(pointee, None)?;
+                    reflection.push_constant_stages = module.stages;
+                },
+                STORAGE_INPUT if module.stages.contains(gfx::pso::ShaderStageFlags::VERTEX) => {
+                    if module.decorations.contains_key(&(*id, DECORATION_BUILT_IN)) {
+                        continue;
+                    }
+                    if let Some(location) = module.decorations.get(&(*id, DECORATION_LOCATION)) {
+                        reflection.vertex_inputs.push(ReflectedVertexInput { location: *location, format: module.vertex_format(pointee)?, name: module.name(*id) });
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        reflection.bindings.sort_by_key(|b| (b.set, b.binding));
+        reflection.vertex_inputs.sort_by_key(|input| input.location);
+        return Ok(reflection);
+    }
+
+    pub fn from_file(path: &str) -> Result<ShaderReflection, Error> {
+        let bytes = std::fs::read(path)?;
+        return Self::from_spirv(&bytes);
+    }
+
+    /// Combines the reflection of another stage into this one.
+    /// Bindings used by both stages must have the same type.
+    pub fn merge(mut self, other: &ShaderReflection) -> Result<ShaderReflection, Error> {
+        for binding in other.bindings.iter() {
+            if let Some(existing) = self.bindings.iter_mut().find(|b| b.set == binding.set && b.binding == binding.binding) {
+                if !descriptor_types_compatible(existing.ty, binding.ty) || existing.count != binding.count {
+                    return reflection_error(format!("Binding {} of set {} is declared as {:?} and {:?} by different stages.", binding.binding, binding.set, existing.ty, binding.ty));
+                }
+                existing.stages |= binding.stages;
+                continue;
+            }
+            self.bindings.push(binding.clone());
+        }
+        self.bindings.sort_by_key(|b| (b.set, b.binding));
+
+        self.stages |= other.stages;
+        self.push_constant_size = self.push_constant_size.max(other.push_constant_size);
+        self.push_constant_stages |= other.push_constant_stages;
+        if other.stages.contains(gfx::pso::ShaderStageFlags::VERTEX) {
+            self.vertex_inputs = other.vertex_inputs.clone();
+        }
+        return Ok(self);
+    }
+
+    /// Marks a buffer binding as dynamic, so that it is bound with a dynamic offset.
+    pub fn with_dynamic(mut self, set: u32, binding: u32) -> ShaderReflection {
+        if let Some(b) = self.bindings.iter_mut().find(|b| b.set == set && b.binding == binding) {
+            b.ty = match b.ty {
+                gfx::pso::DescriptorType::UniformBuffer => gfx::pso::DescriptorType::UniformBufferDynamic,
+                gfx::pso::DescriptorType::StorageBuffer => gfx::pso::DescriptorType::StorageBufferDynamic,
+                ty => ty,
+            };
+        }
+        return self;
+    }
+
+    /// The number of descriptor sets used, including any unused sets before the last one.
+    pub fn set_count(&self) -> u32 {
+        return self.bindings.iter().map(|b| b.set + 1).max().unwrap_or(0);
+    }
+
+    pub fn binding(&self, set: u32, binding: u32) -> Option<&ReflectedBinding> {
+        return self.bindings.iter().find(|b| b.set == set && b.binding == binding);
+    }
+
+    /// Creates a layout for every descriptor set used by the shaders, in set order.
+    pub fn create_set_layouts(&self, device: &core::Device) -> Result<Vec<pipeline::DescriptorSetLayout>, Error> {
+        return (0..self.set_count()).map(|set| {
+            let bindings: Vec<gfx::pso::DescriptorSetLayoutBinding> = self.bindings.iter()
+                .filter(|b| b.set == set)
+                .map(|b| gfx::pso::DescriptorSetLayoutBinding {
+                    binding: b.binding,
+                    ty: b.ty,
+                    count: b.count,
+                    stage_flags: b.stages,
+                    immutable_samplers: false,
+                }).collect();
+            pipeline::DescriptorSetLayout::create_raw(bindings, device)
+        }).collect();
+    }
+
+    /// The push constant range used by the shaders, if any. Ranges are measured in 32 bit words.
+    pub fn push_constant_ranges(&self) -> Vec<(gfx::pso::ShaderStageFlags, Range<u32>)> {
+        if self.push_constant_size == 0 {
+            return Vec::new();
+        }
+        return vec![(self.push_constant_stages, 0..(self.push_constant_size + 3) / 4)];
+    }
+
+    /// Checks that the shader inputs which will be written to a set match the bindings declared by the shaders.
+    pub fn validate_inputs(&self, set: u32, inputs: &[(&pipeline::ShaderInput, u32)]) -> Result<(), Error> {
+        for (input, binding) in inputs.iter() {
+            let reflected = match self.binding(set, *binding) {
+                Some(reflected) => reflected,
+                None => return reflection_error(format!("Binding {} of set {} is not used by the shaders.", binding, set)),
+            };
+            if !descriptor_types_compatible(reflected.ty, input.get_binding_type()) {
+                return reflection_error(format!("Binding {} of set {} ('{}') is {:?} in the shaders but the input is {:?}.", binding, set, reflected.name, reflected.ty, input.get_binding_type()));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Checks that descriptor set layouts contain every binding used by the shaders, with the same types.
+    pub fn validate_set_layouts(&self, layouts: &[&pipeline::DescriptorSetLayout]) -> Result<(), Error> {
+        for reflected in self.bindings.iter() {
+            let layout = match layouts.get(reflected.set as usize) {
+                Some(layout) => layout,
+                None => return reflection_error(format!("The shaders use set {} but the pipeline only has {} set layouts.", reflected.set, layouts.len())),
+            };
+            match layout.bindings.iter().find(|b| b.binding == reflected.binding) {
+                Some(b) if descriptor_types_compatible(reflected.ty, b.ty) && b.stage_flags.contains(reflected.stages) => {},
+                Some(b) => return reflection_error(format!("Binding {} of set {} ('{}') is {:?} for {:?} in the shaders but {:?} for {:?} in the layout.",
+                    reflected.binding, reflected.set, reflected.name, reflected.ty, reflected.stages, b.ty, b.stage_flags)),
+                None => return reflection_error(format!("Binding {} of set {} ('{}') is missing from the layout.", reflected.binding, reflected.set, reflected.name)),
+            }
+        }
+        return Ok(());
+    }
+
+    /// Checks that the vertex layouts provide every attribute read by the vertex shader, with the same format.
+    pub fn validate_vertex_layouts(&self, layouts: &[pipeline::VertexLayout]) -> Result<(), Error> {
+        for input in self.vertex_inputs.iter() {
+            let attribute = layouts.iter().flat_map(|layout| layout.attributes.iter()).find(|a| a.location == input.location);
+            match attribute {
+                Some(attribute) if attribute.format == input.format => {},
+                Some(attribute) => return reflection_error(format!("Vertex input {} ('{}') is {:?} in the shader but {:?} in the vertex layout.", input.location, input.name, input.format, attribute.format)),
+                None => return reflection_error(format!("Vertex input {} ('{}') is missing from the vertex layout.", input.location, input.name)),
+            }
+        }
+        return Ok(());
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use gfx::format::Format;
+    use gfx::pso::DescriptorType;
+    use gfx::pso::ShaderStageFlags;
+
+    const OP_SPEC_CONSTANT: u32 = 50;
+    const DECORATION_BLOCK: u32 = 2;
+
+    fn op(opcode: u32, operands: &[u32]) -> Vec<u32> {
+        let mut words = vec![(operands.len() as u32 + 1) << 16 | opcode];
+        words.extend_from_slice(operands);
+        return words;
+    }
+
+    /// Assembles a module with a single entry point of the execution model specified.
+    fn assemble(execution_model: u32, instructions: &[Vec<u32>]) -> Vec<u8> {
+        // The entry point is named "main", which is one word followed by the nul terminator.
+        let mut words = vec![SPIRV_MAGIC, 0x0001_0000, 0, 100, 0];
+        words.extend(op(OP_ENTRY_POINT, &[execution_model, 99, 0x6e69_616d, 0]));
+        for instruction in instructions {
+            words.extend_from_slice(instruction);
+        }
+        return words.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect();
+    }
+
+    /// Float, vec4, mat4, int and uint types, and a constant of 3.
+    fn scalar_types() -> Vec<Vec<u32>> {
+        return vec![
+            op(OP_TYPE_FLOAT, &[1, 32]),
+            op(OP_TYPE_VECTOR, &[2, 1, 4]),
+            op(OP_TYPE_MATRIX, &[3, 2, 4]),
+            op(OP_TYPE_INT, &[4, 32, 1]),
+            op(OP_TYPE_INT, &[5, 32, 0]),
+            op(OP_CONSTANT, &[5, 6, 3]),
+        ];
+    }
+
+    /// A 2D texture array of the length specified, bound to binding 2 of set 1.
+    fn texture_array(length_instruction: Vec<u32>) -> Vec<Vec<u32>> {
+        let mut instructions = scalar_types();
+        instructions.push(length_instruction);
+        instructions.extend(vec![
+            op(OP_DECORATE, &[13, DECORATION_DESCRIPTOR_SET, 1]),
+            op(OP_DECORATE, &[13, DECORATION_BINDING, 2]),
+            // Sampled type, 2D, not depth, not arrayed, single sampled, sampled, unknown format.
+            op(OP_TYPE_IMAGE, &[10, 1, 1, 0, 0, 0, 1, 0]),
+            op(OP_TYPE_ARRAY, &[11, 10, 7]),
+            op(OP_TYPE_POINTER, &[12, STORAGE_UNIFORM_CONSTANT, 11]),
+            op(OP_VARIABLE, &[12, 13, STORAGE_UNIFORM_CONSTANT]),
+        ]);
+        return instructions;
+    }
+
+    #[test]
+    fn descriptor_arrays_are_counted() {
+        let bytes = assemble(4, &texture_array(op(OP_CONSTANT, &[5, 7, 3])));
+        let reflection = ShaderReflection::from_spirv(&bytes).unwrap();
+        assert_eq!(reflection.stages, ShaderStageFlags::FRAGMENT);
+        assert_eq!(reflection.bindings.len(), 1);
+        let binding = &reflection.bindings[0];
+        assert_eq!((binding.set, binding.binding, binding.ty, binding.count), (1, 2, DescriptorType::SampledImage, 3));
+        assert_eq!(binding.stages, ShaderStageFlags::FRAGMENT);
+    }
+
+    #[test]
+    fn arrays_sized_by_specialization_constants_are_rejected() {
+        let bytes = assemble(4, &texture_array(op(OP_SPEC_CONSTANT, &[5, 7, 3])));
+        match ShaderReflection::from_spirv(&bytes) {
+            Err(Error::Reflection(_)) => {},
+            other => panic!("expected a reflection error, got {:?}", other.map(|r| r.bindings)),
+        }
+    }
+
+    #[test]
+    fn push_constant_size_uses_offsets_and_strides() {
+        let mut instructions = scalar_types();
+        instructions.extend(vec![
+            op(OP_MEMBER_DECORATE, &[20, 0, DECORATION_OFFSET, 0]),
+            op(OP_MEMBER_DECORATE, &[20, 0, DECORATION_MATRIX_STRIDE, 16]),
+            op(OP_MEMBER_DECORATE, &[20, 1, DECORATION_OFFSET, 64]),
+            op(OP_DECORATE, &[20, DECORATION_BLOCK]),
+            op(OP_TYPE_STRUCT, &[20, 3, 4]),
+            op(OP_TYPE_POINTER, &[21, STORAGE_PUSH_CONSTANT, 20]),
+            op(OP_VARIABLE, &[21, 22, STORAGE_PUSH_CONSTANT]),
+        ]);
+        let reflection = ShaderReflection::from_spirv(&assemble(0, &instructions)).unwrap();
+        assert_eq!(reflection.push_constant_size, 68);
+        assert_eq!(reflection.push_constant_stages, ShaderStageFlags::VERTEX);
+        // Ranges are measured in words and rounded up.
+        assert_eq!(reflection.push_constant_ranges(), vec![(ShaderStageFlags::VERTEX, 0..17)]);
+    }
+
+    #[test]
+    fn buffer_blocks_and_vertex_inputs_are_reflected() {
+        let mut instructions = scalar_types();
+        instructions.extend(vec![
+            op(OP_DECORATE, &[30, DECORATION_BUFFER_BLOCK]),
+            op(OP_DECORATE, &[32, DECORATION_DESCRIPTOR_SET, 0]),
+            op(OP_DECORATE, &[32, DECORATION_BINDING, 3]),
+            op(OP_DECORATE, &[41, DECORATION_LOCATION, 1]),
+            op(OP_DECORATE, &[42, DECORATION_BUILT_IN, 42]),
+            op(OP_TYPE_STRUCT, &[30, 2]),
+            op(OP_TYPE_POINTER, &[31, STORAGE_UNIFORM, 30]),
+            op(OP_VARIABLE, &[31, 32, STORAGE_UNIFORM]),
+            op(OP_TYPE_VECTOR, &[39, 4, 3]),
+            op(OP_TYPE_POINTER, &[40, STORAGE_INPUT, 39]),
+            op(OP_VARIABLE, &[40, 41, STORAGE_INPUT]),
+            op(OP_TYPE_POINTER, &[43, STORAGE_INPUT, 4]),
+            op(OP_VARIABLE, &[43, 42, STORAGE_INPUT]),
+        ]);
+        let reflection = ShaderReflection::from_spirv(&assemble(0, &instructions)).unwrap();
+        assert_eq!(reflection.bindings.len(), 1);
+        assert_eq!((reflection.bindings[0].binding, reflection.bindings[0].ty), (3, DescriptorType::StorageBuffer));
+        // The built in input is not a vertex attribute.
+        assert_eq!(reflection.vertex_inputs.len(), 1);
+        assert_eq!((reflection.vertex_inputs[0].location, reflection.vertex_inputs[0].format), (1, Format::Rgb32Int));
+    }
+
+    #[test]
+    fn byte_swapped_modules_are_read() {
+        let bytes = assemble(5, &[]);
+        let swapped: Vec<u8> = bytes.chunks(4).flat_map(|w| vec![w[3], w[2], w[1], w[0]]).collect();
+        assert_eq!(ShaderReflection::from_spirv(&swapped).unwrap().stages, ShaderStageFlags::COMPUTE);
+    }
+
+    #[test]
+    fn invalid_modules_are_rejected() {
+        assert!(ShaderReflection::from_spirv(&[0; 19]).is_err());
+        assert!(ShaderReflection::from_spirv(&[0; 20]).is_err());
+        let mut truncated = assemble(0, &[op(OP_TYPE_FLOAT, &[1, 32])]);
+        truncated.truncate(truncated.len() - 4);
+        assert!(ShaderReflection::from_spirv(&truncated).is_err());
+    }
+
+    #[test]
+    fn truncated_instructions_are_rejected() {
+        // A decoration without its decoration operand.
+        let bytes = assemble(0, &[op(OP_DECORATE, &[1])]);
+        match ShaderReflection::from_spirv(&bytes) {
+            Err(Error::Reflection(_)) => {},
+            other => panic!("expected a reflection error, got {:?}", other.map(|r| r.bindings)),
+        }
+        // An image type without its sampled and format operands.
+        assert!(ShaderReflection::from_spirv(&assemble(0, &[op(OP_TYPE_IMAGE, &[10, 1, 1, 0, 0, 0])])).is_err());
+        assert!(ShaderReflection::from_spirv(&assemble(0, &[op(OP_ENTRY_POINT, &[0])])).is_err());
+    }
+
+    fn reflect_mesh_shaders(vertex: &[u8]) -> ShaderReflection {
+        let fragment = ShaderReflection::from_spirv(include_bytes!(concat!(env!("OUT_DIR"), "/std_mesh_f.spv"))).unwrap();
+        return ShaderReflection::from_spirv(vertex).unwrap().merge(&fragment).unwrap();
+    }
+
+    fn binding_summary(reflection: &ShaderReflection) -> Vec<(u32, u32, DescriptorType, usize, ShaderStageFlags)> {
+        return reflection.bindings.iter().map(|b| (b.set, b.binding, b.ty, b.count, b.stages)).collect();
+    }
+
+    #[test]
+    fn mesh_shader_interface() {
+        let reflection = reflect_mesh_shaders(include_bytes!(concat!(env!("OUT_DIR"), "/std_mesh_v.spv")));
+        let (v, f) = (ShaderStageFlags::VERTEX, ShaderStageFlags::FRAGMENT);
+        assert_eq!(binding_summary(&reflection), vec![
+            (0, 0, DescriptorType::UniformBuffer, 1, v),
+            (0, 1, DescriptorType::UniformBuffer, 1, f),
+            (0, 2, DescriptorType::UniformBuffer, 1, f),
+            (0, 3, DescriptorType::Sampler, 1, f),
+            (0, 4, DescriptorType::SampledImage, 1, f),
+            (0, 5, DescriptorType::SampledImage, 1, f),
+            (1, 0, DescriptorType::UniformBuffer, 1, f),
+            (1, 1, DescriptorType::Sampler, 1, f),
+            (1, 2, DescriptorType::SampledImage, 1, f),
+            (1, 3, DescriptorType::SampledImage, 1, f),
+            (1, 4, DescriptorType::SampledImage, 1, f),
+            (1, 5, DescriptorType::SampledImage, 1, f),
+        ]);
+        // Three matrices and the shadow flag.
+        assert_eq!(reflection.push_constant_size, 196);
+        assert_eq!(reflection.push_constant_stages, v);
+        let inputs: Vec<(u32, Format)> = reflection.vertex_inputs.iter().map(|i| (i.location, i.format)).collect();
+        assert_eq!(inputs, vec![(0, Format::Rgb32Float), (1, Format::Rgb32Float), (2, Format::Rg32Float), (3, Format::Rgba32Int), (4, Format::Rgba32Float)]);
+    }
+
+    #[test]
+    fn instanced_mesh_shader_reads_the_model_matrix_per_instance() {
+        let reflection = reflect_mesh_shaders(include_bytes!(concat!(env!("OUT_DIR"), "/std_mesh_v-instanced.spv")));
+        let locations: Vec<u32> = reflection.vertex_inputs.iter().map(|i| i.location).collect();
+        assert_eq!(locations, (0..9).collect::<Vec<u32>>());
+        assert!(reflection.vertex_inputs[5..].iter().all(|i| i.format == Format::Rgba32Float));
+        assert_eq!(reflection.set_count(), 2);
+    }
+
+    #[test]
+    fn shadow_shader_interface() {
+        let reflection = ShaderReflection::from_spirv(include_bytes!(concat!(env!("OUT_DIR"), "/std_shadow_v.spv"))).unwrap();
+        assert!(reflection.bindings.is_empty());
+        // The model and view projection matrices.
+        assert_eq!(reflection.push_constant_size, 128);
+        assert_eq!(reflection.push_constant_ranges(), vec![(ShaderStageFlags::VERTEX, 0..32)]);
+        let inputs: Vec<(u32, Format)> = reflection.vertex_inputs.iter().map(|i| (i.location, i.format)).collect();
+        assert_eq!(inputs, vec![(0, Format::Rgb32Float)]);
+    }
+
+}
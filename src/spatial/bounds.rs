@@ -0,0 +1,303 @@
+use crate::*;
+
+use spatial::model::ModelVertex;
+
+/// An axis aligned bounding box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+
+    pub min: Vector3f,
+    pub max: Vector3f,
+
+}
+
+impl BoundingBox {
+
+    pub fn new(min: Vector3f, max: Vector3f) -> Self {
+        return Self { min, max };
+    }
+
+    /// The smallest box which contains every point, or `None` if there are no points.
+    pub fn from_points<I: IntoIterator<Item = Vector3f>>(points: I) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        let mut bounds = Self::new(first, first);
+        for point in points {
+            bounds.min = Vector3f::new(bounds.min.x.min(point.x), bounds.min.y.min(point.y), bounds.min.z.min(point.z));
+            bounds.max = Vector3f::new(bounds.max.x.max(point.x), bounds.max.y.max(point.y), bounds.max.z.max(point.z));
+        }
+        return Some(bounds);
+    }
+
+    pub fn center(&self) -> Vector3f {
+        return (self.min + self.max) * 0.5;
+    }
+
+    pub fn extents(&self) -> Vector3f {
+        return (self.max - self.min) * 0.5;
+    }
+
+    /// The axis aligned box which contains this box after it has been transformed by the matrix.
+    pub fn transform(&self, matrix: &Matrix4f) -> Self {
+        // Each axis of the transformed box is the sum of the smallest and largest contributions of every column.
+        let translation = matrix.w.truncate();
+        let mut min = translation;
+        let mut max = translation;
+        for column in 0..3 {
+            let axis = matrix[column].truncate();
+            for row in 0..3 {
+                let a = axis[row] * self.min[column];
+                let b = axis[row] * self.max[column];
+                min[row] += a.min(b);
+                max[row] += a.max(b);
+            }
+        }
+        return Self::new(min, max);
+    }
+
+}
+
+/// A bounding sphere.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingSphere {
+
+    pub center: Vector3f,
+    pub radius: f32,
+
+}
+
+impl BoundingSphere {
+
+    pub fn new(center: Vector3f, radius: f32) -> Self {
+        return Self { center, radius };
+    }
+
+    /// The sphere centered on the box which contains all of the points, or `None` if there are no points.
+    pub fn from_points<I: IntoIterator<Item = Vector3f> + Clone>(points: I) -> Option<Self> {
+        let center = BoundingBox::from_points(points.clone())?.center();
+        let radius = points.into_iter().map(|point| (point - center).magnitude2()).fold(0.0f32, f32::max).sqrt();
+        return Some(Self::new(center, radius));
+    }
+
+    /// The sphere which contains this sphere after it has been transformed by the matrix.
+    /// The radius is scaled by the largest scale of the matrix, so non-uniform scaling gives a larger sphere than needed.
+    pub fn transform(&self, matrix: &Matrix4f) -> Self {
+        let center = (matrix * self.center.extend(1.0)).truncate();
+        let scale = matrix.x.truncate().magnitude2().max(matrix.y.truncate().magnitude2()).max(matrix.z.truncate().magnitude2()).sqrt();
+        return Self::new(center, self.radius * scale);
+    }
+
+}
+
+/// The local space bounding volumes of a mesh, which are used to skip meshes outside of the view.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshBounds {
+
+    pub aabb: BoundingBox,
+    pub sphere: BoundingSphere,
+
+}
+
+impl MeshBounds {
+
+    /// Computes the bounds of the vertex positions, or `None` if there are no vertices.
+    pub fn from_vertices(vertices: &[ModelVertex]) -> Option<Self> {
+        let positions = vertices.iter().map(|vertex| vertex.pos);
+        let aabb = BoundingBox::from_points(positions.clone())?;
+        let sphere = BoundingSphere::from_points(positions)?;
+        return Some(Self { aabb, sphere });
+    }
+
+    /// Transforms the bounds into world space with the model matrix of a node.
+    pub fn transform(&self, matrix: &Matrix4f) -> Self {
+        return Self { aabb: self.aabb.transform(matrix), sphere: self.sphere.transform(matrix) };
+    }
+
+}
+
+/// The six planes of a view frustum in world space, with normals pointing into the frustum.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+
+    /// The left, right, bottom, top, near and far planes, as `(normal, distance)` with `dot(normal, p) + distance >= 0` inside.
+    pub planes: [Vector4f; 6],
+
+}
+
+impl Frustum {
+
+    /// Extracts the frustum from a view-projection matrix which maps depth to the `[0, 1]` range.
+    pub fn from_matrix(view_projection: &Matrix4f) -> Self {
+        let r0 = view_projection.row(0);
+        let r1 = view_projection.row(1);
+        let r2 = view_projection.row(2);
+        let r3 = view_projection.row(3);
+        let mut planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2];
+        for plane in planes.iter_mut() {
+            let length = plane.truncate().magnitude();
+            if length > 0.0 {
+                *plane /= length;
+            }
+        }
+        return Self { planes };
+    }
+
+    /// The frustum of a camera.
+    pub fn from_camera(camera_transform: &scene::CameraTransform) -> Self {
+        return Self::from_matrix(&(camera_transform.projection * camera_transform.view));
+    }
+
+    fn distance(plane: &Vector4f, point: Vector3f) -> f32 {
+        return plane.truncate().dot(point) + plane.w;
+    }
+
+    /// Returns false if the sphere is entirely outside of the frustum.
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        return self.planes.iter().all(|plane| Self::distance(plane, sphere.center) >= -sphere.radius);
+    }
+
+    /// Returns false if the box is entirely outside of the frustum.
+    /// Boxes near the corners of the frustum may be reported as intersecting when they are not.
+    pub fn intersects_box(&self, aabb: &BoundingBox) -> bool {
+        return self.planes.iter().all(|plane| {
+            // The corner of the box furthest along the normal of the plane.
+            let corner = Vector3f::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            return Self::distance(plane, corner) >= 0.0;
+        });
+    }
+
+    /// Returns false if the world space bounds are entirely outside of the frustum.
+    /// The sphere is tested first as it is cheaper, and the box only if the sphere intersects.
+    pub fn intersects(&self, bounds: &MeshBounds) -> bool {
+        return self.intersects_sphere(&bounds.sphere) && self.intersects_box(&bounds.aabb);
+    }
+
+}
+
+/// Counts of the meshes drawn and culled by the mesh render system in the last frame, for profiling.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RenderStats {
+
+    /// The number of meshes which were drawn.
+    pub drawn: usize,
+    /// The number of meshes which were skipped because their bounds are outside of the view frustum.
+    pub culled: usize,
+    /// The number of draw calls the drawn meshes were submitted with. Meshes which share buffers and a material are drawn with one instanced draw call, unless there was no space left for their model matrices.
+    pub draw_calls: usize,
+    /// The number of draw calls which rendered shadow casting meshes into the layers of the shadow maps.
+    pub shadow_draw_calls: usize,
+
+}
+
+impl RenderStats {
+
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// The total number of meshes which were considered for drawing.
+    pub fn total(&self) -> usize {
+        return self.drawn + self.culled;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// The frustum of a camera with a 90 degree field of view and a square frame, so that the side planes are at 45 degrees.
+    /// The camera looks along -z from the position specified.
+    fn frustum(position: Vector3f) -> Frustum {
+        let camera = spatial::Camera::create(Vector2f::new(512.0, 512.0), std::f32::consts::FRAC_PI_2);
+        let view = Matrix4f::from_translation(-position);
+        return Frustum::from_camera(&scene::CameraTransform::new(camera.projection, view));
+    }
+
+    /// The bounds of a cube with the center and half size specified.
+    fn cube(center: Vector3f, half_size: f32) -> MeshBounds {
+        let extents = Vector3f::new(half_size, half_size, half_size);
+        return MeshBounds { aabb: BoundingBox::new(center - extents, center + extents), sphere: BoundingSphere::new(center, extents.magnitude()) };
+    }
+
+    #[test]
+    fn box_in_front_of_the_camera_is_inside() {
+        let frustum = frustum(Vector3f::zero());
+        assert!(frustum.intersects(&cube(Vector3f::new(0.0, 0.0, -100.0), 5.0)));
+        assert!(frustum.intersects(&cube(Vector3f::new(80.0, -80.0, -100.0), 5.0)));
+        assert!(frustum.intersects(&cube(Vector3f::new(0.0, 0.0, -100000.0), 5.0)));
+    }
+
+    #[test]
+    fn boxes_outside_each_plane_are_culled() {
+        let frustum = frustum(Vector3f::zero());
+        let outside = [
+            Vector3f::new(-200.0, 0.0, -100.0),
+            Vector3f::new(200.0, 0.0, -100.0),
+            Vector3f::new(0.0, -200.0, -100.0),
+            Vector3f::new(0.0, 200.0, -100.0),
+            // The near plane of the camera is 20 units in front of it.
+            Vector3f::new(0.0, 0.0, -10.0),
+            Vector3f::new(0.0, 0.0, 100.0),
+            // The far plane is 1000000 units in front of it.
+            Vector3f::new(0.0, 0.0, -2000000.0),
+        ];
+        for center in outside.iter() {
+            assert!(!frustum.intersects(&cube(*center, 5.0)), "a box at {:?} was not culled", center);
+        }
+    }
+
+    #[test]
+    fn boxes_straddling_a_plane_are_inside() {
+        let frustum = frustum(Vector3f::zero());
+        let straddling = [
+            // At a depth of 100 the side planes are 100 units from the center of the view.
+            Vector3f::new(-103.0, 0.0, -100.0),
+            Vector3f::new(103.0, 0.0, -100.0),
+            Vector3f::new(0.0, -103.0, -100.0),
+            Vector3f::new(0.0, 103.0, -100.0),
+            Vector3f::new(0.0, 0.0, -18.0),
+        ];
+        for center in straddling.iter() {
+            assert!(frustum.intersects(&cube(*center, 5.0)), "a box at {:?} was culled", center);
+        }
+    }
+
+    #[test]
+    fn frustum_follows_the_camera() {
+        let frustum = frustum(Vector3f::new(500.0, 0.0, 0.0));
+        assert!(frustum.intersects(&cube(Vector3f::new(500.0, 0.0, -100.0), 5.0)));
+        assert!(!frustum.intersects(&cube(Vector3f::new(0.0, 0.0, -100.0), 5.0)));
+    }
+
+    #[test]
+    fn mesh_bounds_are_culled_in_world_space() {
+        let vertices: Vec<ModelVertex> = [-5.0f32, 5.0].iter().flat_map(|x| [-5.0f32, 5.0].iter().map(move |y| (*x, *y)))
+            .flat_map(|(x, y)| [-5.0f32, 5.0].iter().map(move |z| ModelVertex::new(Vector3f::new(x, y, *z), Vector3f::unit_z(), Vector2f::new(0.0, 0.0))))
+            .collect();
+        let bounds = MeshBounds::from_vertices(&vertices).unwrap();
+        assert_eq!(bounds.aabb, BoundingBox::new(Vector3f::new(-5.0, -5.0, -5.0), Vector3f::new(5.0, 5.0, 5.0)));
+
+        let frustum = frustum(Vector3f::zero());
+        assert!(frustum.intersects(&bounds.transform(&Matrix4f::from_translation(Vector3f::new(0.0, 0.0, -100.0)))));
+        assert!(!frustum.intersects(&bounds.transform(&Matrix4f::from_translation(Vector3f::new(0.0, 0.0, 100.0)))));
+        // Scaling the mesh up makes it reach into the view from beside it.
+        let scaled = Matrix4f::from_translation(Vector3f::new(-130.0, 0.0, -100.0)) * Matrix4f::from_scale(8.0);
+        assert!(!frustum.intersects(&bounds.transform(&Matrix4f::from_translation(Vector3f::new(-130.0, 0.0, -100.0)))));
+        assert!(frustum.intersects(&bounds.transform(&scaled)));
+    }
+
+    #[test]
+    fn transformed_boxes_contain_the_rotated_box() {
+        let aabb = BoundingBox::new(Vector3f::new(-1.0, -2.0, -3.0), Vector3f::new(1.0, 2.0, 3.0));
+        let rotated = aabb.transform(&Matrix4f::from_angle_y(cgmath::Deg(90.0)));
+        assert!((rotated.min - Vector3f::new(-3.0, -2.0, -1.0)).magnitude() < 1e-5);
+        assert!((rotated.max - Vector3f::new(3.0, 2.0, 1.0)).magnitude() < 1e-5);
+    }
+
+}
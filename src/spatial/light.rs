@@ -11,11 +11,18 @@ pub trait Light {
 
 pub struct LightComponent {
     pub light: Box<Light + Send + Sync>,
+    /// If true, the light is given a shadow map while there is one free. See `render::ShadowSettings` for the number of shadow maps.
+    pub cast_shadows: bool,
 }
 
 impl LightComponent {
     pub fn new<L: 'static + Light + Send + Sync>(light: L) -> Self {
-        return Self { light: Box::new(light) };
+        return Self { light: Box::new(light), cast_shadows: false };
+    }
+
+    pub fn with_shadows(mut self, cast_shadows: bool) -> Self {
+        self.cast_shadows = cast_shadows;
+        return self;
     }
 }
 
@@ -54,17 +61,59 @@ impl Light for PointLight {
 
 }
 
+/// A light which shines in one direction everywhere, such as the sun. Its position is ignored and it is not attenuated.
+#[derive(Copy, Clone)]
+pub struct DirectionalLight {
+
+    pub color: OpaqueColor,
+    /// The direction the light travels in.
+    pub direction: Vector3f,
+
+}
+
+impl DirectionalLight {
+
+    pub fn new(color: OpaqueColor, direction: Vector3f) -> Self {
+        return Self { color, direction: direction.normalize() };
+    }
+
+}
+
+impl Light for DirectionalLight {
+
+    fn get_data(&self, _pos: Vector3f) -> LightData {
+        return LightData::directional(self.direction, self.color);
+    }
+
+}
+
+pub const POINT_LIGHT: i32 = 0;
+pub const DIRECTIONAL_LIGHT: i32 = 1;
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct LightData {
-    pub pos: Al16<Vector3f>,
+    /// The position of a point light, or the direction of a directional light.
+    pub pos: Vector3f,
+    /// `POINT_LIGHT` or `DIRECTIONAL_LIGHT`.
+    pub kind: i32,
     pub color: OpaqueColor,
+    /// The index of the shadow map of the light, or -1 if it casts no shadows. This is assigned by the light system.
+    pub shadow_index: i32,
 
 }
 
 impl LightData {
     pub fn new(pos: Vector3f, color: OpaqueColor) -> Self {
-        return Self { pos: Al16::new(pos), color };
+        return Self { pos, kind: POINT_LIGHT, color, shadow_index: -1 };
+    }
+
+    pub fn directional(direction: Vector3f, color: OpaqueColor) -> Self {
+        return Self { pos: direction, kind: DIRECTIONAL_LIGHT, color, shadow_index: -1 };
+    }
+
+    pub fn is_directional(&self) -> bool {
+        return self.kind == DIRECTIONAL_LIGHT;
     }
 }
 
@@ -115,17 +164,17 @@ impl LightsList {
 
 }
 
-/// A struct which contains light data and controls the GPU buffer.
+/// A struct which contains light data and writes it to the uniform ring each frame.
 pub struct LightsController {
     pub lights: LightsList,
-    pub buffer: buffer::Buffer,
+    /// The dynamic offset of the lights written for the current frame.
+    pub offset: u32,
 }
 
 impl LightsController {
 
-    pub fn new(device: &mut core::Device) -> Self {
-        let lights: LightsList = LightsList::new();
-        return Self { lights, buffer: buffer::Buffer::alloc_uniform(&[lights], device) };
+    pub fn new() -> Self {
+        return Self { lights: LightsList::new(), offset: 0 };
     }
 
 
@@ -137,8 +186,12 @@ impl LightsController {
         return self.lights.remove_light(index);
     }
 
-    pub fn update_buffer(&mut self, device: &mut core::Device) {
-        self.buffer.fill_buffer(&[self.lights], device);
+    /// Writes the lights for the current frame into the ring.
+    /// The previous offset is kept if the ring is full, so the lights of an earlier frame are used.
+    pub fn update_buffer(&mut self, ring: &mut memory::UniformRing) {
+        if let Some(offset) = ring.push(&self.lights) {
+            self.offset = offset;
+        }
     }
 
 }
\ No newline at end of file
@@ -4,13 +4,11 @@ use std::sync::Arc;
 
 static mut SHARED_SAMPLER: Option<pipeline::TextureSampler> = None;
 
-unsafe fn shared_sampler(device: &core::Device) -> &pipeline::TextureSampler {
-    if let Some(sampler) = SHARED_SAMPLER.as_ref() {
-        return sampler;
-    } else {
-        SHARED_SAMPLER = Some(pipeline::TextureSampler::new(device));
+unsafe fn shared_sampler(device: &core::Device) -> Result<&pipeline::TextureSampler, Error> {
+    if SHARED_SAMPLER.is_none() {
+        SHARED_SAMPLER = Some(pipeline::TextureSampler::new(device)?);
     }
-    return SHARED_SAMPLER.as_ref().unwrap();
+    return Ok(SHARED_SAMPLER.as_ref().unwrap());
 }
 
 pub struct Material {
@@ -23,20 +21,46 @@ pub struct Material {
     pub albedo_global: OpaqueColor,
     pub metallic_global: f32,
     pub roughness_global: f32,
+    /// Transparent materials are drawn after opaque ones, back-to-front, so that they blend correctly.
+    pub transparent: bool,
 
 }
 
 impl Material {
 
     pub fn new(albedo_texture: Option<Res<texture::Texture>>, normal_texture: Option<Res<texture::Texture>>, metallic_texture: Option<Res<texture::Texture>>, roughness_texture: Option<Res<texture::Texture>>, albedo_global: OpaqueColor, metallic_global: f32, roughness_global: f32) -> Self {
-        return Self { albedo_texture, normal_texture, metallic_texture, roughness_texture, albedo_global, metallic_global, roughness_global };
+        return Self { albedo_texture, normal_texture, metallic_texture, roughness_texture, albedo_global, metallic_global, roughness_global, transparent: false };
     }
 
     pub fn color(color: OpaqueColor, metallic: f32, roughness: f32) -> Self {
         return Self::new(None, None, None, None, color, metallic, roughness);
     }
 
-    pub fn create_buffer(&self, graphics: &mut render::Graphics) -> MaterialBuffer {
+    pub fn with_transparent(mut self, transparent: bool) -> Self {
+        self.transparent = transparent;
+        return self;
+    }
+
+    /// Gets a copy of the material which shares its textures, if every texture is held in an `Arc`.
+    pub fn share(&self) -> Option<Self> {
+        fn share_texture(texture: &Option<Res<texture::Texture>>) -> Option<Option<Res<texture::Texture>>> {
+            return match texture {
+                Some(texture) => texture.share().map(Some),
+                None => Some(None),
+            };
+        }
+        return Some(Self::new(
+            share_texture(&self.albedo_texture)?,
+            share_texture(&self.normal_texture)?,
+            share_texture(&self.metallic_texture)?,
+            share_texture(&self.roughness_texture)?,
+            self.albedo_global,
+            self.metallic_global,
+            self.roughness_global,
+        ).with_transparent(self.transparent));
+    }
+
+    pub fn create_buffer(&self, descriptors: &MaterialDescriptors, graphics: &mut render::Graphics) -> Result<MaterialBuffer, Error> {
         let mut options: i32 = 0;
         let mut albedo: Option<Arc<buffer::TextureBuffer>> = None;
         let mut normal: Option<Arc<buffer::TextureBuffer>> = None;
@@ -44,27 +68,27 @@ impl Material {
         let mut roughness: Option<Arc<buffer::TextureBuffer>> = None;
 
         if let Some(tex) = self.albedo_texture.as_ref() {
-            albedo = Some(Arc::new(buffer::TextureBuffer::create(tex, &mut graphics.device)));
+            albedo = Some(Arc::new(buffer::TextureBuffer::create(tex, &mut graphics.device)?));
             options |= ShaderData::USE_ALBEDO_BIT;
         }
         if let Some(tex) = self.normal_texture.as_ref() {
-            normal = Some(Arc::new(buffer::TextureBuffer::create(tex, &mut graphics.device)));
+            normal = Some(Arc::new(buffer::TextureBuffer::create(tex, &mut graphics.device)?));
             options |= ShaderData::USE_NORMAL_BIT;
         }
         if let Some(tex) = self.metallic_texture.as_ref() {
-            metallic = Some(Arc::new(buffer::TextureBuffer::create(tex, &mut graphics.device)));
+            metallic = Some(Arc::new(buffer::TextureBuffer::create(tex, &mut graphics.device)?));
             options |= ShaderData::USE_METALLIC_BIT;
         }
         if let Some(tex) = self.roughness_texture.as_ref() {
-            roughness = Some(Arc::new(buffer::TextureBuffer::create(tex, &mut graphics.device)));
+            roughness = Some(Arc::new(buffer::TextureBuffer::create(tex, &mut graphics.device)?));
             options |= ShaderData::USE_ROUGHNESS_BIT;
         }
 
         let shader_data = ShaderData::new(options, self.albedo_global, self.metallic_global, self.roughness_global);
         let texture_buffers: MaterialTextureBuffers = MaterialTextureBuffers { albedo, normal, metallic, roughness };
-        let data_buffer = buffer::Buffer::alloc_uniform(&[shader_data], &graphics.device);
+        let data_buffer = buffer::Buffer::alloc_uniform(&[shader_data], &graphics.device)?;
 
-        return MaterialBuffer::new(texture_buffers, data_buffer, &graphics.device).expect("Failed to create material buffer!");
+        return MaterialBuffer::new(texture_buffers, data_buffer, descriptors, &graphics.device);
     }
 
 }
@@ -78,13 +102,22 @@ pub struct MaterialComponent {
 
 impl MaterialComponent {
 
-    pub fn new(material: Material, graphics: &mut render::Graphics) -> Self {
-        let buffer = Arc::new(material.create_buffer(graphics));
-        return Self { material, buffer };
+    /// Creates the buffers of the material. The descriptors can be taken from the mesh render pipeline with `material_descriptors`.
+    pub fn new(material: Material, descriptors: &MaterialDescriptors, graphics: &mut render::Graphics) -> Result<Self, Error> {
+        let buffer = Arc::new(material.create_buffer(descriptors, graphics)?);
+        return Ok(Self { material, buffer });
+    }
+
+    pub fn write_buffers(&mut self, descriptors: &MaterialDescriptors, graphics: &mut render::Graphics) -> Result<(), Error> {
+        self.buffer = Arc::new(self.material.create_buffer(descriptors, graphics)?);
+        return Ok(());
     }
 
-    pub fn write_buffers(&mut self, graphics: &mut render::Graphics) {
-        self.buffer = Arc::new(self.material.create_buffer(graphics));
+    /// Gets a component for another entity which uses the same material buffer.
+    /// Entities with the same material and vertex buffer are drawn together with instancing.
+    /// Returns `None` if the textures of the material are not shared, see `Material::share`.
+    pub fn instance(&self) -> Option<Self> {
+        return Some(Self { material: self.material.share()?, buffer: self.buffer.clone() });
     }
 
 }
@@ -145,59 +178,66 @@ impl MaterialTextureBuffers {
 
 }
 
+/// The allocator and layout which material descriptor sets are created with.
+#[derive(Clone)]
+pub struct MaterialDescriptors {
+
+    pub allocator: pipeline::DescriptorAllocator,
+    pub layout: Arc<pipeline::DescriptorSetLayout>,
+
+}
+
+impl MaterialDescriptors {
+
+    pub fn new(allocator: pipeline::DescriptorAllocator, layout: Arc<pipeline::DescriptorSetLayout>) -> Self {
+        return Self { allocator, layout };
+    }
+
+}
+
 pub struct MaterialBuffer {
 
     pub texture_buffers: MaterialTextureBuffers,
     pub data_buffer: buffer::Buffer,
-    pub descriptor_set: pipeline::DescriptorSet,
+    /// The set is returned to the allocator when the buffer is dropped.
+    pub descriptor_set: pipeline::AllocatedDescriptorSet,
 
 }
 
 
 impl MaterialBuffer {
 
-    fn create_desc_set(device: &core::Device) -> Result<pipeline::DescriptorSet, &'static str> {
-        if let Some(layout) = unsafe { spatial::pipe::mesh::MATERIAL_DESCRIPTOR_LAYOUT.as_ref() } {
-            let mut descriptor_pool: pipeline::DescriptorPool = pipeline::DescriptorPool::new(1, &[
-                (layout.as_ref(), 1)
-            ], device);
-            let descriptor_set: pipeline::DescriptorSet = pipeline::DescriptorSet::new(layout.as_ref(), &mut descriptor_pool,device)?;
-            return Ok(descriptor_set);
-        }
-        return Err("Failed to create material buffer - the mesh render pipeline has not yet been initialised.");
-    }
-
-    pub fn new(texture_buffers: MaterialTextureBuffers, data_buffer: buffer::Buffer, device: &core::Device) -> Result<Self, &'static str> {
-        let descriptor_set = Self::create_desc_set(device)?;
+    pub fn new(texture_buffers: MaterialTextureBuffers, data_buffer: buffer::Buffer, descriptors: &MaterialDescriptors, device: &core::Device) -> Result<Self, Error> {
+        let descriptor_set = descriptors.allocator.allocate(&descriptors.layout, device)?;
         let this = Self {
             texture_buffers,
             data_buffer,
             descriptor_set
         };
-        this.write_descriptor_input(device);
+        this.write_descriptor_input(device)?;
         return Ok(this);
     }
 
-    pub fn empty(device: &core::Device) -> Result<Self, &'static str> {
-        let data_buffer = buffer::Buffer::alloc_uniform(&[0i32], device);
-        return Self::new(MaterialTextureBuffers::none(), data_buffer, device);
+    pub fn empty(descriptors: &MaterialDescriptors, device: &core::Device) -> Result<Self, Error> {
+        let data_buffer = buffer::Buffer::alloc_uniform(&[0i32], device)?;
+        return Self::new(MaterialTextureBuffers::none(), data_buffer, descriptors, device);
     }
 
-    pub fn basic_albedo(albedo: Arc<buffer::TextureBuffer>, metallic: f32, roughness: f32, device: &core::Device) -> Result<Self, &'static str> {
+    pub fn basic_albedo(albedo: Arc<buffer::TextureBuffer>, metallic: f32, roughness: f32, descriptors: &MaterialDescriptors, device: &core::Device) -> Result<Self, Error> {
         let shader_data = ShaderData::new(ShaderData::USE_ALBEDO_BIT, OpaqueColor::black(), metallic, roughness);
-        let data_buffer = buffer::Buffer::alloc_uniform(&[shader_data], device);
-        return Self::new(MaterialTextureBuffers::from_albedo(albedo), data_buffer, device);
+        let data_buffer = buffer::Buffer::alloc_uniform(&[shader_data], device)?;
+        return Self::new(MaterialTextureBuffers::from_albedo(albedo), data_buffer, descriptors, device);
     }
 
-    pub fn with_color(color: OpaqueColor, metallic: f32, roughness: f32, device: &core::Device) -> Result<Self, &'static str> {
+    pub fn with_color(color: OpaqueColor, metallic: f32, roughness: f32, descriptors: &MaterialDescriptors, device: &core::Device) -> Result<Self, Error> {
         let shader_data = ShaderData::new(0, color, metallic, roughness);
-        let data_buffer = buffer::Buffer::alloc_uniform(&[shader_data], device);
-        return Self::new(MaterialTextureBuffers::none(), data_buffer, device);
+        let data_buffer = buffer::Buffer::alloc_uniform(&[shader_data], device)?;
+        return Self::new(MaterialTextureBuffers::none(), data_buffer, descriptors, device);
     }
 
-    pub fn write_descriptor_input(&self, device: &core::Device) {
+    pub fn write_descriptor_input(&self, device: &core::Device) -> Result<(), Error> {
         self.descriptor_set.write_input(&self.data_buffer, 0, device);
-        self.descriptor_set.write_input(unsafe { shared_sampler(device) }, 1, device);
+        self.descriptor_set.write_input(unsafe { shared_sampler(device)? }, 1, device);
         if let Some(tex) = self.texture_buffers.albedo.as_ref() {
             self.descriptor_set.write_input(tex.as_ref(), 2, device);
         }
@@ -210,5 +250,6 @@ impl MaterialBuffer {
         if let Some(tex) = self.texture_buffers.roughness.as_ref() {
             self.descriptor_set.write_input(tex.as_ref(), 5, device);
         }
+        return Ok(());
     }
 }
\ No newline at end of file
@@ -13,7 +13,10 @@ use physics::nphysics3d::volumetric::Volumetric;
 use gfx::Device;
 
 pub mod model;
+pub mod bounds;
+pub mod queue;
 pub mod light;
+pub mod shadow;
 pub mod material;
 
 pub mod pipe;
@@ -28,11 +31,12 @@ use self::light::*;
 use node::*;
 
 use self::pipe::mesh::*;
+use self::pipe::shadow::*;
 
 pub type Scene3D<'a, 'b> = scene::Scene<'a, 'b, Spatial>;
 
 impl<'a, 'b> Scene3D<'a, 'b> {
-    pub fn create_3d(graphics: &mut render::Graphics) -> Self {
+    pub fn create_3d(graphics: &mut render::Graphics) -> Result<Self, Error> {
         return Self::create(Spatial::new(graphics), graphics);
     }
 }
@@ -45,15 +49,35 @@ impl scene::ComponentOf<Spatial> for NodeObject3D {}
 impl scene::ComponentOf<Spatial> for LightComponent {}
 
 /// The spatial aggregator for use with a `Scene`.
-pub struct Spatial {
-    render_pass: pass::SpatialPass,
-}
+/// The render graph is created when the aggregator is loaded into a scene, and is stored as a `pass::SpatialPass` resource.
+pub struct Spatial;
 
 impl Spatial {
 
-    pub fn new(graphics: &mut render::Graphics) -> Self {
-        let render_pass: pass::SpatialPass = pass::SpatialPass::new(graphics);
-        return Self { render_pass };
+    pub fn new(_graphics: &mut render::Graphics) -> Self {
+        return Spatial;
+    }
+
+    /// Recreates the spatial pass with the current graphics settings, along with the pipelines which render in it.
+    /// The previous pass and pipelines are kept if any of them fail to be created.
+    fn rebuild_spatial_pass(world: &World, graphics: &mut render::Graphics) -> Result<(), Error> {
+        let mut spatial_pass = world.write_resource::<pass::SpatialPass>();
+        let shaders = world.read_resource::<shader::ShaderManager>();
+        let mut mesh_pipeline = world.write_resource::<MeshRenderPipeline>();
+        let mut shadow_pipeline = world.write_resource::<ShadowRenderPipeline>();
+
+        let new_pass = pass::SpatialPass::create(graphics)?;
+        mesh_pipeline.rebuild(&shaders, new_pass.mesh_render_pass(), &graphics.device)?;
+        if let Err(e) = shadow_pipeline.rebuild(&shaders, new_pass.shadow_render_pass(), &graphics.device) {
+            // The mesh pipelines are rebuilt for the previous pass, which they were already built for once.
+            if let Err(e) = mesh_pipeline.rebuild(&shaders, spatial_pass.mesh_render_pass(), &graphics.device) {
+                log!(err, "Failed to rebuild the mesh render pipeline for the previous spatial pass: {}", e);
+            }
+            return Err(e);
+        }
+        *spatial_pass = new_pass;
+        // The shadow maps are recreated with the graph, so the descriptors which sample them must be rewritten.
+        return mesh_pipeline.set_shadow_maps(&spatial_pass, &graphics.device);
     }
 
 }
@@ -71,7 +95,7 @@ impl scene::Aggregator for Spatial {
     fn build_entity(mut entity_builder: scene::EntityBuilder) -> EntityBuilder where Self : Sized {
         entity_builder.with(NodeObject3D::new())
     }
-    fn load<'a, 'b : 'a>(&mut self, graphics: &mut render::Graphics, dispatcher_builder: scene::DispatcherBuilder<'a, 'b>, world: &mut scene::World) -> scene::DispatcherBuilder<'a, 'b> {
+    fn load<'a, 'b : 'a>(&mut self, graphics: &mut render::Graphics, dispatcher_builder: scene::DispatcherBuilder<'a, 'b>, world: &mut scene::World) -> Result<scene::DispatcherBuilder<'a, 'b>, Error> {
         // Camera and node types already registered.
         // Here we register additional types.
         world.register::<model::BufferedMesh>();
@@ -79,14 +103,53 @@ impl scene::Aggregator for Spatial {
         world.register::<material::MaterialComponent>();
 
         world.add_resource::<scene::GraphicsCapsule>(scene::GraphicsCapsule::new());
-        world.add_resource(pass::SpatialPass::new(graphics));
-        world.add_resource(MeshRenderPipeline::create(&mut graphics.device, &mut self.render_pass.pass));
-        world.add_resource(LightsController::new(&mut graphics.device));
-
-        dispatcher_builder.with(sys::NodeHierarchySystem, "node_hierarchy",&[]).with(sys::MeshRenderSystem, "mesh_render", &[])//.with(sys::LightSystem, "light", &[])
+        let spatial_pass = pass::SpatialPass::create(graphics)?;
+        let uniform_ring = memory::UniformRing::new(memory::UniformRing::DEFAULT_SEGMENT_SIZE, spatial_pass.graph.frames_in_flight(), &graphics.device)?;
+        let descriptor_allocator = pipeline::DescriptorAllocator::new(spatial_pass.graph.frames_in_flight(), &graphics.device);
+        let mut shaders = shader::ShaderManager::new(shader::ShaderManager::DEFAULT_SOURCE_DIR);
+        world.add_resource(MeshRenderPipeline::create(&mut graphics.device, &spatial_pass, &uniform_ring, &descriptor_allocator, &mut shaders)?);
+        world.add_resource(ShadowRenderPipeline::create(&graphics.device, spatial_pass.shadow_render_pass(), &mut shaders)?);
+        world.add_resource(shaders);
+        world.add_resource(spatial_pass);
+        world.add_resource(uniform_ring);
+        world.add_resource(descriptor_allocator);
+        world.add_resource(LightsController::new());
+        world.add_resource(shadow::ShadowsController::new());
+        world.add_resource(bounds::RenderStats::new());
+
+        return Ok(dispatcher_builder.with(sys::NodeHierarchySystem, "node_hierarchy",&[]).with(sys::LightSystem, "light", &[]).with(sys::MeshRenderSystem, "mesh_render", &["light"]));
     }
     fn dispatch_systems(&mut self, world: &mut World, dispatcher: &mut Dispatcher, graphics: &mut render::Graphics) {
         world.write_resource::<scene::GraphicsCapsule>().lend_graphics(graphics);
+        // Per-frame uniform data is written into the next segment of the ring.
+        world.write_resource::<memory::UniformRing>().begin_frame();
+        // Descriptor sets released by completed frames are recycled.
+        world.read_resource::<pipeline::DescriptorAllocator>().begin_frame();
+        // Instance data is written into the next segment of the mesh pipeline's instance ring.
+        world.write_resource::<MeshRenderPipeline>().begin_frame();
+        // The graph and the pipelines which render in it are rebuilt when the number of samples, the shadow settings or the color format change.
+        if world.read_resource::<pass::SpatialPass>().needs_rebuild(graphics) {
+            if let Err(e) = Self::rebuild_spatial_pass(world, graphics) {
+                log!(err, "Failed to apply the graphics settings to the spatial render graph, the previous settings are still in use: {}", e);
+                let spatial_pass = world.read_resource::<pass::SpatialPass>();
+                graphics.render_surface.settings.msaa_samples = spatial_pass.graph.requested_samples();
+                graphics.render_surface.settings.shadows = spatial_pass.shadows;
+                if spatial_pass.graph.color_format() != graphics.device.color_format {
+                    // The swapchain is rebuilt with the format the previous graph renders in before anything else is rendered.
+                    graphics.render_surface.settings.color_format = Some(spatial_pass.graph.color_format());
+                    graphics.device.color_format = spatial_pass.graph.color_format();
+                    graphics.render_surface.invalidate();
+                }
+            }
+        }
+        // Pipelines are rebuilt between frames when shader files change on disk.
+        let changed = world.write_resource::<shader::ShaderManager>().poll_changes();
+        if !changed.is_empty() {
+            let shaders = world.read_resource::<shader::ShaderManager>();
+            let spatial_pass = world.read_resource::<pass::SpatialPass>();
+            world.write_resource::<MeshRenderPipeline>().reload_shaders(&changed, &shaders, spatial_pass.mesh_render_pass(), &graphics.device);
+            world.write_resource::<ShadowRenderPipeline>().reload_shaders(&changed, &shaders, spatial_pass.shadow_render_pass(), &graphics.device);
+        }
         dispatcher.dispatch(&world.res);
         world.write_resource::<scene::GraphicsCapsule>().invalidate();
     }
@@ -142,14 +205,6 @@ pub trait RenderComponent {
 
 }
 
-/// Defines a component which can be rendered in batch by the scene.
-/// The 'render_batch' function should call the necessary functions on the scene object in order to properly execute a batch render.
-/*
-pub trait BatchRenderComponent {
-    fn render_batch(&mut self, transforms: &[Matrix4f], cycle: &mut RenderCycle);
-}
-*/
-
 /// The container struct for all the physics data of a node.
 /// This includes bodies and will also include joints in the future.
 pub struct PhysicsData {
@@ -7,6 +7,8 @@ use std::mem;
 use libc::*;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use specs::Builder;
 
 use spatial::pipe::mesh::MeshRenderPipeline;
@@ -24,63 +26,134 @@ pub struct BufferedMesh {
     /// The optional index buffer type.
     pub index_buffer: Option<Res<buffer::Buffer>>,
 
+    /// The local space bounds of the vertices, used for frustum culling.
+    /// Meshes without bounds are always drawn.
+    pub bounds: Option<spatial::bounds::MeshBounds>,
+
+    /// If true, the mesh is drawn into the shadow maps of lights which cast shadows.
+    pub cast_shadows: bool,
+
+    /// If true, the mesh is shadowed by other meshes which cast shadows.
+    pub receive_shadows: bool,
+
 }
 
 impl BufferedMesh {
 
     /// Creates a enw mesh component from a mesh object.
     /// This mesh can be loaded from a model file.
-    pub fn new(mesh: &Mesh, device: &core::Device) -> BufferedMesh {
+    pub fn new(mesh: &Mesh, device: &mut core::Device) -> Result<BufferedMesh, Error> {
         return BufferedMesh::create(&mesh.vertices, Some(mesh.indices.as_slice()), device);
     }
 
     /// Creates a new mesh component from the specified raw vertex buffer, index buffer and texture.
-    /// The scene and render objects are required in order to load the mesh properly.
-    pub fn create(verts: &[ModelVertex], indices: Option<&[u32]>, device: &core::Device) -> BufferedMesh {
-        let vertex_buffer: Res<buffer::Buffer> = Res::Val(buffer::Buffer::alloc_vertex(verts, device));
+    /// The vertex and index data is uploaded to device local memory, and this waits for the upload to complete.
+    pub fn create(verts: &[ModelVertex], indices: Option<&[u32]>, device: &mut core::Device) -> Result<BufferedMesh, Error> {
+        let mut batch = buffer::UploadBatch::new(device)?;
+        let mesh = BufferedMesh::create_batched(verts, indices, &mut batch, device)?;
+        batch.submit(device)?;
+        return Ok(mesh);
+    }
+
+    /// Creates a new mesh component whose vertex and index data is uploaded as part of a batch.
+    /// The mesh must not be rendered until the batch has been submitted.
+    pub fn create_batched(verts: &[ModelVertex], indices: Option<&[u32]>, batch: &mut buffer::UploadBatch, device: &core::Device) -> Result<BufferedMesh, Error> {
+        let vertex_buffer: Res<buffer::Buffer> = Res::Val(batch.upload_vertices(verts, device)?);
         let mut index_buffer: Option<Res<buffer::Buffer>> = None;
         if let Some(indices) = indices {
-            index_buffer = Some(Res::Val(buffer::Buffer::alloc(indices, gfx::buffer::Usage::INDEX, gfx::memory::Properties::CPU_VISIBLE, device)));
+            index_buffer = Some(Res::Val(batch.upload_buffer(indices, gfx::buffer::Usage::INDEX, device)?));
         }
-        return BufferedMesh { vertex_buffer, index_buffer };
+        let bounds = spatial::bounds::MeshBounds::from_vertices(verts);
+        return Ok(BufferedMesh { vertex_buffer, index_buffer, bounds, cast_shadows: true, receive_shadows: true });
     }
 
 
     /// Creates a new BufferedMesh from the specified vertex buffer, index buffer and texture buffer.
     /// The scene and graphics object are needed to create the descriptor set that properly represents the scene.
+    /// The mesh has no bounds, so it is never culled unless they are set with `with_bounds`.
     pub fn from_raw_buffers(vertex_buffer: Res<buffer::Buffer>, index_buffer: Option<Res<buffer::Buffer>>) -> Self {
-        return BufferedMesh { vertex_buffer, index_buffer };
+        return BufferedMesh { vertex_buffer, index_buffer, bounds: None, cast_shadows: true, receive_shadows: true };
     }
 
-    pub fn render(&mut self, transform: render::RenderTransform, materials_desc: &pipeline::DescriptorSet, pipeline: &mut spatial::pipe::mesh::MeshRenderPipeline, encoder: &mut command::Encoder) {
-        let index_buffer: Option<&buffer::Buffer> = {
-            if let Some(ibuf) = self.index_buffer.as_ref() {
-                Some(ibuf.as_ref())
-            } else {
-                None
-            }
+    /// Moves the vertex and index buffers into `Arc`s, so that other entities can draw the mesh with `instance`.
+    pub fn into_shared(self) -> Self {
+        return BufferedMesh {
+            vertex_buffer: self.vertex_buffer.into_shared(),
+            index_buffer: self.index_buffer.map(|index_buffer| index_buffer.into_shared()),
+            bounds: self.bounds,
+            cast_shadows: self.cast_shadows,
+            receive_shadows: self.receive_shadows,
         };
-        let vertex_input = pipeline::VertexInput { vertex_buffer: &self.vertex_buffer, index_buffer };
-        pipeline.render(&vertex_input, materials_desc, transform, encoder);
     }
-}
 
-impl specs::Component for BufferedMesh {
-    type Storage = specs::VecStorage<Self>;
-}
+    /// Gets a mesh for another entity which uses the same vertex and index buffers.
+    /// Entities with the same buffers and material are drawn together with instancing.
+    /// Returns `None` if the buffers are not shared, see `into_shared`.
+    pub fn instance(&self) -> Option<Self> {
+        let vertex_buffer = self.vertex_buffer.share()?;
+        let index_buffer = match self.index_buffer.as_ref() {
+            Some(index_buffer) => Some(index_buffer.share()?),
+            None => None,
+        };
+        return Some(BufferedMesh { vertex_buffer, index_buffer, bounds: self.bounds, cast_shadows: self.cast_shadows, receive_shadows: self.receive_shadows });
+    }
 
-impl scene::ComponentOf<spatial::Spatial> for BufferedMesh {}
+    /// Returns true if both meshes draw the same buffers, so that they can be instanced.
+    pub fn shares_buffers(&self, other: &BufferedMesh) -> bool {
+        let index_buffers_eq = match (self.index_buffer.as_ref(), other.index_buffer.as_ref()) {
+            (Some(a), Some(b)) => a.ptr_eq(b),
+            (None, None) => true,
+            _ => false,
+        };
+        return self.vertex_buffer.ptr_eq(&other.vertex_buffer) && index_buffers_eq;
+    }
+
+    pub fn with_bounds(mut self, bounds: spatial::bounds::MeshBounds) -> Self {
+        self.bounds = Some(bounds);
+        return self;
+    }
 
-/*
-impl spatial::BatchRenderComponent for BufferedMesh {
+    pub fn with_cast_shadows(mut self, cast_shadows: bool) -> Self {
+        self.cast_shadows = cast_shadows;
+        return self;
+    }
 
-    fn render_batch(&mut self, transforms: &[Matrix4f], cycle: &mut spatial::RenderCycle) {
+    pub fn with_receive_shadows(mut self, receive_shadows: bool) -> Self {
+        self.receive_shadows = receive_shadows;
+        return self;
+    }
 
-        let mut render_transforms: Vec<render::RenderTransform> = Vec::with_capacity(transforms.len());
+    /// Returns false if the mesh is entirely outside of the frustum when transformed by the model matrix.
+    pub fn is_visible(&self, model: &Matrix4f, frustum: &spatial::bounds::Frustum) -> bool {
+        return match self.bounds {
+            Some(bounds) => frustum.intersects(&bounds.transform(model)),
+            None => true,
+        };
+    }
 
-        for transform in transforms {
-            render_transforms.push(render::RenderTransform::new(*transform, cycle.scene.camera.get_view_matrix(), cycle.scene.camera.get_projection_matrix()));
-        }
+    pub fn render(&self, transform: render::RenderTransform, materials_desc: &pipeline::DescriptorSet, pipeline: &mut spatial::pipe::mesh::MeshRenderPipeline, encoder: &mut command::Encoder) {
+        let constants = spatial::pipe::mesh::MeshConstants::new(transform, self.receive_shadows);
+        pipeline.render(&self.vertex_input(), materials_desc, constants, encoder);
+    }
+
+    /// Draws the mesh without writing to the depth buffer, so that it can be blended over the meshes behind it.
+    pub fn render_transparent(&self, transform: render::RenderTransform, materials_desc: &pipeline::DescriptorSet, pipeline: &mut spatial::pipe::mesh::MeshRenderPipeline, encoder: &mut command::Encoder) {
+        let constants = spatial::pipe::mesh::MeshConstants::new(transform, self.receive_shadows);
+        pipeline.render_transparent(&self.vertex_input(), materials_desc, constants, encoder);
+    }
+
+    /// Draws the mesh once for each model matrix in a single draw call.
+    /// Returns false without drawing anything if there is no space left for the model matrices this frame.
+    pub fn render_instanced(&self, models: &[Matrix4f], camera_transform: &scene::CameraTransform, materials_desc: &pipeline::DescriptorSet, pipeline: &mut spatial::pipe::mesh::MeshRenderPipeline, encoder: &mut command::Encoder) -> bool {
+        return pipeline.render_instanced(&self.vertex_input(), materials_desc, models, camera_transform, self.receive_shadows, encoder);
+    }
+
+    /// Draws the depth of the mesh into a layer of a shadow map. The shadow pipeline must be bound.
+    pub fn render_shadow(&self, transform: spatial::pipe::shadow::ShadowTransform, pipeline: &spatial::pipe::shadow::ShadowRenderPipeline, encoder: &mut command::Encoder) {
+        pipeline.render(&self.vertex_input(), transform, encoder);
+    }
+
+    fn vertex_input(&self) -> pipeline::VertexInput {
         let index_buffer: Option<&buffer::Buffer> = {
             if let Some(ibuf) = self.index_buffer.as_ref() {
                 Some(ibuf.as_ref())
@@ -88,12 +161,15 @@ impl spatial::BatchRenderComponent for BufferedMesh {
                 None
             }
         };
-        let vertex_input = pipeline::VertexInput { vertex_buffer: &self.vertex_buffer, index_buffer };
-        cycle.scene.render_mesh_batch(&vertex_input, &self.texture_input_set, &render_transforms, cycle.graphics, cycle.encoder);
+        return pipeline::VertexInput { vertex_buffer: &self.vertex_buffer, index_buffer };
     }
+}
 
+impl specs::Component for BufferedMesh {
+    type Storage = specs::VecStorage<Self>;
 }
-*/
+
+impl scene::ComponentOf<spatial::Spatial> for BufferedMesh {}
 
 pub trait FromAiVec3f {
 
@@ -145,6 +221,7 @@ impl FromAiMat4f for Matrix4f {
 }
 
 #[derive(Copy, Clone)]
+#[repr(C)]
 pub struct ModelVertex {
 
     pub pos: Vector3f,
@@ -155,6 +232,14 @@ pub struct ModelVertex {
 
 }
 
+vertex_format!(ModelVertex {
+    0 => pos,
+    1 => normal,
+    2 => uv,
+    3 => bone_ids,
+    4 => bone_weights,
+});
+
 impl ModelVertex {
 
     pub fn new(pos: Vector3f, normal: Vector3f, uv: Vector2f) -> ModelVertex {
@@ -192,11 +277,9 @@ pub struct Mesh {
 
 impl Mesh {
 
-    pub fn from_file(path: &str, mesh_index: usize) -> Result<Self, &'static str> {
-        if let Ok(model) = Model::from_file(path) {
-            return model.meshes.get(mesh_index).cloned().ok_or("The index specified does not exist.");
-        }
-        return Err("Failed to load mesh from path");
+    pub fn from_file(path: &str, mesh_index: usize) -> Result<Self, Error> {
+        let model = Model::from_file(path)?;
+        return model.meshes.get(mesh_index).cloned().ok_or(Error::Model(format!("The model does not contain a mesh with index {}.", mesh_index)));
     }
 
     /**
@@ -728,17 +811,23 @@ pub struct Model {
     pub animations: Vec<Animation>,
     pub global_inv_transform: Matrix4f,
 
+    /// The components created for the meshes by the first call to `add_to_scene`. Later calls instance them, so that every copy of the model is drawn in the same batches.
+    scene_meshes: Vec<(BufferedMesh, material::MaterialComponent)>,
+
 }
 
 impl Model {
 
     pub fn new() -> Model {
 
-        return Model { meshes: Vec::new(), materials: Vec::new(), animations: Vec::new(), global_inv_transform: Matrix4f::identity() };
+        return Model { meshes: Vec::new(), materials: Vec::new(), animations: Vec::new(), global_inv_transform: Matrix4f::identity(), scene_meshes: Vec::new() };
 
     }
 
-    pub fn from_file(path: &str) -> Result<Model, &'static str> {
+    pub fn from_file(path: &str) -> Result<Model, Error> {
+        // Assimp only reports a generic message for missing files, so check for the file first to give a proper io error.
+        std::fs::metadata(path)?;
+
         let mut meshes: Vec<Mesh>;
 
         let mut materials: Vec<MaterialData>;
@@ -771,36 +860,57 @@ impl Model {
                     animations.push(Animation::from_ai(*(*scene).animations.offset(i as isize), (*scene).root_node, git));
                 }
             } else {
-                return Err("Failed to load model from path");
+                let message = std::ffi::CStr::from_ptr(aiGetErrorString()).to_string_lossy().into_owned();
+                return Err(Error::Model(message));
             }
         }
 
-        return Ok(Model { meshes, materials, animations, global_inv_transform: git });
+        return Ok(Model { meshes, materials, animations, global_inv_transform: git, scene_meshes: Vec::new() });
     }
 
     pub fn assign_material(&mut self, mesh_index: usize, material: MaterialData) {
         let mat_index: usize = self.meshes[mesh_index].material_index;
         self.materials[mat_index] = material;
+        // The components of meshes already in a scene keep the previous material.
+        self.scene_meshes.clear();
     }
 
     /// Return the parent node containing all the meshes of the model file.
-    pub fn add_to_scene(&self, scene: &mut spatial::Scene3D, graphics: &mut render::Graphics) -> spatial::BaseEntity3D {
+    /// The geometry and materials are uploaded by the first call, and every later call shares them, so that each mesh of the model is drawn with one instanced draw call however many times it is added.
+    pub fn add_to_scene(&mut self, scene: &mut spatial::Scene3D, graphics: &mut render::Graphics) -> Result<spatial::BaseEntity3D, Error> {
+        if self.scene_meshes.len() != self.meshes.len() {
+            self.scene_meshes = self.create_scene_meshes(scene, graphics)?;
+        }
         let parent_entity = scene.create_base_entity();
-        for mesh in self.meshes.iter() {
-            let buffered_mesh: BufferedMesh = BufferedMesh::new(mesh, &graphics.device);
-            let material_component: material::MaterialComponent;
-            if let Some(material_data) = self.materials.get(mesh.material_index) {
-                if let Some(material) = material_data.load_material() {
-                    material_component = material::MaterialComponent::new(material, graphics);
-                } else {
-                    material_component = material::MaterialComponent::new(material::Material::color(OpaqueColor::black(), 0.0, 1.0), graphics);
-                }
-            } else {
-                material_component = material::MaterialComponent::new(material::Material::color(OpaqueColor::black(), 0.0, 1.0), graphics);
-            }
-            let entity = scene.basic_builder().with(buffered_mesh).with(material_component).with(scene::Parent::new(parent_entity.entity)).build();
+        for (mesh, material_component) in self.scene_meshes.iter() {
+            let mesh = mesh.instance().expect("The buffers of model meshes are shared.");
+            let material_component = material_component.instance().expect("The textures of model materials are shared.");
+            scene.basic_builder().with(mesh).with(material_component).with(scene::Parent::new(parent_entity.entity)).build();
         }
-        return parent_entity;
+        return Ok(parent_entity);
+    }
+
+    /// Uploads the meshes into shared buffers. Meshes which use the same material share its buffer.
+    fn create_scene_meshes(&self, scene: &spatial::Scene3D, graphics: &mut render::Graphics) -> Result<Vec<(BufferedMesh, material::MaterialComponent)>, Error> {
+        let descriptors = scene.world.read_resource::<MeshRenderPipeline>().material_descriptors();
+        let mut materials: HashMap<usize, material::MaterialComponent> = HashMap::new();
+        let mut scene_meshes: Vec<(BufferedMesh, material::MaterialComponent)> = Vec::with_capacity(self.meshes.len());
+        // The geometry of every mesh is uploaded together, behind a single fence.
+        let mut batch = buffer::UploadBatch::new(&graphics.device)?;
+        for mesh in self.meshes.iter() {
+            let buffered_mesh: BufferedMesh = BufferedMesh::create_batched(&mesh.vertices, Some(mesh.indices.as_slice()), &mut batch, &graphics.device)?.into_shared();
+            let material_component = match materials.entry(mesh.material_index) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let material = self.materials.get(mesh.material_index).and_then(|material_data| material_data.load_material())
+                        .unwrap_or_else(|| material::Material::color(OpaqueColor::black(), 0.0, 1.0));
+                    entry.insert(material::MaterialComponent::new(material, &descriptors, graphics)?)
+                },
+            };
+            scene_meshes.push((buffered_mesh, material_component.instance().expect("The textures of model materials are shared.")));
+        }
+        batch.submit(&mut graphics.device)?;
+        return Ok(scene_meshes);
     }
 
 }
@@ -2,36 +2,119 @@ use crate::*;
 
 
 
+/// The render graph used by the spatial aggregator.
+/// By default this contains a depth-only pass for each layer of the shadow maps, followed by a pass which renders meshes to the backbuffer with a depth attachment,
+/// multisampled as specified by the graphics settings. Further passes can be added to the graph before or after them.
+/// The shadow maps of point lights are not cube maps. Their faces are layers of a 2D array image, which each shadow pass renders one of, and the mesh shader picks the layer to sample by the major axis of the direction from the light.
+/// This lets the point and cascade shadow maps share the depth-only passes and the comparison sampling of 2D arrays.
 pub struct SpatialPass {
 
-    pub pass: render::RenderPass,
-    pub frame_aggregator: command::FrameAggregator,
+    pub graph: graph::RenderGraph,
+    /// The shadow settings the graph was built with.
+    pub shadows: render::ShadowSettings,
 
 }
 
 impl SpatialPass {
 
-    pub fn new(graphics: &mut render::Graphics) -> Self {
+    pub const MESH_PASS: &'static str = "spatial_mesh";
+    pub const DEPTH_ATTACHMENT: &'static str = "spatial_depth";
+    /// The shadow maps of point lights, with six layers per light, one for each face of a cube.
+    pub const POINT_SHADOW_ATTACHMENT: &'static str = "spatial_point_shadows";
+    /// The shadow map of the directional light, with one layer per cascade.
+    pub const CASCADE_SHADOW_ATTACHMENT: &'static str = "spatial_cascade_shadows";
+    pub const SHADOW_FORMAT: gfx::format::Format = gfx::format::Format::D32Float;
 
-        let pass: render::RenderPass = render::RenderPass::create_basic(&graphics.device);
-        let frame_aggregator = command::FrameAggregator::create(&pass, Some(render::RenderPass::STD_DEPTH_FORMAT), graphics);
-        return Self { pass, frame_aggregator };
+    /// Builds the graph from the current graphics settings.
+    pub fn create(graphics: &mut render::Graphics) -> Result<Self, Error> {
+        let samples = graphics.settings().msaa_samples;
+        let shadows = graphics.settings().shadows;
+        let point_layers = shadows.point_shadows() as u16 * 6;
+        let cascades = shadows.cascades() as u16;
 
+        // The shadow maps exist even if no pass renders to them, so that the mesh shaders always have them to sample.
+        // They are persistent so that they can be sampled without having been rendered.
+        let mut builder = graph::RenderGraphBuilder::new()
+            .with_samples(samples)
+            .with_attachment(graph::AttachmentDesc::new(Self::DEPTH_ATTACHMENT, render::RenderPass::STD_DEPTH_FORMAT, graph::AttachmentSize::swapchain()))
+            .with_attachment(Self::shadow_attachment(Self::POINT_SHADOW_ATTACHMENT, shadows.point_resolution, point_layers))
+            .with_attachment(Self::shadow_attachment(Self::CASCADE_SHADOW_ATTACHMENT, shadows.directional_resolution, cascades));
+        for layer in 0..point_layers {
+            builder = builder.with_pass(Self::shadow_pass(&Self::point_shadow_pass(layer), Self::POINT_SHADOW_ATTACHMENT, layer));
+        }
+        for cascade in 0..cascades {
+            builder = builder.with_pass(Self::shadow_pass(&Self::cascade_shadow_pass(cascade), Self::CASCADE_SHADOW_ATTACHMENT, cascade));
+        }
+
+        let graph = builder
+            .with_pass(graph::PassDesc::new(Self::MESH_PASS)
+                .with_color(graph::BACKBUFFER)
+                .with_depth(Self::DEPTH_ATTACHMENT)
+                .with_input(Self::POINT_SHADOW_ATTACHMENT)
+                .with_input(Self::CASCADE_SHADOW_ATTACHMENT)
+                .with_clear_color(Color::black())
+                .with_clear_depth(1.0))
+            .build(graphics)?;
+        return Ok(Self { graph, shadows });
     }
 
-    pub fn next(&mut self, graphics: &mut render::Graphics) -> Option<(command::Frame, &render::RenderPass)> {
-        if graphics.render_surface.did_rebuild {
-            self.frame_aggregator = command::FrameAggregator::create(&self.pass, Some(render::RenderPass::STD_DEPTH_FORMAT), graphics);
-        }
-        if let Some(frame) = self.frame_aggregator.acquire_next(graphics) {
-            // Invalidate swapchain for rebuilding.
-            return Some((frame, &self.pass));
-        } else {
-            graphics.render_surface.invalidate();
+    fn shadow_attachment(name: &str, resolution: u32, layers: u16) -> graph::AttachmentDesc {
+        let size = if layers > 0 { resolution.max(1) } else { 1 };
+        return graph::AttachmentDesc::new(name, Self::SHADOW_FORMAT, graph::AttachmentSize::Absolute(Vector2u::new(size, size)))
+            .with_layers(layers.max(1))
+            .persistent();
+    }
 
-        }
-        None
+    fn shadow_pass(name: &str, attachment: &str, layer: u16) -> graph::PassDesc {
+        return graph::PassDesc::new(name)
+            .with_depth(attachment)
+            .with_layer(layer)
+            .with_clear_depth(1.0);
+    }
+
+    /// The name of the pass which renders a layer of the point light shadow maps. Each light has six consecutive layers.
+    pub fn point_shadow_pass(layer: u16) -> String {
+        return format!("spatial_point_shadow_{}", layer);
+    }
+
+    /// The name of the pass which renders a cascade of the directional light shadow map.
+    pub fn cascade_shadow_pass(cascade: u16) -> String {
+        return format!("spatial_cascade_shadow_{}", cascade);
+    }
 
+    /// Returns true if the number of samples, the shadow settings or the color format of the surface have changed since the graph was built.
+    /// Every render pass is recreated with the graph, so pipelines created with the render passes of the graph must be recreated too.
+    pub fn needs_rebuild(&self, graphics: &render::Graphics) -> bool {
+        return self.graph.requested_samples() != graphics.settings().msaa_samples || self.shadows != graphics.settings().shadows
+            || self.graph.color_format() != graphics.device.color_format;
     }
 
-}
\ No newline at end of file
+    /// The render pass which meshes are rendered in. Mesh pipelines must be created with this render pass.
+    pub fn mesh_render_pass(&self) -> &render::RenderPass {
+        return self.graph.render_pass(Self::MESH_PASS).log_expect("The spatial render graph has no mesh pass.");
+    }
+
+    /// The render pass of the first shadow pass, or `None` if shadows are disabled.
+    /// Every shadow pass has a compatible render pass, so shadow pipelines can be created with this one.
+    pub fn shadow_render_pass(&self) -> Option<&render::RenderPass> {
+        return self.graph.render_pass(&Self::point_shadow_pass(0))
+            .or_else(|| self.graph.render_pass(&Self::cascade_shadow_pass(0)));
+    }
+
+    /// The point light shadow maps, which are sampled as a 2D array rather than as cube maps. The face of each light is chosen with `shadow::cube_face`.
+    pub fn point_shadow_maps(&self) -> &buffer::TextureBuffer {
+        return self.graph.attachment_image(Self::POINT_SHADOW_ATTACHMENT).log_expect("The spatial render graph has no point shadow maps.");
+    }
+
+    /// The cascades of the directional light shadow map, which are sampled as a 2D array.
+    pub fn cascade_shadow_maps(&self) -> &buffer::TextureBuffer {
+        return self.graph.attachment_image(Self::CASCADE_SHADOW_ATTACHMENT).log_expect("The spatial render graph has no cascade shadow maps.");
+    }
+
+    /// Executes the graph for the next frame, calling the function once for each pass.
+    pub fn render<F>(&mut self, graphics: &mut render::Graphics, f: F) -> bool
+        where F: FnMut(&str, &mut render::Graphics, &mut command::Encoder) {
+        return self.graph.execute(graphics, f);
+    }
+
+}
@@ -2,6 +2,7 @@ use crate::*;
 
 use spatial::*;
 use spatial::light::LightsList;
+use spatial::shadow::ShadowsList;
 
 use std::sync::Arc;
 use std::cell::RefCell;
@@ -13,6 +14,9 @@ const MAX_DESCRIPTORS: usize = 1;
 
 const MAX_BONES: usize = 100;
 
+/// The size of the per-frame instance data, which is enough for 65536 instances per frame.
+const INSTANCE_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct BoneList {
@@ -30,202 +34,381 @@ impl BoneList {
 
 }
 
-pub static mut MATERIAL_DESCRIPTOR_LAYOUT: Option<Arc<pipeline::DescriptorSetLayout>> = None;
+/// The push constants of the mesh pipelines.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct MeshConstants {
+
+    pub transform: render::RenderTransform,
+    /// 1 if the mesh is shadowed by the lights which cast shadows, otherwise 0.
+    pub receive_shadows: i32,
+
+}
+
+impl MeshConstants {
+
+    pub fn new(transform: render::RenderTransform, receive_shadows: bool) -> MeshConstants {
+        return MeshConstants { transform, receive_shadows: receive_shadows as i32 };
+    }
+
+}
+
+/// The per-instance vertex data of instanced meshes, which is the columns of the model matrix.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct InstanceData {
+
+    pub model_0: Vector4f,
+    pub model_1: Vector4f,
+    pub model_2: Vector4f,
+    pub model_3: Vector4f,
+
+}
+
+vertex_format!(InstanceData {
+    5 => model_0,
+    6 => model_1,
+    7 => model_2,
+    8 => model_3,
+});
+
+impl InstanceData {
+
+    pub fn new(model: Matrix4f) -> InstanceData {
+        return InstanceData { model_0: model.x, model_1: model.y, model_2: model.z, model_3: model.w };
+    }
+
+}
+
+/// The pipeline, descriptor sets and buffers bound by the last draw in a pass.
+/// Resources are identified by their address, which is only compared and never dereferenced.
+#[derive(Clone, Copy, Default)]
+struct BoundState {
+
+    pipeline: Option<queue::DrawPipeline>,
+    material_set: Option<usize>,
+    vertex_buffer: Option<usize>,
+    index_buffer: Option<usize>,
+
+}
 
 /// The structure responsible for rendering mesh objects.
 /// This is invoked by the scene when a mesh should be rendered.
 pub struct MeshRenderPipeline {
 
     pub pipeline: pipeline::PipelineController,
+    /// The pipeline which draws many instances of a mesh at once, reading their model matrices from `instance_ring`.
+    pub instanced_pipeline: pipeline::PipelineController,
+    /// The pipeline which draws transparent meshes. It tests against the depth buffer without writing to it, so that meshes behind a transparent mesh are still blended.
+    pub transparent_pipeline: pipeline::PipelineController,
     pub descriptor_pool: pipeline::DescriptorPool,
     pub intrinsic_descriptor_interface: pipeline::DescriptorSetInterface,
     pub material_input_layout: Arc<pipeline::DescriptorSetLayout>,
+    /// The allocator material descriptor sets are allocated from.
+    pub material_allocator: pipeline::DescriptorAllocator,
     pub bone_uniform: buffer::Buffer,
+    pub vertex_shader: shader::ShaderHandle,
+    pub instanced_vertex_shader: shader::ShaderHandle,
+    pub fragment_shader: shader::ShaderHandle,
+    /// The model matrices of the instances drawn in the current frame, which are bound as a per-instance vertex buffer.
+    pub instance_ring: memory::UniformRing,
+    /// The dynamic offset of the lights in the uniform ring, which is bound with the intrinsic descriptor set.
+    pub lights_offset: u32,
+    /// The dynamic offset of the shadow matrices in the uniform ring, which is bound with the intrinsic descriptor set.
+    pub shadows_offset: u32,
+    /// The sampler the shadow maps are compared with.
+    pub shadow_sampler: pipeline::TextureSampler,
+
+    /// The state bound in the current pass, which is used to skip redundant binds.
+    bound: BoundState,
 
-    pub is_bound: bool,
 }
 
 impl MeshRenderPipeline {
 
-    pub fn create(device: &mut core::Device, render_pass: &render::RenderPass) -> MeshRenderPipeline {
-        let mut lights_uniform = buffer::Buffer::alloc_uniform(&[LightsList::new()], device);
-        let mut bone_uniform = buffer::Buffer::alloc_uniform(&[BoneList::new()], device);
-        let instrinsic_set_layout = pipeline::DescriptorSetLayout::create(&[
-            (&bone_uniform, pipeline::ShaderStage::Vertex),
-            (&lights_uniform, pipeline::ShaderStage::Fragment),
-        ], device);
-        let material_input_layout: Arc<pipeline::DescriptorSetLayout> = Arc::new(pipeline::DescriptorSetLayout::create(&[
-            (&pipeline::ShaderInputDescriptor::uniform_buffer_descriptor(), pipeline::ShaderStage::Fragment),
-            (&pipeline::ShaderInputDescriptor::sampler_descriptor(), pipeline::ShaderStage::Fragment),
-            (&pipeline::ShaderInputDescriptor::image_descriptor(), pipeline::ShaderStage::Fragment),
-            (&pipeline::ShaderInputDescriptor::image_descriptor(), pipeline::ShaderStage::Fragment),
-            (&pipeline::ShaderInputDescriptor::image_descriptor(), pipeline::ShaderStage::Fragment),
-            (&pipeline::ShaderInputDescriptor::image_descriptor(), pipeline::ShaderStage::Fragment),
-        ], device));
+    /// Creates the pipelines for the mesh pass of the spatial pass, which sample its shadow maps.
+    pub fn create(device: &mut core::Device, spatial_pass: &pass::SpatialPass, uniform_ring: &memory::UniformRing, descriptor_allocator: &pipeline::DescriptorAllocator, shaders: &mut shader::ShaderManager) -> Result<MeshRenderPipeline, Error> {
+        let render_pass = spatial_pass.mesh_render_pass();
+        let frames_in_flight = spatial_pass.graph.frames_in_flight();
+        let lights_uniform = uniform_ring.uniform_binding::<LightsList>();
+        let shadows_uniform = uniform_ring.uniform_binding::<ShadowsList>();
+        let shadow_sampler = pipeline::TextureSampler::comparison(device)?;
+        let bone_uniform = buffer::Buffer::alloc_uniform(&[BoneList::new()], device)?;
+
+        // The precompiled shaders are used unless their sources are edited while the engine is running.
+        let vertex_shader = shaders.load_or_embedded("std_mesh_v", include_bytes!(concat!(env!("OUT_DIR"), "/std_mesh_v.spv")))?;
+        let instanced_vertex_shader = shaders.load_variant_or_embedded("std_mesh_v", &["INSTANCED"], include_bytes!(concat!(env!("OUT_DIR"), "/std_mesh_v-instanced.spv")))?;
+        let fragment_shader = shaders.load_or_embedded("std_mesh_f", include_bytes!(concat!(env!("OUT_DIR"), "/std_mesh_f.spv")))?;
+
+        // The descriptor set layouts are built from the interface of the compiled shaders. The lights and shadows are bound with dynamic offsets into the uniform ring.
+        let reflection = Self::reflect(vertex_shader, fragment_shader, shaders)?;
+        let mut set_layouts = reflection.create_set_layouts(device)?.into_iter();
+        let instrinsic_set_layout = set_layouts.next().ok_or_else(|| Error::Reflection(String::from("The mesh shaders have no intrinsic descriptor set.")))?;
+        let material_input_layout: Arc<pipeline::DescriptorSetLayout> = Arc::new(set_layouts.next().ok_or_else(|| Error::Reflection(String::from("The mesh shaders have no material descriptor set.")))?);
         log!(debug, 4, "Attempting to create descriptor sets.");
 
-        unsafe { MATERIAL_DESCRIPTOR_LAYOUT = Some(material_input_layout.clone()) };
+        let intrinsic_inputs: [(&pipeline::ShaderInput, u32); 4] = [
+            (&bone_uniform, 0),
+            (&lights_uniform, 1),
+            (&shadows_uniform, 2),
+            (&shadow_sampler, 3),
+        ];
+        reflection.validate_inputs(0, &intrinsic_inputs)?;
+        // The shadow maps are written by `set_shadow_maps`, in the layout the render graph leaves them in.
+        let shadow_maps: [(&pipeline::ShaderInput, u32); 2] = [
+            (spatial_pass.point_shadow_maps(), 4),
+            (spatial_pass.cascade_shadow_maps(), 5),
+        ];
+        reflection.validate_inputs(0, &shadow_maps)?;
 
         let mut descriptor_pool: pipeline::DescriptorPool = pipeline::DescriptorPool::new(1, &[
             (&instrinsic_set_layout, 1)
-        ], device);
-        let intrinsic_descriptor_set: pipeline::DescriptorSet = pipeline::DescriptorSet::with_inputs(&[
-            (&bone_uniform, 0),
-            (&lights_uniform, 1),
-        ], &instrinsic_set_layout, &mut descriptor_pool, device
-        ).log_expect("Failed to create intrinsic descriptor set for mesh render pipeline.");
+        ], device)?;
+        let intrinsic_descriptor_set: pipeline::DescriptorSet = pipeline::DescriptorSet::with_inputs(&intrinsic_inputs, &instrinsic_set_layout, &mut descriptor_pool, device
+        )?;
         let intrinsic_descriptor_interface = pipeline::DescriptorSetInterface::new(instrinsic_set_layout, intrinsic_descriptor_set);
 
         log!(debug, 4, "Successfully created and allocated internal descriptor sets.");
 
-        //shader_input.write_input(&render::TextureSampler::new(device), 3, device);
-
-        let pipeline_layout = pipeline::PipelineLayout::create(&[&intrinsic_descriptor_interface.layout, material_input_layout.as_ref()], &[(gfx::pso::ShaderStageFlags::VERTEX, 0..(Self::num_push_constants() as u32))], device);
-
-        let vertex_shader_module = device.load_shader_raw(include_bytes!("../../../shaders/bin/std_mesh_v.spv")).expect("Fatal Error: Failed to create model vertex shader.");
-        let fragment_shader_module = device.load_shader_raw(include_bytes!("../../../shaders/bin/std_mesh_f.spv")).expect("Fatal Error: Failed to create model fragment shader.");
-        let pipeline_object: pipeline::Pipeline = {
-            let vs_entry = gfx::pso::EntryPoint::<backend::Backend> {
-                entry: "main",
-                module: &vertex_shader_module,
-                specialization: Default::default(),
-            };
-
-            let fs_entry = gfx::pso::EntryPoint::<backend::Backend> {
-                entry: "main",
-                module: &fragment_shader_module,
-                specialization: Default::default(),
-            };
-
-            let shader_entries = gfx::pso::GraphicsShaderSet {
-                vertex: vs_entry,
-                hull: None,
-                domain: None,
-                geometry: None,
-                fragment: Some(fs_entry),
-            };
-
-            let subpass = gfx::pass::Subpass {
-                index: 0,
-                main_pass: &render_pass.raw_render_pass,
-            };
-
-            let rasterizer: gfx::pso::Rasterizer = gfx::pso::Rasterizer {
-                polygon_mode: gfx::pso::PolygonMode::Fill,
-                cull_face: gfx::pso::Face::BACK,
-                front_face: gfx::pso::FrontFace::CounterClockwise,
-                depth_clamping: false,
-                depth_bias: None,
-                conservative: true,
-            };
-
-            let mut pipeline_desc = gfx::pso::GraphicsPipelineDesc::new(
-                shader_entries,
-                gfx::Primitive::TriangleList,
-                rasterizer,
-                &pipeline_layout.layout,
-                subpass,
-            );
-
-            pipeline_desc
-                .blender
-                .targets
-                .push(gfx::pso::ColorBlendDesc(gfx::pso::ColorMask::ALL, gfx::pso::BlendState::ALPHA));
-
-            pipeline_desc.vertex_buffers.push(gfx::pso::VertexBufferDesc {
-                binding: 0,
-                stride: std::mem::size_of::<model::ModelVertex>() as u32,
-                rate: 0,
-            });
-
-            pipeline_desc.attributes.push(gfx::pso::AttributeDesc {
-                location: 0,
-                binding: 0,
-                element: gfx::pso::Element {
-                    format: gfx::format::Format::Rgb32Float,
-                    offset: 0,
-                },
-            });
-
-            pipeline_desc.attributes.push(gfx::pso::AttributeDesc {
-                location: 1,
-                binding: 0,
-                element: gfx::pso::Element {
-                    format: gfx::format::Format::Rgb32Float,
-                    offset: 12,
-                },
-            });
-            pipeline_desc.attributes.push(gfx::pso::AttributeDesc {
-                location: 2,
-                binding: 0,
-                element: gfx::pso::Element {
-                    format: gfx::format::Format::Rg32Float,
-                    offset: 24,
-                },
-            });
-            pipeline_desc.attributes.push(gfx::pso::AttributeDesc {
-                location: 3,
-                binding: 0,
-                element: gfx::pso::Element {
-                    format: gfx::format::Format::Rgba32Int,
-                    offset: 32,
-                },
-            });
-            pipeline_desc.attributes.push(gfx::pso::AttributeDesc {
-                location: 4,
-                binding: 0,
-                element: gfx::pso::Element {
-                    format: gfx::format::Format::Rgba32Float,
-                    offset: 48,
-                },
-            });
-
-            pipeline_desc.depth_stencil = gfx::pso::DepthStencilDesc {
-                depth: gfx::pso::DepthTest::On {
-                    fun: gfx::pso::Comparison::Less,
-                    write: true,
-                },
-                depth_bounds: false,
-                stencil: gfx::pso::StencilTest::default(),
-            };
-            log!(debug, 3, "Attempting to create mesh render pipeline.");
-            pipeline::Pipeline::create(pipeline_desc, device).log_expect("Failed to create pipeline.")
-        };
+        log!(debug, 3, "Attempting to create mesh render pipeline.");
+        let (pipeline, instanced_pipeline, transparent_pipeline) = Self::build_pipelines(vertex_shader, instanced_vertex_shader, fragment_shader, &[&intrinsic_descriptor_interface.layout, material_input_layout.as_ref()], shaders, render_pass, device)?;
+        let instance_ring = memory::UniformRing::with_usage(INSTANCE_SEGMENT_SIZE, frames_in_flight, gfx::buffer::Usage::VERTEX, device)?;
 
-        let pipeline = pipeline::PipelineController::new(pipeline_object, pipeline_layout);
         log!(debug, 3, "Successfully created mesh render pipeline.");
-        return MeshRenderPipeline { pipeline, descriptor_pool, intrinsic_descriptor_interface, material_input_layout, bone_uniform, is_bound: false };
+        let mesh_pipeline = MeshRenderPipeline {
+            pipeline, instanced_pipeline, transparent_pipeline, descriptor_pool, intrinsic_descriptor_interface, material_input_layout, material_allocator: descriptor_allocator.clone(), bone_uniform,
+            vertex_shader, instanced_vertex_shader, fragment_shader, instance_ring, lights_offset: 0, shadows_offset: 0, shadow_sampler, bound: BoundState::default()
+        };
+        mesh_pipeline.set_shadow_maps(spatial_pass, device)?;
+        return Ok(mesh_pipeline);
+    }
+
+    /// Binds the shadow maps of the spatial pass. This must be called again whenever the spatial pass is recreated, as its shadow maps are recreated with it.
+    /// The intrinsic descriptor set may be in use by frames in flight, so this waits for the device to be idle.
+    pub fn set_shadow_maps(&self, spatial_pass: &pass::SpatialPass, device: &core::Device) -> Result<(), Error> {
+        device.wait_idle()?;
+        let layout = gfx::image::Layout::ShaderReadOnlyOptimal;
+        self.intrinsic_descriptor_interface.write_descriptor(Some(gfx::pso::Descriptor::Image(&spatial_pass.point_shadow_maps().image_view, layout)), 4, device);
+        self.intrinsic_descriptor_interface.write_descriptor(Some(gfx::pso::Descriptor::Image(&spatial_pass.cascade_shadow_maps().image_view, layout)), 5, device);
+        return Ok(());
+    }
+
+    /// The descriptors which materials rendered by this pipeline should be created with.
+    pub fn material_descriptors(&self) -> material::MaterialDescriptors {
+        return material::MaterialDescriptors::new(self.material_allocator.clone(), self.material_input_layout.clone());
+    }
+
+    fn reflect(vertex_shader: shader::ShaderHandle, fragment_shader: shader::ShaderHandle, shaders: &shader::ShaderManager) -> Result<shader::ShaderReflection, Error> {
+        return Ok(shaders.reflect(&[vertex_shader, fragment_shader])?.with_dynamic(0, 1).with_dynamic(0, 2));
+    }
+
+    /// Builds the opaque, instanced and transparent pipelines. None of them are kept if any fails to build.
+    fn build_pipelines(vertex_shader: shader::ShaderHandle, instanced_vertex_shader: shader::ShaderHandle, fragment_shader: shader::ShaderHandle, set_layouts: &[&pipeline::DescriptorSetLayout],
+                       shaders: &shader::ShaderManager, render_pass: &render::RenderPass, device: &core::Device) -> Result<(pipeline::PipelineController, pipeline::PipelineController, pipeline::PipelineController), Error> {
+        let pipeline = Self::build_pipeline(vertex_shader, fragment_shader, queue::DrawPipeline::Mesh, set_layouts, shaders, render_pass, device)?;
+        let instanced_pipeline = match Self::build_pipeline(instanced_vertex_shader, fragment_shader, queue::DrawPipeline::InstancedMesh, set_layouts, shaders, render_pass, device) {
+            Ok(instanced_pipeline) => instanced_pipeline,
+            Err(e) => {
+                pipeline.destroy(device);
+                return Err(e);
+            },
+        };
+        let transparent_pipeline = match Self::build_pipeline(vertex_shader, fragment_shader, queue::DrawPipeline::TransparentMesh, set_layouts, shaders, render_pass, device) {
+            Ok(transparent_pipeline) => transparent_pipeline,
+            Err(e) => {
+                pipeline.destroy(device);
+                instanced_pipeline.destroy(device);
+                return Err(e);
+            },
+        };
+        return Ok((pipeline, instanced_pipeline, transparent_pipeline));
+    }
+
+    fn build_pipeline(vertex_shader: shader::ShaderHandle, fragment_shader: shader::ShaderHandle, kind: queue::DrawPipeline, set_layouts: &[&pipeline::DescriptorSetLayout],
+                      shaders: &shader::ShaderManager, render_pass: &render::RenderPass, device: &core::Device) -> Result<pipeline::PipelineController, Error> {
+        let reflection = Self::reflect(vertex_shader, fragment_shader, shaders)?;
+        let vertex_shader_module = shaders.create_module(vertex_shader, device)?;
+        let fragment_shader_module = match shaders.create_module(fragment_shader, device) {
+            Ok(module) => module,
+            Err(e) => {
+                unsafe { device.gpu.destroy_shader_module(vertex_shader_module) };
+                return Err(e);
+            },
+        };
+
+        let mut builder = pipeline::PipelineBuilder::new()
+            .with_vertex_shader(&vertex_shader_module)
+            .with_fragment_shader(&fragment_shader_module)
+            .with_vertex::<model::ModelVertex>();
+        if kind == queue::DrawPipeline::InstancedMesh {
+            builder = builder.with_vertex_layout(<InstanceData as pipeline::VertexFormat>::vertex_layout().per_instance());
+        }
+        for set_layout in set_layouts {
+            builder = builder.with_descriptor_set_layout(set_layout);
+        }
+        let pipeline = builder
+            .with_push_constants(gfx::pso::ShaderStageFlags::VERTEX, std::mem::size_of::<MeshConstants>() as u32)
+            .with_reflection(&reflection)
+            .with_blend(pipeline::BlendPreset::Alpha)
+            .with_depth(if kind == queue::DrawPipeline::TransparentMesh { pipeline::DepthPreset::ReadOnly } else { pipeline::DepthPreset::ReadWrite })
+            .with_cull(pipeline::CullPreset::Back)
+            .build(render_pass, device);
+
+        unsafe {
+            device.gpu.destroy_shader_module(vertex_shader_module);
+            device.gpu.destroy_shader_module(fragment_shader_module);
+        }
+        return pipeline;
+    }
+
+    /// Rebuilds the pipeline if any of its shaders were reloaded.
+    /// The reloaded shaders are validated against the existing descriptor set layouts, and the previous pipeline is kept if they do not match or fail to build.
+    pub fn reload_shaders(&mut self, changed: &[shader::ShaderHandle], shaders: &shader::ShaderManager, render_pass: &render::RenderPass, device: &core::Device) {
+        if !changed.contains(&self.vertex_shader) && !changed.contains(&self.instanced_vertex_shader) && !changed.contains(&self.fragment_shader) {
+            return;
+        }
+        if let Err(e) = self.rebuild(shaders, render_pass, device) {
+            log!(err, "Failed to rebuild mesh render pipeline, the previous shaders are still in use: {}", e);
+        }
+    }
+
+    /// Rebuilds the pipeline for the render pass specified, e.g. after the number of samples of the render pass has changed.
+    /// The previous pipelines are kept if any of the new ones fails to build.
+    pub fn rebuild(&mut self, shaders: &shader::ShaderManager, render_pass: &render::RenderPass, device: &core::Device) -> Result<(), Error> {
+        let (pipeline, instanced_pipeline, transparent_pipeline) = Self::build_pipelines(self.vertex_shader, self.instanced_vertex_shader, self.fragment_shader, &[&self.intrinsic_descriptor_interface.layout, self.material_input_layout.as_ref()], shaders, render_pass, device)?;
+        // The previous pipelines may still be in use by frames in flight.
+        if let Err(e) = device.wait_idle() {
+            pipeline.destroy(device);
+            instanced_pipeline.destroy(device);
+            transparent_pipeline.destroy(device);
+            return Err(e);
+        }
+        std::mem::replace(&mut self.pipeline, pipeline).destroy(device);
+        std::mem::replace(&mut self.instanced_pipeline, instanced_pipeline).destroy(device);
+        std::mem::replace(&mut self.transparent_pipeline, transparent_pipeline).destroy(device);
+        log!(msg, "Rebuilt mesh render pipeline.");
+        return Ok(());
+    }
+
+    /// Moves to the next frame of instance data. This must be called once per frame, before any instances are drawn.
+    pub fn begin_frame(&mut self) {
+        self.instance_ring.begin_frame();
+    }
+
+    /// Binds the pipeline used by `render`. This must be called at the start of each pass, before anything is drawn.
+    pub fn bind_pipeline(&mut self, encoder: &mut command::Encoder) {
+        self.bound = BoundState::default();
+        self.use_pipeline(queue::DrawPipeline::Mesh, encoder);
+    }
+
+    /// Binds the pipeline used by `render_instanced`. This must be called at the start of each pass, before anything is drawn.
+    pub fn bind_instanced_pipeline(&mut self, encoder: &mut command::Encoder) {
+        self.bound = BoundState::default();
+        self.use_pipeline(queue::DrawPipeline::InstancedMesh, encoder);
+    }
+
+    /// Binds the pipeline specified unless it is already bound in the current pass.
+    pub fn use_pipeline(&mut self, pipeline: queue::DrawPipeline, encoder: &mut command::Encoder) {
+        if self.bound.pipeline == Some(pipeline) {
+            return;
+        }
+        self.controller(pipeline).bind_encoder(encoder);
+        // Descriptor sets are rebound with the layout of the new pipeline.
+        self.bound.pipeline = Some(pipeline);
+        self.bound.material_set = None;
     }
 
-    pub fn bind_pipeline(&self, command_buffer: &mut command::CommandBuffer) {
-        self.pipeline.bind(command_buffer);
+    /// Binds the intrinsic descriptor set and the material set, unless the material is already bound.
+    pub fn bind_descriptors(&mut self, material_set: &pipeline::DescriptorSet, encoder: &mut command::Encoder) {
+        let material_address = material_set as *const pipeline::DescriptorSet as usize;
+        if self.bound.material_set == Some(material_address) {
+            return;
+        }
+        let pipeline = self.bound.pipeline.unwrap_or(queue::DrawPipeline::Mesh);
+        self.controller(pipeline).bind_descriptor_sets_dynamic(&[&self.intrinsic_descriptor_interface.set, &material_set], &[self.lights_offset, self.shadows_offset], encoder);
+        self.bound.material_set = Some(material_address);
     }
 
-    pub fn bind_descriptors(&self, material_set: &pipeline::DescriptorSet, encoder: &mut command::Encoder) {
-        self.pipeline.bind_descriptor_sets(&[&self.intrinsic_descriptor_interface.set, &material_set], encoder);
+    /// Binds the vertex buffer to the first binding and the index buffer, unless they are already bound.
+    fn bind_vertex_input(&mut self, vertex_input: &pipeline::VertexInput, encoder: &mut command::Encoder) {
+        let vertex_address = vertex_input.vertex_buffer as *const buffer::Buffer as usize;
+        if self.bound.vertex_buffer != Some(vertex_address) {
+            unsafe { encoder.pass.bind_vertex_buffers(0, vec![(&vertex_input.vertex_buffer.buf, 0)]) };
+            self.bound.vertex_buffer = Some(vertex_address);
+        }
+        if let Some(index_buffer) = vertex_input.index_buffer {
+            let index_address = index_buffer as *const buffer::Buffer as usize;
+            if self.bound.index_buffer != Some(index_address) {
+                unsafe { encoder.pass.bind_index_buffer(gfx::buffer::IndexBufferView { buffer: &index_buffer.buf, offset: 0, index_type: gfx::IndexType::U32 }) };
+                self.bound.index_buffer = Some(index_address);
+            }
+        }
+    }
+
+    fn controller(&self, pipeline: queue::DrawPipeline) -> &pipeline::PipelineController {
+        return match pipeline {
+            queue::DrawPipeline::Mesh => &self.pipeline,
+            queue::DrawPipeline::InstancedMesh => &self.instanced_pipeline,
+            queue::DrawPipeline::TransparentMesh => &self.transparent_pipeline,
+        };
     }
 
     /// Renders the vertex input data with a texture.
     /// The texture in this case part of the ShaderInputSet object.
     /// Each texture rendering object should construct on of these using the layout specified in the 'material_set' field.
     /// This layout is ()
-    pub fn render(&mut self, vertex_input: &pipeline::VertexInput, material_set: &pipeline::DescriptorSet, transform: render::RenderTransform, encoder: &mut command::Encoder) {
+    pub fn render(&mut self, vertex_input: &pipeline::VertexInput, material_set: &pipeline::DescriptorSet, constants: MeshConstants, encoder: &mut command::Encoder) {
+        self.draw(queue::DrawPipeline::Mesh, vertex_input, material_set, constants, encoder);
+    }
+
+    /// Renders the vertex input data with the transparent pipeline, which does not write to the depth buffer.
+    /// Transparent meshes should be drawn after every opaque mesh, back-to-front.
+    pub fn render_transparent(&mut self, vertex_input: &pipeline::VertexInput, material_set: &pipeline::DescriptorSet, constants: MeshConstants, encoder: &mut command::Encoder) {
+        self.draw(queue::DrawPipeline::TransparentMesh, vertex_input, material_set, constants, encoder);
+    }
+
+    fn draw(&mut self, pipeline: queue::DrawPipeline, vertex_input: &pipeline::VertexInput, material_set: &pipeline::DescriptorSet, constants: MeshConstants, encoder: &mut command::Encoder) {
+        self.use_pipeline(pipeline, encoder);
         self.bind_descriptors(material_set, encoder);
+        self.bind_vertex_input(vertex_input, encoder);
         unsafe {
-            encoder.pass.bind_vertex_buffers(0, vec![(&vertex_input.vertex_buffer.buf, 0)]);
+            encoder.pass.push_graphics_constants(&self.controller(pipeline).layout.layout, gfx::pso::ShaderStageFlags::VERTEX, 0, std::slice::from_raw_parts(&constants as *const MeshConstants as *const u32, Self::num_push_constants()));
             if let Some(index_buffer) = vertex_input.index_buffer {
-                encoder.pass.push_graphics_constants(&self.pipeline.layout.layout, gfx::pso::ShaderStageFlags::VERTEX, 0, unsafe { std::slice::from_raw_parts(&transform as *const render::RenderTransform as *const u32, Self::num_push_constants()) });
-                encoder.pass.bind_index_buffer(gfx::buffer::IndexBufferView { buffer: &index_buffer.buf, offset: 0, index_type: gfx::IndexType::U32 });
                 encoder.pass.draw_indexed(0..index_buffer.count as u32, 0, 0..1);
             } else {
-                encoder.pass.push_graphics_constants(&self.pipeline.layout.layout, gfx::pso::ShaderStageFlags::VERTEX, 0, unsafe { std::slice::from_raw_parts(&transform as *const render::RenderTransform as *const u32, Self::num_push_constants()) });
                 encoder.pass.draw(0..vertex_input.vertex_buffer.count as u32, 0..1);
             }
         }
     }
 
+    /// Renders the vertex input data once for each of the model matrices, in a single draw call.
+    /// The model matrices are written to the instance ring. Returns false without drawing anything if it is full.
+    pub fn render_instanced(&mut self, vertex_input: &pipeline::VertexInput, material_set: &pipeline::DescriptorSet, models: &[Matrix4f], camera_transform: &scene::CameraTransform, receive_shadows: bool, encoder: &mut command::Encoder) -> bool {
+        let instances: Vec<InstanceData> = models.iter().map(|model| InstanceData::new(*model)).collect();
+        let instance_offset = match self.instance_ring.push_slice(&instances) {
+            Some(offset) => offset as u64,
+            None => return false,
+        };
+        self.use_pipeline(queue::DrawPipeline::InstancedMesh, encoder);
+        self.bind_descriptors(material_set, encoder);
+        self.bind_vertex_input(vertex_input, encoder);
+        // The model matrix of the push constants is ignored by the instanced shader.
+        let constants = MeshConstants::new(render::RenderTransform::new(Matrix4f::identity(), camera_transform.view, camera_transform.projection), receive_shadows);
+        let instance_count = instances.len() as u32;
+        unsafe {
+            encoder.pass.bind_vertex_buffers(1, vec![(&self.instance_ring.buffer.buf, instance_offset)]);
+            encoder.pass.push_graphics_constants(&self.instanced_pipeline.layout.layout, gfx::pso::ShaderStageFlags::VERTEX, 0, std::slice::from_raw_parts(&constants as *const MeshConstants as *const u32, Self::num_push_constants()));
+            if let Some(index_buffer) = vertex_input.index_buffer {
+                encoder.pass.draw_indexed(0..index_buffer.count as u32, 0, 0..instance_count);
+            } else {
+                encoder.pass.draw(0..vertex_input.vertex_buffer.count as u32, 0..instance_count);
+            }
+        }
+        return true;
+    }
+
     const fn num_push_constants() -> usize {
-        return std::mem::size_of::<render::RenderTransform>() / std::mem::size_of::<u32>();
+        return std::mem::size_of::<MeshConstants>() / std::mem::size_of::<u32>();
     }
 
 }
\ No newline at end of file
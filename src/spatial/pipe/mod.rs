@@ -1 +1,2 @@
-pub mod mesh;
\ No newline at end of file
+pub mod mesh;
+pub mod shadow;
\ No newline at end of file
@@ -0,0 +1,131 @@
+use crate::*;
+
+use spatial::*;
+
+use gfx::Device as GfxDevice;
+
+/// The push constants of the shadow pipeline.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ShadowTransform {
+
+    pub model: Matrix4f,
+    pub view_projection: Matrix4f,
+
+}
+
+impl ShadowTransform {
+
+    pub fn new(model: Matrix4f, view_projection: Matrix4f) -> ShadowTransform {
+        return ShadowTransform { model, view_projection };
+    }
+
+}
+
+/// The depth-only pipeline which renders shadow casting meshes into the shadow maps.
+pub struct ShadowRenderPipeline {
+
+    /// The pipeline, or `None` if shadows are disabled and there is no shadow pass to create it for.
+    pub pipeline: Option<pipeline::PipelineController>,
+    pub vertex_shader: shader::ShaderHandle,
+
+}
+
+impl ShadowRenderPipeline {
+
+    /// Creates the pipeline for the shadow passes of the spatial pass, see `SpatialPass::shadow_render_pass`.
+    pub fn create(device: &core::Device, render_pass: Option<&render::RenderPass>, shaders: &mut shader::ShaderManager) -> Result<ShadowRenderPipeline, Error> {
+        let vertex_shader = shaders.load_or_embedded("std_shadow_v", include_bytes!(concat!(env!("OUT_DIR"), "/std_shadow_v.spv")))?;
+        let pipeline = match render_pass {
+            Some(render_pass) => Some(Self::build_pipeline(vertex_shader, shaders, render_pass, device)?),
+            None => None,
+        };
+        return Ok(ShadowRenderPipeline { pipeline, vertex_shader });
+    }
+
+    fn build_pipeline(vertex_shader: shader::ShaderHandle, shaders: &shader::ShaderManager, render_pass: &render::RenderPass, device: &core::Device) -> Result<pipeline::PipelineController, Error> {
+        let reflection = shaders.reflect(&[vertex_shader])?;
+        let vertex_shader_module = shaders.create_module(vertex_shader, device)?;
+
+        // Both faces are drawn so that open meshes cast shadows, and acne is avoided with the bias of the mesh shaders instead.
+        let pipeline = pipeline::PipelineBuilder::new()
+            .with_vertex_shader(&vertex_shader_module)
+            .with_vertex::<model::ModelVertex>()
+            .with_push_constants(gfx::pso::ShaderStageFlags::VERTEX, std::mem::size_of::<ShadowTransform>() as u32)
+            .with_reflection(&reflection)
+            .with_color_targets(0)
+            .with_depth(pipeline::DepthPreset::ReadWrite)
+            .with_cull(pipeline::CullPreset::None)
+            .build(render_pass, device);
+
+        unsafe { device.gpu.destroy_shader_module(vertex_shader_module) };
+        return pipeline;
+    }
+
+    /// Rebuilds the pipeline if its shader was reloaded. The previous pipeline is kept if the new one fails to build.
+    pub fn reload_shaders(&mut self, changed: &[shader::ShaderHandle], shaders: &shader::ShaderManager, render_pass: Option<&render::RenderPass>, device: &core::Device) {
+        if !changed.contains(&self.vertex_shader) {
+            return;
+        }
+        if let Err(e) = self.rebuild(shaders, render_pass, device) {
+            log!(err, "Failed to rebuild shadow pipeline, the previous shader is still in use: {}", e);
+        }
+    }
+
+    /// Rebuilds the pipeline for the shadow passes of a rebuilt spatial pass.
+    /// The pipeline is destroyed if the spatial pass has no shadow passes, and the previous one is kept if the new one fails to build.
+    pub fn rebuild(&mut self, shaders: &shader::ShaderManager, render_pass: Option<&render::RenderPass>, device: &core::Device) -> Result<(), Error> {
+        let pipeline = match render_pass {
+            Some(render_pass) => Some(Self::build_pipeline(self.vertex_shader, shaders, render_pass, device)?),
+            None => None,
+        };
+        // The previous pipeline may still be in use by frames in flight.
+        if let Err(e) = device.wait_idle() {
+            if let Some(pipeline) = pipeline {
+                pipeline.destroy(device);
+            }
+            return Err(e);
+        }
+        if let Some(previous) = std::mem::replace(&mut self.pipeline, pipeline) {
+            previous.destroy(device);
+        }
+        if self.pipeline.is_some() {
+            log!(msg, "Rebuilt shadow pipeline.");
+        }
+        return Ok(());
+    }
+
+    /// Binds the pipeline at the start of a shadow pass. Returns false if there is no pipeline, in which case nothing can be drawn.
+    pub fn bind_pipeline(&self, encoder: &mut command::Encoder) -> bool {
+        return match self.pipeline.as_ref() {
+            Some(pipeline) => {
+                pipeline.bind_encoder(encoder);
+                true
+            },
+            None => false,
+        };
+    }
+
+    /// Renders the depth of the vertex input data. The pipeline must be bound.
+    pub fn render(&self, vertex_input: &pipeline::VertexInput, transform: ShadowTransform, encoder: &mut command::Encoder) {
+        let pipeline = match self.pipeline.as_ref() {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        unsafe {
+            encoder.pass.bind_vertex_buffers(0, vec![(&vertex_input.vertex_buffer.buf, 0)]);
+            encoder.pass.push_graphics_constants(&pipeline.layout.layout, gfx::pso::ShaderStageFlags::VERTEX, 0, std::slice::from_raw_parts(&transform as *const ShadowTransform as *const u32, Self::num_push_constants()));
+            if let Some(index_buffer) = vertex_input.index_buffer {
+                encoder.pass.bind_index_buffer(gfx::buffer::IndexBufferView { buffer: &index_buffer.buf, offset: 0, index_type: gfx::IndexType::U32 });
+                encoder.pass.draw_indexed(0..index_buffer.count as u32, 0, 0..1);
+            } else {
+                encoder.pass.draw(0..vertex_input.vertex_buffer.count as u32, 0..1);
+            }
+        }
+    }
+
+    const fn num_push_constants() -> usize {
+        return std::mem::size_of::<ShadowTransform>() / std::mem::size_of::<u32>();
+    }
+
+}
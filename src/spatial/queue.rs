@@ -0,0 +1,243 @@
+use crate::*;
+
+use spatial::model::BufferedMesh;
+use spatial::material::*;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The pipeline a draw item is drawn with, which is the most significant part of the sort key of opaque items.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrawPipeline {
+
+    Mesh = 0,
+    InstancedMesh = 1,
+    /// Draws transparent meshes, which are tested against the depth buffer without writing to it.
+    TransparentMesh = 2,
+
+}
+
+/// A key which orders draw items so that the renderer changes as little state as possible and blends transparent items correctly.
+/// Opaque items are ordered by pipeline, then material, then front-to-back, so that items sharing a material are drawn together. Transparent items come after every opaque item, ordered back-to-front.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SortKey(pub u64);
+
+impl SortKey {
+
+    const TRANSPARENT_BIT: u64 = 1 << 63;
+    const MATERIAL_BITS: u32 = 28;
+
+    /// `[0: 1][pipeline: 3][material: 28][depth: 32]`
+    pub fn opaque(pipeline: DrawPipeline, material: u32, depth: f32) -> Self {
+        return SortKey(
+            (pipeline as u64) << 60 |
+            Self::material_bits(material) << 32 |
+            Self::depth_bits(depth)
+        );
+    }
+
+    /// `[1: 1][inverted depth: 32][pipeline: 3][material: 28]`
+    pub fn transparent(pipeline: DrawPipeline, material: u32, depth: f32) -> Self {
+        return SortKey(
+            Self::TRANSPARENT_BIT |
+            (!Self::depth_bits(depth) & 0xFFFF_FFFF) << 31 |
+            (pipeline as u64) << Self::MATERIAL_BITS |
+            Self::material_bits(material)
+        );
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        return self.0 & Self::TRANSPARENT_BIT != 0;
+    }
+
+    fn material_bits(material: u32) -> u64 {
+        return (material & ((1 << Self::MATERIAL_BITS) - 1)) as u64;
+    }
+
+    /// The bits of non-negative floats order the same way as the floats themselves.
+    fn depth_bits(depth: f32) -> u64 {
+        let depth = if depth.is_nan() { 0.0 } else { depth.max(0.0) };
+        return depth.to_bits() as u64;
+    }
+
+}
+
+/// A single draw call, which draws a mesh with a material once for each model matrix.
+pub struct DrawItem<'a> {
+
+    pub mesh: &'a BufferedMesh,
+    pub material: &'a MaterialComponent,
+    pub models: Vec<Matrix4f>,
+    /// The view space depth of the nearest instance.
+    pub depth: f32,
+    pub transparent: bool,
+    /// The key the item is sorted by, which is computed by `RenderQueue::sort`.
+    pub key: SortKey,
+
+}
+
+impl<'a> DrawItem<'a> {
+
+    /// Returns true if the item is drawn with the instanced pipeline.
+    pub fn is_instanced(&self) -> bool {
+        return self.models.len() > 1;
+    }
+
+    pub fn pipeline(&self) -> DrawPipeline {
+        if self.transparent {
+            return DrawPipeline::TransparentMesh;
+        }
+        return if self.is_instanced() { DrawPipeline::InstancedMesh } else { DrawPipeline::Mesh };
+    }
+
+}
+
+/// The draw items of a frame, collected from the visible meshes and sorted before they are drawn.
+/// Opaque meshes which share buffers and a material, and which either both receive shadows or both do not, are merged into a single instanced item.
+/// Transparent meshes are never merged, so that each of them can be blended in depth order.
+pub struct RenderQueue<'a> {
+
+    pub items: Vec<DrawItem<'a>>,
+    view: Matrix4f,
+    /// The items opaque meshes were merged into, by vertex buffer, index buffer, material and whether they receive shadows.
+    batches: HashMap<(*const buffer::Buffer, *const buffer::Buffer, *const MaterialBuffer, bool), usize>,
+    /// Small ids for the materials in the queue, by order of first use.
+    material_ids: HashMap<*const MaterialBuffer, u32>,
+
+}
+
+impl<'a> RenderQueue<'a> {
+
+    /// Creates a queue for meshes which are viewed with the camera specified.
+    pub fn new(camera_transform: &scene::CameraTransform) -> Self {
+        return Self { items: Vec::new(), view: camera_transform.view, batches: HashMap::new(), material_ids: HashMap::new() };
+    }
+
+    /// Adds a mesh which is drawn with the model matrix specified.
+    pub fn push(&mut self, mesh: &'a BufferedMesh, material: &'a MaterialComponent, model: Matrix4f) {
+        let depth = self.depth(mesh, &model);
+        let transparent = material.material.transparent;
+        if transparent {
+            self.items.push(DrawItem { mesh, material, models: vec![model], depth, transparent, key: SortKey(0) });
+            return;
+        }
+
+        let index_buffer: *const buffer::Buffer = mesh.index_buffer.as_ref().map_or(std::ptr::null(), |index_buffer| index_buffer.as_ref());
+        let batch = (mesh.vertex_buffer.as_ref() as *const buffer::Buffer, index_buffer, Arc::as_ref(&material.buffer) as *const MaterialBuffer, mesh.receive_shadows);
+        match self.batches.get(&batch) {
+            Some(index) => {
+                let item = &mut self.items[*index];
+                item.models.push(model);
+                item.depth = item.depth.min(depth);
+            },
+            None => {
+                self.batches.insert(batch, self.items.len());
+                self.items.push(DrawItem { mesh, material, models: vec![model], depth, transparent, key: SortKey(0) });
+            },
+        }
+    }
+
+    /// Computes the sort keys of the items and sorts them.
+    pub fn sort(&mut self) {
+        for i in 0..self.items.len() {
+            let material = self.material_id(&self.items[i].material.buffer);
+            let item = &mut self.items[i];
+            item.key = if item.transparent {
+                SortKey::transparent(item.pipeline(), material, item.depth)
+            } else {
+                SortKey::opaque(item.pipeline(), material, item.depth)
+            };
+        }
+        self.items.sort_by_key(|item| item.key);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.items.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.items.is_empty();
+    }
+
+    fn material_id(&mut self, material: &Arc<MaterialBuffer>) -> u32 {
+        let next_id = self.material_ids.len() as u32;
+        return *self.material_ids.entry(Arc::as_ref(material) as *const MaterialBuffer).or_insert(next_id);
+    }
+
+    /// The distance in front of the camera of the center of the mesh bounds, or of the origin of the mesh if it has no bounds.
+    fn depth(&self, mesh: &BufferedMesh, model: &Matrix4f) -> f32 {
+        let center = mesh.bounds.map_or(Vector3f::zero(), |bounds| bounds.aabb.center());
+        let view_position = self.view * (model * center.extend(1.0));
+        // The camera looks down the negative z axis.
+        return -view_position.z;
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn opaque_keys_are_packed() {
+        let key = SortKey::opaque(DrawPipeline::InstancedMesh, 5, 2.0);
+        assert_eq!(key.0, 1 << 60 | 5 << 32 | 2.0f32.to_bits() as u64);
+        assert!(!key.is_transparent());
+        // Material ids are truncated to the bits they are packed into.
+        assert_eq!(SortKey::opaque(DrawPipeline::Mesh, (1 << 28) | 3, 0.0), SortKey::opaque(DrawPipeline::Mesh, 3, 0.0));
+    }
+
+    #[test]
+    fn transparent_keys_are_packed() {
+        let key = SortKey::transparent(DrawPipeline::TransparentMesh, 5, 2.0);
+        assert_eq!(key.0, 1 << 63 | (!2.0f32.to_bits() as u64) << 31 | 2 << 28 | 5);
+        assert!(key.is_transparent());
+    }
+
+    #[test]
+    fn opaque_keys_order_by_pipeline_then_material_then_depth() {
+        let near_first_material = SortKey::opaque(DrawPipeline::Mesh, 0, 10.0);
+        let near_second_material = SortKey::opaque(DrawPipeline::Mesh, 1, 10.0);
+        let far_first_material = SortKey::opaque(DrawPipeline::Mesh, 0, 100.0);
+        let instanced = SortKey::opaque(DrawPipeline::InstancedMesh, 0, 1.0);
+        let mut keys = vec![instanced, far_first_material, near_second_material, near_first_material];
+        keys.sort();
+        assert_eq!(keys, vec![near_first_material, far_first_material, near_second_material, instanced]);
+    }
+
+    #[test]
+    fn opaque_draws_with_the_same_material_are_adjacent() {
+        // Draws of three materials at interleaved depths.
+        let draws: Vec<(u32, f32)> = vec![(2, 5.0), (0, 40.0), (1, 1.0), (2, 60.0), (0, 3.0), (1, 80.0), (0, 20.0), (2, 0.5)];
+        let mut keys: Vec<(SortKey, u32, f32)> = draws.iter().map(|(material, depth)| (SortKey::opaque(DrawPipeline::Mesh, *material, *depth), *material, *depth)).collect();
+        keys.sort_by_key(|(key, _, _)| *key);
+
+        let materials: Vec<u32> = keys.iter().map(|(_, material, _)| *material).collect();
+        assert_eq!(materials, vec![0, 0, 0, 1, 1, 2, 2, 2]);
+        // Draws of each material are still front-to-back.
+        for pair in keys.windows(2) {
+            if pair[0].1 == pair[1].1 {
+                assert!(pair[0].2 <= pair[1].2);
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_keys_order_back_to_front_after_opaque_keys() {
+        let near = SortKey::transparent(DrawPipeline::TransparentMesh, 0, 10.0);
+        let far = SortKey::transparent(DrawPipeline::TransparentMesh, 1, 100.0);
+        let opaque = SortKey::opaque(DrawPipeline::InstancedMesh, (1 << 28) - 1, f32::MAX);
+        let mut keys = vec![near, opaque, far];
+        keys.sort();
+        assert_eq!(keys, vec![opaque, far, near]);
+    }
+
+    #[test]
+    fn depth_behind_the_camera_sorts_as_zero() {
+        assert_eq!(SortKey::opaque(DrawPipeline::Mesh, 0, -5.0), SortKey::opaque(DrawPipeline::Mesh, 0, 0.0));
+        assert_eq!(SortKey::opaque(DrawPipeline::Mesh, 0, f32::NAN), SortKey::opaque(DrawPipeline::Mesh, 0, 0.0));
+        assert!(SortKey::opaque(DrawPipeline::Mesh, 0, 0.0) < SortKey::opaque(DrawPipeline::Mesh, 0, 0.001));
+    }
+
+}
@@ -0,0 +1,366 @@
+use crate::*;
+
+use spatial::light::LightData;
+use spatial::pass::SpatialPass;
+
+pub const MAX_POINT_SHADOWS: usize = render::ShadowSettings::MAX_POINT_SHADOWS as usize;
+pub const MAX_CASCADES: usize = render::ShadowSettings::MAX_CASCADES as usize;
+
+/// The direction and up vector of each face of a point light's shadow map, in layer order.
+/// The mesh fragment shader picks the face of a fragment by the major axis of its direction from the light, in the same order, see `cube_face`.
+const CUBE_FACES: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+    ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+];
+
+/// How far the split distances of the cascades are moved from an even split towards a logarithmic one.
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+
+/// The shadows uniform, which holds the light space matrix of every layer of the shadow maps.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ShadowsList {
+
+    /// Six matrices per point light, one for each face.
+    pub point_matrices: [Matrix4f; MAX_POINT_SHADOWS * 6],
+    pub cascade_matrices: [Matrix4f; MAX_CASCADES],
+    /// The distance from the camera at which each cascade ends.
+    pub cascade_splits: Vector4f,
+    pub cascade_count: i32,
+    pub pcf_radius: i32,
+    pub depth_bias: f32,
+    pub normal_bias: f32,
+    /// The near and far planes of the projection of every face of the point light shadow maps.
+    pub point_near: f32,
+    pub point_far: f32,
+    /// The bias of point light shadow maps in world units, which the shader converts to depth with the near and far planes.
+    pub point_depth_bias: f32,
+    /// Pads the list to a multiple of 16 bytes, which is its size in the std140 layout of the shader.
+    pub padding: f32,
+
+}
+
+impl ShadowsList {
+
+    pub fn new(settings: &render::ShadowSettings) -> Self {
+        return ShadowsList {
+            point_matrices: [Matrix4f::identity(); MAX_POINT_SHADOWS * 6],
+            cascade_matrices: [Matrix4f::identity(); MAX_CASCADES],
+            cascade_splits: Vector4f::zero(),
+            cascade_count: 0,
+            pcf_radius: settings.pcf_radius as i32,
+            depth_bias: settings.depth_bias,
+            normal_bias: settings.normal_bias,
+            point_near: settings.point_near(),
+            point_far: settings.point_range,
+            point_depth_bias: settings.point_depth_bias,
+            padding: 0.0,
+        };
+    }
+
+}
+
+/// A layer of a shadow map which is rendered in the current frame, and the pass which renders it.
+#[derive(Clone, Debug)]
+pub struct ShadowView {
+
+    pub pass: String,
+    pub view_projection: Matrix4f,
+
+}
+
+/// Assigns shadow maps to the lights which cast shadows each frame, and writes their matrices to the uniform ring.
+pub struct ShadowsController {
+
+    pub shadows: ShadowsList,
+    /// The layers to render in the current frame. The passes of layers which are not assigned to a light only clear them.
+    pub views: Vec<ShadowView>,
+    /// The dynamic offset of the shadows written for the current frame.
+    pub offset: u32,
+
+    settings: render::ShadowSettings,
+    point_count: usize,
+
+}
+
+impl ShadowsController {
+
+    pub fn new() -> Self {
+        let settings = render::ShadowSettings::disabled();
+        return Self { shadows: ShadowsList::new(&settings), views: Vec::new(), offset: 0, settings, point_count: 0 };
+    }
+
+    /// Releases the shadow maps of the previous frame. The settings must be those the spatial pass was built with.
+    pub fn begin_frame(&mut self, settings: &render::ShadowSettings) {
+        self.settings = *settings;
+        self.shadows = ShadowsList::new(settings);
+        self.views.clear();
+        self.point_count = 0;
+    }
+
+    /// Assigns a shadow map to the light if one of its kind is free, and returns its index, or -1 if there is none.
+    /// Directional lights are split into cascades along the view of the camera.
+    pub fn add_light(&mut self, light: &LightData, camera_transform: &scene::CameraTransform) -> i32 {
+        if light.is_directional() {
+            if self.shadows.cascade_count > 0 || self.settings.cascades() == 0 {
+                return -1;
+            }
+            self.add_cascades(light.pos, camera_transform);
+            return 0;
+        }
+
+        if self.point_count >= self.settings.point_shadows() as usize {
+            return -1;
+        }
+        let index = self.point_count;
+        let projection = perspective(self.settings.point_near(), self.settings.point_range);
+        let eye = Point3::from_vec(light.pos);
+        for (face, (direction, up)) in CUBE_FACES.iter().enumerate() {
+            let direction = Vector3f::new(direction[0], direction[1], direction[2]);
+            let up = Vector3f::new(up[0], up[1], up[2]);
+            let view_projection = projection * Matrix4f::look_at(eye, eye + direction, up);
+            let layer = index * 6 + face;
+            self.shadows.point_matrices[layer] = view_projection;
+            self.views.push(ShadowView { pass: SpatialPass::point_shadow_pass(layer as u16), view_projection });
+        }
+        self.point_count += 1;
+        return index as i32;
+    }
+
+    /// Fits an orthographic projection around each cascade of the camera's view.
+    fn add_cascades(&mut self, direction: Vector3f, camera_transform: &scene::CameraTransform) {
+        let projection = camera_transform.projection;
+        // The camera projection maps view space depth to `(a * z + b) / -z`, so its near plane is at `b / a`.
+        let near = projection.w.z / projection.z.z;
+        let near = if near.is_finite() && near > 0.0 { near } else { 1.0 };
+        let far = self.settings.directional_distance.max(near);
+        let tan_x = 1.0 / projection.x.x;
+        let tan_y = 1.0 / projection.y.y.abs();
+        let camera = camera_transform.view.invert().unwrap_or(Matrix4f::identity());
+
+        let up = if direction.y.abs() > 0.99 { Vector3f::unit_z() } else { Vector3f::unit_y() };
+        let light_view = Matrix4f::look_at(Point3::new(0.0, 0.0, 0.0), Point3::from_vec(direction), up);
+
+        let splits = cascade_splits(near, far, self.settings.cascades() as usize);
+        let mut start = near;
+        for (cascade, &end) in splits.iter().enumerate() {
+            // The bounding sphere of the slice of the view between the split distances, which keeps the same size as the camera turns.
+            let mut corners: Vec<Vector3f> = Vec::with_capacity(8);
+            for &distance in [start, end].iter() {
+                for &(x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)].iter() {
+                    corners.push((camera * Vector4f::new(x * distance * tan_x, y * distance * tan_y, -distance, 1.0)).truncate());
+                }
+            }
+            let center = corners.iter().fold(Vector3f::zero(), |sum, corner| sum + corner) / 8.0;
+            let radius = corners.iter().map(|corner| (corner - center).magnitude()).fold(0.0f32, f32::max);
+
+            // The center is snapped to whole texels, so that the edges of shadows do not shimmer as the camera moves.
+            let light_center = (light_view * center.extend(1.0)).truncate();
+            let texel = 2.0 * radius / self.settings.directional_resolution as f32;
+            let x = (light_center.x / texel).floor() * texel;
+            let y = (light_center.y / texel).floor() * texel;
+            // Casters up to the shadow distance in front of the slice are included.
+            let depth = -light_center.z;
+            let view_projection = orthographic(x - radius, x + radius, y - radius, y + radius, depth - radius - far, depth + radius) * light_view;
+
+            self.shadows.cascade_matrices[cascade] = view_projection;
+            self.shadows.cascade_splits[cascade] = end;
+            self.views.push(ShadowView { pass: SpatialPass::cascade_shadow_pass(cascade as u16), view_projection });
+            start = end;
+        }
+        self.shadows.cascade_count = splits.len() as i32;
+    }
+
+    /// Gets the layer rendered by a pass, if it is assigned to a light in the current frame.
+    pub fn view(&self, pass: &str) -> Option<&ShadowView> {
+        return self.views.iter().find(|view| view.pass == pass);
+    }
+
+    /// Writes the shadows for the current frame into the ring.
+    /// The previous offset is kept if the ring is full, so the shadows of an earlier frame are used.
+    pub fn update_buffer(&mut self, ring: &mut memory::UniformRing) {
+        if let Some(offset) = ring.push(&self.shadows) {
+            self.offset = offset;
+        }
+    }
+
+}
+
+/// The face of a point light's shadow map which a direction from the light falls on, which is its index in `CUBE_FACES`.
+/// Ties go to the earlier axis. This must match `cube_face` in `shadows.glsl`, which the mesh shader picks the layer to sample with.
+pub fn cube_face(direction: Vector3f) -> usize {
+    let (x, y, z) = (direction.x.abs(), direction.y.abs(), direction.z.abs());
+    if x >= y && x >= z {
+        return if direction.x > 0.0 { 0 } else { 1 };
+    }
+    if y >= z {
+        return if direction.y > 0.0 { 2 } else { 3 };
+    }
+    return if direction.z > 0.0 { 4 } else { 5 };
+}
+
+/// The view space depth at which each of the cascades ends, when the view between the near and far distances is split into the number of cascades specified.
+/// The splits are moved from an even split towards a logarithmic one, so that the nearer cascades cover less of the view at a higher resolution.
+pub fn cascade_splits(near: f32, far: f32, count: usize) -> Vec<f32> {
+    return (0..count).map(|cascade| {
+        let ratio = (cascade + 1) as f32 / count as f32;
+        let even = near + (far - near) * ratio;
+        let logarithmic = near * (far / near).powf(ratio);
+        return even + (logarithmic - even) * CASCADE_SPLIT_LAMBDA;
+    }).collect();
+}
+
+/// A perspective projection with a 90 degree field of view, which covers one face of a cube.
+/// Depth is mapped to `[0, 1]` and y is flipped, like the camera projection.
+fn perspective(near: f32, far: f32) -> Matrix4f {
+    return Matrix4f::new(
+        1.0, 0.0, 0.0, 0.0,
+        0.0, -1.0, 0.0, 0.0,
+        0.0, 0.0, far / (near - far), -1.0,
+        0.0, 0.0, (near * far) / (near - far), 0.0,
+    );
+}
+
+/// An orthographic projection of the box between the planes specified, looking down the negative z axis.
+/// Depth is mapped to `[0, 1]` and y is flipped, like the camera projection.
+fn orthographic(left: f32, right: f32, bottom: f32, top: f32, near: f32, far: f32) -> Matrix4f {
+    return Matrix4f::new(
+        2.0 / (right - left), 0.0, 0.0, 0.0,
+        0.0, -2.0 / (top - bottom), 0.0, 0.0,
+        0.0, 0.0, -1.0 / (far - near), 0.0,
+        -(right + left) / (right - left), (top + bottom) / (top - bottom), -near / (far - near), 1.0,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn project(matrix: &Matrix4f, pos: Vector3f) -> Vector3f {
+        let clip = matrix * pos.extend(1.0);
+        return clip.truncate() / clip.w;
+    }
+
+    fn assert_near(actual: Vector3f, expected: Vector3f) {
+        assert!((actual - expected).magnitude() < 1e-4, "expected {:?}, got {:?}", expected, actual);
+    }
+
+    #[test]
+    fn shadows_list_has_its_std140_size() {
+        // Each matrix takes 64 bytes, the cascade splits 16 and the eight scalars 32.
+        assert_eq!(std::mem::size_of::<ShadowsList>(), (MAX_POINT_SHADOWS * 6 + MAX_CASCADES) * 64 + 16 + 32);
+        assert_eq!(std::mem::size_of::<ShadowsList>() % 16, 0);
+    }
+
+    #[test]
+    fn perspective_maps_depth_to_unit_range_and_flips_y() {
+        let (near, far) = (1.0, 1000.0);
+        let projection = perspective(near, far);
+        assert_near(project(&projection, Vector3f::new(0.0, 0.0, -near)), Vector3f::new(0.0, 0.0, 0.0));
+        assert_near(project(&projection, Vector3f::new(0.0, 0.0, -far)), Vector3f::new(0.0, 0.0, 1.0));
+        // The field of view is 90 degrees, so the edges of the face are at 45 degrees.
+        let corner = project(&projection, Vector3f::new(10.0, 10.0, -10.0));
+        assert!((corner.x - 1.0).abs() < 1e-5 && (corner.y + 1.0).abs() < 1e-5);
+        // The shader converts distances to depth with the same near and far planes.
+        let distance = 250.0;
+        let depth = project(&projection, Vector3f::new(0.0, 0.0, -distance)).z;
+        assert!((depth - far * (distance - near) / ((far - near) * distance)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn orthographic_maps_the_box_to_clip_space() {
+        let projection = orthographic(-2.0, 6.0, -1.0, 3.0, 10.0, 20.0);
+        assert_near(project(&projection, Vector3f::new(-2.0, -1.0, -10.0)), Vector3f::new(-1.0, 1.0, 0.0));
+        assert_near(project(&projection, Vector3f::new(6.0, 3.0, -20.0)), Vector3f::new(1.0, -1.0, 1.0));
+        assert_near(project(&projection, Vector3f::new(2.0, 1.0, -15.0)), Vector3f::new(0.0, 0.0, 0.5));
+    }
+
+    #[test]
+    fn cube_face_matches_the_face_directions() {
+        for (face, (direction, _)) in CUBE_FACES.iter().enumerate() {
+            assert_eq!(cube_face(Vector3f::new(direction[0], direction[1], direction[2])), face);
+        }
+        // Ties go to the earlier axis, like the shader.
+        assert_eq!(cube_face(Vector3f::new(1.0, 1.0, 1.0)), 0);
+        assert_eq!(cube_face(Vector3f::new(0.0, -1.0, 1.0)), 3);
+    }
+
+    /// Every direction must fall inside the face which `cube_face` picks, otherwise the shader would sample the wrong layer.
+    #[test]
+    fn cube_face_picks_the_face_which_contains_the_direction() {
+        let projection = perspective(0.1, 100.0);
+        let steps = [-1.0, -0.7, -0.2, 0.0, 0.3, 0.9, 1.0];
+        for &x in steps.iter() {
+            for &y in steps.iter() {
+                for &z in steps.iter() {
+                    let direction = Vector3f::new(x, y, z);
+                    if direction.magnitude() < 0.1 {
+                        continue;
+                    }
+                    let (forward, up) = CUBE_FACES[cube_face(direction)];
+                    let view = Matrix4f::look_at(Point3::new(0.0, 0.0, 0.0), Point3::new(forward[0], forward[1], forward[2]), Vector3f::new(up[0], up[1], up[2]));
+                    let coords = project(&(projection * view), direction * 10.0);
+                    assert!(coords.x.abs() <= 1.0 + 1e-5 && coords.y.abs() <= 1.0 + 1e-5 && coords.z >= 0.0 && coords.z <= 1.0, "{:?} is outside of face {} at {:?}", direction, cube_face(direction), coords);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cascade_splits_end_at_the_far_plane() {
+        assert!(cascade_splits(1.0, 100.0, 0).is_empty());
+        assert!((cascade_splits(1.0, 100.0, 1)[0] - 100.0).abs() < 1e-3);
+
+        let splits = cascade_splits(1.0, 1000.0, 4);
+        assert_eq!(splits.len(), 4);
+        assert!((splits[3] - 1000.0).abs() < 1e-2);
+        for (cascade, pair) in splits.windows(2).enumerate() {
+            assert!(pair[0] < pair[1], "cascade {} ends after the next one", cascade);
+        }
+        // The nearer cascades are smaller than an even split.
+        assert!(splits[0] < 250.0);
+    }
+
+    #[test]
+    fn directional_lights_are_split_into_cascades() {
+        let settings = render::ShadowSettings::new().with_cascades(10, 1024).with_directional_distance(500.0);
+        let camera = spatial::Camera::create(Vector2f::new(512.0, 512.0), 1.0);
+        let camera_transform = scene::CameraTransform::new(camera.projection, Matrix4f::identity());
+        let mut controller = ShadowsController::new();
+        controller.begin_frame(&settings);
+
+        let light = LightData::directional(Vector3f::new(0.0, -1.0, 0.2), OpaqueColor::white());
+        assert_eq!(controller.add_light(&light, &camera_transform), 0);
+        assert_eq!(controller.shadows.cascade_count, render::ShadowSettings::MAX_CASCADES as i32);
+        assert_eq!(controller.views.len(), MAX_CASCADES);
+        assert!((controller.shadows.cascade_splits[MAX_CASCADES - 1] - 500.0).abs() < 1e-2);
+        // Only one directional light casts shadows.
+        assert_eq!(controller.add_light(&light, &camera_transform), -1);
+    }
+
+    #[test]
+    fn point_lights_take_the_free_shadow_maps() {
+        let settings = render::ShadowSettings::new().with_point_shadows(2, 256).with_point_range(100.0).with_point_bias(0.25);
+        let mut controller = ShadowsController::new();
+        controller.begin_frame(&settings);
+        assert_eq!(controller.shadows.point_near, 0.1);
+        assert_eq!(controller.shadows.point_far, 100.0);
+        assert_eq!(controller.shadows.point_depth_bias, 0.25);
+
+        let camera_transform = scene::CameraTransform::new(Matrix4f::identity(), Matrix4f::identity());
+        let light = LightData::new(Vector3f::new(5.0, 0.0, 0.0), OpaqueColor::white());
+        assert_eq!(controller.add_light(&light, &camera_transform), 0);
+        assert_eq!(controller.add_light(&light, &camera_transform), 1);
+        assert_eq!(controller.add_light(&light, &camera_transform), -1);
+        assert_eq!(controller.views.len(), 12);
+        // A point in front of the +X face of the second light is inside its layer.
+        let coords = project(&controller.shadows.point_matrices[6], Vector3f::new(10.0, 1.0, -1.0));
+        assert!(coords.x.abs() < 1.0 && coords.y.abs() < 1.0 && coords.z > 0.0 && coords.z < 1.0);
+    }
+
+}
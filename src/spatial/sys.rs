@@ -1,12 +1,16 @@
 use crate::*;
 use node::*;
 use spatial::pipe::mesh::*;
+use spatial::pipe::shadow::*;
 use spatial::model::BufferedMesh;
 use spatial::RenderComponent;
 use scene::*;
 use spatial::light::*;
 use spatial::material::*;
 use spatial::pass::SpatialPass;
+use spatial::bounds::*;
+use spatial::queue::RenderQueue;
+use spatial::shadow::ShadowsController;
 use specs::prelude::*;
 
 use std::time::Instant;
@@ -18,24 +22,31 @@ pub struct LightSystem;
 
 impl <'a> System<'a> for LightSystem {
     type SystemData = (
-        WriteExpect<'a, scene::GraphicsCapsule>,
         WriteExpect<'a, spatial::light::LightsController>,
+        WriteExpect<'a, ShadowsController>,
+        WriteExpect<'a, memory::UniformRing>,
+        ReadExpect<'a, SceneData>,
+        ReadExpect<'a, SpatialPass>,
         ReadStorage<'a, LightComponent>,
         ReadStorage<'a, node::NodeObject3D>,
     );
 
-    fn run(&mut self, (mut graphics, mut lights_controller, lights, nodes): Self::SystemData) {
+    fn run(&mut self, (mut lights_controller, mut shadows_controller, mut uniform_ring, scene_data, spatial_pass, lights, nodes): Self::SystemData) {
         // Here we update the shared lights buffer if we need to.
+        // Lights which cast shadows are given the free shadow maps in the order they are joined.
         let mut lights_list: LightsList = LightsList::new();
+        shadows_controller.begin_frame(&spatial_pass.shadows);
         for (light_component, node) in (&lights, &nodes).join() {
-            let light_data: LightData = light_component.light.get_data(node.get_pos());
+            let mut light_data: LightData = light_component.light.get_data(node.get_pos());
+            if light_component.cast_shadows {
+                light_data.shadow_index = shadows_controller.add_light(&light_data, &scene_data.camera_transform);
+            }
             lights_list.add_light(light_data);
         }
 
         lights_controller.lights = lights_list;
-        if let Some(graphics) = unsafe { graphics.unsafe_borrow() } {
-            lights_controller.update_buffer(&mut graphics.device);
-        }
+        lights_controller.update_buffer(&mut uniform_ring);
+        shadows_controller.update_buffer(&mut uniform_ring);
     }
 }
 
@@ -48,31 +59,85 @@ impl<'a> System<'a> for MeshRenderSystem {
         WriteExpect<'a, SpatialPass>,
         ReadExpect<'a, SceneData>,
         WriteExpect<'a, MeshRenderPipeline>,
+        ReadExpect<'a, ShadowRenderPipeline>,
         ReadExpect<'a, spatial::light::LightsController>,
-        WriteStorage<'a, BufferedMesh>,
+        ReadExpect<'a, ShadowsController>,
+        ReadStorage<'a, BufferedMesh>,
         ReadStorage<'a, MaterialComponent>,
         ReadStorage<'a, node::NodeObject3D>,
+        WriteExpect<'a, RenderStats>,
     );
 
-    fn run(&mut self, (mut graphics, mut render_pass, scene_data, mut mesh_pipeline, lights, mut meshes, materials, nodes): Self::SystemData) {
+    fn run(&mut self, (mut graphics, mut render_pass, scene_data, mut mesh_pipeline, shadow_pipeline, lights, shadows, meshes, materials, nodes, mut stats): Self::SystemData) {
+        *stats = RenderStats::new();
         // Only render if the render core is valid.
         if let Some(mut graphics) = unsafe { graphics.unsafe_borrow() } {
             // Get camera transform.
             let camera_transform: CameraTransform = scene_data.camera_transform;
-            // Lights
-            //mesh_pipeline.intrinsic_descriptor_interface.set.write_input(lights.buffer.as_ref(), 1, &graphics.graphics.device);
-
-            if let Some((mut frame, pass)) = render_pass.next(graphics) {
-                frame.begin_render(graphics, | dispatch | {
-                    mesh_pipeline.bind_pipeline(&mut dispatch.command_buffer);
-                    dispatch.begin_render_pass_inline(Color::black(), pass, |graphics, encoder| {
-                        for (mut mesh, material, node) in (&mut meshes, &materials, &nodes).join() {
-                            let transform: render::RenderTransform = render::RenderTransform::new(node.get_trans(), camera_transform.view, camera_transform.projection);
-                            mesh.render(transform, &material.buffer.descriptor_set, &mut mesh_pipeline, encoder);
-                        }
-                    });
-                });
+            let frustum = Frustum::from_camera(&camera_transform);
+            // Lights and shadows are written to the uniform ring by the light system and bound with dynamic offsets.
+            mesh_pipeline.lights_offset = lights.offset;
+            mesh_pipeline.shadows_offset = shadows.offset;
+
+            // Visible meshes are collected into a queue which merges opaque meshes into instanced draws and sorts them to minimise state changes.
+            let mut queue = RenderQueue::new(&camera_transform);
+            for (mesh, material, node) in (&meshes, &materials, &nodes).join() {
+                let model: Matrix4f = node.get_trans();
+                if !mesh.is_visible(&model, &frustum) {
+                    stats.culled += 1;
+                    continue;
+                }
+                stats.drawn += 1;
+                queue.push(mesh, material, model);
             }
+            queue.sort();
+
+            // Shadow casters are culled against the view of each layer of the shadow maps rather than the camera.
+            let casters: Vec<(&BufferedMesh, Matrix4f)> = if shadows.views.is_empty() {
+                Vec::new()
+            } else {
+                (&meshes, &nodes).join().filter(|(mesh, _)| mesh.cast_shadows).map(|(mesh, node)| (mesh, node.get_trans())).collect()
+            };
+
+            render_pass.render(graphics, |pass, graphics, encoder| {
+                // Layers of the shadow maps which are not assigned to a light are only cleared.
+                if let Some(view) = shadows.view(pass) {
+                    if !shadow_pipeline.bind_pipeline(encoder) {
+                        return;
+                    }
+                    let frustum = Frustum::from_matrix(&view.view_projection);
+                    for (mesh, model) in casters.iter() {
+                        if mesh.is_visible(model, &frustum) {
+                            mesh.render_shadow(ShadowTransform::new(*model, view.view_projection), &shadow_pipeline, encoder);
+                            stats.shadow_draw_calls += 1;
+                        }
+                    }
+                    return;
+                }
+                if pass != SpatialPass::MESH_PASS {
+                    return;
+                }
+                mesh_pipeline.bind_pipeline(encoder);
+                for item in queue.items.iter() {
+                    if item.is_instanced() {
+                        if item.mesh.render_instanced(&item.models, &camera_transform, &item.material.buffer.descriptor_set, &mut mesh_pipeline, encoder) {
+                            stats.draw_calls += 1;
+                            continue;
+                        }
+                        // The instance ring is full, so the batch is drawn one mesh at a time instead.
+                        log!(debug, 2, "Drawing a batch of {} instances with separate draw calls.", item.models.len());
+                    }
+                    for model in item.models.iter() {
+                        let transform: render::RenderTransform = render::RenderTransform::new(*model, camera_transform.view, camera_transform.projection);
+                        if item.transparent {
+                            item.mesh.render_transparent(transform, &item.material.buffer.descriptor_set, &mut mesh_pipeline, encoder);
+                        } else {
+                            item.mesh.render(transform, &item.material.buffer.descriptor_set, &mut mesh_pipeline, encoder);
+                        }
+                        stats.draw_calls += 1;
+                    }
+                }
+            });
         }
     }
 }
@@ -4,6 +4,7 @@ use crate::*;
 /// The 3D vertex structure which represents the vetex data which is passed to the shader.
 /// This contains both the position of the vertex (in world space) and the uv coordinate used for texturing.
 #[derive(Copy, Clone)]
+#[repr(C)]
 pub struct UvVertex3f {
 
     pub pos: Vector3f,
@@ -11,6 +12,7 @@ pub struct UvVertex3f {
 
 }
 
+vertex_format!(UvVertex3f { 0 => pos, 1 => uv });
 
 impl UvVertex3f {
 
@@ -21,6 +23,7 @@ impl UvVertex3f {
 }
 
 #[derive(Copy, Clone)]
+#[repr(C)]
 pub struct UvVertex2f {
 
     pub pos: Vector2f,
@@ -28,6 +31,8 @@ pub struct UvVertex2f {
 
 }
 
+vertex_format!(UvVertex2f { 0 => pos, 1 => uv });
+
 impl UvVertex2f {
 
     pub fn new(pos: Vector2f, uv: Vector2f) -> UvVertex2f {
@@ -77,23 +82,31 @@ impl Texture {
 
     }
 
-    pub fn from_file(path: &str) -> Result<Texture, &'static str> {
-        if let Ok(image) = image::open(path) {
-            let img = image.to_rgba();
-            let (width, height) = img.dimensions();
-            return Ok(Texture { data: Vec::from(img.as_ref()), dimensions: Vector2u::new(width, height) });
-        }
-        return Err("Could not find a valid image file at the path specified.");
+    pub fn from_file(path: &str) -> Result<Texture, Error> {
+        let img = image::open(path)?.to_rgba();
+        let (width, height) = img.dimensions();
+        return Ok(Texture { data: Vec::from(img.as_ref()), dimensions: Vector2u::new(width, height) });
+
+    }
+
+    pub fn from_image_bytes(bytes: &[u8]) -> Result<Texture, Error> {
+        let img = image::load_from_memory(bytes)?.to_rgba();
+        let (width, height) = img.dimensions();
+        return Ok(Texture { data: Vec::from(img.as_ref()), dimensions: Vector2::new(width, height) });
+    }
 
+    /// Converts the texture data into an image buffer.
+    /// This returns `None` if the data does not match the dimensions of the texture.
+    pub fn to_image(&self) -> Option<image::ImageBuffer<image::Rgba<u8>, Vec<u8>>> {
+        return image::ImageBuffer::from_raw(self.dimensions.x, self.dimensions.y, self.data.clone());
     }
 
-    pub fn from_image_bytes(bytes: &[u8]) -> Result<Texture, &'static str> {
-        if let Ok(image) = image::load_from_memory(bytes) {
-            let img = image.to_rgba();
-            let (width, height) = img.dimensions();
-            return Ok(Texture { data: Vec::from(img.as_ref()), dimensions: Vector2::new(width, height) });
-        }
-        return Err("Failed to load texture from bytes. Perhaps the bytes were of invalid format?");
+    /// Saves the texture as a png file at the specified path.
+    pub fn save_png(&self, path: &str) -> Result<(), Error> {
+        let file = std::fs::File::create(path)?;
+        let encoder = image::png::PNGEncoder::new(file);
+        encoder.encode(&self.data, self.dimensions.x, self.dimensions.y, image::RGBA(8))?;
+        return Ok(());
     }
 
 }
@@ -92,7 +92,7 @@ impl From<Color> for OpaqueColor {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Color {
 
     pub r: f32,
@@ -291,6 +291,32 @@ pub enum Res<T> {
     Val(T),
 }
 
+impl<T> Res<T> {
+
+    /// Moves the value into an `Arc` so that it can be shared with `share`.
+    pub fn into_shared(self) -> Res<T> {
+        match self {
+            Res::Heap(Heap::Arc(v)) => return Res::Heap(Heap::Arc(v)),
+            Res::Heap(Heap::Box(v)) => return Res::Heap(Heap::Arc(std::sync::Arc::new(*v))),
+            Res::Val(v) => return Res::Heap(Heap::Arc(std::sync::Arc::new(v))),
+        }
+    }
+
+    /// Gets another reference to the same value, if it is held in an `Arc`.
+    pub fn share(&self) -> Option<Res<T>> {
+        match self {
+            Res::Heap(Heap::Arc(v)) => return Some(Res::Heap(Heap::Arc(v.clone()))),
+            _ => return None,
+        }
+    }
+
+    /// Returns true if both hold the same value, rather than equal values.
+    pub fn ptr_eq(&self, other: &Res<T>) -> bool {
+        return std::ptr::eq(self.as_ref(), other.as_ref());
+    }
+
+}
+
 impl<T> std::convert::AsRef<T> for Res<T> {
     fn as_ref(&self) -> &T {
         match self {
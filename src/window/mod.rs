@@ -4,8 +4,6 @@ pub use crate::winit;
 
 pub type Event = winit::Event;
 
-pub struct WindowCreationError(&'static str);
-
 /// Represents a window in the Imperium engine.
 /// Each window should have its own 'Renderer' which represents the render pipeline targeting a window.
 /// The window can be created with any 'create' method (e.g. 'create' or 'create_fullscreen').
@@ -24,22 +22,69 @@ pub struct Window {
 
 impl Window {
 
-    pub fn create(title: &str, size: Vector2f) -> Result<Window, &'static str> {
+    pub fn create(title: &str, size: Vector2f) -> Result<Window, Error> {
         let events_loop = winit::EventsLoop::new();
         let builder = winit::WindowBuilder::new().with_title(title).with_dimensions(winit::dpi::LogicalSize::new(size.x as f64, size.y as f64));
-        if let Ok(window) = builder.build(&events_loop) {
-            return Ok(Window { handle: window, events_loop, lock_cursor: false });
-        }
-        return Err("Failed to create window (winit).");
+        let window = builder.build(&events_loop).map_err(|e| Error::Window(format!("{:?}", e)))?;
+        return Ok(Window { handle: window, events_loop, lock_cursor: false });
     }
 
-    pub fn create_fullscreen(title: &str) -> Result<Window, &'static str> {
+    pub fn create_fullscreen(title: &str) -> Result<Window, Error> {
         let events_loop = winit::EventsLoop::new();
         let builder = winit::WindowBuilder::new().with_title(title).with_fullscreen(None);
-        if let Ok(window) = builder.build(&events_loop) {
-            return Ok(Window { handle: window, events_loop, lock_cursor: false });
+        let window = builder.build(&events_loop).map_err(|e| Error::Window(format!("{:?}", e)))?;
+        return Ok(Window { handle: window, events_loop, lock_cursor: false });
+    }
+
+    /// Creates a window shown in the window mode of the settings.
+    pub fn create_with_settings(title: &str, settings: &render::GraphicsSettings) -> Result<Window, Error> {
+        let events_loop = winit::EventsLoop::new();
+        let mut builder = winit::WindowBuilder::new().with_title(title);
+        if let Some(size) = settings.size {
+            builder = builder.with_dimensions(winit::dpi::LogicalSize::new(size.x as f64, size.y as f64));
+        }
+        builder = match settings.window_mode {
+            render::WindowMode::Windowed => builder,
+            render::WindowMode::Borderless => builder.with_decorations(false),
+            render::WindowMode::Exclusive => builder.with_fullscreen(Some(events_loop.get_primary_monitor())),
+        };
+        let window = builder.build(&events_loop).map_err(|e| Error::Window(format!("{:?}", e)))?;
+        let window = Window { handle: window, events_loop, lock_cursor: false };
+        if settings.window_mode == render::WindowMode::Borderless {
+            window.cover_monitor();
         }
-        return Err("Failed to create window (winit).");
+        return Ok(window);
+    }
+
+    /// Changes the window mode and windowed size to those of the settings.
+    pub fn apply_settings(&mut self, settings: &render::GraphicsSettings) {
+        match settings.window_mode {
+            render::WindowMode::Windowed => {
+                self.handle.set_fullscreen(None);
+                self.handle.set_decorations(true);
+                self.handle.set_maximized(false);
+                if let Some(size) = settings.size {
+                    self.handle.set_inner_size(winit::dpi::LogicalSize::new(size.x as f64, size.y as f64));
+                }
+            },
+            render::WindowMode::Borderless => {
+                self.handle.set_fullscreen(None);
+                self.handle.set_decorations(false);
+                self.handle.set_maximized(false);
+                self.cover_monitor();
+            },
+            render::WindowMode::Exclusive => {
+                self.handle.set_fullscreen(Some(self.handle.get_primary_monitor()));
+            },
+        }
+    }
+
+    /// Moves and resizes the window so that it covers the monitor it is on.
+    fn cover_monitor(&self) {
+        let monitor = self.handle.get_current_monitor();
+        let dpi_factor = monitor.get_hidpi_factor();
+        self.handle.set_position(monitor.get_position().to_logical(dpi_factor));
+        self.handle.set_inner_size(monitor.get_dimensions().to_logical(dpi_factor));
     }
 
     pub fn update(&mut self) {
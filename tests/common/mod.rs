@@ -0,0 +1,309 @@
+use imperium::*;
+
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The environment variable which, when set, causes golden tests to record their rendered output as the reference images.
+/// Without it, a missing reference image fails the test.
+pub const UPDATE_ENV_VAR: &str = "IMPERIUM_UPDATE_GOLDEN";
+
+/// The environment variable which, when set, causes tests which need a Vulkan driver to fail rather than be skipped when there is none.
+/// CI sets it on machines which have a software driver installed, so that a broken driver setup cannot silently skip the golden tests.
+pub const REQUIRE_DRIVER_ENV_VAR: &str = "IMPERIUM_REQUIRE_DRIVER";
+
+/// Reports that a test is skipped because there is no Vulkan driver, or panics if a driver is required.
+pub fn skip_without_driver(reason: &str) {
+    if std::env::var_os(REQUIRE_DRIVER_ENV_VAR).is_some() {
+        panic!("No Vulkan driver to render with, but {} is set: {}", REQUIRE_DRIVER_ENV_VAR, reason);
+    }
+    eprintln!("Skipping test - {}", reason);
+}
+
+/// The directory the reference images are committed to.
+pub fn reference_dir() -> &'static Path {
+    return Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden"));
+}
+
+/// The result of comparing a rendered image with a reference image.
+pub struct ImageComparison {
+
+    /// The number of pixels where at least one channel differs by more than the tolerance.
+    pub mismatched_pixels: usize,
+
+    /// The largest difference found in any channel of any pixel.
+    pub max_difference: u8,
+
+    /// An image which highlights the mismatched pixels in red over a faded copy of the reference image.
+    pub diff: texture::Texture,
+
+}
+
+impl ImageComparison {
+
+    /// Compares two RGBA textures pixel by pixel.
+    /// A pixel only counts as mismatched if one of its channels differs by more than `tolerance`.
+    pub fn compare(actual: &texture::Texture, expected: &texture::Texture, tolerance: u8) -> Result<ImageComparison, String> {
+        if actual.dimensions != expected.dimensions {
+            return Err(format!("Failed to compare images - the dimensions {:?} and {:?} do not match.", actual.dimensions, expected.dimensions));
+        }
+        if actual.data.len() != expected.data.len() {
+            return Err(String::from("Failed to compare images - the image data is not of the expected size."));
+        }
+
+        let mut mismatched_pixels: usize = 0;
+        let mut max_difference: u8 = 0;
+        let mut diff_data: Vec<u8> = Vec::with_capacity(expected.data.len());
+
+        for (a, e) in actual.data.chunks(4).zip(expected.data.chunks(4)) {
+            let mut pixel_difference: u8 = 0;
+            for c in 0..4 {
+                let difference = (a[c] as i16 - e[c] as i16).abs() as u8;
+                if difference > pixel_difference {
+                    pixel_difference = difference;
+                }
+            }
+            if pixel_difference > max_difference {
+                max_difference = pixel_difference;
+            }
+            if pixel_difference > tolerance {
+                mismatched_pixels += 1;
+                // Scale the intensity so that small differences are still clearly visible.
+                let intensity = 128 + (pixel_difference / 2);
+                diff_data.extend_from_slice(&[intensity, 0, 0, 255]);
+            } else {
+                let luminance = ((e[0] as u16 + e[1] as u16 + e[2] as u16) / 3 / 4) as u8;
+                diff_data.extend_from_slice(&[luminance, luminance, luminance, 255]);
+            }
+        }
+
+        let diff = texture::Texture::from_bytes(&diff_data, expected.dimensions);
+
+        return Ok(ImageComparison { mismatched_pixels, max_difference, diff });
+    }
+
+}
+
+/// A single golden image test case.
+/// Rendered output is compared with a reference png stored at `reference_dir/<name>.png`.
+/// When a comparison fails, the rendered image and a diff image are written to the output directory.
+/// A missing reference image is a failure, unless the `IMPERIUM_UPDATE_GOLDEN` environment variable is set, in which case the rendered image is recorded as the new reference.
+pub struct GoldenTest {
+
+    pub name: String,
+    pub reference_dir: PathBuf,
+    pub output_dir: PathBuf,
+
+    /// The largest per channel difference which is still considered a match.
+    pub tolerance: u8,
+
+    /// The number of mismatched pixels which are allowed before the test fails.
+    pub max_mismatched_pixels: usize,
+
+}
+
+impl GoldenTest {
+
+    pub fn new(name: &str, reference_dir: &Path) -> Self {
+        return Self {
+            name: name.to_string(),
+            reference_dir: reference_dir.to_path_buf(),
+            output_dir: reference_dir.join("failures"),
+            tolerance: 0,
+            max_mismatched_pixels: 0,
+        };
+    }
+
+    pub fn with_tolerance(mut self, tolerance: u8) -> Self {
+        self.tolerance = tolerance;
+        return self;
+    }
+
+    pub fn with_max_mismatched_pixels(mut self, max_mismatched_pixels: usize) -> Self {
+        self.max_mismatched_pixels = max_mismatched_pixels;
+        return self;
+    }
+
+    pub fn reference_path(&self) -> PathBuf {
+        return self.reference_dir.join(format!("{}.png", self.name));
+    }
+
+    /// Checks the rendered texture against the reference image.
+    /// The error contains a description of the failure which is suitable for a test assertion.
+    pub fn check(&self, actual: &texture::Texture) -> Result<(), String> {
+        let reference_path = self.reference_path();
+        let path_str = reference_path.to_str().ok_or("Invalid reference path.")?.to_string();
+
+        if std::env::var_os(UPDATE_ENV_VAR).is_some() {
+            std::fs::create_dir_all(&self.reference_dir).map_err(|e| format!("Failed to create reference directory: {}", e))?;
+            actual.save_png(&path_str).map_err(|e| e.to_string())?;
+            log!(msg, "Recorded reference image for golden test '{}' at {}.", self.name, path_str);
+            return Ok(());
+        }
+        if !reference_path.exists() {
+            self.write_output(actual, None)?;
+            return Err(format!(
+                "Golden test '{}' has no reference image at {}. Run the test with {} set to record it, and commit the image. The rendered output was written to {}.",
+                self.name,
+                path_str,
+                UPDATE_ENV_VAR,
+                self.output_dir.display(),
+            ));
+        }
+
+        let expected = texture::Texture::from_file(&path_str).map_err(|e| e.to_string())?;
+        let comparison = ImageComparison::compare(actual, &expected, self.tolerance)?;
+
+        if comparison.mismatched_pixels > self.max_mismatched_pixels {
+            self.write_output(actual, Some(&comparison.diff))?;
+            return Err(format!(
+                "Golden test '{}' failed: {} pixels differ by more than {} (max difference {}). Output written to {}.",
+                self.name,
+                comparison.mismatched_pixels,
+                self.tolerance,
+                comparison.max_difference,
+                self.output_dir.display(),
+            ));
+        }
+
+        return Ok(());
+    }
+
+    /// Writes the rendered image, and the diff image if there is one, to the output directory.
+    fn write_output(&self, actual: &texture::Texture, diff: Option<&texture::Texture>) -> Result<(), String> {
+        std::fs::create_dir_all(&self.output_dir).map_err(|e| format!("Failed to create output directory: {}", e))?;
+        let actual_path = self.output_dir.join(format!("{}.actual.png", self.name));
+        actual.save_png(actual_path.to_str().ok_or("Invalid output path.")?).map_err(|e| e.to_string())?;
+        if let Some(diff) = diff {
+            let diff_path = self.output_dir.join(format!("{}.diff.png", self.name));
+            diff.save_png(diff_path.to_str().ok_or("Invalid output path.")?).map_err(|e| e.to_string())?;
+        }
+        return Ok(());
+    }
+
+}
+
+/// Owns a headless instance and renderer which scenes can be rendered with for image comparisons.
+pub struct HeadlessRenderer {
+
+    // The graphics object must be dropped before the instance, which is only kept alive for it.
+    pub graphics: render::Graphics,
+    _instance: core::Instance,
+
+}
+
+impl HeadlessRenderer {
+
+    /// Creates a headless renderer using the adapter which best matches the preference.
+    /// Golden images should be rendered on the same adapter each time (typically `AdapterPreference::Software`) for reproducible output.
+    pub fn new(size: Vector2u, preference: &core::AdapterPreference) -> Result<Self, Error> {
+        let instance: core::Instance = core::Instance::create("imperium-golden");
+        let graphics: render::Graphics = render::Graphics::create_headless(&instance, size, preference)?;
+        return Ok(Self { graphics, _instance: instance });
+    }
+
+    pub fn get_size(&self) -> Vector2f {
+        return self.graphics.render_surface.get_size();
+    }
+
+    /// Dispatches the scene systems for the specified number of frames then reads back the last frame.
+    /// The surface is revalidated before each frame, as `AppInterface::update` does, so that changed settings are applied.
+    pub fn render_scene(&mut self, scene: &mut spatial::Scene3D, frames: usize) -> Result<texture::Texture, Error> {
+        for _ in 0..frames {
+            self.graphics.update(None)?;
+            scene.dispatch_systems(&mut self.graphics);
+        }
+        return self.graphics.capture_frame();
+    }
+
+}
+
+/// Creates the vertices and indices of an axis aligned cube centred on the origin.
+/// This is useful for building small deterministic scenes.
+pub fn cube_geometry(size: f32) -> (Vec<spatial::model::ModelVertex>, Vec<u32>) {
+    let h = size / 2.0;
+    let faces: [(Vector3f, Vector3f, Vector3f); 6] = [
+        (Vector3f::new(0.0, 0.0, 1.0), Vector3f::new(1.0, 0.0, 0.0), Vector3f::new(0.0, 1.0, 0.0)),
+        (Vector3f::new(0.0, 0.0, -1.0), Vector3f::new(-1.0, 0.0, 0.0), Vector3f::new(0.0, 1.0, 0.0)),
+        (Vector3f::new(1.0, 0.0, 0.0), Vector3f::new(0.0, 0.0, -1.0), Vector3f::new(0.0, 1.0, 0.0)),
+        (Vector3f::new(-1.0, 0.0, 0.0), Vector3f::new(0.0, 0.0, 1.0), Vector3f::new(0.0, 1.0, 0.0)),
+        (Vector3f::new(0.0, 1.0, 0.0), Vector3f::new(1.0, 0.0, 0.0), Vector3f::new(0.0, 0.0, -1.0)),
+        (Vector3f::new(0.0, -1.0, 0.0), Vector3f::new(1.0, 0.0, 0.0), Vector3f::new(0.0, 0.0, 1.0)),
+    ];
+
+    let mut vertices: Vec<spatial::model::ModelVertex> = Vec::with_capacity(24);
+    let mut indices: Vec<u32> = Vec::with_capacity(36);
+
+    for (normal, right, up) in faces.iter() {
+        let base = vertices.len() as u32;
+        let center = *normal * h;
+        let corners: [(f32, f32); 4] = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)];
+        for (x, y) in corners.iter() {
+            let pos = center + *right * (x * h) + *up * (y * h);
+            let uv = Vector2f::new((x + 1.0) / 2.0, (y + 1.0) / 2.0);
+            vertices.push(spatial::model::ModelVertex::new(pos, *normal, uv));
+        }
+        indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+    }
+
+    return (vertices, indices);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// A texture with every pixel set to the color specified.
+    fn solid(width: u32, height: u32, color: [u8; 4]) -> texture::Texture {
+        let data: Vec<u8> = color.iter().cloned().cycle().take((width * height * 4) as usize).collect();
+        return texture::Texture::from_bytes(&data, Vector2u::new(width, height));
+    }
+
+    #[test]
+    fn identical_images_match() {
+        let image = solid(4, 4, [10, 20, 30, 255]);
+        let comparison = ImageComparison::compare(&image, &image, 0).unwrap();
+        assert_eq!(comparison.mismatched_pixels, 0);
+        assert_eq!(comparison.max_difference, 0);
+    }
+
+    #[test]
+    fn differences_within_the_tolerance_match() {
+        let expected = solid(4, 4, [10, 20, 30, 255]);
+        let actual = solid(4, 4, [12, 20, 29, 255]);
+        let comparison = ImageComparison::compare(&actual, &expected, 2).unwrap();
+        assert_eq!(comparison.mismatched_pixels, 0);
+        assert_eq!(comparison.max_difference, 2);
+    }
+
+    #[test]
+    fn differences_outside_the_tolerance_are_counted() {
+        let expected = solid(4, 4, [10, 20, 30, 255]);
+        let mut actual = expected.clone();
+        actual.data[4 * 5 + 1] = 30;
+        actual.data[4 * 9 + 3] = 200;
+        let comparison = ImageComparison::compare(&actual, &expected, 2).unwrap();
+        assert_eq!(comparison.mismatched_pixels, 2);
+        assert_eq!(comparison.max_difference, 55);
+    }
+
+    #[test]
+    fn images_of_different_sizes_do_not_compare() {
+        assert!(ImageComparison::compare(&solid(4, 4, [0; 4]), &solid(4, 2, [0; 4]), 0).is_err());
+        let truncated = texture::Texture::from_bytes(&[0; 12], Vector2u::new(2, 2));
+        assert!(ImageComparison::compare(&truncated, &solid(2, 2, [0; 4]), 0).is_err());
+    }
+
+    #[test]
+    fn diff_image_highlights_mismatched_pixels() {
+        let expected = solid(2, 1, [120, 60, 0, 255]);
+        let mut actual = expected.clone();
+        actual.data[0] = 220;
+        let comparison = ImageComparison::compare(&actual, &expected, 0).unwrap();
+        assert_eq!(comparison.diff.dimensions, expected.dimensions);
+        // The mismatched pixel is red, scaled by the difference, and the other is the faded luminance of the reference.
+        assert_eq!(&comparison.diff.data[0..4], &[128 + 50, 0, 0, 255]);
+        assert_eq!(&comparison.diff.data[4..8], &[15, 15, 15, 255]);
+    }
+
+}
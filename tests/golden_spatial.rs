@@ -0,0 +1,153 @@
+extern crate imperium;
+
+mod common;
+
+use imperium::*;
+use imperium::node::Node3D;
+use imperium::scene::Builder;
+use imperium::spatial::light::*;
+use imperium::spatial::material::*;
+use imperium::spatial::model::BufferedMesh;
+use imperium::spatial::pipe::mesh::MeshRenderPipeline;
+
+use common::*;
+
+// These tests render with a Vulkan driver, which is chosen with `AdapterPreference::Software` so that the output is reproducible.
+// CI machines without a GPU need a software driver such as lavapipe installed. Without any driver the tests which need a device are skipped,
+// unless `IMPERIUM_REQUIRE_DRIVER` is set, as it is on CI, in which case they fail.
+
+const FRAME_SIZE: u32 = 256;
+
+/// Creates the renderer, or returns `None` if there is no Vulkan driver to render with, in which case the calling test should return early.
+/// If a driver is required by the environment, a missing driver panics instead.
+fn create_renderer() -> Option<HeadlessRenderer> {
+    if !core::Instance::is_available() {
+        skip_without_driver("the Vulkan loader could not be found.");
+        return None;
+    }
+    match HeadlessRenderer::new(Vector2u::new(FRAME_SIZE, FRAME_SIZE), &core::AdapterPreference::Software) {
+        Ok(renderer) => return Some(renderer),
+        Err(Error::NoSuitableAdapter(reason)) => {
+            skip_without_driver(&reason);
+            return None;
+        },
+        Err(e) => panic!("Failed to create headless renderer: {}", e),
+    }
+}
+
+/// Creates a camera, three lit cubes and two point lights. The first light is moved by the offset specified.
+fn create_basic_scene(renderer: &mut HeadlessRenderer, light_offset: Vector3f) -> spatial::Scene3D<'static, 'static> {
+    let frame_size = renderer.get_size();
+    let mut scene = spatial::Scene3D::create_3d(&mut renderer.graphics).expect("Failed to create scene.");
+
+    let camera = scene.create_primary_entity(spatial::Camera::create(frame_size, 0.8));
+    camera.node_mut(&mut scene.world).unwrap().set_pos(Vector3f::new(0.0, 0.0, 0.0));
+
+    let (vertices, indices) = cube_geometry(10.0);
+    let descriptors = scene.world.read_resource::<MeshRenderPipeline>().material_descriptors();
+    let cubes: [(Vector3f, OpaqueColor, f32, f32); 3] = [
+        (Vector3f::new(-15.0, 0.0, -60.0), OpaqueColor::red(), 0.0, 0.8),
+        (Vector3f::new(0.0, 0.0, -70.0), OpaqueColor::green(), 0.5, 0.4),
+        (Vector3f::new(15.0, 0.0, -60.0), OpaqueColor::blue(), 1.0, 0.2),
+    ];
+    for (pos, color, metallic, roughness) in cubes.iter() {
+        let mesh = BufferedMesh::create(&vertices, Some(&indices), &mut renderer.graphics.device).expect("Failed to create mesh.");
+        let material = MaterialComponent::new(Material::color(*color, *metallic, *roughness), &descriptors, &mut renderer.graphics).expect("Failed to create material.");
+        let mut node = node::NodeObject3D::new();
+        node.set_pos(*pos);
+        scene.basic_builder().with(mesh).with(material).with(node).build();
+    }
+
+    let lights: [(Vector3f, OpaqueColor); 2] = [
+        (Vector3f::new(-20.0, 20.0, -40.0) + light_offset, OpaqueColor::new(400.0, 400.0, 400.0)),
+        (Vector3f::new(20.0, -10.0, -40.0), OpaqueColor::new(200.0, 150.0, 100.0)),
+    ];
+    for (pos, color) in lights.iter() {
+        let mut node = node::NodeObject3D::new();
+        node.set_pos(*pos);
+        scene.basic_builder().with(LightComponent::new(PointLight::new(*color))).with(node).build();
+    }
+
+    return scene;
+}
+
+/// Renders the basic scene with the first light moved by the offset specified.
+/// Nothing is returned if there is no driver to render with.
+fn render_basic_scene(light_offset: Vector3f) -> Option<texture::Texture> {
+    let mut renderer = create_renderer()?;
+    let mut scene = create_basic_scene(&mut renderer, light_offset);
+    return Some(renderer.render_scene(&mut scene, 1).expect("Failed to capture frame."));
+}
+
+/// Compares the basic scene with `tests/golden/spatial_basic.png`.
+/// The reference is recorded by running the test with `IMPERIUM_UPDATE_GOLDEN` set on a machine with a software driver.
+#[test]
+fn spatial_basic_scene() {
+    let frame = match render_basic_scene(Vector3f::zero()) {
+        Some(frame) => frame,
+        None => return,
+    };
+    let test = GoldenTest::new("spatial_basic", reference_dir())
+        .with_tolerance(2)
+        .with_max_mismatched_pixels(16);
+    if let Err(e) = test.check(&frame) {
+        panic!("{}", e);
+    }
+}
+
+#[test]
+fn rendering_is_deterministic() {
+    let (first, second) = match (render_basic_scene(Vector3f::zero()), render_basic_scene(Vector3f::zero())) {
+        (Some(first), Some(second)) => (first, second),
+        _ => return,
+    };
+    let comparison = ImageComparison::compare(&second, &first, 0).unwrap();
+    assert_eq!(comparison.mismatched_pixels, 0);
+}
+
+/// A visible change to the scene must fail the comparison, otherwise the golden tests could not catch regressions.
+#[test]
+fn moved_light_is_detected() {
+    let (frame, moved) = match (render_basic_scene(Vector3f::zero()), render_basic_scene(Vector3f::new(30.0, 0.0, 0.0))) {
+        (Some(frame), Some(moved)) => (frame, moved),
+        _ => return,
+    };
+    let comparison = ImageComparison::compare(&moved, &frame, 2).unwrap();
+    assert!(comparison.mismatched_pixels > 100, "only {} pixels changed", comparison.mismatched_pixels);
+    assert!(comparison.max_difference > 2);
+}
+
+/// Applying settings invalidates the surface, which a headless renderer must revalidate without a window.
+#[test]
+fn headless_renderer_renders_after_applying_settings() {
+    let mut renderer = match create_renderer() {
+        Some(renderer) => renderer,
+        None => return,
+    };
+    let mut scene = create_basic_scene(&mut renderer, Vector3f::zero());
+    let first = renderer.render_scene(&mut scene, 1).expect("Failed to capture frame.");
+
+    let settings = renderer.graphics.settings().clone().with_msaa_samples(4);
+    renderer.graphics.apply_settings(settings);
+    assert!(!renderer.graphics.render_surface.is_valid);
+    let second = renderer.render_scene(&mut scene, 2).expect("Failed to capture frame after applying settings.");
+    assert!(renderer.graphics.render_surface.is_valid);
+    assert_eq!(second.dimensions, first.dimensions);
+
+    renderer.graphics.render_surface.invalidate();
+    assert!(renderer.render_scene(&mut scene, 1).is_ok());
+}
+
+#[test]
+fn missing_reference_fails() {
+    if std::env::var_os(UPDATE_ENV_VAR).is_some() {
+        return;
+    }
+    let frame = texture::Texture::from_bytes(&[255; 16], Vector2u::new(2, 2));
+    let dir = std::env::temp_dir().join("imperium-golden-missing");
+    let test = GoldenTest::new("no_such_reference", &dir);
+    assert!(test.check(&frame).is_err());
+    assert!(!test.reference_path().exists());
+    // The rendered output is still written, so that it can be inspected and committed.
+    assert!(test.output_dir.join("no_such_reference.actual.png").exists());
+}